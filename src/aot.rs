@@ -0,0 +1,598 @@
+//! Ahead-of-time compilation of a verified program into a standalone Rust source file, which
+//! `rustc -O` turns into a native binary that behaves like `VM::run` on the same program.
+//!
+//! Each instruction becomes one arm of a `loop { match pc { ... } }`, so jumps, including jumps
+//! through registers, just assign `pc`. Every arm starts by moving `pc` past its instruction, as
+//! `VM::read_next_instruction` does. Error messages are rendered from `VmError` while compiling,
+//! so a failing binary reports exactly what the VM would, as `error: <message>` with exit status
+//! 1.
+//!
+//! The binary runs a single thread with no heap. Programs that spawn threads or processes, pass
+//! messages, or use arrays, maps or byte buffers are rejected. Strings are supported, but
+//! without identity: `LOADS` makes a new string each time.
+
+use std::fmt::{self, Write};
+
+use crate::{
+    instruction::{Constant, Instruction, Opcode},
+    verifier::{self, VerifyError},
+    vm::{ArithmeticMode, VmError, MAX_CALL_DEPTH, MAX_STACK_SIZE, REGISTER_COUNT},
+};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AotError {
+    /// The verifier rejected the program.
+    Invalid(Vec<VerifyError>),
+    /// The instruction at `pc` needs a part of the VM that compiled programs do not have.
+    Unsupported { pc: usize, opcode: Opcode },
+}
+
+impl fmt::Display for AotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AotError::Invalid(errors) => {
+                write!(f, "program does not verify")?;
+                for error in errors {
+                    write!(f, "\n{}", error)?;
+                }
+                Ok(())
+            }
+            AotError::Unsupported { pc, opcode } => write!(f, "{}: {:?} cannot be compiled ahead of time", pc, opcode),
+        }
+    }
+}
+
+impl std::error::Error for AotError {}
+
+/// The parts of the generated source that do not depend on the program.
+const RUNTIME: &str = r#"
+#[derive(Debug, Clone)]
+enum Value {
+    Nil,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(Rc<str>),
+}
+
+struct Vm {
+    registers: [i32; REGISTER_COUNT],
+    float_registers: [f64; REGISTER_COUNT],
+    value_registers: Vec<Value>,
+    value_stack: Vec<Value>,
+    stack: Vec<i32>,
+    frame_pointer: usize,
+    call_stack: Vec<usize>,
+    remainder: u32,
+    equal: bool,
+    overflow: bool,
+    carry: bool,
+}
+
+impl Vm {
+    fn read_pair(&self, register: usize) -> i64 {
+        ((self.registers[register + 1] as i64) << 32) | (self.registers[register] as u32 as i64)
+    }
+
+    fn write_pair(&mut self, register: usize, value: i64) {
+        self.registers[register] = value as i32;
+        self.registers[register + 1] = (value >> 32) as i32;
+    }
+
+    fn frame_slot(&self, offset: i64, error: &str) -> Result<usize, String> {
+        match usize::try_from(self.frame_pointer as i64 + offset) {
+            Ok(slot) if slot < self.stack.len() => Ok(slot),
+            _ => Err(error.to_string()),
+        }
+    }
+
+    fn relative_target(&self, pc: usize, offset: i64, error: &str) -> Result<usize, String> {
+        usize::try_from(pc as i64 + offset).map_err(|_| error.to_string())
+    }
+
+    fn string(&self, register: usize, error: &str) -> Result<Rc<str>, String> {
+        match &self.value_registers[register] {
+            Value::Str(string) => Ok(string.clone()),
+            _ => Err(error.to_string()),
+        }
+    }
+}
+
+fn main() {
+    let mut vm = Vm {
+        registers: [0; REGISTER_COUNT],
+        float_registers: [0.0; REGISTER_COUNT],
+        value_registers: vec![Value::Nil; REGISTER_COUNT],
+        value_stack: vec![],
+        stack: vec![],
+        frame_pointer: 0,
+        call_stack: vec![],
+        remainder: 0,
+        equal: false,
+        overflow: false,
+        carry: false,
+    };
+    if let Err(error) = run(&mut vm) {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+}
+"#;
+
+/// Translates a program into the source of a Rust binary that runs it with the given arithmetic
+/// mode.
+pub fn to_rust(program: &[Instruction], constants: &[Constant], mode: ArithmeticMode) -> Result<String, AotError> {
+    let verification = verifier::verify(program, constants);
+    if !verification.is_ok() {
+        return Err(AotError::Invalid(verification.errors));
+    }
+
+    let mut source = String::new();
+    writeln!(source, "// Compiled from a lang-vm program of {} instructions, with {:?} arithmetic.", program.len(), mode).unwrap();
+    writeln!(source, "#![allow(dead_code, unused_assignments, unused_variables, unreachable_code, clippy::all)]").unwrap();
+    writeln!(source).unwrap();
+    writeln!(source, "use std::rc::Rc;").unwrap();
+    writeln!(source).unwrap();
+    writeln!(source, "const REGISTER_COUNT: usize = {};", REGISTER_COUNT).unwrap();
+    writeln!(source, "const MAX_CALL_DEPTH: usize = {};", MAX_CALL_DEPTH).unwrap();
+    writeln!(source, "const MAX_STACK_SIZE: usize = {};", MAX_STACK_SIZE).unwrap();
+    source.push_str(RUNTIME);
+    writeln!(source).unwrap();
+    writeln!(source, "fn run(vm: &mut Vm) -> Result<(), String> {{").unwrap();
+    writeln!(source, "    let mut pc: usize = 0;").unwrap();
+    writeln!(source, "    loop {{").unwrap();
+    writeln!(source, "        match pc {{").unwrap();
+    for (pc, instruction) in program.iter().enumerate() {
+        writeln!(source, "            // {:?} {:?} {}", instruction.opcode, instruction.registers, instruction.integer_operand).unwrap();
+        writeln!(source, "            {} => {{", pc).unwrap();
+        writeln!(source, "                pc = {};", pc + 1).unwrap();
+        for line in statements(*instruction, pc, constants, mode)? {
+            writeln!(source, "                {}", line).unwrap();
+        }
+        writeln!(source, "            }}").unwrap();
+    }
+    // Running off the end finishes the only thread, like `HLT` but without the message.
+    writeln!(source, "            _ => return Ok(()),").unwrap();
+    writeln!(source, "        }}").unwrap();
+    writeln!(source, "    }}").unwrap();
+    writeln!(source, "}}").unwrap();
+    return Ok(source);
+}
+
+/// Returns from `run` with the message the VM gives for `error`.
+fn fail(error: VmError) -> String {
+    format!("return Err({:?}.to_string())", error.to_string())
+}
+
+/// Stores `result` from an integer operation whose outcome is bound as `(wrapped, overflowed)`,
+/// following the arithmetic mode as `VM::arithmetic_result` does.
+fn arithmetic(lines: &mut Vec<String>, mode: ArithmeticMode, pc: usize, saturated: &str, carry: &str, store: &str) {
+    lines.push("vm.overflow = overflowed;".to_string());
+    lines.push(format!("vm.carry = {};", carry));
+    match mode {
+        ArithmeticMode::Trap => {
+            lines.push(format!("if overflowed {{ {} }}", fail(VmError::IntegerOverflow { pc })));
+            lines.push("let result = wrapped;".to_string());
+        }
+        ArithmeticMode::Wrap => lines.push("let result = wrapped;".to_string()),
+        ArithmeticMode::Saturate => lines.push(format!("let result = if overflowed {{ {} }} else {{ wrapped }};", saturated)),
+    }
+    lines.push(store.to_string());
+}
+
+/// The statements that run one instruction, after `pc` has moved past it.
+fn statements(instruction: Instruction, pc: usize, constants: &[Constant], mode: ArithmeticMode) -> Result<Vec<String>, AotError> {
+    let [first, second, third] = instruction.registers;
+    let operand = instruction.integer_operand;
+    let register = |register: usize| format!("vm.registers[{}]", register);
+    // The second input of a binary integer operation, which the immediate forms take from the
+    // instruction.
+    let second_number = if instruction.opcode.has_integer_operand() { format!("{}i32", operand as i32) } else { register(second) };
+    let mut lines = vec![];
+
+    match instruction.opcode {
+        Opcode::LOAD => lines.push(format!("{} = {};", register(first), operand as i32)),
+        Opcode::HLT => {
+            lines.push("println!(\"HLT encountered\");".to_string());
+            lines.push("return Ok(());".to_string());
+        }
+        Opcode::ADD | Opcode::ADDI | Opcode::SUB | Opcode::SUBI | Opcode::MUL | Opcode::MULI => {
+            let operation = match instruction.opcode {
+                Opcode::ADD | Opcode::ADDI => "add",
+                Opcode::SUB | Opcode::SUBI => "sub",
+                _ => "mul",
+            };
+            lines.push(format!("let (first, second) = ({}, {});", register(first), second_number));
+            lines.push(format!("let (wrapped, overflowed) = first.overflowing_{}(second);", operation));
+            let saturated = format!("first.saturating_{}(second)", operation);
+            let carry = format!("(first as u32).overflowing_{}(second as u32).1", operation);
+            arithmetic(&mut lines, mode, pc, &saturated, &carry, &format!("{} = result;", register(third)));
+        }
+        Opcode::DIV | Opcode::DIVI => {
+            lines.push(format!("let (first, second) = ({}, {});", register(first), second_number));
+            lines.push(format!("if second == 0 {{ {} }}", fail(VmError::DivisionByZero { pc })));
+            lines.push("let (wrapped, overflowed) = first.overflowing_div(second);".to_string());
+            arithmetic(&mut lines, mode, pc, "first.saturating_div(second)", "false", &format!("{} = result;", register(third)));
+            lines.push("vm.remainder = first.wrapping_rem(second) as u32;".to_string());
+        }
+        Opcode::INC | Opcode::DEC => {
+            let (operation, carry) = if instruction.opcode == Opcode::INC { ("add", "number == -1") } else { ("sub", "number == 0") };
+            lines.push(format!("let number = {};", register(first)));
+            lines.push(format!("let (wrapped, overflowed) = number.overflowing_{}(1);", operation));
+            let saturated = format!("number.saturating_{}(1)", operation);
+            arithmetic(&mut lines, mode, pc, &saturated, carry, &format!("{} = result;", register(first)));
+        }
+        Opcode::JMP => lines.push(format!("pc = {} as usize;", register(first))),
+        Opcode::JMPF | Opcode::JMPB | Opcode::JEQF | Opcode::JEQB | Opcode::JNEQF | Opcode::JNEQB => {
+            let sign = if matches!(instruction.opcode, Opcode::JMPF | Opcode::JEQF | Opcode::JNEQF) { "" } else { "-" };
+            let error = VmError::JumpOutOfRange { pc }.to_string();
+            let jump = format!("pc = vm.relative_target(pc, {}({} as i64), {:?})?;", sign, register(first), error);
+            lines.push(match instruction.opcode {
+                Opcode::JEQF | Opcode::JEQB => format!("if vm.equal {{ {} }}", jump),
+                Opcode::JNEQF | Opcode::JNEQB => format!("if !vm.equal {{ {} }}", jump),
+                _ => jump,
+            });
+        }
+        Opcode::EQ
+        | Opcode::EQI
+        | Opcode::NEQ
+        | Opcode::NEQI
+        | Opcode::GT
+        | Opcode::GTI
+        | Opcode::LT
+        | Opcode::LTI
+        | Opcode::GTQ
+        | Opcode::GTQI
+        | Opcode::LTQ
+        | Opcode::LTQI => {
+            let comparison = comparison(instruction.opcode);
+            lines.push(format!("vm.equal = {} {} {};", register(first), comparison, second_number));
+        }
+        Opcode::JEQ => lines.push(format!("if vm.equal {{ pc = {} as usize; }}", register(first))),
+        Opcode::JNEQ => lines.push(format!("if !vm.equal {{ pc = {} as usize; }}", register(first))),
+        Opcode::JZ => lines.push(format!("if {} == 0 {{ pc = {} as usize; }}", register(first), register(second))),
+        Opcode::JNZ => lines.push(format!("if {} != 0 {{ pc = {} as usize; }}", register(first), register(second))),
+        Opcode::JO => lines.push(format!("if vm.overflow {{ pc = {} as usize; }}", register(first))),
+        Opcode::JNO => lines.push(format!("if !vm.overflow {{ pc = {} as usize; }}", register(first))),
+        Opcode::AND => lines.push(format!("{} = {} & {};", register(third), register(first), register(second))),
+        Opcode::OR => lines.push(format!("{} = {} | {};", register(third), register(first), register(second))),
+        Opcode::XOR => lines.push(format!("{} = {} ^ {};", register(third), register(first), register(second))),
+        Opcode::NOT => lines.push(format!("{} = !{};", register(second), register(first))),
+        Opcode::SHL => lines.push(format!("{} = {}.wrapping_shl({} as u32);", register(third), register(first), register(second))),
+        Opcode::SHR => {
+            lines.push(format!("{} = ({} as u32).wrapping_shr({} as u32) as i32;", register(third), register(first), register(second)))
+        }
+        Opcode::SAR => lines.push(format!("{} = {}.wrapping_shr({} as u32);", register(third), register(first), register(second))),
+        Opcode::MOD => {
+            lines.push(format!("if {} == 0 {{ {} }}", register(second), fail(VmError::DivisionByZero { pc })));
+            lines.push(format!("{} = {}.wrapping_rem_euclid({});", register(third), register(first), register(second)));
+        }
+        Opcode::GETREM => lines.push(format!("{} = vm.remainder as i32;", register(first))),
+        Opcode::LOADF => match constants.get(operand as usize) {
+            Some(Constant::Float(number)) => {
+                lines.push(format!("vm.float_registers[{}] = f64::from_bits({:#x});", first, number.to_bits()))
+            }
+            _ => lines.push(format!("{};", fail(VmError::BadConstant { pc, index: operand }))),
+        },
+        Opcode::ADDF | Opcode::SUBF | Opcode::MULF | Opcode::DIVF => {
+            let operation = match instruction.opcode {
+                Opcode::ADDF => "+",
+                Opcode::SUBF => "-",
+                Opcode::MULF => "*",
+                _ => "/",
+            };
+            lines.push(format!(
+                "vm.float_registers[{}] = vm.float_registers[{}] {} vm.float_registers[{}];",
+                third, first, operation, second
+            ));
+        }
+        Opcode::EQF | Opcode::NEQF | Opcode::GTF | Opcode::LTF | Opcode::GTQF | Opcode::LTQF => {
+            let comparison = comparison(instruction.opcode);
+            lines.push(format!("vm.equal = vm.float_registers[{}] {} vm.float_registers[{}];", first, comparison, second));
+        }
+        Opcode::ITOF => lines.push(format!("vm.float_registers[{}] = {} as f64;", second, register(first))),
+        Opcode::FTOI => {
+            lines.push(format!("let number = vm.float_registers[{}].trunc();", first));
+            lines.push("let converted = number as i32;".to_string());
+            lines.push("let overflowed = number.is_nan() || number < i32::MIN as f64 || number > i32::MAX as f64;".to_string());
+            lines.push("let wrapped = converted;".to_string());
+            arithmetic(&mut lines, mode, pc, "converted", "false", &format!("{} = result;", register(second)));
+        }
+        Opcode::LOADL => lines.push(format!("vm.write_pair({}, {});", first, operand)),
+        Opcode::ADDL | Opcode::SUBL | Opcode::MULL => {
+            let operation = match instruction.opcode {
+                Opcode::ADDL => "add",
+                Opcode::SUBL => "sub",
+                _ => "mul",
+            };
+            lines.push(format!("let (first, second) = (vm.read_pair({}), vm.read_pair({}));", first, second));
+            lines.push(format!("let (wrapped, overflowed) = first.overflowing_{}(second);", operation));
+            let saturated = format!("first.saturating_{}(second)", operation);
+            let carry = format!("(first as u64).overflowing_{}(second as u64).1", operation);
+            arithmetic(&mut lines, mode, pc, &saturated, &carry, &format!("vm.write_pair({}, result);", third));
+        }
+        Opcode::DIVL => {
+            lines.push(format!("let (first, second) = (vm.read_pair({}), vm.read_pair({}));", first, second));
+            lines.push(format!("if second == 0 {{ {} }}", fail(VmError::DivisionByZero { pc })));
+            lines.push("let (wrapped, overflowed) = first.overflowing_div(second);".to_string());
+            arithmetic(&mut lines, mode, pc, "first.saturating_div(second)", "false", &format!("vm.write_pair({}, result);", third));
+        }
+        Opcode::MODL => {
+            lines.push(format!("let (first, second) = (vm.read_pair({}), vm.read_pair({}));", first, second));
+            lines.push(format!("if second == 0 {{ {} }}", fail(VmError::DivisionByZero { pc })));
+            lines.push(format!("vm.write_pair({}, first.wrapping_rem_euclid(second));", third));
+        }
+        Opcode::EQL | Opcode::NEQL | Opcode::GTL | Opcode::LTL | Opcode::GTQL | Opcode::LTQL => {
+            let comparison = comparison(instruction.opcode);
+            lines.push(format!("vm.equal = vm.read_pair({}) {} vm.read_pair({});", first, comparison, second));
+        }
+        Opcode::SEXT => lines.push(format!("vm.write_pair({}, {} as i64);", second, register(first))),
+        Opcode::NIL => lines.push(format!("vm.value_registers[{}] = Value::Nil;", first)),
+        Opcode::BOXI => lines.push(format!("vm.value_registers[{}] = Value::Int({} as i64);", second, register(first))),
+        Opcode::BOXF => lines.push(format!("vm.value_registers[{}] = Value::Float(vm.float_registers[{}]);", second, first)),
+        Opcode::BOXB => lines.push(format!("vm.value_registers[{}] = Value::Bool(vm.equal);", first)),
+        Opcode::UNBOX => {
+            lines.push(format!("let number = match vm.value_registers[{}].clone() {{", first));
+            lines.push("    Value::Int(number) => {".to_string());
+            let mut unboxed = vec![
+                "let saturated = number.clamp(i32::MIN as i64, i32::MAX as i64) as i32;".to_string(),
+                "let (wrapped, overflowed) = (number as i32, saturated as i64 != number);".to_string(),
+            ];
+            arithmetic(&mut unboxed, mode, pc, "saturated", "false", "result");
+            lines.extend(unboxed.into_iter().map(|line| format!("        {}", line)));
+            lines.push("    }".to_string());
+            lines.push("    Value::Bool(boolean) => boolean as i32,".to_string());
+            lines.push(format!("    _ => {},", fail(VmError::TypeError { pc })));
+            lines.push("};".to_string());
+            lines.push(format!("{} = number;", register(second)));
+        }
+        Opcode::UNBOXF => {
+            lines.push(format!("vm.float_registers[{}] = match vm.value_registers[{}] {{", second, first));
+            lines.push("    Value::Float(number) => number,".to_string());
+            lines.push("    Value::Int(number) => number as f64,".to_string());
+            lines.push(format!("    _ => {},", fail(VmError::TypeError { pc })));
+            lines.push("};".to_string());
+        }
+        Opcode::MOVV => lines.push(format!("vm.value_registers[{}] = vm.value_registers[{}].clone();", second, first)),
+        Opcode::PUSHV => lines.push(format!("vm.value_stack.push(vm.value_registers[{}].clone());", first)),
+        Opcode::POPV => {
+            let underflow = fail(VmError::StackUnderflow { pc });
+            lines.push(format!("vm.value_registers[{}] = match vm.value_stack.pop() {{ Some(value) => value, None => {} }};", first, underflow));
+        }
+        Opcode::LOADS => match constants.get(operand as usize) {
+            Some(Constant::String(string)) => lines.push(format!("vm.value_registers[{}] = Value::Str(Rc::from({:?}));", first, string)),
+            _ => lines.push(format!("{};", fail(VmError::BadConstant { pc, index: operand }))),
+        },
+        Opcode::CONCAT => {
+            let error = VmError::TypeError { pc }.to_string();
+            lines.push(format!("let first = vm.string({}, {:?})?;", first, error));
+            lines.push(format!("let second = vm.string({}, {:?})?;", second, error));
+            lines.push(format!("vm.value_registers[{}] = Value::Str(Rc::from([&*first, &*second].concat()));", third));
+        }
+        Opcode::SLEN => {
+            let error = VmError::TypeError { pc }.to_string();
+            lines.push(format!("{} = vm.string({}, {:?})?.chars().count() as i32;", register(second), first, error));
+        }
+        Opcode::SUBSTR => {
+            lines.push(format!("let (start, end) = ({}, {});", register(second), register(second + 1)));
+            lines.push(format!("let string = vm.string({}, {:?})?;", first, VmError::TypeError { pc }.to_string()));
+            lines.push(format!(
+                "if start < 0 || end < start || end as usize > string.chars().count() {{ {} }}",
+                fail(VmError::IndexOutOfBounds { pc })
+            ));
+            lines.push("let substring: String = string.chars().skip(start as usize).take((end - start) as usize).collect();".to_string());
+            lines.push(format!("vm.value_registers[{}] = Value::Str(Rc::from(substring));", third));
+        }
+        Opcode::SCMP => {
+            let error = VmError::TypeError { pc }.to_string();
+            lines.push(format!("let first = vm.string({}, {:?})?;", first, error));
+            lines.push(format!("let second = vm.string({}, {:?})?;", second, error));
+            lines.push("let ordering = first.cmp(&second) as i32;".to_string());
+            lines.push("vm.equal = ordering == 0;".to_string());
+            lines.push(format!("{} = ordering;", register(third)));
+        }
+        Opcode::CHARAT => {
+            lines.push(format!("let index = {};", register(second)));
+            lines.push(format!("let string = vm.string({}, {:?})?;", first, VmError::TypeError { pc }.to_string()));
+            lines.push("match usize::try_from(index).ok().and_then(|index| string.chars().nth(index)) {".to_string());
+            lines.push(format!("    Some(character) => {} = character as i32,", register(third)));
+            lines.push(format!("    None => {},", fail(VmError::IndexOutOfBounds { pc })));
+            lines.push("}".to_string());
+        }
+        Opcode::ITOS => lines.push(format!("vm.value_registers[{}] = Value::Str(Rc::from({}.to_string()));", second, register(first))),
+        Opcode::STOI => {
+            let error = VmError::TypeError { pc }.to_string();
+            lines.push(format!("let parsed = vm.string({}, {:?})?.trim().parse::<i32>();", first, error));
+            lines.push("vm.equal = parsed.is_ok();".to_string());
+            lines.push(format!("{} = parsed.unwrap_or(0);", register(second)));
+        }
+        Opcode::PRTS => {
+            let error = VmError::TypeError { pc }.to_string();
+            lines.push(format!("println!(\"{{}}\", vm.string({}, {:?})?);", first, error));
+        }
+        Opcode::CALL => {
+            lines.push(format!("if vm.call_stack.len() >= MAX_CALL_DEPTH {{ {} }}", fail(VmError::StackOverflow { pc })));
+            lines.push("vm.call_stack.push(pc);".to_string());
+            lines.push(format!("pc = {} as usize;", register(first)));
+        }
+        Opcode::RET => {
+            let underflow = fail(VmError::StackUnderflow { pc });
+            lines.push(format!("pc = match vm.call_stack.pop() {{ Some(pc) => pc, None => {} }};", underflow));
+        }
+        Opcode::PUSH => lines.push(format!("vm.stack.push({});", register(first))),
+        Opcode::POP => {
+            let underflow = fail(VmError::StackUnderflow { pc });
+            lines.push(format!("{} = match vm.stack.pop() {{ Some(number) => number, None => {} }};", register(first), underflow));
+        }
+        Opcode::ENTER => {
+            if operand < 0 {
+                lines.push(format!("{};", fail(VmError::NegativeSize { pc })));
+            } else {
+                let overflow = fail(VmError::StackOverflow { pc });
+                lines.push(format!("if vm.stack.len() + 1 + {} > MAX_STACK_SIZE {{ {} }}", operand, overflow));
+                lines.push("vm.stack.push(vm.frame_pointer as i32);".to_string());
+                lines.push("vm.frame_pointer = vm.stack.len();".to_string());
+                lines.push(format!("vm.stack.resize(vm.frame_pointer + {}, 0);", operand));
+            }
+        }
+        Opcode::LEAVE => {
+            let underflow = fail(VmError::StackUnderflow { pc });
+            lines.push(format!("if vm.frame_pointer == 0 || vm.frame_pointer > vm.stack.len() {{ {} }}", underflow));
+            lines.push("vm.stack.truncate(vm.frame_pointer);".to_string());
+            lines.push(format!("vm.frame_pointer = match vm.stack.pop() {{ Some(pointer) => pointer as usize, None => {} }};", underflow));
+        }
+        Opcode::LDF => {
+            let error = VmError::IndexOutOfBounds { pc }.to_string();
+            lines.push(format!("let slot = vm.frame_slot({}, {:?})?;", operand, error));
+            lines.push(format!("{} = vm.stack[slot];", register(first)));
+        }
+        Opcode::STF => {
+            let error = VmError::IndexOutOfBounds { pc }.to_string();
+            lines.push(format!("let slot = vm.frame_slot({}, {:?})?;", operand, error));
+            lines.push(format!("vm.stack[slot] = {};", register(first)));
+        }
+        // With only one thread, the scheduler always picks it again.
+        Opcode::YIELD => lines.push("// Nothing to do with a single thread.".to_string()),
+        Opcode::IGL => lines.push(format!("{};", fail(VmError::IllegalOpcode { pc }))),
+        Opcode::ALOC
+        | Opcode::TAG
+        | Opcode::EQV
+        | Opcode::NEWARR
+        | Opcode::NEWMAP
+        | Opcode::GETV
+        | Opcode::SETV
+        | Opcode::LENV
+        | Opcode::APPV
+        | Opcode::SPAWN
+        | Opcode::JOIN
+        | Opcode::SPAWNP
+        | Opcode::SEND
+        | Opcode::RECV
+        | Opcode::SELF => return Err(AotError::Unsupported { pc, opcode: instruction.opcode }),
+    }
+    return Ok(lines);
+}
+
+/// The Rust operator for a comparison opcode of any type.
+fn comparison(opcode: Opcode) -> &'static str {
+    match opcode {
+        Opcode::EQ | Opcode::EQI | Opcode::EQF | Opcode::EQL => "==",
+        Opcode::NEQ | Opcode::NEQI | Opcode::NEQF | Opcode::NEQL => "!=",
+        Opcode::GT | Opcode::GTI | Opcode::GTF | Opcode::GTL => ">",
+        Opcode::LT | Opcode::LTI | Opcode::LTF | Opcode::LTL => "<",
+        Opcode::GTQ | Opcode::GTQI | Opcode::GTQF | Opcode::GTQL => ">=",
+        _ => "<=",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use super::*;
+    use crate::testing::{self, assemble};
+
+    /// Compiles the generated source with `rustc` and runs it, returning its exit status, standard
+    /// output and standard error.
+    fn build_and_run(source: &str) -> (i32, String, String) {
+        let binary_path = testing::temp_path("aot", "");
+        let source_path = binary_path.with_extension("rs");
+        fs::write(&source_path, source).unwrap();
+        let build = Command::new("rustc").arg("--edition=2021").arg("-o").arg(&binary_path).arg(&source_path).output().unwrap();
+        assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+        let output = Command::new(&binary_path).output().unwrap();
+        fs::remove_file(&source_path).unwrap();
+        fs::remove_file(&binary_path).unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        return (output.status.code().unwrap(), stdout, stderr);
+    }
+
+    /// Runs the program with the VM, describing what it did the way the compiled binary would.
+    fn interpret(program: Vec<Instruction>, constants: Vec<Constant>, mode: ArithmeticMode) -> (i32, String, String) {
+        let interpreted = testing::interpret(program, constants, mode);
+        let mut stdout: String = interpreted.output.iter().map(|line| format!("{}\n", line)).collect();
+        if interpreted.halted {
+            stdout.push_str("HLT encountered\n");
+        }
+        return match interpreted.result {
+            Ok(()) => (0, stdout, String::new()),
+            Err(error) => (1, stdout, format!("error: {}\n", error)),
+        };
+    }
+
+    fn assert_same_as_interpreter(program: Vec<Instruction>, constants: Vec<Constant>, mode: ArithmeticMode) -> (i32, String, String) {
+        let source = to_rust(&program, &constants, mode).unwrap();
+        let compiled = build_and_run(&source);
+        assert_eq!(compiled, interpret(program, constants, mode));
+        return compiled;
+    }
+
+    #[test]
+    fn test_compiled_lang_programs_match_interpreter() {
+        for program in testing::compiled_workloads() {
+            let (status, stdout, _) = assert_same_as_interpreter(program, vec![], ArithmeticMode::Wrap);
+            assert_eq!((status, stdout.as_str()), (0, "111144\n610\nHLT encountered\n"));
+        }
+    }
+
+    #[test]
+    fn test_compiled_assembly_matches_interpreter() {
+        // Floats, register pairs, the stack, strings and relative jumps, with the results printed.
+        let source = ".data\nhalf: .float 0.5\ngreeting: .string \"total: \"\n.code\n\
+            LOAD $0 #7\nITOF $0 $0\nLOADF $1 @half\nMULF $0 $1 $2\nFTOI $2 $3\n\
+            LOADL $4 #4294967296\nLOAD $6 #3\nSEXT $6 $6\nMULL $4 $6 $8\nSUBL $8 $6 $8\n\
+            PUSH $3\nPOP $10\nLOAD $11 #1\nJMPF $11\nLOAD $10 #99\nLOAD $10 #98\n\
+            ADD $10 $8 $12\nLOADS $0 @greeting\nITOS $12 $1\nCONCAT $0 $1 $2\nPRTS $2\nSLEN $2 $13\nITOS $13 $3\nPRTS $3\nHLT";
+        let (program, constants) = assemble(source);
+        let (status, stdout, _) = assert_same_as_interpreter(program, constants, ArithmeticMode::Wrap);
+        assert_eq!((status, stdout.as_str()), (0, "total: 95\n9\nHLT encountered\n"));
+    }
+
+    #[test]
+    fn test_remainders_match_interpreter() {
+        let (program, constants) = assemble(testing::REMAINDERS);
+        let (status, stdout, _) = assert_same_as_interpreter(program, constants, ArithmeticMode::Trap);
+        assert_eq!((status, stdout.as_str()), (0, "2\n2\n0\n2147483647\n0\n-1\n2147483647\n993\n0\nHLT encountered\n"));
+    }
+
+    #[test]
+    fn test_relative_jumps_match_interpreter() {
+        let program = vec![
+            Instruction::new(Opcode::LOAD, [0, 0, 0], 50),
+            Instruction::new(Opcode::LOAD, [1, 0, 0], 1),
+            Instruction::new(Opcode::ADD, [0, 1, 0], 0),
+            Instruction::new(Opcode::EQ, [0, 0, 0], 0),
+            Instruction::new(Opcode::JEQB, [0, 0, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ];
+        let (status, _, stderr) = assert_same_as_interpreter(program, vec![], ArithmeticMode::Wrap);
+        assert_eq!((status, stderr.as_str()), (1, "error: 4: relative jump lands before the start of the program\n"));
+    }
+
+    #[test]
+    fn test_arithmetic_modes_match_interpreter() {
+        let program = vec![
+            Instruction::new(Opcode::LOAD, [0, 0, 0], i32::MAX as i64),
+            Instruction::new(Opcode::ADDI, [0, 0, 1], 1),
+            Instruction::new(Opcode::ITOS, [1, 0, 0], 0),
+            Instruction::new(Opcode::PRTS, [0, 0, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ];
+        let (status, _, stderr) = assert_same_as_interpreter(program.clone(), vec![], ArithmeticMode::Trap);
+        assert_eq!((status, stderr.as_str()), (1, "error: 1: integer overflow\n"));
+        let (_, stdout, _) = assert_same_as_interpreter(program.clone(), vec![], ArithmeticMode::Wrap);
+        assert_eq!(stdout, "-2147483648\nHLT encountered\n");
+        let (_, stdout, _) = assert_same_as_interpreter(program, vec![], ArithmeticMode::Saturate);
+        assert_eq!(stdout, "2147483647\nHLT encountered\n");
+    }
+
+    #[test]
+    fn test_rejects_unsupported_and_invalid_programs() {
+        let program = vec![Instruction::new(Opcode::NEWMAP, [0, 0, 0], 0), Instruction::new(Opcode::HLT, [0; 3], 0)];
+        assert_eq!(to_rust(&program, &[], ArithmeticMode::Wrap), Err(AotError::Unsupported { pc: 0, opcode: Opcode::NEWMAP }));
+
+        let program = vec![Instruction::new(Opcode::LOAD, [40, 0, 0], 1), Instruction::new(Opcode::HLT, [0; 3], 0)];
+        assert!(matches!(to_rust(&program, &[], ArithmeticMode::Wrap), Err(AotError::Invalid(_))));
+    }
+}
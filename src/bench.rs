@@ -0,0 +1,96 @@
+//! Benchmarks comparing `VM::run` with the pre-decoded `dispatch` engine, with and without
+//! superinstructions, on a few compiled programs. Run them with `lang-vm bench [scale]` on a
+//! release build.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    dispatch::{self, Code},
+    lang,
+    vm::VM,
+};
+
+#[derive(Debug, Clone)]
+pub struct Benchmark {
+    pub name: &'static str,
+    /// Fastest time with `VM::run`.
+    pub step: Duration,
+    /// Fastest time with `dispatch::Code::unfused`, decoding included.
+    pub decoded: Duration,
+    /// Fastest time with `dispatch::run`, which also fuses superinstructions.
+    pub fused: Duration,
+}
+
+impl Benchmark {
+    /// How many times faster the decoded engine ran with superinstructions.
+    pub fn speedup(&self) -> f64 {
+        self.step.as_secs_f64() / self.fused.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// The benchmark programs, sized by `scale`.
+fn workloads(scale: u32) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "loop",
+            format!("let n = {};\nlet total = 0;\nwhile n > 0 {{\n    total = total + n * 3 - 1;\n    n = n - 1;\n}}", 100_000 * scale),
+        ),
+        (
+            "fib",
+            format!("fn fib(n) {{\n    if n < 2 {{ return n; }}\n    return fib(n - 1) + fib(n - 2);\n}}\nlet f = fib({});", 20 + scale),
+        ),
+        (
+            "primes",
+            format!(
+                "let count = 0;\nlet n = 2;\nwhile n < {} {{\n    let d = 2;\n    let prime = 1;\n    while d * d <= n {{\n        if n % d == 0 {{ prime = 0; }}\n        d = d + 1;\n    }}\n    count = count + prime;\n    n = n + 1;\n}}",
+                2_000 * scale
+            ),
+        ),
+    ]
+}
+
+/// Runs every benchmark `repetitions` times with each engine, keeping the fastest times. Panics if
+/// the two engines ever disagree on the result.
+pub fn run_benchmarks(scale: u32, repetitions: usize) -> Vec<Benchmark> {
+    let mut benchmarks = vec![];
+    for (name, source) in workloads(scale) {
+        let program = lang::compile(&source).unwrap();
+        let mut benchmark = Benchmark { name, step: Duration::MAX, decoded: Duration::MAX, fused: Duration::MAX };
+        for _ in 0..repetitions.max(1) {
+            let mut stepped = VM::new();
+            stepped.program = program.clone().into();
+            let start = Instant::now();
+            stepped.run().unwrap();
+            benchmark.step = benchmark.step.min(start.elapsed());
+
+            let mut decoded = VM::new();
+            decoded.program = stepped.program.clone();
+            let start = Instant::now();
+            Code::unfused(&decoded.program).run(&mut decoded).unwrap();
+            benchmark.decoded = benchmark.decoded.min(start.elapsed());
+
+            let mut fused = VM::new();
+            fused.program = stepped.program.clone();
+            let start = Instant::now();
+            dispatch::run(&mut fused).unwrap();
+            benchmark.fused = benchmark.fused.min(start.elapsed());
+
+            assert_eq!(decoded.registers, stepped.registers, "{} gave different results", name);
+            assert_eq!(fused.registers, stepped.registers, "{} gave different results", name);
+        }
+        benchmarks.push(benchmark);
+    }
+    return benchmarks;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_benchmarks_agree() {
+        let benchmarks = run_benchmarks(1, 1);
+        assert_eq!(benchmarks.iter().map(|benchmark| benchmark.name).collect::<Vec<_>>(), ["loop", "fib", "primes"]);
+        assert!(benchmarks.iter().all(|benchmark| benchmark.speedup() > 0.0));
+    }
+}
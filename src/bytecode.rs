@@ -0,0 +1,254 @@
+use std::fmt;
+
+use crate::instruction::{Constant, Instruction, Opcode};
+
+/// Every encoded program starts with these bytes.
+pub const MAGIC: [u8; 4] = *b"LVM\0";
+pub const VERSION: u8 = 4;
+
+/// Size of one encoded instruction: opcode, three register bytes and a little-endian `i32` operand.
+pub const INSTRUCTION_SIZE: usize = 8;
+
+/// Set on the opcode byte when the operand does not fit in an `i32` and is instead written as a
+/// little-endian `i64`, making the instruction `WIDE_INSTRUCTION_SIZE` bytes. Only opcodes with a
+/// wide immediate may set it. Opcodes are numbered below 128 so this bit is always free.
+pub const WIDE_FLAG: u8 = 0x80;
+pub const WIDE_INSTRUCTION_SIZE: usize = 12;
+
+const FLOAT_TAG: u8 = 0;
+/// Followed by a `u32` byte length and that many bytes of UTF-8.
+const STRING_TAG: u8 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum BytecodeError {
+    BadHeader,
+    UnsupportedVersion(u8),
+    /// The constant pool entry at this index is truncated or has an unknown tag.
+    BadConstant { index: usize },
+    /// The input ends partway through the instruction at this pc.
+    Truncated { pc: usize },
+    /// A register index does not fit in the single byte the format gives it.
+    RegisterTooLarge { pc: usize, register: usize },
+    /// A 64-bit operand on an opcode that only takes 32 bits.
+    OperandTooWide { pc: usize },
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::BadHeader => write!(f, "missing bytecode header"),
+            BytecodeError::UnsupportedVersion(version) => write!(f, "unsupported bytecode version {}", version),
+            BytecodeError::BadConstant { index } => write!(f, "constant {} is malformed", index),
+            BytecodeError::Truncated { pc } => write!(f, "{}: bytecode ends partway through an instruction", pc),
+            BytecodeError::RegisterTooLarge { pc, register } => {
+                write!(f, "{}: register ${} cannot be encoded in one byte", pc, register)
+            }
+            BytecodeError::OperandTooWide { pc } => write!(f, "{}: operand does not fit in 32 bits", pc),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+/// Encodes a program as the header, a `u32` count of constants followed by each tagged
+/// constant, and then the instructions.
+pub fn encode(program: &[Instruction], constants: &[Constant]) -> Result<Vec<u8>, BytecodeError> {
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + program.len() * INSTRUCTION_SIZE);
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(VERSION);
+
+    bytes.extend_from_slice(&(constants.len() as u32).to_le_bytes());
+    for constant in constants {
+        match constant {
+            Constant::Float(number) => {
+                bytes.push(FLOAT_TAG);
+                bytes.extend_from_slice(&number.to_le_bytes());
+            }
+            Constant::String(string) => {
+                bytes.push(STRING_TAG);
+                bytes.extend_from_slice(&(string.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(string.as_bytes());
+            }
+        }
+    }
+
+    for (pc, instruction) in program.iter().enumerate() {
+        let opcode: u8 = instruction.opcode.into();
+        let narrow_operand = i32::try_from(instruction.integer_operand);
+        match narrow_operand {
+            Ok(_) => bytes.push(opcode),
+            Err(_) if instruction.opcode.has_wide_immediate() => bytes.push(opcode | WIDE_FLAG),
+            Err(_) => return Err(BytecodeError::OperandTooWide { pc }),
+        }
+        for register in instruction.registers {
+            match u8::try_from(register) {
+                Ok(register) => bytes.push(register),
+                Err(_) => return Err(BytecodeError::RegisterTooLarge { pc, register }),
+            }
+        }
+        match narrow_operand {
+            Ok(operand) => bytes.extend_from_slice(&operand.to_le_bytes()),
+            Err(_) => bytes.extend_from_slice(&instruction.integer_operand.to_le_bytes()),
+        }
+    }
+    return Ok(bytes);
+}
+
+pub fn decode(bytes: &[u8]) -> Result<(Vec<Instruction>, Vec<Constant>), BytecodeError> {
+    if bytes.len() < MAGIC.len() + 1 || bytes[..MAGIC.len()] != MAGIC {
+        return Err(BytecodeError::BadHeader);
+    }
+    if bytes[MAGIC.len()] != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(bytes[MAGIC.len()]));
+    }
+    let mut reader = Reader { bytes, position: MAGIC.len() + 1 };
+
+    let constant_count = match reader.take::<4>() {
+        Some(count) => u32::from_le_bytes(count) as usize,
+        None => return Err(BytecodeError::BadHeader),
+    };
+    let mut constants = vec![];
+    for index in 0..constant_count {
+        let constant = match reader.take::<1>() {
+            Some([FLOAT_TAG]) => reader.take::<8>().map(|number| Constant::Float(f64::from_le_bytes(number))),
+            Some([STRING_TAG]) => reader
+                .take::<4>()
+                .and_then(|length| reader.take_slice(u32::from_le_bytes(length) as usize))
+                .and_then(|string| String::from_utf8(string.to_vec()).ok())
+                .map(Constant::String),
+            _ => None,
+        };
+        match constant {
+            Some(constant) => constants.push(constant),
+            None => return Err(BytecodeError::BadConstant { index }),
+        }
+    }
+
+    let mut program = vec![];
+    while !reader.is_empty() {
+        let pc = program.len();
+        let Some([opcode, first, second, third]) = reader.take::<4>() else {
+            return Err(BytecodeError::Truncated { pc });
+        };
+        let integer_operand = match opcode & WIDE_FLAG {
+            0 => reader.take::<4>().map(|operand| i32::from_le_bytes(operand) as i64),
+            _ => reader.take::<8>().map(i64::from_le_bytes),
+        };
+        let Some(integer_operand) = integer_operand else {
+            return Err(BytecodeError::Truncated { pc });
+        };
+        let wide = opcode & WIDE_FLAG != 0;
+        let opcode: Opcode = (opcode & !WIDE_FLAG).into();
+        if wide && !opcode.has_wide_immediate() {
+            return Err(BytecodeError::OperandTooWide { pc });
+        }
+        program.push(Instruction::new(opcode, [first as usize, second as usize, third as usize], integer_operand));
+    }
+    return Ok((program, constants));
+}
+
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    pub(crate) fn new(bytes: &[u8]) -> Reader<'_> {
+        Reader { bytes, position: 0 }
+    }
+
+    pub(crate) fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.bytes.get(self.position..self.position + N)?;
+        self.position += N;
+        bytes.try_into().ok()
+    }
+
+    pub(crate) fn take_slice(&mut self, length: usize) -> Option<&[u8]> {
+        let bytes = self.bytes.get(self.position..self.position.checked_add(length)?)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+}
+
+/// Renders a program as assembly the lexer can read back: a `.data` section declaring each
+/// constant as `c<n>`, which is how instructions refer to them, followed by one instruction
+/// per line.
+pub fn disassemble(program: &[Instruction], constants: &[Constant]) -> String {
+    let mut assembly = String::new();
+    if !constants.is_empty() {
+        assembly.push_str(".data\n");
+        for (index, constant) in constants.iter().enumerate() {
+            match constant {
+                Constant::Float(number) => assembly.push_str(&format!("c{}: .float {:?}\n", index, number)),
+                Constant::String(string) => assembly.push_str(&format!("c{}: .string {:?}\n", index, string)),
+            }
+        }
+        assembly.push_str(".code\n");
+    }
+    for instruction in program {
+        assembly.push_str(&instruction.to_string());
+        assembly.push('\n');
+    }
+    return assembly;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    #[test]
+    fn test_round_trip() {
+        let constants = vec![
+            Constant::Float(2.5),
+            Constant::Float(f64::NEG_INFINITY),
+            Constant::String("tab\there \"é\"".to_string()),
+        ];
+        let program = vec![
+            Instruction::new(Opcode::LOADF, [1, 0, 0], 1),
+            Instruction::new(Opcode::LOADS, [7, 0, 0], 2),
+            Instruction::new(Opcode::LOADL, [6, 0, 0], -5_000_000_000),
+            Instruction::new(Opcode::LOAD, [3, 0, 0], -40000),
+            Instruction::new(Opcode::SHL, [3, 1, 2], 0),
+            Instruction::new(Opcode::NOT, [2, 4, 0], 0),
+            Instruction::new(Opcode::GETREM, [5, 0, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ];
+        let bytes = encode(&program, &constants).unwrap();
+        assert_eq!(bytes.len(), 5 + 4 + 2 * 9 + 1 + 4 + 13 + (program.len() - 1) * INSTRUCTION_SIZE + WIDE_INSTRUCTION_SIZE);
+        assert_eq!(decode(&bytes).unwrap(), (program.clone(), constants.clone()));
+
+        let assembly = disassemble(&program, &constants);
+        assert_eq!(
+            assembly,
+            ".data\nc0: .float 2.5\nc1: .float -inf\nc2: .string \"tab\\there \\\"é\\\"\"\n.code\nLOADF $1 @c1\nLOADS $7 @c2\nLOADL $6 #-5000000000\nLOAD $3 #-40000\nSHL $3 $1 $2\nNOT $2 $4\nGETREM $5\nHLT\n"
+        );
+        let mut lexer = Lexer::new(assembly.trim_end().to_string());
+        let mut reassembled = vec![];
+        while let Some(instruction) = lexer.next_line() {
+            reassembled.push(instruction);
+        }
+        assert_eq!(reassembled, program);
+        assert_eq!(lexer.constants, constants);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode(b"nope"), Err(BytecodeError::BadHeader));
+        let mut bytes = encode(&[Instruction::new(Opcode::HLT, [0; 3], 0)], &[]).unwrap();
+        bytes.pop();
+        assert_eq!(decode(&bytes), Err(BytecodeError::Truncated { pc: 0 }));
+        let program = [Instruction::new(Opcode::INC, [300, 0, 0], 0)];
+        assert_eq!(encode(&program, &[]), Err(BytecodeError::RegisterTooLarge { pc: 0, register: 300 }));
+
+        let program = [Instruction::new(Opcode::HLT, [0; 3], 0), Instruction::new(Opcode::LOAD, [0; 3], 1 << 32)];
+        assert_eq!(encode(&program, &[]), Err(BytecodeError::OperandTooWide { pc: 1 }));
+        let mut bytes = encode(&[Instruction::new(Opcode::LOADL, [0; 3], 1 << 32)], &[]).unwrap();
+        bytes[MAGIC.len() + 5] = u8::from(Opcode::LOAD) | WIDE_FLAG;
+        assert_eq!(decode(&bytes), Err(BytecodeError::OperandTooWide { pc: 0 }));
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    instruction::{Instruction, JumpKind, Opcode},
+    vm::REGISTER_COUNT,
+};
+
+/// Where a jump instruction transfers control to.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JumpTarget {
+    /// The target register holds a known constant that lands inside the program.
+    Resolved(usize),
+    /// The target register holds a known constant that lands outside the program.
+    OutOfRange(i64),
+    /// The target register is not a known constant.
+    Unresolved,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BasicBlock {
+    /// Pc of the first instruction in the block.
+    pub start: usize,
+    /// Pc one past the last instruction in the block.
+    pub end: usize,
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+    /// Set when execution can run past the last instruction of the program from this block.
+    pub falls_off_end: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+    /// Pcs of jumps whose target could not be resolved to a constant.
+    pub unresolved_jumps: Vec<usize>,
+    /// Pcs and targets of jumps whose constant target is outside the program.
+    pub out_of_range_jumps: Vec<(usize, i64)>,
+}
+
+impl ControlFlowGraph {
+    pub fn build(program: &[Instruction]) -> Self {
+        let constants = register_constants(program);
+        let mut leaders: BTreeSet<usize> = BTreeSet::new();
+        if !program.is_empty() {
+            leaders.insert(0);
+        }
+        for (pc, instruction) in program.iter().enumerate() {
+            if instruction.opcode.is_control_flow() && pc + 1 < program.len() {
+                leaders.insert(pc + 1);
+            }
+            if let JumpTarget::Resolved(target) = jump_target(program, &constants, pc) {
+                leaders.insert(target);
+            }
+        }
+
+        let starts: Vec<usize> = leaders.iter().copied().collect();
+        let mut blocks: Vec<BasicBlock> = starts
+            .iter()
+            .enumerate()
+            .map(|(index, start)| BasicBlock {
+                start: *start,
+                end: starts.get(index + 1).copied().unwrap_or(program.len()),
+                successors: vec![],
+                predecessors: vec![],
+                falls_off_end: false,
+            })
+            .collect();
+
+        let mut unresolved_jumps = vec![];
+        let mut out_of_range_jumps = vec![];
+        let block_at = |pc: usize| starts.binary_search(&pc).ok();
+
+        for block in &mut blocks {
+            let last = block.end - 1;
+            let instruction = program[last];
+            let mut successors = vec![];
+            let mut falls_through = !instruction.opcode.is_control_flow();
+
+            if instruction.opcode.jump_kind().is_some() {
+                match jump_target(program, &constants, last) {
+                    JumpTarget::Resolved(target) => successors.extend(block_at(target)),
+                    JumpTarget::OutOfRange(target) => out_of_range_jumps.push((last, target)),
+                    JumpTarget::Unresolved => unresolved_jumps.push(last),
+                }
+                falls_through = instruction.opcode.is_conditional_jump();
+            }
+
+            if falls_through {
+                match block_at(block.end) {
+                    Some(next) => successors.push(next),
+                    None => block.falls_off_end = true,
+                }
+            }
+            successors.dedup();
+            block.successors = successors;
+        }
+
+        for index in 0..blocks.len() {
+            for successor in blocks[index].successors.clone() {
+                if !blocks[successor].predecessors.contains(&index) {
+                    blocks[successor].predecessors.push(index);
+                }
+            }
+        }
+
+        ControlFlowGraph { blocks, unresolved_jumps, out_of_range_jumps }
+    }
+
+    /// Index of the block containing `pc`.
+    pub fn block_containing(&self, pc: usize) -> Option<usize> {
+        self.blocks.iter().position(|block| block.start <= pc && pc < block.end)
+    }
+
+    /// Renders the graph in Graphviz DOT format, one node per basic block.
+    pub fn to_dot(&self, program: &[Instruction]) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for (index, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for (pc, instruction) in program.iter().enumerate().take(block.end).skip(block.start) {
+                label.push_str(&format!("{}: {}\\l", pc, instruction));
+            }
+            dot.push_str(&format!("    b{} [label=\"{}\"];\n", index, label));
+        }
+        for (index, block) in self.blocks.iter().enumerate() {
+            for successor in &block.successors {
+                dot.push_str(&format!("    b{} -> b{};\n", index, successor));
+            }
+            let last = block.end - 1;
+            if self.unresolved_jumps.contains(&last) {
+                dot.push_str(&format!("    u{} [label=\"?\", shape=circle];\n", last));
+                dot.push_str(&format!("    b{} -> u{} [style=dashed];\n", index, last));
+            }
+        }
+        dot.push_str("}\n");
+        return dot;
+    }
+}
+
+/// Works out the target of the jump at `pc`, or `Unresolved` if it is not a jump or its
+/// register is not a known constant. `constants` is what `register_constants` found.
+pub fn jump_target(program: &[Instruction], constants: &[[Known; REGISTER_COUNT]], pc: usize) -> JumpTarget {
+    let instruction = program[pc];
+    let Some(kind) = instruction.opcode.jump_kind() else {
+        return JumpTarget::Unresolved;
+    };
+    let register = instruction.registers[instruction.opcode.jump_register_slot()];
+    let value = match constants[pc].get(register) {
+        Some(Known::Constant(value)) => *value as i64,
+        _ => return JumpTarget::Unresolved,
+    };
+    return target_of(kind, program.len(), pc, value);
+}
+
+fn target_of(kind: JumpKind, length: usize, pc: usize, value: i64) -> JumpTarget {
+    // The VM has already advanced past the jump when the relative forms apply their offset.
+    let target = match kind {
+        JumpKind::Absolute => value,
+        JumpKind::Forward => pc as i64 + 1 + value,
+        JumpKind::Backward => pc as i64 + 1 - value,
+    };
+    if target >= 0 && (target as usize) < length {
+        return JumpTarget::Resolved(target as usize);
+    }
+    return JumpTarget::OutOfRange(target);
+}
+
+/// What is known about a register's value at some pc.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Known {
+    /// No path that reaches the pc has been followed yet.
+    Unreached,
+    /// Every path to the pc leaves this value in the register.
+    Constant(i32),
+    Varies,
+}
+
+impl Known {
+    fn merge(self, other: Known) -> Known {
+        match (self, other) {
+            (Known::Unreached, known) | (known, Known::Unreached) => known,
+            (Known::Constant(first), Known::Constant(second)) if first == second => self,
+            _ => Known::Varies,
+        }
+    }
+}
+
+/// What each register holds just before each pc executes, found by following every path from
+/// the start of the program and joining what the `LOAD`s on them wrote.
+///
+/// Registers start out unknown, as the VM's can be set before it runs. Jumps are followed to the
+/// targets their constants give, while jumps through registers that vary add no paths, like
+/// `unresolved_jumps`. A callee may write any register, so nothing is known after a `CALL`
+/// returns, nor at the start of a thread or process. Code no path reaches is followed from its
+/// first instruction with nothing known, so that it is still checked.
+pub fn register_constants(program: &[Instruction]) -> Vec<[Known; REGISTER_COUNT]> {
+    let unknown = [Known::Varies; REGISTER_COUNT];
+    let mut constants = vec![[Known::Unreached; REGISTER_COUNT]; program.len()];
+    let mut worklist: Vec<usize> = vec![];
+    let mut next_unreached = 0;
+    loop {
+        while let Some(pc) = worklist.pop() {
+            let instruction = program[pc];
+            let mut state = constants[pc];
+            let mut successors = vec![];
+            if let Some(kind) = instruction.opcode.jump_kind() {
+                let register = instruction.registers[instruction.opcode.jump_register_slot()];
+                if let Some(Known::Constant(value)) = state.get(register) {
+                    if let JumpTarget::Resolved(target) = target_of(kind, program.len(), pc, *value as i64) {
+                        let starts_thread = matches!(instruction.opcode, Opcode::SPAWN | Opcode::SPAWNP);
+                        successors.push((target, starts_thread));
+                    }
+                }
+            }
+            for register in instruction.writes() {
+                if let Some(known) = state.get_mut(register) {
+                    *known = match instruction.opcode {
+                        Opcode::LOAD => Known::Constant(instruction.integer_operand as i32),
+                        _ => Known::Varies,
+                    };
+                }
+            }
+            let falls_through = !instruction.opcode.is_control_flow() || instruction.opcode.is_conditional_jump();
+            if falls_through && pc + 1 < program.len() {
+                successors.push((pc + 1, instruction.opcode == Opcode::CALL));
+            }
+
+            for (successor, forget) in successors {
+                let incoming = if forget { &unknown } else { &state };
+                let mut merged = constants[successor];
+                for (known, incoming) in merged.iter_mut().zip(incoming) {
+                    *known = known.merge(*incoming);
+                }
+                if merged != constants[successor] {
+                    constants[successor] = merged;
+                    worklist.push(successor);
+                }
+            }
+        }
+
+        let unreached = (next_unreached..program.len()).find(|pc| constants[*pc][0] == Known::Unreached);
+        let Some(unreached) = unreached else {
+            break;
+        };
+        constants[unreached] = unknown;
+        worklist.push(unreached);
+        next_unreached = unreached + 1;
+    }
+    return constants;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instruction(opcode: Opcode, registers: [usize; 3], integer_operand: i64) -> Instruction {
+        Instruction::new(opcode, registers, integer_operand)
+    }
+
+    #[test]
+    fn test_conditional_loop() {
+        let program = vec![
+            instruction(Opcode::LOAD, [3, 0, 0], 2),
+            instruction(Opcode::LOAD, [1, 0, 0], 5),
+            instruction(Opcode::DEC, [1, 0, 0], 0),
+            instruction(Opcode::GT, [1, 4, 0], 0),
+            instruction(Opcode::JEQ, [3, 0, 0], 0),
+            instruction(Opcode::HLT, [0; 3], 0),
+        ];
+        let cfg = ControlFlowGraph::build(&program);
+        let ranges: Vec<(usize, usize)> = cfg.blocks.iter().map(|block| (block.start, block.end)).collect();
+        assert_eq!(ranges, vec![(0, 2), (2, 5), (5, 6)]);
+        assert_eq!(cfg.blocks[1].successors, vec![1, 2]);
+        assert_eq!(cfg.blocks[1].predecessors, vec![0, 1]);
+        assert!(cfg.blocks[2].successors.is_empty());
+        assert!(cfg.unresolved_jumps.is_empty());
+    }
+
+    #[test]
+    fn test_relative_jumps() {
+        let program = vec![
+            instruction(Opcode::LOAD, [0, 0, 0], 1),
+            instruction(Opcode::JMPF, [0, 0, 0], 0),
+            instruction(Opcode::HLT, [0; 3], 0),
+            instruction(Opcode::LOAD, [0, 0, 0], 3),
+            instruction(Opcode::JMPB, [0, 0, 0], 0),
+        ];
+        let cfg = ControlFlowGraph::build(&program);
+        let jmpf = cfg.block_containing(1).unwrap();
+        let jmpb = cfg.block_containing(4).unwrap();
+        assert_eq!(cfg.blocks[cfg.blocks[jmpf].successors[0]].start, 3);
+        assert_eq!(cfg.blocks[cfg.blocks[jmpb].successors[0]].start, 2);
+    }
+
+    #[test]
+    fn test_unresolved_and_out_of_range_jumps() {
+        let program = vec![
+            instruction(Opcode::LOAD, [0, 0, 0], 1),
+            instruction(Opcode::ADD, [0, 0, 1], 0),
+            instruction(Opcode::JMP, [1, 0, 0], 0),
+            instruction(Opcode::LOAD, [2, 0, 0], 40),
+            instruction(Opcode::JMP, [2, 0, 0], 0),
+        ];
+        let cfg = ControlFlowGraph::build(&program);
+        assert_eq!(cfg.unresolved_jumps, vec![2]);
+        assert_eq!(cfg.out_of_range_jumps, vec![(4, 40)]);
+
+        let dot = cfg.to_dot(&program);
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("b0 -> u2 [style=dashed];"));
+        assert!(dot.contains("2: JMP $1\\l"));
+    }
+
+    #[test]
+    fn test_constants_must_reach_every_path() {
+        // $5 is only loaded when the first jump is taken, so the second can go either way. $31 is
+        // changed by the function the `CALL` runs.
+        let program = vec![
+            instruction(Opcode::LOAD, [1, 0, 0], 4),
+            instruction(Opcode::JEQ, [1, 0, 0], 0),
+            instruction(Opcode::JMP, [5, 0, 0], 0),
+            instruction(Opcode::HLT, [0; 3], 0),
+            instruction(Opcode::LOAD, [5, 0, 0], 3),
+            instruction(Opcode::LOAD, [6, 0, 0], 2),
+            instruction(Opcode::JMP, [6, 0, 0], 0),
+            instruction(Opcode::LOAD, [31, 0, 0], 10),
+            instruction(Opcode::CALL, [31, 0, 0], 0),
+            instruction(Opcode::JMP, [31, 0, 0], 0),
+            instruction(Opcode::LOAD, [31, 0, 0], 3),
+            instruction(Opcode::RET, [0; 3], 0),
+        ];
+        let cfg = ControlFlowGraph::build(&program);
+        assert_eq!(cfg.unresolved_jumps, vec![2, 9]);
+        let constants = register_constants(&program);
+        assert_eq!(constants[2][1], Known::Constant(4));
+        assert_eq!(constants[2][5], Known::Varies);
+        assert_eq!(constants[9][31], Known::Varies);
+        assert_eq!(jump_target(&program, &constants, 6), JumpTarget::Resolved(2));
+    }
+
+    #[test]
+    fn test_falls_off_end() {
+        let program = vec![instruction(Opcode::LOAD, [0, 0, 0], 1), instruction(Opcode::INC, [0, 0, 0], 0)];
+        let cfg = ControlFlowGraph::build(&program);
+        assert_eq!(cfg.blocks.len(), 1);
+        assert!(cfg.blocks[0].falls_off_end);
+    }
+}
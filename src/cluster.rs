@@ -0,0 +1,880 @@
+//! Nodes that run VMs for each other over TCP.
+//!
+//! A node listens for requests, runs the jobs it is given, which are VMs started from a program
+//! image or resumed from a snapshot, and can migrate a job to another node by pausing it and
+//! sending its snapshot there. Every message is a `u64` length followed by that many bytes, the
+//! first of which says which request or response it is.
+//!
+//! Nodes find each other by heartbeats. Each node sends one to every peer it knows of at every
+//! `heartbeat_interval`, and the reply lists the peers that node knows are alive, which is how
+//! nodes started with one seed learn about the rest. A peer not heard from directly for
+//! `failure_timeout` is no longer a member.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    mem,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bytecode::{self, Reader},
+    pool,
+    snapshot::{self, SnapshotError, SnapshotReader, Writer},
+    verifier,
+    vm::{DEFAULT_QUANTUM, VM},
+};
+
+/// Messages longer than this are refused rather than read into memory.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 26;
+
+#[derive(Debug)]
+pub enum ClusterError {
+    Io(io::Error),
+    /// A message could not be decoded.
+    Malformed(SnapshotError),
+    /// The other node answered with an error.
+    Remote(String),
+    /// The other node answered with a response that does not fit the request.
+    UnexpectedResponse,
+}
+
+impl fmt::Display for ClusterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClusterError::Io(error) => write!(f, "{}", error),
+            ClusterError::Malformed(error) => write!(f, "malformed message: {}", error),
+            ClusterError::Remote(message) => write!(f, "remote node: {}", message),
+            ClusterError::UnexpectedResponse => write!(f, "unexpected response"),
+        }
+    }
+}
+
+impl std::error::Error for ClusterError {}
+
+impl From<io::Error> for ClusterError {
+    fn from(error: io::Error) -> Self {
+        ClusterError::Io(error)
+    }
+}
+
+impl From<SnapshotError> for ClusterError {
+    fn from(error: SnapshotError) -> Self {
+        ClusterError::Malformed(error)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum JobStatus {
+    Running,
+    Paused,
+    /// Every thread finished.
+    Finished,
+    Failed(String),
+    /// Moved to the node at `to`, where it is job `job`.
+    Migrated { to: String, job: u64 },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Request {
+    /// Starts a job from a program in the `bytecode` format.
+    Run(Vec<u8>),
+    /// Starts a job from a snapshot, carrying on where it was taken.
+    Resume(Vec<u8>),
+    Status(u64),
+    Pause(u64),
+    Continue(u64),
+    /// Moves a running or paused job to the node at `to`.
+    Migrate { job: u64, to: String },
+    /// Asks for a snapshot of a job that is not running.
+    Fetch(u64),
+    Heartbeat { from: String },
+    Members,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Response {
+    Job(u64),
+    Status(JobStatus),
+    Snapshot(Vec<u8>),
+    Members(Vec<String>),
+    Done,
+    Error(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub heartbeat_interval: Duration,
+    pub failure_timeout: Duration,
+    /// How many instructions a job runs before the node moves on to the next one.
+    pub quantum: usize,
+    /// How long a connection can wait on its peer before the node closes it.
+    pub connection_timeout: Duration,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            heartbeat_interval: Duration::from_secs(1),
+            failure_timeout: Duration::from_secs(5),
+            quantum: DEFAULT_QUANTUM,
+            connection_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+struct Job {
+    /// `None` once the job has migrated, or while it is on its way.
+    vm: Option<VM>,
+    status: JobStatus,
+}
+
+struct Peer {
+    last_heard: Instant,
+    /// Whether the peer has been heard from directly since it was last timed out.
+    alive: bool,
+    /// Seeds are kept after they time out so the node can find them again.
+    seed: bool,
+}
+
+struct State {
+    jobs: Vec<Job>,
+    peers: HashMap<String, Peer>,
+}
+
+struct Shared {
+    address: String,
+    config: NodeConfig,
+    state: Mutex<State>,
+    shutdown: AtomicBool,
+}
+
+/// A running node. Dropping it stops the node.
+pub struct Node {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Node {
+    /// Starts a node listening on `address`, which can have port 0 to pick a free one, that
+    /// first looks for other nodes at `seeds`.
+    pub fn start(address: &str, seeds: &[String], config: NodeConfig) -> io::Result<Node> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?.to_string();
+        let now = Instant::now();
+        let peers = seeds
+            .iter()
+            .filter(|seed| **seed != address)
+            .map(|seed| (seed.clone(), Peer { last_heard: now, alive: false, seed: true }))
+            .collect();
+        let shared = Arc::new(Shared {
+            address,
+            config,
+            state: Mutex::new(State { jobs: vec![], peers }),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let threads = vec![
+            thread::spawn({
+                let shared = shared.clone();
+                move || accept(&shared, listener)
+            }),
+            thread::spawn({
+                let shared = shared.clone();
+                move || run_jobs(&shared)
+            }),
+            thread::spawn({
+                let shared = shared.clone();
+                move || send_heartbeats(&shared)
+            }),
+        ];
+        return Ok(Node { shared, threads });
+    }
+
+    pub fn address(&self) -> &str {
+        &self.shared.address
+    }
+
+    /// This node and every peer it has heard from recently, sorted.
+    pub fn members(&self) -> Vec<String> {
+        self.shared.members()
+    }
+
+    /// Stops the node and waits for it to finish the request it is handling.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Shared {
+    fn is_shut_down(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    fn members(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut members: Vec<String> = state.peers.iter().filter(|(_, peer)| peer.alive).map(|(address, _)| address.clone()).collect();
+        members.push(self.address.clone());
+        members.sort();
+        return members;
+    }
+
+    fn heard_from(&self, address: &str) {
+        let mut state = self.state.lock().unwrap();
+        let peer = state.peers.entry(address.to_string()).or_insert(Peer { last_heard: Instant::now(), alive: false, seed: false });
+        peer.last_heard = Instant::now();
+        peer.alive = true;
+    }
+
+    /// Adds peers another node knows about, to be contacted at the next heartbeat.
+    fn learn(&self, addresses: Vec<String>) {
+        let mut state = self.state.lock().unwrap();
+        for address in addresses {
+            if address != self.address {
+                state.peers.entry(address).or_insert(Peer { last_heard: Instant::now(), alive: false, seed: false });
+            }
+        }
+    }
+
+    fn add_job(&self, vm: VM) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.jobs.push(Job { vm: Some(vm), status: JobStatus::Running });
+        return state.jobs.len() as u64 - 1;
+    }
+
+    /// Adds a job for a VM from the network, unless the verifier rejects its program, which could
+    /// then index past the registers or constants.
+    fn add_verified_job(&self, vm: VM) -> Response {
+        let verification = verifier::verify(&vm.program, &vm.constants);
+        match verification.errors.first() {
+            Some(error) => return Response::Error(format!("invalid program: {}", error)),
+            None => return Response::Job(self.add_job(vm)),
+        }
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::Run(program) => match bytecode::decode(&program) {
+                Ok((program, constants)) => {
+                    let mut vm = VM::new();
+                    vm.program = program.into();
+                    vm.constants = constants;
+                    self.add_verified_job(vm)
+                }
+                Err(error) => Response::Error(error.to_string()),
+            },
+            Request::Resume(snapshot) => match snapshot::decode(&snapshot) {
+                Ok(vm) => self.add_verified_job(vm),
+                Err(error) => Response::Error(error.to_string()),
+            },
+            Request::Status(job) => self.with_job(job, |job| Response::Status(job.status.clone())),
+            Request::Pause(job) => self.with_job(job, |job| match job.status {
+                JobStatus::Running | JobStatus::Paused => {
+                    job.status = JobStatus::Paused;
+                    Response::Done
+                }
+                _ => Response::Error("job is not running".to_string()),
+            }),
+            Request::Continue(job) => self.with_job(job, |job| match job.status {
+                JobStatus::Running | JobStatus::Paused => {
+                    job.status = JobStatus::Running;
+                    Response::Done
+                }
+                _ => Response::Error("job is not paused".to_string()),
+            }),
+            Request::Migrate { job, to } => self.migrate(job, to),
+            Request::Fetch(job) => self.with_job(job, |job| match (&job.status, &job.vm) {
+                (JobStatus::Running, _) => Response::Error("job is running".to_string()),
+                (_, Some(vm)) => match snapshot::encode(vm) {
+                    Ok(snapshot) => Response::Snapshot(snapshot),
+                    Err(error) => Response::Error(error.to_string()),
+                },
+                (_, None) => Response::Error("job has migrated".to_string()),
+            }),
+            Request::Heartbeat { from } => {
+                self.heard_from(&from);
+                Response::Members(self.members())
+            }
+            Request::Members => Response::Members(self.members()),
+        }
+    }
+
+    fn with_job(&self, job: u64, action: impl FnOnce(&mut Job) -> Response) -> Response {
+        let mut state = self.state.lock().unwrap();
+        match state.jobs.get_mut(job as usize) {
+            Some(job) => action(job),
+            None => Response::Error(format!("no job {}", job)),
+        }
+    }
+
+    /// Takes the job's VM, so it stops running here, and resumes it on the other node. If that
+    /// fails, the job carries on here as before.
+    fn migrate(&self, id: u64, to: String) -> Response {
+        let (vm, previous, snapshot) = {
+            let mut state = self.state.lock().unwrap();
+            let Some(job) = state.jobs.get_mut(id as usize) else {
+                return Response::Error(format!("no job {}", id));
+            };
+            if !matches!(job.status, JobStatus::Running | JobStatus::Paused) {
+                return Response::Error("job is not running".to_string());
+            }
+            let Some(vm) = job.vm.take() else {
+                return Response::Error("job is already migrating".to_string());
+            };
+            let snapshot = match snapshot::encode(&vm) {
+                Ok(snapshot) => snapshot,
+                Err(error) => {
+                    job.vm = Some(vm);
+                    return Response::Error(error.to_string());
+                }
+            };
+            (vm, mem::replace(&mut job.status, JobStatus::Paused), snapshot)
+        };
+
+        match Client::new(&to).resume(snapshot) {
+            Ok(remote) => self.with_job(id, |job| {
+                job.status = JobStatus::Migrated { to, job: remote };
+                Response::Job(remote)
+            }),
+            Err(error) => self.with_job(id, |job| {
+                job.vm = Some(vm);
+                job.status = previous;
+                Response::Error(format!("migration failed: {}", error))
+            }),
+        }
+    }
+}
+
+fn accept(shared: &Arc<Shared>, listener: TcpListener) {
+    while !shared.is_shut_down() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let _ = serve(&shared, stream);
+                });
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(5)),
+            Err(_) => {}
+        }
+    }
+}
+
+/// Answers requests on one connection until the other end closes it.
+fn serve(shared: &Shared, mut stream: TcpStream) -> Result<(), ClusterError> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(shared.config.connection_timeout))?;
+    stream.set_write_timeout(Some(shared.config.connection_timeout))?;
+    while let Some(message) = read_message(&mut stream)? {
+        let response = match decode_request(&message) {
+            Ok(request) => shared.handle(request),
+            Err(error) => Response::Error(error.to_string()),
+        };
+        write_message(&mut stream, &encode_response(&response))?;
+    }
+    return Ok(());
+}
+
+/// Runs every running job for a quantum in turn. A job that panics fails on its own, without
+/// unwinding through the lock on `state`, which would poison it for the rest of the node.
+fn run_jobs(shared: &Shared) {
+    while !shared.is_shut_down() {
+        let mut ran = false;
+        {
+            let mut state = shared.state.lock().unwrap();
+            for job in &mut state.jobs {
+                let Job { vm: Some(vm), status: status @ JobStatus::Running } = job else {
+                    continue;
+                };
+                ran = true;
+                let quantum = shared.config.quantum;
+                match panic::catch_unwind(AssertUnwindSafe(|| run_quantum(vm, quantum))) {
+                    Ok(Some(finished)) => *status = finished,
+                    Ok(None) => {}
+                    Err(payload) => *status = JobStatus::Failed(format!("job panicked: {}", pool::panic_message(payload.as_ref()))),
+                }
+            }
+        }
+        if !ran {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// Steps `vm` up to `quantum` times, returning the job's new status if it stopped.
+fn run_quantum(vm: &mut VM, quantum: usize) -> Option<JobStatus> {
+    for _ in 0..quantum {
+        match vm.step() {
+            Ok(true) => {}
+            Ok(false) => match vm.check_suspension() {
+                Ok(()) => return Some(JobStatus::Finished),
+                Err(error) => return Some(JobStatus::Failed(error.to_string())),
+            },
+            Err(error) => return Some(JobStatus::Failed(error.to_string())),
+        }
+    }
+    return None;
+}
+
+fn send_heartbeats(shared: &Shared) {
+    let mut next = Instant::now();
+    while !shared.is_shut_down() {
+        if Instant::now() < next {
+            thread::sleep(Duration::from_millis(5));
+            continue;
+        }
+        next = Instant::now() + shared.config.heartbeat_interval;
+
+        let peers: Vec<String> = shared.state.lock().unwrap().peers.keys().cloned().collect();
+        for peer in peers {
+            let client = Client { address: peer.clone(), timeout: shared.config.heartbeat_interval };
+            if let Ok(Response::Members(members)) = client.request(&Request::Heartbeat { from: shared.address.clone() }) {
+                shared.heard_from(&peer);
+                shared.learn(members);
+            }
+        }
+
+        let timeout = shared.config.failure_timeout;
+        shared.state.lock().unwrap().peers.retain(|_, peer| {
+            if peer.last_heard.elapsed() <= timeout {
+                return true;
+            }
+            peer.alive = false;
+            return peer.seed;
+        });
+    }
+}
+
+/// Sends requests to a node, one connection per request.
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub address: String,
+    /// How long to wait to connect, and then for each read and write.
+    pub timeout: Duration,
+}
+
+impl Client {
+    pub fn new(address: &str) -> Client {
+        Client { address: address.to_string(), timeout: Duration::from_secs(10) }
+    }
+
+    pub fn request(&self, request: &Request) -> Result<Response, ClusterError> {
+        let address = self.address.to_socket_addrs()?.next().ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+        let mut stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        write_message(&mut stream, &encode_request(request))?;
+        let Some(message) = read_message(&mut stream)? else {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        };
+        match decode_response(&message)? {
+            Response::Error(message) => Err(ClusterError::Remote(message)),
+            response => Ok(response),
+        }
+    }
+
+    pub fn run(&self, program: Vec<u8>) -> Result<u64, ClusterError> {
+        match self.request(&Request::Run(program))? {
+            Response::Job(job) => Ok(job),
+            _ => Err(ClusterError::UnexpectedResponse),
+        }
+    }
+
+    pub fn resume(&self, snapshot: Vec<u8>) -> Result<u64, ClusterError> {
+        match self.request(&Request::Resume(snapshot))? {
+            Response::Job(job) => Ok(job),
+            _ => Err(ClusterError::UnexpectedResponse),
+        }
+    }
+
+    pub fn status(&self, job: u64) -> Result<JobStatus, ClusterError> {
+        match self.request(&Request::Status(job))? {
+            Response::Status(status) => Ok(status),
+            _ => Err(ClusterError::UnexpectedResponse),
+        }
+    }
+
+    pub fn pause(&self, job: u64) -> Result<(), ClusterError> {
+        self.expect_done(&Request::Pause(job))
+    }
+
+    pub fn resume_job(&self, job: u64) -> Result<(), ClusterError> {
+        self.expect_done(&Request::Continue(job))
+    }
+
+    /// Moves a job to the node at `to`, returning its job id there.
+    pub fn migrate(&self, job: u64, to: &str) -> Result<u64, ClusterError> {
+        match self.request(&Request::Migrate { job, to: to.to_string() })? {
+            Response::Job(job) => Ok(job),
+            _ => Err(ClusterError::UnexpectedResponse),
+        }
+    }
+
+    pub fn fetch(&self, job: u64) -> Result<VM, ClusterError> {
+        match self.request(&Request::Fetch(job))? {
+            Response::Snapshot(snapshot) => Ok(snapshot::decode(&snapshot)?),
+            _ => Err(ClusterError::UnexpectedResponse),
+        }
+    }
+
+    pub fn members(&self) -> Result<Vec<String>, ClusterError> {
+        match self.request(&Request::Members)? {
+            Response::Members(members) => Ok(members),
+            _ => Err(ClusterError::UnexpectedResponse),
+        }
+    }
+
+    fn expect_done(&self, request: &Request) -> Result<(), ClusterError> {
+        match self.request(request)? {
+            Response::Done => Ok(()),
+            _ => Err(ClusterError::UnexpectedResponse),
+        }
+    }
+}
+
+/// Reads one message, or `None` if the stream ends before it starts. The body is read as it
+/// arrives rather than allocated up front, so a peer cannot claim a large length for free.
+fn read_message(stream: &mut impl Read) -> Result<Option<Vec<u8>>, ClusterError> {
+    let mut length = [0; 8];
+    match stream.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    }
+    let length = u64::from_le_bytes(length);
+    if length > MAX_MESSAGE_SIZE as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large").into());
+    }
+    let mut message = vec![];
+    stream.take(length).read_to_end(&mut message)?;
+    if message.len() as u64 != length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    return Ok(Some(message));
+}
+
+fn write_message(stream: &mut TcpStream, message: &[u8]) -> Result<(), ClusterError> {
+    stream.write_all(&(message.len() as u64).to_le_bytes())?;
+    stream.write_all(message)?;
+    return Ok(());
+}
+
+fn encode_request(request: &Request) -> Vec<u8> {
+    let mut writer = Writer(vec![]);
+    match request {
+        Request::Run(program) => {
+            writer.u8(0);
+            writer.bytes(program);
+        }
+        Request::Resume(snapshot) => {
+            writer.u8(1);
+            writer.bytes(snapshot);
+        }
+        Request::Status(job) => {
+            writer.u8(2);
+            writer.u64(*job);
+        }
+        Request::Pause(job) => {
+            writer.u8(3);
+            writer.u64(*job);
+        }
+        Request::Continue(job) => {
+            writer.u8(4);
+            writer.u64(*job);
+        }
+        Request::Migrate { job, to } => {
+            writer.u8(5);
+            writer.u64(*job);
+            writer.bytes(to.as_bytes());
+        }
+        Request::Fetch(job) => {
+            writer.u8(6);
+            writer.u64(*job);
+        }
+        Request::Heartbeat { from } => {
+            writer.u8(7);
+            writer.bytes(from.as_bytes());
+        }
+        Request::Members => writer.u8(8),
+    }
+    return writer.0;
+}
+
+fn decode_request(message: &[u8]) -> Result<Request, SnapshotError> {
+    let mut reader = SnapshotReader(Reader::new(message));
+    let request = match reader.u8()? {
+        0 => Request::Run(reader.bytes()?.to_vec()),
+        1 => Request::Resume(reader.bytes()?.to_vec()),
+        2 => Request::Status(reader.u64()?),
+        3 => Request::Pause(reader.u64()?),
+        4 => Request::Continue(reader.u64()?),
+        5 => Request::Migrate { job: reader.u64()?, to: read_string(&mut reader)? },
+        6 => Request::Fetch(reader.u64()?),
+        7 => Request::Heartbeat { from: read_string(&mut reader)? },
+        8 => Request::Members,
+        tag => return Err(SnapshotError::BadTag(tag)),
+    };
+    return Ok(request);
+}
+
+fn encode_response(response: &Response) -> Vec<u8> {
+    let mut writer = Writer(vec![]);
+    match response {
+        Response::Job(job) => {
+            writer.u8(0);
+            writer.u64(*job);
+        }
+        Response::Status(status) => {
+            writer.u8(1);
+            match status {
+                JobStatus::Running => writer.u8(0),
+                JobStatus::Paused => writer.u8(1),
+                JobStatus::Finished => writer.u8(2),
+                JobStatus::Failed(message) => {
+                    writer.u8(3);
+                    writer.bytes(message.as_bytes());
+                }
+                JobStatus::Migrated { to, job } => {
+                    writer.u8(4);
+                    writer.bytes(to.as_bytes());
+                    writer.u64(*job);
+                }
+            }
+        }
+        Response::Snapshot(snapshot) => {
+            writer.u8(2);
+            writer.bytes(snapshot);
+        }
+        Response::Members(members) => {
+            writer.u8(3);
+            writer.u64(members.len() as u64);
+            for member in members {
+                writer.bytes(member.as_bytes());
+            }
+        }
+        Response::Done => writer.u8(4),
+        Response::Error(message) => {
+            writer.u8(5);
+            writer.bytes(message.as_bytes());
+        }
+    }
+    return writer.0;
+}
+
+fn decode_response(message: &[u8]) -> Result<Response, SnapshotError> {
+    let mut reader = SnapshotReader(Reader::new(message));
+    let response = match reader.u8()? {
+        0 => Response::Job(reader.u64()?),
+        1 => Response::Status(match reader.u8()? {
+            0 => JobStatus::Running,
+            1 => JobStatus::Paused,
+            2 => JobStatus::Finished,
+            3 => JobStatus::Failed(read_string(&mut reader)?),
+            4 => JobStatus::Migrated { to: read_string(&mut reader)?, job: reader.u64()? },
+            tag => return Err(SnapshotError::BadTag(tag)),
+        }),
+        2 => Response::Snapshot(reader.bytes()?.to_vec()),
+        3 => {
+            let mut members = vec![];
+            for _ in 0..reader.u64()? {
+                members.push(read_string(&mut reader)?);
+            }
+            Response::Members(members)
+        }
+        4 => Response::Done,
+        5 => Response::Error(read_string(&mut reader)?),
+        tag => return Err(SnapshotError::BadTag(tag)),
+    };
+    return Ok(response);
+}
+
+fn read_string(reader: &mut SnapshotReader) -> Result<String, SnapshotError> {
+    String::from_utf8(reader.bytes()?.to_vec()).map_err(|_| SnapshotError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        heap::{Object, Value},
+        instruction::{Constant, Instruction, Opcode},
+    };
+
+    const LOCALHOST: &str = "127.0.0.1:0";
+
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Adds `$1` down to zero into `$0`, after loading a string into `%0`.
+    fn counting_program(count: i64) -> Vec<u8> {
+        let program = vec![
+            Instruction::new(Opcode::LOADS, [0, 0, 0], 0),
+            Instruction::new(Opcode::LOAD, [1, 0, 0], count),
+            Instruction::new(Opcode::LOAD, [2, 0, 0], 3),
+            Instruction::new(Opcode::ADD, [0, 1, 0], 0),
+            Instruction::new(Opcode::DEC, [1, 0, 0], 0),
+            Instruction::new(Opcode::JNZ, [1, 2, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ];
+        return bytecode::encode(&program, &[Constant::String("travels".to_string())]).unwrap();
+    }
+
+    #[test]
+    fn test_protocol_round_trip() {
+        let requests = [
+            Request::Run(vec![1, 2]),
+            Request::Migrate { job: 3, to: "127.0.0.1:9".to_string() },
+            Request::Heartbeat { from: "here".to_string() },
+            Request::Members,
+        ];
+        for request in requests {
+            assert_eq!(decode_request(&encode_request(&request)), Ok(request));
+        }
+        let responses = [
+            Response::Status(JobStatus::Migrated { to: "there".to_string(), job: 4 }),
+            Response::Status(JobStatus::Failed("division by zero".to_string())),
+            Response::Members(vec!["a".to_string(), "b".to_string()]),
+            Response::Error("no job 9".to_string()),
+        ];
+        for response in responses {
+            assert_eq!(decode_response(&encode_response(&response)), Ok(response));
+        }
+        assert_eq!(decode_request(&[42]), Err(SnapshotError::BadTag(42)));
+    }
+
+    #[test]
+    fn test_read_message_limits() {
+        let mut huge = &(MAX_MESSAGE_SIZE as u64 + 1).to_le_bytes()[..];
+        assert!(matches!(read_message(&mut huge), Err(ClusterError::Io(error)) if error.kind() == io::ErrorKind::InvalidData));
+        let mut short = &[5, 0, 0, 0, 0, 0, 0, 0, 1, 2][..];
+        assert!(matches!(read_message(&mut short), Err(ClusterError::Io(error)) if error.kind() == io::ErrorKind::UnexpectedEof));
+        let mut whole = &[2, 0, 0, 0, 0, 0, 0, 0, 1, 2][..];
+        assert_eq!(read_message(&mut whole).unwrap(), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn test_run_and_fetch() {
+        let node = Node::start(LOCALHOST, &[], NodeConfig::default()).unwrap();
+        let client = Client::new(node.address());
+        let job = client.run(counting_program(1000)).unwrap();
+        wait_until(|| client.status(job).unwrap() == JobStatus::Finished);
+        assert_eq!(client.fetch(job).unwrap().registers[0], 500500);
+        assert!(matches!(client.fetch(job + 1), Err(ClusterError::Remote(_))));
+        node.shutdown();
+    }
+
+    #[test]
+    fn test_migrate_running_job() {
+        let first = Node::start(LOCALHOST, &[], NodeConfig::default()).unwrap();
+        let second = Node::start(LOCALHOST, &[], NodeConfig::default()).unwrap();
+        let (from, to) = (Client::new(first.address()), Client::new(second.address()));
+        let count = 2_000_000_000;
+        let job = from.run(counting_program(count)).unwrap();
+        wait_until(|| from.status(job).unwrap() == JobStatus::Running);
+
+        let moved = to_job(from.migrate(job, second.address()));
+        assert_eq!(from.status(job).unwrap(), JobStatus::Migrated { to: second.address().to_string(), job: moved });
+        assert!(matches!(from.fetch(job), Err(ClusterError::Remote(_))));
+        assert_eq!(to.status(moved).unwrap(), JobStatus::Running);
+
+        to.pause(moved).unwrap();
+        let vm = to.fetch(moved).unwrap();
+        // The count carried on from where it was: `$1` has only gone down, and the string loaded
+        // before the move is still on the heap.
+        assert!(vm.registers[1] > 0 && (vm.registers[1] as i64) < count);
+        let Value::Ref(string) = vm.value_registers[0] else {
+            panic!("expected a string in %0");
+        };
+        assert_eq!(vm.heap.get(string), Some(&Object::String("travels".to_string())));
+        to.resume_job(moved).unwrap();
+        assert_eq!(to.status(moved).unwrap(), JobStatus::Running);
+    }
+
+    #[test]
+    fn test_panicking_job_fails_alone() {
+        let node = Node::start(LOCALHOST, &[], NodeConfig::default()).unwrap();
+        let client = Client::new(node.address());
+        // A VM whose current thread is not in its thread list, which `snapshot::decode` refuses.
+        let mut broken = VM::new();
+        broken.current_thread = 5;
+        let job = node.shared.add_job(broken);
+        wait_until(|| matches!(client.status(job).unwrap(), JobStatus::Failed(message) if message.starts_with("job panicked")));
+
+        let job = client.run(counting_program(10)).unwrap();
+        wait_until(|| client.status(job).unwrap() == JobStatus::Finished);
+        assert_eq!(client.members().unwrap(), [node.address().to_string()]);
+    }
+
+    #[test]
+    fn test_invalid_programs_are_refused() {
+        let node = Node::start(LOCALHOST, &[], NodeConfig::default()).unwrap();
+        let client = Client::new(node.address());
+        let program = vec![Instruction::new(Opcode::LOAD, [40, 0, 0], 1), Instruction::new(Opcode::HLT, [0; 3], 0)];
+        let refused = client.run(bytecode::encode(&program, &[]).unwrap());
+        assert!(matches!(refused, Err(ClusterError::Remote(message)) if message.starts_with("invalid program")));
+
+        let mut vm = VM::new();
+        vm.program = program.into();
+        assert!(matches!(client.resume(snapshot::encode(&vm).unwrap()), Err(ClusterError::Remote(_))));
+        let mut vm = VM::new();
+        vm.program = vec![Instruction::new(Opcode::HLT, [0; 3], 0)].into();
+        vm.pc = 7;
+        let refused = client.resume(snapshot::encode(&vm).unwrap());
+        assert!(matches!(refused, Err(ClusterError::Remote(message)) if message.contains("past the end of the program")));
+        node.shutdown();
+    }
+
+    fn to_job(result: Result<u64, ClusterError>) -> u64 {
+        match result {
+            Ok(job) => job,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    #[test]
+    fn test_membership() {
+        let config = NodeConfig { heartbeat_interval: Duration::from_millis(20), failure_timeout: Duration::from_millis(300), ..NodeConfig::default() };
+        let seed = Node::start(LOCALHOST, &[], config.clone()).unwrap();
+        let seeds = [seed.address().to_string()];
+        let second = Node::start(LOCALHOST, &seeds, config.clone()).unwrap();
+        let third = Node::start(LOCALHOST, &seeds, config).unwrap();
+        let mut everyone = vec![seed.address().to_string(), second.address().to_string(), third.address().to_string()];
+        everyone.sort();
+
+        // The second and third nodes only know the seed, and find each other through it.
+        wait_until(|| [&seed, &second, &third].iter().all(|node| node.members() == everyone));
+        assert_eq!(Client::new(second.address()).members().unwrap(), everyone);
+
+        let gone = third.address().to_string();
+        third.shutdown();
+        everyone.retain(|address| *address != gone);
+        wait_until(|| seed.members() == everyone && second.members() == everyone);
+    }
+}
@@ -0,0 +1,219 @@
+//! Code coverage. `Coverage::run` steps the VM like `VM::run`, recording which instructions ran
+//! and which way each conditional jump went. Several runs can add to the same `Coverage`, as
+//! when a test suite runs a library many times. The results map back to source lines through the
+//! lexer's `source_lines`. They are written as an annotated listing or as LCOV tracefiles.
+
+use std::fmt::Write;
+
+use crate::{
+    instruction::{Instruction, Opcode},
+    vm::{VmError, VM},
+};
+
+/// Whether `opcode` is a conditional jump, which is either taken or falls through. Calls and
+/// spawns are left out, as they always go to their destination.
+pub fn is_branch(opcode: Opcode) -> bool {
+    return opcode.is_conditional_jump() && !matches!(opcode, Opcode::CALL | Opcode::SPAWN | Opcode::SPAWNP);
+}
+
+/// How often a conditional jump went each way.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coverage {
+    /// How many times each instruction ran, by pc.
+    pub counts: Vec<u64>,
+    /// Which way the jump at each pc went. Only conditional jumps have nonzero counts. A jump to
+    /// the next instruction counts as not taken.
+    pub branches: Vec<BranchCounts>,
+}
+
+impl Coverage {
+    pub fn new(program_length: usize) -> Coverage {
+        Coverage { counts: vec![0; program_length], branches: vec![BranchCounts::default(); program_length] }
+    }
+
+    /// Runs until every thread has finished, failing if the VM is suspended, like `VM::run`,
+    /// adding what ran to the coverage. What ran before an error is kept.
+    pub fn run(&mut self, vm: &mut VM) -> Result<(), VmError> {
+        loop {
+            let (thread, pc) = (vm.current_thread, vm.pc);
+            let result = vm.step();
+            if pc < self.counts.len() {
+                self.counts[pc] += 1;
+                if result.is_ok() && is_branch(vm.program[pc].opcode) {
+                    // The step may have switched threads, leaving this one's pc in its context.
+                    let next = if vm.current_thread == thread { vm.pc } else { vm.threads[thread].context.pc };
+                    if next == pc + 1 {
+                        self.branches[pc].not_taken += 1;
+                    } else {
+                        self.branches[pc].taken += 1;
+                    }
+                }
+            }
+            match result {
+                Ok(true) => {}
+                Ok(false) => return vm.check_suspension(),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Adds the coverage of another run of the same program.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        for (branch, other) in self.branches.iter_mut().zip(&other.branches) {
+            branch.taken += other.taken;
+            branch.not_taken += other.not_taken;
+        }
+    }
+
+    /// Instructions that ran at least once, and how many instructions there are.
+    pub fn instructions_hit(&self) -> (usize, usize) {
+        return (self.counts.iter().filter(|count| **count > 0).count(), self.counts.len());
+    }
+
+    /// Branch directions that were followed at least once, and how many directions there are.
+    /// Every conditional jump has two directions.
+    pub fn branches_hit(&self, program: &[Instruction]) -> (usize, usize) {
+        let mut hit = 0;
+        let mut found = 0;
+        for (pc, instruction) in program.iter().enumerate() {
+            if is_branch(instruction.opcode) {
+                found += 2;
+                hit += (self.branches[pc].taken > 0) as usize + (self.branches[pc].not_taken > 0) as usize;
+            }
+        }
+        return (hit, found);
+    }
+
+    /// A one-line summary such as `instructions: 9/10 (90.0%), branches: 1/2 (50.0%)`.
+    pub fn summary(&self, program: &[Instruction]) -> String {
+        let percent = |(hit, found): (usize, usize)| format!("{}/{} ({:.1}%)", hit, found, 100.0 * hit as f64 / found.max(1) as f64);
+        return format!("instructions: {}, branches: {}", percent(self.instructions_hit()), percent(self.branches_hit(program)));
+    }
+
+    /// The source with each line prefixed by how many times its instructions ran, in the style
+    /// of gcov. Lines without instructions get `-` and lines that never ran get `#####`.
+    /// Conditional jumps are followed by how many times they were taken and not taken.
+    /// `source_lines` gives the line of each instruction, as collected by the lexer.
+    pub fn listing(&self, program: &[Instruction], source: &str, source_lines: &[usize]) -> String {
+        let mut listing = String::new();
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let pcs: Vec<usize> = (0..program.len()).filter(|pc| source_lines[*pc] == line).collect();
+            let count = match pcs.iter().map(|pc| self.counts[*pc]).max() {
+                None => "-".to_string(),
+                Some(0) => "#####".to_string(),
+                Some(count) => count.to_string(),
+            };
+            write!(listing, "{:>9}:{:>5}: {}", count, line, text).unwrap();
+            for pc in pcs.into_iter().filter(|pc| is_branch(program[*pc].opcode)) {
+                let branch = self.branches[pc];
+                write!(listing, "  [taken {}, not taken {}]", branch.taken, branch.not_taken).unwrap();
+            }
+            listing.push('\n');
+        }
+        return listing;
+    }
+
+    /// An LCOV tracefile record for `path`, with line counts and a pair of branches, taken and
+    /// then not taken, for each conditional jump. Branches are grouped by pc.
+    pub fn lcov(&self, program: &[Instruction], path: &str, source_lines: &[usize]) -> String {
+        let mut lcov = format!("TN:\nSF:{}\n", path);
+        let mut lines: Vec<(usize, u64)> = vec![];
+        for (pc, instruction) in program.iter().enumerate() {
+            let line = source_lines[pc];
+            match lines.iter_mut().find(|(existing, _)| *existing == line) {
+                Some((_, count)) => *count = (*count).max(self.counts[pc]),
+                None => lines.push((line, self.counts[pc])),
+            }
+            if is_branch(instruction.opcode) {
+                let branch = self.branches[pc];
+                for (number, taken) in [branch.taken, branch.not_taken].into_iter().enumerate() {
+                    // `-` marks a branch whose jump never ran at all.
+                    let taken = if self.counts[pc] == 0 { "-".to_string() } else { taken.to_string() };
+                    writeln!(lcov, "BRDA:{},{},{},{}", line, pc, number, taken).unwrap();
+                }
+            }
+        }
+        let (branches_hit, branches_found) = self.branches_hit(program);
+        writeln!(lcov, "BRF:{}\nBRH:{}", branches_found, branches_hit).unwrap();
+        lines.sort();
+        for (line, count) in &lines {
+            writeln!(lcov, "DA:{},{}", line, count).unwrap();
+        }
+        let lines_hit = lines.iter().filter(|(_, count)| *count > 0).count();
+        writeln!(lcov, "LF:{}\nLH:{}\nend_of_record", lines.len(), lines_hit).unwrap();
+        return lcov;
+    }
+}
+
+/// Runs `vm`'s program with a new `Coverage`, returning it along with how the run ended.
+pub fn run(vm: &mut VM) -> (Coverage, Result<(), VmError>) {
+    let mut coverage = Coverage::new(vm.program.len());
+    let result = coverage.run(vm);
+    return (coverage, result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    // Counts $0 down from 2, skipping the `INC` once $0 reaches 0.
+    const SOURCE: &str = "LOAD $0 #2\nLOAD $2 @done\nLOAD $3 @loop\nloop:\nDEC $0\nJZ $0 $2\nJMP $3\nINC $1\ndone: HLT\n";
+
+    fn lex(source: &str) -> (VM, Vec<usize>) {
+        let mut vm = VM::new();
+        let mut lexer = Lexer::new(source.to_string());
+        while let Some(instruction) = lexer.next_line() {
+            vm.add_instruction(instruction);
+        }
+        return (vm, lexer.source_lines);
+    }
+
+    #[test]
+    fn test_instructions_and_branch_directions() {
+        let (mut vm, _) = lex(SOURCE);
+        let (coverage, result) = run(&mut vm);
+        result.unwrap();
+        assert_eq!(coverage.counts, [1, 1, 1, 2, 2, 1, 0, 1]);
+        assert_eq!(coverage.branches[4], BranchCounts { taken: 1, not_taken: 1 });
+        assert_eq!(coverage.branches[5], BranchCounts::default());
+        assert_eq!(coverage.instructions_hit(), (7, 8));
+        assert_eq!(coverage.branches_hit(&vm.program), (2, 2));
+        assert_eq!(coverage.summary(&vm.program), "instructions: 7/8 (87.5%), branches: 2/2 (100.0%)");
+    }
+
+    #[test]
+    fn test_listing_and_lcov() {
+        let (mut vm, source_lines) = lex(SOURCE);
+        // Starting at 1, the jump is taken the first time round.
+        vm.program = [vec![Instruction::new(Opcode::LOAD, [0, 0, 0], 1)], vm.program[1..].to_vec()].concat().into();
+        let (mut coverage, result) = run(&mut vm);
+        result.unwrap();
+        assert_eq!(coverage.branches[4], BranchCounts { taken: 1, not_taken: 0 });
+
+        let listing = coverage.listing(&vm.program, SOURCE, &source_lines);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[3], "        -:    4: loop:");
+        assert_eq!(lines[5], "        1:    6: JZ $0 $2  [taken 1, not taken 0]");
+        assert_eq!(lines[6], "    #####:    7: JMP $3");
+
+        let lcov = coverage.lcov(&vm.program, "count.asm", &source_lines);
+        assert!(lcov.starts_with("TN:\nSF:count.asm\nBRDA:6,4,0,1\nBRDA:6,4,1,0\nBRF:2\nBRH:1\n"));
+        assert!(lcov.contains("DA:6,1\nDA:7,0\nDA:8,0\nDA:9,1\nLF:8\nLH:6\nend_of_record\n"));
+
+        let (mut vm, _) = lex(SOURCE);
+        coverage.merge(&run(&mut vm).0);
+        assert_eq!(coverage.branches[4], BranchCounts { taken: 2, not_taken: 1 });
+        assert_eq!(coverage.counts[0], 2);
+    }
+}
@@ -0,0 +1,580 @@
+//! A faster way to run a VM. The program is decoded once into compact operations, each holding a
+//! pointer to the function that executes it, so the run loop makes one indirect call per
+//! instruction instead of copying the `Instruction` and matching on its opcode.
+//!
+//! The common integer, comparison, jump, stack and frame opcodes have handlers of their own. The
+//! rest go through `VM::execute_instruction` just as they do under `VM::run`, so running a VM
+//! here has the same effect as `VM::run`, thread scheduling included.
+//!
+//! Sequences that compilers emit all the time, such as a comparison followed by loading a jump
+//! target and jumping on the result, are also fused into superinstructions that run the whole
+//! sequence in one dispatch. Every instruction keeps its own operation too, so jumps into the
+//! middle of a sequence still work, and `Code::step` and `Code::run_traced` only ever run one
+//! instruction at a time, so a debugger or tracer sees every original pc.
+
+use crate::{
+    instruction::{Instruction, Opcode},
+    vm::{VmError, MAX_CALL_DEPTH, REGISTER_COUNT, VM},
+};
+
+/// Executes an operation whose pc has already been stepped past. Returns whether any thread can
+/// still run, like `VM::step`.
+type Handler = fn(&mut VM, &Operation) -> Result<bool, VmError>;
+
+/// Executes the sequence of operations that starts with the first one in the slice, once its pc
+/// has been stepped past.
+type FusedHandler = fn(&mut VM, &[Operation]) -> Result<bool, VmError>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Operation {
+    handler: Handler,
+    opcode: Opcode,
+    /// Always below `REGISTER_COUNT` for the handlers that read them.
+    registers: [u8; 3],
+    operand: i64,
+    /// The superinstruction that starts here, if any.
+    pub superinstruction: Option<Superinstruction>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Superinstruction {
+    handler: FusedHandler,
+    /// How many instructions it runs.
+    pub length: usize,
+}
+
+/// A program decoded for running with `Code::run`.
+#[derive(Debug, Clone)]
+pub struct Code {
+    pub operations: Vec<Operation>,
+}
+
+impl Code {
+    pub fn decode(program: &[Instruction]) -> Code {
+        let mut code = Code::unfused(program);
+        for (pc, operation) in code.operations.iter_mut().enumerate() {
+            operation.superinstruction = fuse(&program[pc..]);
+        }
+        return code;
+    }
+
+    /// Decodes without fusing any superinstructions.
+    pub fn unfused(program: &[Instruction]) -> Code {
+        Code { operations: program.iter().map(decode).collect() }
+    }
+
+    /// Runs until every thread has finished, failing if the VM is suspended, like `VM::run`. The
+    /// VM must be running the program this was decoded from.
+    pub fn run(&self, vm: &mut VM) -> Result<(), VmError> {
+        loop {
+            let pc = vm.pc;
+            let Some(operation) = self.operations.get(pc) else {
+                if vm.finish_thread(false)? {
+                    continue;
+                }
+                return Ok(());
+            };
+            vm.pc += 1;
+            // A superinstruction is only run if the thread would not be preempted partway
+            // through it, so threads switch at the same pcs either way.
+            let running = match operation.superinstruction {
+                Some(superinstruction) if vm.slice + superinstruction.length <= vm.quantum => {
+                    vm.slice += superinstruction.length;
+                    (superinstruction.handler)(vm, &self.operations[pc..])?
+                }
+                _ => {
+                    vm.slice += 1;
+                    (operation.handler)(vm, operation)?
+                }
+            };
+            if !running {
+                return vm.check_suspension();
+            }
+            if vm.slice >= vm.quantum {
+                vm.schedule();
+            }
+        }
+    }
+
+    /// Runs one instruction, never a whole superinstruction. Returns whether any thread can still
+    /// run, like `VM::step`.
+    pub fn step(&self, vm: &mut VM) -> Result<bool, VmError> {
+        let Some(operation) = self.operations.get(vm.pc) else {
+            return vm.finish_thread(false);
+        };
+        vm.pc += 1;
+        vm.slice += 1;
+        let running = (operation.handler)(vm, operation)?;
+        if running && vm.slice >= vm.quantum {
+            vm.schedule();
+        }
+        Ok(running)
+    }
+
+    /// Runs like `run`, but one instruction at a time, calling `trace` with the VM before each.
+    pub fn run_traced(&self, vm: &mut VM, mut trace: impl FnMut(&VM)) -> Result<(), VmError> {
+        loop {
+            if vm.pc < self.operations.len() {
+                trace(vm);
+            }
+            if !self.step(vm)? {
+                return vm.check_suspension();
+            }
+        }
+    }
+}
+
+/// Decodes the VM's program and runs it.
+pub fn run(vm: &mut VM) -> Result<(), VmError> {
+    Code::decode(&vm.program).run(vm)
+}
+
+/// The superinstruction for the sequence at the start of `program`, if it begins with one.
+fn fuse(program: &[Instruction]) -> Option<Superinstruction> {
+    let fused = |handler: FusedHandler, length: usize| {
+        let fast = program[..length].iter().all(|instruction| instruction.registers.iter().all(|register| *register < REGISTER_COUNT));
+        fast.then_some(Superinstruction { handler, length })
+    };
+    let opcode = |index: usize| program.get(index).map(|instruction| instruction.opcode);
+    if opcode(0) == Some(Opcode::LOAD) && opcode(1) == Some(Opcode::JMP) {
+        return fused(load_jump, 2);
+    }
+    if opcode(0) == Some(Opcode::DEC) && opcode(1).is_some_and(is_comparison) {
+        let branch = branch_length(&program[2..], &[Opcode::JEQ, Opcode::JNEQ])?;
+        return fused(decrement_compare_branch, 2 + branch);
+    }
+    if opcode(0) == Some(Opcode::DEC) {
+        let branch = branch_length(&program[1..], &[Opcode::JZ, Opcode::JNZ])?;
+        return fused(decrement_branch, 1 + branch);
+    }
+    if opcode(0).is_some_and(is_comparison) {
+        let branch = branch_length(&program[1..], &[Opcode::JEQ, Opcode::JNEQ])?;
+        return fused(compare_branch, 1 + branch);
+    }
+    return None;
+}
+
+/// The length of the conditional jump at the start of `program`, 2 if its target is loaded just
+/// before it, if it is one of `jumps`.
+fn branch_length(program: &[Instruction], jumps: &[Opcode]) -> Option<usize> {
+    match program {
+        [load, jump, ..] if load.opcode == Opcode::LOAD && jumps.contains(&jump.opcode) => Some(2),
+        [jump, ..] if jumps.contains(&jump.opcode) => Some(1),
+        _ => None,
+    }
+}
+
+fn is_comparison(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::EQ | Opcode::EQI | Opcode::NEQ | Opcode::NEQI | Opcode::GT | Opcode::GTI | Opcode::LT | Opcode::LTI | Opcode::GTQ | Opcode::GTQI | Opcode::LTQ | Opcode::LTQI
+    )
+}
+
+fn decode(instruction: &Instruction) -> Operation {
+    let handler: Handler = match instruction.opcode {
+        Opcode::LOAD => load,
+        Opcode::ADD => add::<false>,
+        Opcode::ADDI => add::<true>,
+        Opcode::SUB => sub::<false>,
+        Opcode::SUBI => sub::<true>,
+        Opcode::MUL => mul::<false>,
+        Opcode::MULI => mul::<true>,
+        Opcode::INC => inc,
+        Opcode::DEC => dec,
+        Opcode::AND => and,
+        Opcode::OR => or,
+        Opcode::XOR => xor,
+        Opcode::EQ => eq::<false>,
+        Opcode::EQI => eq::<true>,
+        Opcode::NEQ => neq::<false>,
+        Opcode::NEQI => neq::<true>,
+        Opcode::GT => gt::<false>,
+        Opcode::GTI => gt::<true>,
+        Opcode::LT => lt::<false>,
+        Opcode::LTI => lt::<true>,
+        Opcode::GTQ => gtq::<false>,
+        Opcode::GTQI => gtq::<true>,
+        Opcode::LTQ => ltq::<false>,
+        Opcode::LTQI => ltq::<true>,
+        Opcode::JMP => jmp,
+        Opcode::JEQ => jeq,
+        Opcode::JNEQ => jneq,
+        Opcode::JZ => jz,
+        Opcode::JNZ => jnz,
+        Opcode::CALL => call,
+        Opcode::RET => ret,
+        Opcode::PUSH => push,
+        Opcode::POP => pop,
+        Opcode::LDF => ldf,
+        Opcode::STF => stf,
+        _ => fallback,
+    };
+    // Out of range registers make `execute_instruction` panic, so they must get there.
+    let registers = instruction.registers.map(|register| u8::try_from(register).ok().filter(|register| (*register as usize) < REGISTER_COUNT));
+    let [Some(first), Some(second), Some(third)] = registers else {
+        return Operation { handler: fallback, opcode: instruction.opcode, registers: [0; 3], operand: instruction.integer_operand, superinstruction: None };
+    };
+    return Operation { handler, opcode: instruction.opcode, registers: [first, second, third], operand: instruction.integer_operand, superinstruction: None };
+}
+
+/// The index of the operation's `n`th register. Masking it lets the compiler drop the bounds
+/// check, and changes nothing because decoding already checked it.
+fn register(operation: &Operation, n: usize) -> usize {
+    operation.registers[n] as usize % REGISTER_COUNT
+}
+
+fn operands<const IMMEDIATE: bool>(vm: &VM, operation: &Operation) -> (i32, i32) {
+    let first_number = vm.registers[register(operation, 0)];
+    if IMMEDIATE {
+        return (first_number, operation.operand as i32);
+    }
+    return (first_number, vm.registers[register(operation, 1)]);
+}
+
+fn fallback(vm: &mut VM, _: &Operation) -> Result<bool, VmError> {
+    let instruction = vm.program[vm.pc - 1];
+    vm.execute_instruction(instruction)
+}
+
+fn load(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    vm.registers[register(operation, 0)] = operation.operand as i32;
+    Ok(true)
+}
+
+fn add<const IMMEDIATE: bool>(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<IMMEDIATE>(vm, operation);
+    vm.registers[register(operation, 2)] = vm.arithmetic_result(
+        first_number.overflowing_add(second_number),
+        first_number.saturating_add(second_number),
+        (first_number as u32).overflowing_add(second_number as u32).1,
+    )?;
+    Ok(true)
+}
+
+fn sub<const IMMEDIATE: bool>(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<IMMEDIATE>(vm, operation);
+    vm.registers[register(operation, 2)] = vm.arithmetic_result(
+        first_number.overflowing_sub(second_number),
+        first_number.saturating_sub(second_number),
+        (first_number as u32).overflowing_sub(second_number as u32).1,
+    )?;
+    Ok(true)
+}
+
+fn mul<const IMMEDIATE: bool>(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<IMMEDIATE>(vm, operation);
+    vm.registers[register(operation, 2)] = vm.arithmetic_result(
+        first_number.overflowing_mul(second_number),
+        first_number.saturating_mul(second_number),
+        (first_number as u32).overflowing_mul(second_number as u32).1,
+    )?;
+    Ok(true)
+}
+
+fn inc(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let number = vm.registers[register(operation, 0)];
+    vm.registers[register(operation, 0)] = vm.arithmetic_result(number.overflowing_add(1), number.saturating_add(1), number == -1)?;
+    Ok(true)
+}
+
+fn dec(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let number = vm.registers[register(operation, 0)];
+    vm.registers[register(operation, 0)] = vm.arithmetic_result(number.overflowing_sub(1), number.saturating_sub(1), number == 0)?;
+    Ok(true)
+}
+
+fn and(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<false>(vm, operation);
+    vm.registers[register(operation, 2)] = first_number & second_number;
+    Ok(true)
+}
+
+fn or(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<false>(vm, operation);
+    vm.registers[register(operation, 2)] = first_number | second_number;
+    Ok(true)
+}
+
+fn xor(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<false>(vm, operation);
+    vm.registers[register(operation, 2)] = first_number ^ second_number;
+    Ok(true)
+}
+
+fn eq<const IMMEDIATE: bool>(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<IMMEDIATE>(vm, operation);
+    vm.equal = first_number == second_number;
+    Ok(true)
+}
+
+fn neq<const IMMEDIATE: bool>(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<IMMEDIATE>(vm, operation);
+    vm.equal = first_number != second_number;
+    Ok(true)
+}
+
+fn gt<const IMMEDIATE: bool>(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<IMMEDIATE>(vm, operation);
+    vm.equal = first_number > second_number;
+    Ok(true)
+}
+
+fn lt<const IMMEDIATE: bool>(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<IMMEDIATE>(vm, operation);
+    vm.equal = first_number < second_number;
+    Ok(true)
+}
+
+fn gtq<const IMMEDIATE: bool>(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<IMMEDIATE>(vm, operation);
+    vm.equal = first_number >= second_number;
+    Ok(true)
+}
+
+fn ltq<const IMMEDIATE: bool>(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let (first_number, second_number) = operands::<IMMEDIATE>(vm, operation);
+    vm.equal = first_number <= second_number;
+    Ok(true)
+}
+
+fn jmp(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    vm.pc = vm.registers[register(operation, 0)] as usize;
+    Ok(true)
+}
+
+fn jeq(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    if vm.equal {
+        vm.pc = vm.registers[register(operation, 0)] as usize;
+    }
+    Ok(true)
+}
+
+fn jneq(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    if !vm.equal {
+        vm.pc = vm.registers[register(operation, 0)] as usize;
+    }
+    Ok(true)
+}
+
+fn jz(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    if vm.registers[register(operation, 0)] == 0 {
+        vm.pc = vm.registers[register(operation, 1)] as usize;
+    }
+    Ok(true)
+}
+
+fn jnz(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    if vm.registers[register(operation, 0)] != 0 {
+        vm.pc = vm.registers[register(operation, 1)] as usize;
+    }
+    Ok(true)
+}
+
+fn call(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    if vm.call_stack.len() >= MAX_CALL_DEPTH {
+        return Err(VmError::StackOverflow { pc: vm.pc - 1 });
+    }
+    vm.call_stack.push(vm.pc);
+    vm.pc = vm.registers[register(operation, 0)] as usize;
+    Ok(true)
+}
+
+fn ret(vm: &mut VM, _: &Operation) -> Result<bool, VmError> {
+    vm.pc = vm.call_stack.pop().ok_or(VmError::StackUnderflow { pc: vm.pc - 1 })?;
+    Ok(true)
+}
+
+fn push(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    vm.stack.push(vm.registers[register(operation, 0)]);
+    Ok(true)
+}
+
+fn pop(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let number = vm.stack.pop().ok_or(VmError::StackUnderflow { pc: vm.pc - 1 })?;
+    vm.registers[register(operation, 0)] = number;
+    Ok(true)
+}
+
+fn ldf(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let slot = vm.frame_slot(operation.operand)?;
+    vm.registers[register(operation, 0)] = vm.stack[slot];
+    Ok(true)
+}
+
+fn stf(vm: &mut VM, operation: &Operation) -> Result<bool, VmError> {
+    let slot = vm.frame_slot(operation.operand)?;
+    vm.stack[slot] = vm.registers[register(operation, 0)];
+    Ok(true)
+}
+
+fn load_jump(vm: &mut VM, operations: &[Operation]) -> Result<bool, VmError> {
+    load(vm, &operations[0])?;
+    vm.pc += 1;
+    jmp(vm, &operations[1])
+}
+
+fn compare_branch(vm: &mut VM, operations: &[Operation]) -> Result<bool, VmError> {
+    let (first_number, second_number) = match operations[0].opcode {
+        Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ => operands::<false>(vm, &operations[0]),
+        _ => operands::<true>(vm, &operations[0]),
+    };
+    vm.equal = match operations[0].opcode {
+        Opcode::EQ | Opcode::EQI => first_number == second_number,
+        Opcode::NEQ | Opcode::NEQI => first_number != second_number,
+        Opcode::GT | Opcode::GTI => first_number > second_number,
+        Opcode::LT | Opcode::LTI => first_number < second_number,
+        Opcode::GTQ | Opcode::GTQI => first_number >= second_number,
+        _ => first_number <= second_number,
+    };
+    vm.pc += 1;
+    branch(vm, &operations[1..])
+}
+
+fn decrement_branch(vm: &mut VM, operations: &[Operation]) -> Result<bool, VmError> {
+    dec(vm, &operations[0])?;
+    vm.pc += 1;
+    branch(vm, &operations[1..])
+}
+
+fn decrement_compare_branch(vm: &mut VM, operations: &[Operation]) -> Result<bool, VmError> {
+    dec(vm, &operations[0])?;
+    vm.pc += 1;
+    compare_branch(vm, &operations[1..])
+}
+
+/// The conditional jump, and the load of its target if there is one, that ends a superinstruction.
+fn branch(vm: &mut VM, operations: &[Operation]) -> Result<bool, VmError> {
+    let mut jump = &operations[0];
+    if jump.opcode == Opcode::LOAD {
+        load(vm, jump)?;
+        vm.pc += 1;
+        jump = &operations[1];
+    }
+    match jump.opcode {
+        Opcode::JEQ => jeq(vm, jump),
+        Opcode::JNEQ => jneq(vm, jump),
+        Opcode::JZ => jz(vm, jump),
+        _ => jnz(vm, jump),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lang, testing, vm::ArithmeticMode};
+
+    /// Runs a copy of the VM each way and checks they end up the same.
+    fn assert_same_as_step(vm: VM) -> VM {
+        let mut stepped = testing::copy_of(&vm);
+        let mut decoded = vm;
+        let expected = stepped.run();
+        assert_eq!(run(&mut decoded), expected);
+        assert_eq!(decoded.output, stepped.output);
+        assert_eq!(decoded.registers, stepped.registers);
+        assert_eq!((decoded.pc, decoded.equal, decoded.overflow, decoded.carry), (stepped.pc, stepped.equal, stepped.overflow, stepped.carry));
+        assert_eq!(decoded.stack, stepped.stack);
+        assert_eq!(decoded.threads.len(), stepped.threads.len());
+        return decoded;
+    }
+
+    #[test]
+    fn test_matches_step() {
+        for program in testing::compiled_workloads() {
+            let mut vm = VM::new();
+            vm.program = program.into();
+            vm.output = Some(vec![]);
+            assert_same_as_step(vm);
+        }
+
+        // Two threads, preempted every few instructions, each counting down its own `$1`.
+        let mut vm = VM::new();
+        vm.quantum = 3;
+        vm.program = vec![
+            Instruction::new(Opcode::LOAD, [3, 0, 0], 6),
+            Instruction::new(Opcode::SPAWN, [3, 4, 0], 0),
+            Instruction::new(Opcode::LOAD, [1, 0, 0], 50),
+            Instruction::new(Opcode::JOIN, [4, 5, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+            Instruction::new(Opcode::LOAD, [1, 0, 0], 40),
+            Instruction::new(Opcode::LOAD, [2, 0, 0], 8),
+            Instruction::new(Opcode::ADDI, [0, 0, 0], 3),
+            Instruction::new(Opcode::DEC, [1, 0, 0], 0),
+            Instruction::new(Opcode::JNZ, [1, 2, 0], 0),
+        ]
+        .into();
+        let vm = assert_same_as_step(vm);
+        assert_eq!(vm.threads[1].result, 120);
+    }
+
+    #[test]
+    fn test_errors_match_step() {
+        for mode in [ArithmeticMode::Trap, ArithmeticMode::Saturate] {
+            let mut vm = VM::new();
+            vm.arithmetic_mode = mode;
+            vm.program = vec![
+                Instruction::new(Opcode::LOAD, [0, 0, 0], i32::MAX as i64),
+                Instruction::new(Opcode::MULI, [0, 0, 1], 2),
+                Instruction::new(Opcode::RET, [0; 3], 0),
+            ]
+            .into();
+            assert_same_as_step(vm);
+        }
+
+        let mut vm = VM::new();
+        vm.program = vec![Instruction::new(Opcode::LDF, [0, 0, 0], -1)].into();
+        assert_eq!(run(&mut vm), Err(VmError::IndexOutOfBounds { pc: 0 }));
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let program = vec![
+            Instruction::new(Opcode::GT, [0, 1, 0], 0),
+            Instruction::new(Opcode::LOAD, [31, 0, 0], 5),
+            Instruction::new(Opcode::JEQ, [31, 0, 0], 0),
+            Instruction::new(Opcode::LOAD, [31, 0, 0], 8),
+            Instruction::new(Opcode::JMP, [31, 0, 0], 0),
+            Instruction::new(Opcode::DEC, [0, 0, 0], 0),
+            Instruction::new(Opcode::NEQI, [0, 0, 0], 2),
+            Instruction::new(Opcode::JEQ, [2, 0, 0], 0),
+            Instruction::new(Opcode::DEC, [0, 0, 0], 0),
+            Instruction::new(Opcode::JNZ, [0, 3, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ];
+        let code = Code::decode(&program);
+        let lengths: Vec<usize> = code.operations.iter().map(|operation| operation.superinstruction.map_or(0, |fused| fused.length)).collect();
+        assert_eq!(lengths, [3, 0, 0, 2, 0, 3, 2, 0, 2, 0, 0]);
+        assert!(Code::unfused(&program).operations.iter().all(|operation| operation.superinstruction.is_none()));
+
+        // Counts $0 down from 7, jumping from the end into the middle of the first sequence.
+        let mut vm = VM::new();
+        vm.registers[0] = 7;
+        vm.registers[2] = 5;
+        vm.registers[3] = 1;
+        vm.program = program.into();
+        let vm = assert_same_as_step(vm);
+        assert_eq!(vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_trace_sees_every_pc() {
+        let source = "let n = 20;\nlet total = 0;\nwhile n > 0 {\n    if n % 2 == 0 { total = total + n; }\n    n = n - 1;\n}";
+        let mut vm = VM::new();
+        vm.program = lang::compile(source).unwrap().into();
+        let code = Code::decode(&vm.program);
+        assert!(code.operations.iter().any(|operation| operation.superinstruction.is_some()));
+
+        let mut stepped = VM::new();
+        stepped.program = vm.program.clone();
+        let mut expected = vec![];
+        while stepped.pc < stepped.program.len() {
+            expected.push(stepped.pc);
+            stepped.step().unwrap();
+        }
+        let mut traced = vec![];
+        code.run_traced(&mut vm, |vm| traced.push(vm.pc)).unwrap();
+        assert_eq!(traced, expected);
+        assert_eq!(vm.registers, stepped.registers);
+    }
+}
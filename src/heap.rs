@@ -0,0 +1,302 @@
+use std::{collections::HashMap, mem};
+
+/// A dynamically typed value, as held in the value registers and value stack.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Value {
+    #[default]
+    Nil,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Ref(ObjectRef),
+}
+
+/// Handle to an object on the `Heap`. Only valid while the object is reachable from a root.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct ObjectRef(pub usize);
+
+/// Map keys are compared by value, so strings are keyed by their contents rather than by which
+/// heap object holds them.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum MapKey {
+    Nil,
+    Int(i64),
+    Float(u64),
+    Bool(bool),
+    String(String),
+    Ref(ObjectRef),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Object {
+    String(String),
+    Array(Vec<Value>),
+    Map(HashMap<MapKey, (Value, Value)>),
+    Bytes(Vec<u8>),
+}
+
+impl Object {
+    /// Rough number of bytes the object keeps alive, used to decide when to collect.
+    fn size(&self) -> usize {
+        let contents = match self {
+            Object::String(string) => string.len(),
+            Object::Array(values) => values.len() * mem::size_of::<Value>(),
+            Object::Map(entries) => entries.len() * mem::size_of::<(MapKey, (Value, Value))>(),
+            Object::Bytes(bytes) => bytes.len(),
+        };
+        return mem::size_of::<Object>() + contents;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub allocations: usize,
+    pub collections: usize,
+    pub live_objects: usize,
+    pub live_bytes: usize,
+    pub freed_objects: usize,
+    pub freed_bytes: usize,
+}
+
+#[derive(Debug)]
+struct HeapEntry {
+    object: Object,
+    marked: bool,
+}
+
+/// Garbage collected object heap with a mark-and-sweep collector. Freed slots are reused by
+/// later allocations, so an `ObjectRef` stays the same for the lifetime of its object.
+#[derive(Debug)]
+pub struct Heap {
+    entries: Vec<Option<HeapEntry>>,
+    free_slots: Vec<usize>,
+    /// Bytes allocated since the last collection after which `should_collect` returns true.
+    pub threshold: usize,
+    allocated_since_collection: usize,
+    stats: HeapStats,
+}
+
+pub const INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            entries: vec![],
+            free_slots: vec![],
+            threshold: INITIAL_THRESHOLD,
+            allocated_since_collection: 0,
+            stats: HeapStats::default(),
+        }
+    }
+
+    pub fn allocate(&mut self, object: Object) -> ObjectRef {
+        let size = object.size();
+        self.stats.allocations += 1;
+        self.stats.live_objects += 1;
+        self.stats.live_bytes += size;
+        self.allocated_since_collection += size;
+
+        let entry = Some(HeapEntry { object, marked: false });
+        match self.free_slots.pop() {
+            Some(slot) => {
+                self.entries[slot] = entry;
+                ObjectRef(slot)
+            }
+            None => {
+                self.entries.push(entry);
+                ObjectRef(self.entries.len() - 1)
+            }
+        }
+    }
+
+    pub fn get(&self, object: ObjectRef) -> Option<&Object> {
+        self.entries.get(object.0)?.as_ref().map(|entry| &entry.object)
+    }
+
+    pub fn get_mut(&mut self, object: ObjectRef) -> Option<&mut Object> {
+        self.entries.get_mut(object.0)?.as_mut().map(|entry| &mut entry.object)
+    }
+
+    /// Every slot in allocation order, with `None` for freed ones, so a heap can be rebuilt with
+    /// `from_slots` and keep every `ObjectRef` valid.
+    pub fn slots(&self) -> impl Iterator<Item = Option<&Object>> {
+        self.entries.iter().map(|entry| entry.as_ref().map(|entry| &entry.object))
+    }
+
+    pub fn from_slots(slots: Vec<Option<Object>>) -> Heap {
+        let mut heap = Heap::new();
+        for (slot, object) in slots.into_iter().enumerate() {
+            match object {
+                Some(object) => {
+                    heap.stats.live_objects += 1;
+                    heap.stats.live_bytes += object.size();
+                    heap.entries.push(Some(HeapEntry { object, marked: false }));
+                }
+                None => {
+                    heap.entries.push(None);
+                    heap.free_slots.push(slot);
+                }
+            }
+        }
+        heap.stats.allocations = heap.stats.live_objects;
+        return heap;
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.allocated_since_collection >= self.threshold
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    /// Frees every object not reachable from `roots`.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) {
+        let mut pending: Vec<ObjectRef> = roots
+            .into_iter()
+            .filter_map(|value| match value {
+                Value::Ref(object) => Some(object),
+                _ => None,
+            })
+            .collect();
+
+        while let Some(object) = pending.pop() {
+            let Some(Some(entry)) = self.entries.get_mut(object.0) else {
+                continue;
+            };
+            if entry.marked {
+                continue;
+            }
+            entry.marked = true;
+            let children: Box<dyn Iterator<Item = &Value>> = match &entry.object {
+                Object::Array(values) => Box::new(values.iter()),
+                Object::Map(entries) => Box::new(entries.values().flat_map(|(key, value)| [key, value])),
+                Object::String(_) | Object::Bytes(_) => Box::new(std::iter::empty()),
+            };
+            for child in children {
+                if let Value::Ref(child) = child {
+                    pending.push(*child);
+                }
+            }
+        }
+
+        // Objects can grow after they are allocated, so live bytes are recounted rather than
+        // adjusted.
+        self.stats.live_bytes = 0;
+        for (slot, entry) in self.entries.iter_mut().enumerate() {
+            match entry {
+                Some(HeapEntry { object, marked }) if *marked => {
+                    *marked = false;
+                    self.stats.live_bytes += object.size();
+                }
+                Some(HeapEntry { object, .. }) => {
+                    let size = object.size();
+                    self.stats.live_objects -= 1;
+                    self.stats.freed_objects += 1;
+                    self.stats.freed_bytes += size;
+                    *entry = None;
+                    self.free_slots.push(slot);
+                }
+                None => {}
+            }
+        }
+
+        self.stats.collections += 1;
+        self.allocated_since_collection = 0;
+        self.threshold = INITIAL_THRESHOLD.max(self.stats.live_bytes * 2);
+    }
+
+    /// Numeric type code of a value, as reported by the `TAG` opcode.
+    pub fn type_tag(&self, value: Value) -> i32 {
+        match value {
+            Value::Nil => 0,
+            Value::Int(_) => 1,
+            Value::Float(_) => 2,
+            Value::Bool(_) => 3,
+            Value::Ref(object) => match self.get(object) {
+                Some(Object::String(_)) => 4,
+                Some(Object::Array(_)) => 5,
+                Some(Object::Map(_)) => 6,
+                Some(Object::Bytes(_)) => 7,
+                None => 0,
+            },
+        }
+    }
+
+    /// The key a value is stored under in a map.
+    pub fn map_key(&self, value: Value) -> MapKey {
+        match value {
+            Value::Nil => MapKey::Nil,
+            Value::Int(number) => MapKey::Int(number),
+            Value::Float(number) => MapKey::Float(number.to_bits()),
+            Value::Bool(boolean) => MapKey::Bool(boolean),
+            Value::Ref(object) => match self.get(object) {
+                Some(Object::String(string)) => MapKey::String(string.clone()),
+                _ => MapKey::Ref(object),
+            },
+        }
+    }
+
+    /// Equality as seen by programs: strings compare by contents, other objects by identity.
+    pub fn values_equal(&self, first: Value, second: Value) -> bool {
+        match (first, second) {
+            (Value::Ref(first_object), Value::Ref(second_object)) => {
+                match (self.get(first_object), self.get(second_object)) {
+                    (Some(Object::String(first_string)), Some(Object::String(second_string))) => {
+                        first_string == second_string
+                    }
+                    _ => first_object == second_object,
+                }
+            }
+            _ => first == second,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_unreachable() {
+        let mut heap = Heap::new();
+        let kept = heap.allocate(Object::Array(vec![]));
+        let child = heap.allocate(Object::String("child".to_string()));
+        let garbage = heap.allocate(Object::Bytes(vec![0; 16]));
+        if let Some(Object::Array(values)) = heap.get_mut(kept) {
+            values.push(Value::Ref(child));
+        }
+
+        heap.collect([Value::Int(1), Value::Ref(kept)]);
+        assert!(heap.get(kept).is_some());
+        assert!(heap.get(child).is_some());
+        assert!(heap.get(garbage).is_none());
+        let stats = heap.stats();
+        assert_eq!(stats.allocations, 3);
+        assert_eq!(stats.collections, 1);
+        assert_eq!(stats.live_objects, 2);
+        assert_eq!(stats.freed_objects, 1);
+
+        assert_eq!(heap.allocate(Object::Bytes(vec![])), garbage);
+    }
+
+    #[test]
+    fn test_cycles_are_collected() {
+        let mut heap = Heap::new();
+        let first = heap.allocate(Object::Array(vec![]));
+        let second = heap.allocate(Object::Array(vec![Value::Ref(first)]));
+        if let Some(Object::Array(values)) = heap.get_mut(first) {
+            values.push(Value::Ref(second));
+        }
+        heap.collect([Value::Ref(second)]);
+        assert_eq!(heap.stats().live_objects, 2);
+        heap.collect([]);
+        assert_eq!(heap.stats().live_objects, 0);
+    }
+}
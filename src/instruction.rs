@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Opcode {
     HLT,
@@ -19,20 +21,372 @@ pub enum Opcode {
     JEQ,
     ALOC,
     INC,
-    DEC
+    DEC,
+    JO,
+    JNO,
+    AND,
+    OR,
+    XOR,
+    NOT,
+    SHL,
+    SHR,
+    SAR,
+    /// The remainder of Euclidean division, which is never negative, unlike the one `DIV` leaves
+    /// for `GETREM`.
+    MOD,
+    GETREM,
+    JNEQ,
+    JZ,
+    JNZ,
+    JEQF,
+    JEQB,
+    JNEQF,
+    JNEQB,
+    ADDI,
+    SUBI,
+    MULI,
+    DIVI,
+    EQI,
+    NEQI,
+    GTI,
+    LTI,
+    GTQI,
+    LTQI,
+    LOADF,
+    ADDF,
+    SUBF,
+    MULF,
+    DIVF,
+    EQF,
+    NEQF,
+    GTF,
+    LTF,
+    GTQF,
+    LTQF,
+    ITOF,
+    FTOI,
+    LOADL,
+    ADDL,
+    SUBL,
+    MULL,
+    DIVL,
+    /// The remainder of Euclidean division, like `MOD`.
+    MODL,
+    EQL,
+    NEQL,
+    GTL,
+    LTL,
+    GTQL,
+    LTQL,
+    SEXT,
+    NIL,
+    BOXI,
+    BOXF,
+    BOXB,
+    UNBOX,
+    UNBOXF,
+    MOVV,
+    TAG,
+    EQV,
+    NEWARR,
+    NEWMAP,
+    GETV,
+    SETV,
+    LENV,
+    APPV,
+    PUSHV,
+    POPV,
+    LOADS,
+    CONCAT,
+    SLEN,
+    SUBSTR,
+    SCMP,
+    CHARAT,
+    ITOS,
+    STOI,
+    PRTS,
+    CALL,
+    RET,
+    PUSH,
+    POP,
+    ENTER,
+    LEAVE,
+    LDF,
+    STF,
+    SPAWN,
+    YIELD,
+    JOIN,
+    SPAWNP,
+    SEND,
+    RECV,
+    SELF,
+}
+
+/// A value in the program's constant pool, referred to by index from `integer_operand`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Constant {
+    Float(f64),
+    String(String),
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction {
     pub opcode: Opcode,
     pub registers: [usize; 3],
-    pub integer_operand: i32,
+    pub integer_operand: i64,
+}
+
+/// How a jump computes its destination from the value in its register.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JumpKind {
+    Absolute,
+    Forward,
+    Backward,
+}
+
+/// How an instruction uses one of its register slots.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RegisterUse {
+    Read,
+    Write,
+    ReadWrite,
+    /// The slot is not a register; assembly writes `integer_operand` in its position as `#n`.
+    Immediate,
+    FloatRead,
+    FloatWrite,
+    /// The slot is not a register; `integer_operand` indexes the constant pool, written `@c<n>`.
+    Constant,
+    /// A 64-bit value held in two consecutive registers, low half first.
+    PairRead,
+    PairWrite,
+    ValueRead,
+    ValueWrite,
+}
+
+impl Opcode {
+    /// The register slots this opcode uses, in the order they appear in `Instruction::registers`.
+    pub fn register_uses(&self) -> &'static [RegisterUse] {
+        use RegisterUse::*;
+        match self {
+            Opcode::HLT | Opcode::IGL => &[],
+            Opcode::LOAD => &[Write, Immediate],
+            Opcode::GETREM => &[Write],
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::SAR
+            | Opcode::MOD => &[Read, Read, Write],
+            Opcode::NOT => &[Read, Write],
+            Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::JEQF
+            | Opcode::JEQB
+            | Opcode::JNEQF
+            | Opcode::JNEQB
+            | Opcode::JO
+            | Opcode::JNO => &[Read],
+            Opcode::ALOC => &[Read, ValueWrite],
+            Opcode::JZ | Opcode::JNZ => &[Read, Read],
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ => &[Read, Read],
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI | Opcode::DIVI => &[Read, Immediate, Write],
+            Opcode::EQI | Opcode::NEQI | Opcode::GTI | Opcode::LTI | Opcode::GTQI | Opcode::LTQI => &[Read, Immediate],
+            Opcode::LOADF => &[FloatWrite, Constant],
+            Opcode::ADDF | Opcode::SUBF | Opcode::MULF | Opcode::DIVF => &[FloatRead, FloatRead, FloatWrite],
+            Opcode::EQF | Opcode::NEQF | Opcode::GTF | Opcode::LTF | Opcode::GTQF | Opcode::LTQF => &[FloatRead, FloatRead],
+            Opcode::ITOF => &[Read, FloatWrite],
+            Opcode::FTOI => &[FloatRead, Write],
+            Opcode::LOADL => &[PairWrite, Immediate],
+            Opcode::ADDL | Opcode::SUBL | Opcode::MULL | Opcode::DIVL | Opcode::MODL => &[PairRead, PairRead, PairWrite],
+            Opcode::EQL | Opcode::NEQL | Opcode::GTL | Opcode::LTL | Opcode::GTQL | Opcode::LTQL => &[PairRead, PairRead],
+            Opcode::SEXT => &[Read, PairWrite],
+            Opcode::NIL | Opcode::BOXB | Opcode::NEWMAP | Opcode::POPV => &[ValueWrite],
+            Opcode::BOXI | Opcode::NEWARR => &[Read, ValueWrite],
+            Opcode::BOXF => &[FloatRead, ValueWrite],
+            Opcode::UNBOX | Opcode::TAG | Opcode::LENV => &[ValueRead, Write],
+            Opcode::UNBOXF => &[ValueRead, FloatWrite],
+            Opcode::MOVV => &[ValueRead, ValueWrite],
+            Opcode::EQV | Opcode::APPV => &[ValueRead, ValueRead],
+            Opcode::GETV => &[ValueRead, ValueRead, ValueWrite],
+            Opcode::SETV => &[ValueRead, ValueRead, ValueRead],
+            Opcode::PUSHV => &[ValueRead],
+            Opcode::LOADS => &[ValueWrite, Constant],
+            Opcode::CONCAT => &[ValueRead, ValueRead, ValueWrite],
+            Opcode::SLEN => &[ValueRead, Write],
+            Opcode::SUBSTR => &[ValueRead, PairRead, ValueWrite],
+            Opcode::SCMP => &[ValueRead, ValueRead, Write],
+            Opcode::CHARAT => &[ValueRead, Read, Write],
+            Opcode::ITOS => &[Read, ValueWrite],
+            Opcode::STOI => &[ValueRead, Write],
+            Opcode::PRTS => &[ValueRead],
+            Opcode::CALL | Opcode::PUSH => &[Read],
+            Opcode::RET | Opcode::LEAVE => &[],
+            Opcode::POP => &[Write],
+            Opcode::ENTER => &[Immediate],
+            Opcode::LDF => &[Write, Immediate],
+            Opcode::STF => &[Read, Immediate],
+            Opcode::SPAWN | Opcode::JOIN | Opcode::SPAWNP => &[Read, Write],
+            Opcode::SEND => &[Read, Read],
+            Opcode::RECV => &[Write, Write, Immediate],
+            Opcode::SELF => &[Write],
+            Opcode::YIELD => &[],
+            Opcode::INC | Opcode::DEC => &[ReadWrite],
+        }
+    }
+
+    /// Whether `constant` is the kind of constant this opcode's `Constant` slot expects.
+    pub fn accepts_constant(&self, constant: &Constant) -> bool {
+        match self {
+            Opcode::LOADS => matches!(constant, Constant::String(_)),
+            _ => matches!(constant, Constant::Float(_)),
+        }
+    }
+
+    pub fn has_integer_operand(&self) -> bool {
+        self.register_uses().contains(&RegisterUse::Immediate)
+    }
+
+    /// Whether the immediate may use the full 64 bits of `integer_operand`. Every other
+    /// immediate must fit in an `i32`.
+    pub fn has_wide_immediate(&self) -> bool {
+        matches!(self, Opcode::LOADL)
+    }
+
+    /// The variant of this opcode that takes its second operand from `integer_operand`, which the
+    /// lexer switches to when it sees `ADD $1 #5 $2` style assembly.
+    pub fn immediate_form(&self) -> Option<Opcode> {
+        match self {
+            Opcode::ADD => Some(Opcode::ADDI),
+            Opcode::SUB => Some(Opcode::SUBI),
+            Opcode::MUL => Some(Opcode::MULI),
+            Opcode::DIV => Some(Opcode::DIVI),
+            Opcode::EQ => Some(Opcode::EQI),
+            Opcode::NEQ => Some(Opcode::NEQI),
+            Opcode::GT => Some(Opcode::GTI),
+            Opcode::LT => Some(Opcode::LTI),
+            Opcode::GTQ => Some(Opcode::GTQI),
+            Opcode::LTQ => Some(Opcode::LTQI),
+            _ => None,
+        }
+    }
+
+    /// Whether this opcode can change control flow instead of falling through to the next instruction.
+    pub fn is_control_flow(&self) -> bool {
+        matches!(self, Opcode::HLT | Opcode::IGL | Opcode::RET) || self.jump_kind().is_some()
+    }
+
+    /// How the destination is computed if this opcode is a jump.
+    pub fn jump_kind(&self) -> Option<JumpKind> {
+        match self {
+            Opcode::JMP
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::JZ
+            | Opcode::JNZ
+            | Opcode::JO
+            | Opcode::JNO
+            | Opcode::CALL
+            | Opcode::SPAWN
+            | Opcode::SPAWNP => {
+                Some(JumpKind::Absolute)
+            }
+            Opcode::JMPF | Opcode::JEQF | Opcode::JNEQF => Some(JumpKind::Forward),
+            Opcode::JMPB | Opcode::JEQB | Opcode::JNEQB => Some(JumpKind::Backward),
+            _ => None,
+        }
+    }
+
+    /// The register slot holding a jump's destination or offset. `JZ` and `JNZ` test their first
+    /// register and jump through their second; every other jump uses its first.
+    pub fn jump_register_slot(&self) -> usize {
+        match self {
+            Opcode::JZ | Opcode::JNZ => 1,
+            _ => 0,
+        }
+    }
+
+    /// Whether this opcode is a jump that falls through to the next instruction when not taken.
+    /// A `CALL` counts, as execution carries on from the next instruction once the callee returns,
+    /// and so do `SPAWN` and `SPAWNP`, whose thread or process starts at their destination.
+    pub fn is_conditional_jump(&self) -> bool {
+        self.jump_kind().is_some() && !matches!(self, Opcode::JMP | Opcode::JMPF | Opcode::JMPB)
+    }
 }
 
 impl Instruction {
-    pub fn new(opcode: Opcode, registers: [usize; 3], integer_operand: i32) -> Instruction {
+    pub fn new(opcode: Opcode, registers: [usize; 3], integer_operand: i64) -> Instruction {
         Instruction { opcode, registers, integer_operand }
     }
+
+    /// Registers whose value this instruction reads, including both halves of any pair.
+    pub fn reads(&self) -> Vec<usize> {
+        let mut registers = vec![];
+        for (register_use, register) in self.opcode.register_uses().iter().zip(self.registers) {
+            match register_use {
+                RegisterUse::Read | RegisterUse::ReadWrite => registers.push(register),
+                RegisterUse::PairRead => registers.extend([register, register + 1]),
+                _ => {}
+            }
+        }
+        return registers;
+    }
+
+    /// Registers whose value this instruction overwrites, including both halves of any pair.
+    pub fn writes(&self) -> Vec<usize> {
+        let mut registers = vec![];
+        for (register_use, register) in self.opcode.register_uses().iter().zip(self.registers) {
+            match register_use {
+                RegisterUse::Write | RegisterUse::ReadWrite => registers.push(register),
+                RegisterUse::PairWrite => registers.extend([register, register + 1]),
+                _ => {}
+            }
+        }
+        return registers;
+    }
+
+    /// Float registers whose value this instruction reads.
+    pub fn float_reads(&self) -> Vec<usize> {
+        self.opcode
+            .register_uses()
+            .iter()
+            .zip(self.registers)
+            .filter(|(register_use, _)| **register_use == RegisterUse::FloatRead)
+            .map(|(_, register)| register)
+            .collect()
+    }
+
+    /// Float registers whose value this instruction overwrites.
+    pub fn float_writes(&self) -> Vec<usize> {
+        self.opcode
+            .register_uses()
+            .iter()
+            .zip(self.registers)
+            .filter(|(register_use, _)| **register_use == RegisterUse::FloatWrite)
+            .map(|(_, register)| register)
+            .collect()
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.opcode)?;
+        for (register_use, register) in self.opcode.register_uses().iter().zip(self.registers) {
+            match register_use {
+                RegisterUse::Immediate => write!(f, " #{}", self.integer_operand)?,
+                RegisterUse::Constant => write!(f, " @c{}", self.integer_operand)?,
+                _ => write!(f, " ${}", register)?,
+            }
+        }
+        Ok(())
+    }
 }
 
 impl From<u8> for Opcode {
@@ -57,6 +411,101 @@ impl From<u8> for Opcode {
             16 => return Opcode::ALOC,
             17 => return Opcode::INC,
             18 => return Opcode::DEC,
+            19 => return Opcode::JO,
+            20 => return Opcode::JNO,
+            21 => return Opcode::AND,
+            22 => return Opcode::OR,
+            23 => return Opcode::XOR,
+            24 => return Opcode::NOT,
+            25 => return Opcode::SHL,
+            26 => return Opcode::SHR,
+            27 => return Opcode::SAR,
+            28 => return Opcode::MOD,
+            29 => return Opcode::GETREM,
+            30 => return Opcode::JNEQ,
+            31 => return Opcode::JZ,
+            32 => return Opcode::JNZ,
+            33 => return Opcode::JEQF,
+            34 => return Opcode::JEQB,
+            35 => return Opcode::JNEQF,
+            36 => return Opcode::JNEQB,
+            37 => return Opcode::ADDI,
+            38 => return Opcode::SUBI,
+            39 => return Opcode::MULI,
+            40 => return Opcode::DIVI,
+            41 => return Opcode::EQI,
+            42 => return Opcode::NEQI,
+            43 => return Opcode::GTI,
+            44 => return Opcode::LTI,
+            45 => return Opcode::GTQI,
+            46 => return Opcode::LTQI,
+            47 => return Opcode::LOADF,
+            48 => return Opcode::ADDF,
+            49 => return Opcode::SUBF,
+            50 => return Opcode::MULF,
+            51 => return Opcode::DIVF,
+            52 => return Opcode::EQF,
+            53 => return Opcode::NEQF,
+            54 => return Opcode::GTF,
+            55 => return Opcode::LTF,
+            56 => return Opcode::GTQF,
+            57 => return Opcode::LTQF,
+            58 => return Opcode::ITOF,
+            59 => return Opcode::FTOI,
+            60 => return Opcode::LOADL,
+            61 => return Opcode::ADDL,
+            62 => return Opcode::SUBL,
+            63 => return Opcode::MULL,
+            64 => return Opcode::DIVL,
+            65 => return Opcode::MODL,
+            66 => return Opcode::EQL,
+            67 => return Opcode::NEQL,
+            68 => return Opcode::GTL,
+            69 => return Opcode::LTL,
+            70 => return Opcode::GTQL,
+            71 => return Opcode::LTQL,
+            72 => return Opcode::SEXT,
+            73 => return Opcode::NIL,
+            74 => return Opcode::BOXI,
+            75 => return Opcode::BOXF,
+            76 => return Opcode::BOXB,
+            77 => return Opcode::UNBOX,
+            78 => return Opcode::UNBOXF,
+            79 => return Opcode::MOVV,
+            80 => return Opcode::TAG,
+            81 => return Opcode::EQV,
+            82 => return Opcode::NEWARR,
+            83 => return Opcode::NEWMAP,
+            84 => return Opcode::GETV,
+            85 => return Opcode::SETV,
+            86 => return Opcode::LENV,
+            87 => return Opcode::APPV,
+            88 => return Opcode::PUSHV,
+            89 => return Opcode::POPV,
+            90 => return Opcode::LOADS,
+            91 => return Opcode::CONCAT,
+            92 => return Opcode::SLEN,
+            93 => return Opcode::SUBSTR,
+            94 => return Opcode::SCMP,
+            95 => return Opcode::CHARAT,
+            96 => return Opcode::ITOS,
+            97 => return Opcode::STOI,
+            98 => return Opcode::PRTS,
+            99 => return Opcode::CALL,
+            100 => return Opcode::RET,
+            101 => return Opcode::PUSH,
+            102 => return Opcode::POP,
+            103 => return Opcode::ENTER,
+            104 => return Opcode::LEAVE,
+            105 => return Opcode::LDF,
+            106 => return Opcode::STF,
+            107 => return Opcode::SPAWN,
+            108 => return Opcode::YIELD,
+            109 => return Opcode::JOIN,
+            110 => return Opcode::SPAWNP,
+            111 => return Opcode::SEND,
+            112 => return Opcode::RECV,
+            113 => return Opcode::SELF,
             _ => return Opcode::IGL
         }
     }
@@ -84,6 +533,101 @@ impl From<String> for Opcode {
             "ALOC" => return Opcode::ALOC,
             "INC" => return Opcode::INC,
             "DEC" => return Opcode::DEC,
+            "JO" => return Opcode::JO,
+            "JNO" => return Opcode::JNO,
+            "AND" => return Opcode::AND,
+            "OR" => return Opcode::OR,
+            "XOR" => return Opcode::XOR,
+            "NOT" => return Opcode::NOT,
+            "SHL" => return Opcode::SHL,
+            "SHR" => return Opcode::SHR,
+            "SAR" => return Opcode::SAR,
+            "MOD" => return Opcode::MOD,
+            "GETREM" => return Opcode::GETREM,
+            "JNEQ" => return Opcode::JNEQ,
+            "JZ" => return Opcode::JZ,
+            "JNZ" => return Opcode::JNZ,
+            "JEQF" => return Opcode::JEQF,
+            "JEQB" => return Opcode::JEQB,
+            "JNEQF" => return Opcode::JNEQF,
+            "JNEQB" => return Opcode::JNEQB,
+            "ADDI" => return Opcode::ADDI,
+            "SUBI" => return Opcode::SUBI,
+            "MULI" => return Opcode::MULI,
+            "DIVI" => return Opcode::DIVI,
+            "EQI" => return Opcode::EQI,
+            "NEQI" => return Opcode::NEQI,
+            "GTI" => return Opcode::GTI,
+            "LTI" => return Opcode::LTI,
+            "GTQI" => return Opcode::GTQI,
+            "LTQI" => return Opcode::LTQI,
+            "LOADF" => return Opcode::LOADF,
+            "ADDF" => return Opcode::ADDF,
+            "SUBF" => return Opcode::SUBF,
+            "MULF" => return Opcode::MULF,
+            "DIVF" => return Opcode::DIVF,
+            "EQF" => return Opcode::EQF,
+            "NEQF" => return Opcode::NEQF,
+            "GTF" => return Opcode::GTF,
+            "LTF" => return Opcode::LTF,
+            "GTQF" => return Opcode::GTQF,
+            "LTQF" => return Opcode::LTQF,
+            "ITOF" => return Opcode::ITOF,
+            "FTOI" => return Opcode::FTOI,
+            "LOADL" => return Opcode::LOADL,
+            "ADDL" => return Opcode::ADDL,
+            "SUBL" => return Opcode::SUBL,
+            "MULL" => return Opcode::MULL,
+            "DIVL" => return Opcode::DIVL,
+            "MODL" => return Opcode::MODL,
+            "EQL" => return Opcode::EQL,
+            "NEQL" => return Opcode::NEQL,
+            "GTL" => return Opcode::GTL,
+            "LTL" => return Opcode::LTL,
+            "GTQL" => return Opcode::GTQL,
+            "LTQL" => return Opcode::LTQL,
+            "SEXT" => return Opcode::SEXT,
+            "NIL" => return Opcode::NIL,
+            "BOXI" => return Opcode::BOXI,
+            "BOXF" => return Opcode::BOXF,
+            "BOXB" => return Opcode::BOXB,
+            "UNBOX" => return Opcode::UNBOX,
+            "UNBOXF" => return Opcode::UNBOXF,
+            "MOVV" => return Opcode::MOVV,
+            "TAG" => return Opcode::TAG,
+            "EQV" => return Opcode::EQV,
+            "NEWARR" => return Opcode::NEWARR,
+            "NEWMAP" => return Opcode::NEWMAP,
+            "GETV" => return Opcode::GETV,
+            "SETV" => return Opcode::SETV,
+            "LENV" => return Opcode::LENV,
+            "APPV" => return Opcode::APPV,
+            "PUSHV" => return Opcode::PUSHV,
+            "POPV" => return Opcode::POPV,
+            "LOADS" => return Opcode::LOADS,
+            "CONCAT" => return Opcode::CONCAT,
+            "SLEN" => return Opcode::SLEN,
+            "SUBSTR" => return Opcode::SUBSTR,
+            "SCMP" => return Opcode::SCMP,
+            "CHARAT" => return Opcode::CHARAT,
+            "ITOS" => return Opcode::ITOS,
+            "STOI" => return Opcode::STOI,
+            "PRTS" => return Opcode::PRTS,
+            "CALL" => return Opcode::CALL,
+            "RET" => return Opcode::RET,
+            "PUSH" => return Opcode::PUSH,
+            "POP" => return Opcode::POP,
+            "ENTER" => return Opcode::ENTER,
+            "LEAVE" => return Opcode::LEAVE,
+            "LDF" => return Opcode::LDF,
+            "STF" => return Opcode::STF,
+            "SPAWN" => return Opcode::SPAWN,
+            "YIELD" => return Opcode::YIELD,
+            "JOIN" => return Opcode::JOIN,
+            "SPAWNP" => return Opcode::SPAWNP,
+            "SEND" => return Opcode::SEND,
+            "RECV" => return Opcode::RECV,
+            "SELF" => return Opcode::SELF,
             _ => return Opcode::IGL
         }
     }
@@ -111,14 +655,109 @@ impl From<&str> for Opcode {
             "ALOC" => return Opcode::ALOC,
             "INC" => return Opcode::INC,
             "DEC" => return Opcode::DEC,
+            "JO" => return Opcode::JO,
+            "JNO" => return Opcode::JNO,
+            "AND" => return Opcode::AND,
+            "OR" => return Opcode::OR,
+            "XOR" => return Opcode::XOR,
+            "NOT" => return Opcode::NOT,
+            "SHL" => return Opcode::SHL,
+            "SHR" => return Opcode::SHR,
+            "SAR" => return Opcode::SAR,
+            "MOD" => return Opcode::MOD,
+            "GETREM" => return Opcode::GETREM,
+            "JNEQ" => return Opcode::JNEQ,
+            "JZ" => return Opcode::JZ,
+            "JNZ" => return Opcode::JNZ,
+            "JEQF" => return Opcode::JEQF,
+            "JEQB" => return Opcode::JEQB,
+            "JNEQF" => return Opcode::JNEQF,
+            "JNEQB" => return Opcode::JNEQB,
+            "ADDI" => return Opcode::ADDI,
+            "SUBI" => return Opcode::SUBI,
+            "MULI" => return Opcode::MULI,
+            "DIVI" => return Opcode::DIVI,
+            "EQI" => return Opcode::EQI,
+            "NEQI" => return Opcode::NEQI,
+            "GTI" => return Opcode::GTI,
+            "LTI" => return Opcode::LTI,
+            "GTQI" => return Opcode::GTQI,
+            "LTQI" => return Opcode::LTQI,
+            "LOADF" => return Opcode::LOADF,
+            "ADDF" => return Opcode::ADDF,
+            "SUBF" => return Opcode::SUBF,
+            "MULF" => return Opcode::MULF,
+            "DIVF" => return Opcode::DIVF,
+            "EQF" => return Opcode::EQF,
+            "NEQF" => return Opcode::NEQF,
+            "GTF" => return Opcode::GTF,
+            "LTF" => return Opcode::LTF,
+            "GTQF" => return Opcode::GTQF,
+            "LTQF" => return Opcode::LTQF,
+            "ITOF" => return Opcode::ITOF,
+            "FTOI" => return Opcode::FTOI,
+            "LOADL" => return Opcode::LOADL,
+            "ADDL" => return Opcode::ADDL,
+            "SUBL" => return Opcode::SUBL,
+            "MULL" => return Opcode::MULL,
+            "DIVL" => return Opcode::DIVL,
+            "MODL" => return Opcode::MODL,
+            "EQL" => return Opcode::EQL,
+            "NEQL" => return Opcode::NEQL,
+            "GTL" => return Opcode::GTL,
+            "LTL" => return Opcode::LTL,
+            "GTQL" => return Opcode::GTQL,
+            "LTQL" => return Opcode::LTQL,
+            "SEXT" => return Opcode::SEXT,
+            "NIL" => return Opcode::NIL,
+            "BOXI" => return Opcode::BOXI,
+            "BOXF" => return Opcode::BOXF,
+            "BOXB" => return Opcode::BOXB,
+            "UNBOX" => return Opcode::UNBOX,
+            "UNBOXF" => return Opcode::UNBOXF,
+            "MOVV" => return Opcode::MOVV,
+            "TAG" => return Opcode::TAG,
+            "EQV" => return Opcode::EQV,
+            "NEWARR" => return Opcode::NEWARR,
+            "NEWMAP" => return Opcode::NEWMAP,
+            "GETV" => return Opcode::GETV,
+            "SETV" => return Opcode::SETV,
+            "LENV" => return Opcode::LENV,
+            "APPV" => return Opcode::APPV,
+            "PUSHV" => return Opcode::PUSHV,
+            "POPV" => return Opcode::POPV,
+            "LOADS" => return Opcode::LOADS,
+            "CONCAT" => return Opcode::CONCAT,
+            "SLEN" => return Opcode::SLEN,
+            "SUBSTR" => return Opcode::SUBSTR,
+            "SCMP" => return Opcode::SCMP,
+            "CHARAT" => return Opcode::CHARAT,
+            "ITOS" => return Opcode::ITOS,
+            "STOI" => return Opcode::STOI,
+            "PRTS" => return Opcode::PRTS,
+            "CALL" => return Opcode::CALL,
+            "RET" => return Opcode::RET,
+            "PUSH" => return Opcode::PUSH,
+            "POP" => return Opcode::POP,
+            "ENTER" => return Opcode::ENTER,
+            "LEAVE" => return Opcode::LEAVE,
+            "LDF" => return Opcode::LDF,
+            "STF" => return Opcode::STF,
+            "SPAWN" => return Opcode::SPAWN,
+            "YIELD" => return Opcode::YIELD,
+            "JOIN" => return Opcode::JOIN,
+            "SPAWNP" => return Opcode::SPAWNP,
+            "SEND" => return Opcode::SEND,
+            "RECV" => return Opcode::RECV,
+            "SELF" => return Opcode::SELF,
             _ => return Opcode::IGL
         }
     }
 }
 
-impl Into<u8> for Opcode {
-    fn into(self) -> u8 {
-        match self {
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        match opcode {
             Opcode::HLT => return 0,
             Opcode::LOAD => return 1,
             Opcode::ADD => return 2,
@@ -138,7 +777,102 @@ impl Into<u8> for Opcode {
             Opcode::ALOC => return 16,
             Opcode::INC => return 17,
             Opcode::DEC => return 18,
-            _ => panic!(),
+            Opcode::JO => return 19,
+            Opcode::JNO => return 20,
+            Opcode::AND => return 21,
+            Opcode::OR => return 22,
+            Opcode::XOR => return 23,
+            Opcode::NOT => return 24,
+            Opcode::SHL => return 25,
+            Opcode::SHR => return 26,
+            Opcode::SAR => return 27,
+            Opcode::MOD => return 28,
+            Opcode::GETREM => return 29,
+            Opcode::JNEQ => return 30,
+            Opcode::JZ => return 31,
+            Opcode::JNZ => return 32,
+            Opcode::JEQF => return 33,
+            Opcode::JEQB => return 34,
+            Opcode::JNEQF => return 35,
+            Opcode::JNEQB => return 36,
+            Opcode::ADDI => return 37,
+            Opcode::SUBI => return 38,
+            Opcode::MULI => return 39,
+            Opcode::DIVI => return 40,
+            Opcode::EQI => return 41,
+            Opcode::NEQI => return 42,
+            Opcode::GTI => return 43,
+            Opcode::LTI => return 44,
+            Opcode::GTQI => return 45,
+            Opcode::LTQI => return 46,
+            Opcode::LOADF => return 47,
+            Opcode::ADDF => return 48,
+            Opcode::SUBF => return 49,
+            Opcode::MULF => return 50,
+            Opcode::DIVF => return 51,
+            Opcode::EQF => return 52,
+            Opcode::NEQF => return 53,
+            Opcode::GTF => return 54,
+            Opcode::LTF => return 55,
+            Opcode::GTQF => return 56,
+            Opcode::LTQF => return 57,
+            Opcode::ITOF => return 58,
+            Opcode::FTOI => return 59,
+            Opcode::LOADL => return 60,
+            Opcode::ADDL => return 61,
+            Opcode::SUBL => return 62,
+            Opcode::MULL => return 63,
+            Opcode::DIVL => return 64,
+            Opcode::MODL => return 65,
+            Opcode::EQL => return 66,
+            Opcode::NEQL => return 67,
+            Opcode::GTL => return 68,
+            Opcode::LTL => return 69,
+            Opcode::GTQL => return 70,
+            Opcode::LTQL => return 71,
+            Opcode::SEXT => return 72,
+            Opcode::NIL => return 73,
+            Opcode::BOXI => return 74,
+            Opcode::BOXF => return 75,
+            Opcode::BOXB => return 76,
+            Opcode::UNBOX => return 77,
+            Opcode::UNBOXF => return 78,
+            Opcode::MOVV => return 79,
+            Opcode::TAG => return 80,
+            Opcode::EQV => return 81,
+            Opcode::NEWARR => return 82,
+            Opcode::NEWMAP => return 83,
+            Opcode::GETV => return 84,
+            Opcode::SETV => return 85,
+            Opcode::LENV => return 86,
+            Opcode::APPV => return 87,
+            Opcode::PUSHV => return 88,
+            Opcode::POPV => return 89,
+            Opcode::LOADS => return 90,
+            Opcode::CONCAT => return 91,
+            Opcode::SLEN => return 92,
+            Opcode::SUBSTR => return 93,
+            Opcode::SCMP => return 94,
+            Opcode::CHARAT => return 95,
+            Opcode::ITOS => return 96,
+            Opcode::STOI => return 97,
+            Opcode::PRTS => return 98,
+            Opcode::CALL => return 99,
+            Opcode::RET => return 100,
+            Opcode::PUSH => return 101,
+            Opcode::POP => return 102,
+            Opcode::ENTER => return 103,
+            Opcode::LEAVE => return 104,
+            Opcode::LDF => return 105,
+            Opcode::STF => return 106,
+            Opcode::SPAWN => return 107,
+            Opcode::YIELD => return 108,
+            Opcode::JOIN => return 109,
+            Opcode::SPAWNP => return 110,
+            Opcode::SEND => return 111,
+            Opcode::RECV => return 112,
+            Opcode::SELF => return 113,
+            Opcode::IGL => return 127,
         }
     }
 }
@@ -153,6 +887,19 @@ mod tests {
         assert_eq!(opcode, Opcode::HLT);
     }
 
+    #[test]
+    fn test_display_instruction() {
+        let instruction = Instruction::new(Opcode::LOAD, [3, 0, 0], 4);
+        assert_eq!(instruction.to_string(), "LOAD $3 #4");
+        let instruction = Instruction::new(Opcode::ADD, [1, 2, 3], 0);
+        assert_eq!(instruction.to_string(), "ADD $1 $2 $3");
+        assert_eq!(instruction.reads(), vec![1, 2]);
+        assert_eq!(instruction.writes(), vec![3]);
+        let instruction = Instruction::new(Opcode::ADDI, [1, 0, 2], 5);
+        assert_eq!(instruction.to_string(), "ADDI $1 #5 $2");
+        assert_eq!(instruction.reads(), vec![1]);
+    }
+
     // #[test]
     // fn test_create_instruction() {
     //     let instruction = Instruction::new(Opcode::HLT, [0; 3]);
@@ -0,0 +1,402 @@
+//! Turns an allocated program into `Instruction`s.
+//!
+//! The entry function comes first and ends in `HLT`, followed by the others. Functions use the
+//! same calling convention as `lang::codegen`: the caller pushes the registers it needs kept and
+//! then the arguments, and the callee returns its result in `RETURN_REGISTER`. Values spilled to
+//! the stack frame go through the two scratch registers, which are never allocated.
+
+use std::collections::HashMap;
+
+use super::{
+    regalloc::{self, Allocation, Location},
+    BinaryOp, BlockId, Function, OperationKind, Program, Terminator, Value,
+};
+use crate::{
+    instruction::{Instruction, Opcode},
+    lang::codegen::{JUMP_REGISTER, PRINT_REGISTER, RETURN_REGISTER},
+};
+
+/// Registers `0..ALLOCATABLE_REGISTERS` hold values.
+pub const ALLOCATABLE_REGISTERS: usize = RETURN_REGISTER - 2;
+
+/// Holds spilled operands while they are used, and the result of an operation with a spilled
+/// destination.
+const SCRATCH_REGISTER: usize = ALLOCATABLE_REGISTERS;
+
+/// Holds a second spilled operand, and breaks cycles of phi moves.
+const SECOND_SCRATCH_REGISTER: usize = ALLOCATABLE_REGISTERS + 1;
+
+pub fn emit(program: &Program) -> Vec<Instruction> {
+    let mut emitter = Emitter { program: vec![], block_jumps: vec![], addresses: HashMap::new(), call_sites: vec![] };
+    let mut addresses = vec![];
+    for (index, function) in program.functions.iter().enumerate() {
+        addresses.push(emitter.program.len());
+        emitter.function(function, index == 0);
+    }
+    for (load, function) in std::mem::take(&mut emitter.call_sites) {
+        emitter.program[load].integer_operand = addresses[function] as i64;
+    }
+    return emitter.program;
+}
+
+struct Emitter {
+    program: Vec<Instruction>,
+    /// Jumps to blocks of the function being emitted, to fill in once its blocks are placed.
+    block_jumps: Vec<(usize, Target)>,
+    /// Where each block and edge of the function being emitted starts.
+    addresses: HashMap<Target, usize>,
+    /// Loads of a function's address to fill in once every function has been placed.
+    call_sites: Vec<(usize, usize)>,
+}
+
+/// Where a jump goes: a block, or the moves for an edge into one.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Target {
+    Block(BlockId),
+    Edge(BlockId, BlockId),
+}
+
+/// The function being emitted.
+struct Context<'a> {
+    function: &'a Function,
+    allocation: Allocation,
+    /// How many times each value is used.
+    uses: HashMap<Value, usize>,
+}
+
+impl Context<'_> {
+    fn location(&self, value: Value) -> Location {
+        self.allocation.locations[&value]
+    }
+
+    /// The phi moves for the edge from `from` to `to`, as (source, destination) pairs.
+    fn edge_moves(&self, from: BlockId, to: BlockId) -> Vec<(Location, Location)> {
+        let mut moves = vec![];
+        for value in &self.function.blocks[to.0].operations {
+            if let OperationKind::Phi(incoming) = &self.function.operation(*value).kind {
+                if let Some((_, input)) = incoming.iter().find(|(block, _)| *block == from) {
+                    if self.location(*input) != self.location(*value) {
+                        moves.push((self.location(*input), self.location(*value)));
+                    }
+                }
+            }
+        }
+        moves
+    }
+
+    /// Whether a comparison is only used by the branch ending its block, right after it, so the
+    /// branch can use the equal flag directly.
+    fn fuses_with_branch(&self, block: BlockId, value: Value) -> bool {
+        let contents = &self.function.blocks[block.0];
+        let is_comparison = matches!(self.function.operation(value).kind, OperationKind::Binary(op, ..) if op.is_comparison());
+        let is_condition = matches!(contents.terminator, Terminator::Branch { condition, .. } if condition == value);
+        is_comparison && is_condition && contents.operations.last() == Some(&value) && self.uses[&value] == 1
+    }
+}
+
+impl Emitter {
+    fn emit(&mut self, opcode: Opcode, registers: [usize; 3], integer_operand: i64) {
+        self.program.push(Instruction::new(opcode, registers, integer_operand));
+    }
+
+    fn jump(&mut self, opcode: Opcode, registers: [usize; 3], target: Target) {
+        self.block_jumps.push((self.program.len(), target));
+        self.emit(Opcode::LOAD, [JUMP_REGISTER, 0, 0], 0);
+        self.emit(opcode, registers, 0);
+    }
+
+    /// The register holding a value, loading it into `scratch` first if it is spilled.
+    fn read(&mut self, location: Location, scratch: usize) -> usize {
+        match location {
+            Location::Register(register) => register,
+            Location::Slot(slot) => {
+                self.emit(Opcode::LDF, [scratch, 0, 0], slot);
+                scratch
+            }
+        }
+    }
+
+    /// The register to compute a value into, to be passed to `write_back` afterwards.
+    fn destination(location: Location) -> usize {
+        match location {
+            Location::Register(register) => register,
+            Location::Slot(_) => SCRATCH_REGISTER,
+        }
+    }
+
+    fn write_back(&mut self, location: Location) {
+        if let Location::Slot(slot) = location {
+            self.emit(Opcode::STF, [SCRATCH_REGISTER, 0, 0], slot);
+        }
+    }
+
+    fn copy(&mut self, source: Location, destination: Location) {
+        match (source, destination) {
+            (Location::Register(source), Location::Register(destination)) => self.emit(Opcode::ADDI, [source, 0, destination], 0),
+            (Location::Slot(slot), Location::Register(destination)) => self.emit(Opcode::LDF, [destination, 0, 0], slot),
+            (source, Location::Slot(slot)) => {
+                let register = self.read(source, SCRATCH_REGISTER);
+                self.emit(Opcode::STF, [register, 0, 0], slot);
+            }
+        }
+    }
+
+    /// Performs moves as if they all happened at once, ordering them so no source is
+    /// overwritten before it is read and breaking cycles through a scratch register.
+    fn parallel_move(&mut self, mut moves: Vec<(Location, Location)>) {
+        while !moves.is_empty() {
+            let ready = moves.iter().position(|(_, destination)| !moves.iter().any(|(source, _)| source == destination));
+            match ready {
+                Some(index) => {
+                    let (source, destination) = moves.remove(index);
+                    self.copy(source, destination);
+                }
+                None => {
+                    let (_, destination) = moves[0];
+                    let temporary = Location::Register(SECOND_SCRATCH_REGISTER);
+                    self.copy(destination, temporary);
+                    for (source, _) in &mut moves {
+                        if *source == destination {
+                            *source = temporary;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, function: &Function, is_entry: bool) {
+        let allocation = regalloc::allocate(function, ALLOCATABLE_REGISTERS);
+        let order = function.reverse_postorder();
+        let mut uses = HashMap::new();
+        for block in &order {
+            let contents = &function.blocks[block.0];
+            let operands = contents.operations.iter().flat_map(|value| function.operation(*value).kind.operands());
+            for operand in operands.chain(contents.terminator.operands()) {
+                *uses.entry(operand).or_insert(0) += 1;
+            }
+        }
+        let context = Context { function, allocation, uses };
+
+        // The entry function only needs a frame for spilled values. Other functions always have
+        // one, as their arguments are addressed relative to it.
+        let has_frame = !is_entry || context.allocation.slots > 0;
+        if has_frame {
+            self.emit(Opcode::ENTER, [0; 3], context.allocation.slots as i64);
+        }
+
+        for (index, block) in order.iter().enumerate() {
+            self.addresses.insert(Target::Block(*block), self.program.len());
+            let next = order.get(index + 1).copied();
+            self.block(&context, *block, next, has_frame);
+        }
+        for (load, target) in std::mem::take(&mut self.block_jumps) {
+            self.program[load].integer_operand = self.addresses[&target] as i64;
+        }
+        self.addresses.clear();
+    }
+
+    fn block(&mut self, context: &Context, block: BlockId, next: Option<BlockId>, has_frame: bool) {
+        let contents = &context.function.blocks[block.0];
+        for value in &contents.operations {
+            if !context.fuses_with_branch(block, *value) {
+                self.operation(context, *value);
+            }
+        }
+
+        match &contents.terminator {
+            Terminator::Jump(target) => {
+                self.parallel_move(context.edge_moves(block, *target));
+                if Some(*target) != next {
+                    self.jump(Opcode::JMP, [JUMP_REGISTER, 0, 0], Target::Block(*target));
+                }
+            }
+            Terminator::Branch { condition, then_block, else_block } => {
+                let then_moves = context.edge_moves(block, *then_block);
+                let then_target = match then_moves.is_empty() {
+                    true => Target::Block(*then_block),
+                    false => Target::Edge(block, *then_block),
+                };
+                if context.fuses_with_branch(block, *condition) {
+                    self.compare(context, *condition);
+                    self.jump(Opcode::JEQ, [JUMP_REGISTER, 0, 0], then_target);
+                } else {
+                    let register = self.read(context.location(*condition), SCRATCH_REGISTER);
+                    self.jump(Opcode::JNZ, [register, JUMP_REGISTER, 0], then_target);
+                }
+                self.parallel_move(context.edge_moves(block, *else_block));
+                if Some(*else_block) != next || !then_moves.is_empty() {
+                    self.jump(Opcode::JMP, [JUMP_REGISTER, 0, 0], Target::Block(*else_block));
+                }
+                if !then_moves.is_empty() {
+                    self.addresses.insert(then_target, self.program.len());
+                    self.parallel_move(then_moves);
+                    self.jump(Opcode::JMP, [JUMP_REGISTER, 0, 0], Target::Block(*then_block));
+                }
+            }
+            Terminator::Return(value) => {
+                let register = self.read(context.location(*value), SCRATCH_REGISTER);
+                self.emit(Opcode::ADDI, [register, 0, RETURN_REGISTER], 0);
+                self.emit(Opcode::LEAVE, [0; 3], 0);
+                self.emit(Opcode::RET, [0; 3], 0);
+            }
+            Terminator::Halt => {
+                if has_frame {
+                    self.emit(Opcode::LEAVE, [0; 3], 0);
+                }
+                self.emit(Opcode::HLT, [0; 3], 0);
+            }
+        }
+    }
+
+    /// Sets the equal flag from a comparison.
+    fn compare(&mut self, context: &Context, value: Value) {
+        let OperationKind::Binary(op, left, right) = context.function.operation(value).kind else {
+            unreachable!("only comparisons are compared");
+        };
+        let left = self.read(context.location(left), SCRATCH_REGISTER);
+        let right = self.read(context.location(right), SECOND_SCRATCH_REGISTER);
+        self.emit(opcode(op), [left, right, 0], 0);
+    }
+
+    fn operation(&mut self, context: &Context, value: Value) {
+        let operation = context.function.operation(value);
+        match &operation.kind {
+            OperationKind::Const(number) => {
+                let location = context.location(value);
+                self.emit(Opcode::LOAD, [Self::destination(location), 0, 0], *number as i64);
+                self.write_back(location);
+            }
+            OperationKind::Param(index) => {
+                // `ENTER` pushed the caller's frame pointer just above the last argument.
+                let offset = *index as i64 - context.function.parameter_count as i64 - 1;
+                let location = context.location(value);
+                self.emit(Opcode::LDF, [Self::destination(location), 0, 0], offset);
+                self.write_back(location);
+            }
+            OperationKind::Binary(op, _, _) if op.is_comparison() => {
+                let location = context.location(value);
+                let destination = Self::destination(location);
+                self.compare(context, value);
+                self.emit(Opcode::LOAD, [destination, 0, 0], 1);
+                let skip = self.program.len();
+                self.emit(Opcode::LOAD, [JUMP_REGISTER, 0, 0], skip as i64 + 3);
+                self.emit(Opcode::JEQ, [JUMP_REGISTER, 0, 0], 0);
+                self.emit(Opcode::LOAD, [destination, 0, 0], 0);
+                self.write_back(location);
+            }
+            OperationKind::Binary(op, left, right) => {
+                let location = context.location(value);
+                let left = self.read(context.location(*left), SCRATCH_REGISTER);
+                let right = self.read(context.location(*right), SECOND_SCRATCH_REGISTER);
+                let destination = Self::destination(location);
+                self.emit(opcode(*op), [left, right, destination], 0);
+                if *op == BinaryOp::Mod {
+                    self.emit(Opcode::GETREM, [destination, 0, 0], 0);
+                }
+                self.write_back(location);
+            }
+            OperationKind::Call { function, arguments } => {
+                let saved = context.allocation.live_across(context.allocation.positions[&value]);
+                for register in &saved {
+                    self.emit(Opcode::PUSH, [*register, 0, 0], 0);
+                }
+                for argument in arguments {
+                    let register = self.read(context.location(*argument), SCRATCH_REGISTER);
+                    self.emit(Opcode::PUSH, [register, 0, 0], 0);
+                }
+                self.call_sites.push((self.program.len(), *function));
+                self.emit(Opcode::LOAD, [JUMP_REGISTER, 0, 0], 0);
+                self.emit(Opcode::CALL, [JUMP_REGISTER, 0, 0], 0);
+                for _ in arguments {
+                    self.emit(Opcode::POP, [JUMP_REGISTER, 0, 0], 0);
+                }
+                for register in saved.iter().rev() {
+                    self.emit(Opcode::POP, [*register, 0, 0], 0);
+                }
+                self.copy(Location::Register(RETURN_REGISTER), context.location(value));
+            }
+            OperationKind::Print(printed) => {
+                let register = self.read(context.location(*printed), SCRATCH_REGISTER);
+                self.emit(Opcode::ITOS, [register, PRINT_REGISTER, 0], 0);
+                self.emit(Opcode::PRTS, [PRINT_REGISTER, 0, 0], 0);
+            }
+            // Phis are resolved by the moves on each edge into their block.
+            OperationKind::Phi(_) => {}
+        }
+    }
+}
+
+fn opcode(op: BinaryOp) -> Opcode {
+    match op {
+        BinaryOp::Add => Opcode::ADD,
+        BinaryOp::Sub => Opcode::SUB,
+        BinaryOp::Mul => Opcode::MUL,
+        // `Mod` is the remainder `DIV` leaves, which matches `Div`, so `operation` follows it with
+        // a `GETREM`.
+        BinaryOp::Div | BinaryOp::Mod => Opcode::DIV,
+        BinaryOp::Eq => Opcode::EQ,
+        BinaryOp::Ne => Opcode::NEQ,
+        BinaryOp::Lt => Opcode::LT,
+        BinaryOp::Le => Opcode::LTQ,
+        BinaryOp::Gt => Opcode::GT,
+        BinaryOp::Ge => Opcode::GTQ,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        instruction::Instruction,
+        lang::{compile, compile_unoptimized},
+        verifier::verify,
+        vm::{ArithmeticMode, VmError, VM},
+    };
+
+    fn output(program: Vec<Instruction>) -> Vec<String> {
+        assert!(verify(&program, &[]).is_ok());
+        let mut vm = VM::new();
+        vm.program = program.into();
+        vm.output = Some(vec![]);
+        vm.run().unwrap();
+        assert_eq!(vm.call_stack.len(), 0);
+        vm.output.unwrap()
+    }
+
+    #[test]
+    fn test_matches_unoptimized() {
+        let sources = [
+            "fn fib(n) {\n    if n < 2 { return n; }\n    return fib(n - 1) + fib(n - 2);\n}\nfn fact(n) {\n    let result = 1;\n    while n > 1 { result = result * n; n = n - 1; }\n    return result;\n}\nfn is_even(n) { if n == 0 { return 1; } return is_odd(n - 1); }\nfn is_odd(n) { if n == 0 { return 0; } return is_even(n - 1); }\nlet a = 3;\nprint a + fib(15) * 2;\nprint fact(fact(a));\nprint is_even(a) + is_even(10) * 10;",
+            "let n = 10;\nlet total = 0;\nwhile n > 0 {\n    if n % 2 == 0 && !(n == 4) { total = total + n; } else { total = total - 1; }\n    n = n - 1;\n}\nprint total;\nprint total >= 20 || total / 0 == 1;",
+            "let a = 1;\nlet b = 2;\nlet i = 0;\nwhile i < 5 { let t = a; a = b; b = t; i = i + 1; }\nprint a;\nprint b;\nlet x = 7 * 6;\nif true { let x = x + 1; print x; }\nprint x;",
+            "fn sum(a, b, c, d) -> int { let s = a + b; return s + c * d; }\nfn positive(n: int) -> bool { return n > 0; }\nlet k = sum(1, 2, 3, 4);\nprint sum(k, k, sum(1, 1, 1, 1), -k);\nif positive(k) { print 1; }",
+            "fn remainder(a, b) { return a % b; }\nlet a = 0 - 7;\nprint a % 3;\nprint a % (0 - 3);\nprint remainder(a, 3) + remainder(a, 0 - 3);\nprint a / 3 * 3 + a % 3;",
+        ];
+        for source in sources {
+            assert_eq!(output(compile(source).unwrap()), output(compile_unoptimized(source).unwrap()), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_overflow_traps_when_optimized() {
+        let sources = ["let big = 2147483647;\nlet unused = big + 1;\nprint 1;", "print 2147483647 * 2;"];
+        for source in sources {
+            for program in [compile(source).unwrap(), compile_unoptimized(source).unwrap()] {
+                let mut vm = VM::new();
+                vm.arithmetic_mode = ArithmeticMode::Trap;
+                vm.program = program.into();
+                vm.output = Some(vec![]);
+                assert!(matches!(vm.run(), Err(VmError::IntegerOverflow { .. })), "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_spills_under_register_pressure() {
+        // More values live at once than there are registers, in `main` and across calls.
+        let lets: String = (0..40).map(|index| format!("let v{} = n * {};\n", index, index)).collect();
+        let sum: Vec<String> = (0..40).map(|index| format!("v{}", index)).collect();
+        let source = format!("fn f(n) {{\n{}return {};\n}}\nlet n = f(1);\n{}print f(n) + {};", lets, sum.join(" + "), lets, sum.join(" + "));
+        assert_eq!(output(compile(&source).unwrap()), vec![(780 * 780 + 780 * 780).to_string()]);
+    }
+}
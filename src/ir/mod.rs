@@ -0,0 +1,420 @@
+//! A typed intermediate representation in SSA form that sits between a frontend and
+//! `Instruction`s.
+//!
+//! A `Program` is a list of functions, the first of which is where execution starts. Each
+//! function is an arena of `Operation`s, each defining at most one `Value`, grouped into basic
+//! blocks that end in a `Terminator`. Phis sit at the start of their block.
+//!
+//! The textual form printed by `Display` looks like:
+//!
+//! ```text
+//! fn main() {
+//! b0:
+//!     v0: int = const 10
+//!     jump b1
+//! b1:
+//!     v1: int = phi [b0: v0], [b2: v3]
+//!     v2: bool = gt v1, v0
+//!     branch v2, b2, b3
+//! ...
+//! }
+//! ```
+
+use std::{collections::HashSet, fmt};
+
+pub mod emit;
+pub mod passes;
+pub mod regalloc;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Type {
+    Int,
+    /// Represented as 1 or 0.
+    Bool,
+}
+
+/// An SSA value, named after the index of the operation that defines it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Value(pub usize);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// The remainder `Div` leaves, which takes the sign of the dividend.
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinaryOp {
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge)
+    }
+
+    /// Whether swapping the operands gives the same result.
+    pub fn is_commutative(&self) -> bool {
+        matches!(self, BinaryOp::Add | BinaryOp::Mul | BinaryOp::Eq | BinaryOp::Ne)
+    }
+
+    /// Evaluates the operation the way the VM does. Gives `None` when the result depends on the
+    /// VM's arithmetic mode, because the operation overflows, or when it traps, as division and
+    /// modulo by zero do.
+    pub fn evaluate(&self, left: i32, right: i32) -> Option<i32> {
+        let result = match self {
+            BinaryOp::Add => left.checked_add(right)?,
+            BinaryOp::Sub => left.checked_sub(right)?,
+            BinaryOp::Mul => left.checked_mul(right)?,
+            BinaryOp::Div => left.checked_div(right)?,
+            BinaryOp::Mod => left.checked_rem(right)?,
+            BinaryOp::Eq => (left == right) as i32,
+            BinaryOp::Ne => (left != right) as i32,
+            BinaryOp::Lt => (left < right) as i32,
+            BinaryOp::Le => (left <= right) as i32,
+            BinaryOp::Gt => (left > right) as i32,
+            BinaryOp::Ge => (left >= right) as i32,
+        };
+        Some(result)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Mod => "mod",
+            BinaryOp::Eq => "eq",
+            BinaryOp::Ne => "ne",
+            BinaryOp::Lt => "lt",
+            BinaryOp::Le => "le",
+            BinaryOp::Gt => "gt",
+            BinaryOp::Ge => "ge",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum OperationKind {
+    Const(i32),
+    /// The function's parameter at this index.
+    Param(usize),
+    Binary(BinaryOp, Value, Value),
+    /// Calls the function at this index in the program.
+    Call { function: usize, arguments: Vec<Value> },
+    Print(Value),
+    /// The value coming from each predecessor block.
+    Phi(Vec<(BlockId, Value)>),
+}
+
+impl OperationKind {
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            OperationKind::Const(_) | OperationKind::Param(_) => vec![],
+            OperationKind::Binary(_, left, right) => vec![*left, *right],
+            OperationKind::Call { arguments, .. } => arguments.clone(),
+            OperationKind::Print(value) => vec![*value],
+            OperationKind::Phi(incoming) => incoming.iter().map(|(_, value)| *value).collect(),
+        }
+    }
+
+    /// Whether removing the operation could change what the program does, even if its result is
+    /// unused. Arithmetic can trap, on overflow in the VM's trapping mode or on division by zero,
+    /// so it only counts as pure when its operands are known not to.
+    pub fn has_side_effects(&self, function: &Function) -> bool {
+        match self {
+            OperationKind::Call { .. } | OperationKind::Print(_) => true,
+            OperationKind::Binary(op, _, _) if op.is_comparison() => false,
+            OperationKind::Binary(op, left, right) => match (function.constant(*left), function.constant(*right)) {
+                (Some(left), Some(right)) => op.evaluate(left, right).is_none(),
+                // Only `i32::MIN / -1` overflows, so any other divisor is safe.
+                (_, Some(divisor)) if matches!(op, BinaryOp::Div | BinaryOp::Mod) => divisor == 0 || divisor == -1,
+                _ => true,
+            },
+            _ => false,
+        }
+    }
+
+    fn replace_uses(&mut self, from: Value, to: Value) {
+        let replace = |value: &mut Value| {
+            if *value == from {
+                *value = to;
+            }
+        };
+        match self {
+            OperationKind::Const(_) | OperationKind::Param(_) => {}
+            OperationKind::Binary(_, left, right) => {
+                replace(left);
+                replace(right);
+            }
+            OperationKind::Call { arguments, .. } => arguments.iter_mut().for_each(replace),
+            OperationKind::Print(value) => replace(value),
+            OperationKind::Phi(incoming) => incoming.iter_mut().for_each(|(_, value)| replace(value)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Operation {
+    pub kind: OperationKind,
+    /// `None` for operations that do not produce a value.
+    pub ty: Option<Type>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch { condition: Value, then_block: BlockId, else_block: BlockId },
+    Return(Value),
+    /// Stops the VM. Only the entry function halts.
+    Halt,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { then_block, else_block, .. } => vec![*then_block, *else_block],
+            Terminator::Return(_) | Terminator::Halt => vec![],
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Terminator::Branch { condition, .. } => vec![*condition],
+            Terminator::Return(value) => vec![*value],
+            Terminator::Jump(_) | Terminator::Halt => vec![],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    /// Operations in execution order, with any phis first.
+    pub operations: Vec<Value>,
+    pub terminator: Terminator,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+    pub name: String,
+    pub parameter_count: usize,
+    pub operations: Vec<Operation>,
+    /// Blocks that are unreachable from the entry, `BlockId(0)`, are ignored everywhere.
+    pub blocks: Vec<Block>,
+}
+
+impl Function {
+    pub fn new(name: &str, parameter_count: usize) -> Function {
+        Function {
+            name: name.to_string(),
+            parameter_count,
+            operations: vec![],
+            blocks: vec![Block { operations: vec![], terminator: Terminator::Halt }],
+        }
+    }
+
+    pub fn add_block(&mut self) -> BlockId {
+        self.blocks.push(Block { operations: vec![], terminator: Terminator::Halt });
+        BlockId(self.blocks.len() - 1)
+    }
+
+    /// Adds an operation to the end of a block, or to the end of its phis for a phi.
+    pub fn append(&mut self, block: BlockId, kind: OperationKind, ty: Option<Type>) -> Value {
+        let value = Value(self.operations.len());
+        let operations = &mut self.blocks[block.0].operations;
+        let position = match kind {
+            OperationKind::Phi(_) => operations.iter().take_while(|value| matches!(self.operations[value.0].kind, OperationKind::Phi(_))).count(),
+            _ => operations.len(),
+        };
+        operations.insert(position, value);
+        self.operations.push(Operation { kind, ty });
+        value
+    }
+
+    pub fn operation(&self, value: Value) -> &Operation {
+        &self.operations[value.0]
+    }
+
+    /// The value's number if it is defined by a `Const`.
+    pub fn constant(&self, value: Value) -> Option<i32> {
+        match self.operations[value.0].kind {
+            OperationKind::Const(number) => Some(number),
+            _ => None,
+        }
+    }
+
+    /// Makes every operation and terminator that used `from` use `to` instead.
+    pub fn replace_uses(&mut self, from: Value, to: Value) {
+        for operation in &mut self.operations {
+            operation.kind.replace_uses(from, to);
+        }
+        for block in &mut self.blocks {
+            match &mut block.terminator {
+                Terminator::Branch { condition: value, .. } | Terminator::Return(value) if *value == from => *value = to,
+                _ => {}
+            }
+        }
+    }
+
+    /// Reachable blocks in reverse postorder, so every block comes after its dominators.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = HashSet::new();
+        let mut postorder = vec![];
+        // Each entry is a block and how many of its successors have been visited.
+        let mut stack = vec![(BlockId(0), 0)];
+        visited.insert(BlockId(0));
+        while let Some((block, next)) = stack.pop() {
+            // Successors are visited last to first so that the first comes first in the order.
+            let successors = self.blocks[block.0].terminator.successors();
+            match successors.iter().rev().nth(next) {
+                Some(successor) => {
+                    stack.push((block, next + 1));
+                    if visited.insert(*successor) {
+                        stack.push((*successor, 0));
+                    }
+                }
+                None => postorder.push(block),
+            }
+        }
+        postorder.reverse();
+        postorder
+    }
+
+    /// Predecessors of each block, counting only reachable ones.
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![vec![]; self.blocks.len()];
+        for block in self.reverse_postorder() {
+            for successor in self.blocks[block.0].terminator.successors() {
+                if !predecessors[successor.0].contains(&block) {
+                    predecessors[successor.0].push(block);
+                }
+            }
+        }
+        predecessors
+    }
+
+    /// The immediate dominator of each reachable block, with the entry as its own, using the
+    /// iterative algorithm of Cooper, Harvey and Kennedy.
+    pub fn immediate_dominators(&self) -> Vec<Option<BlockId>> {
+        let order = self.reverse_postorder();
+        let mut rank = vec![usize::MAX; self.blocks.len()];
+        for (index, block) in order.iter().enumerate() {
+            rank[block.0] = index;
+        }
+        let predecessors = self.predecessors();
+        let mut dominators: Vec<Option<BlockId>> = vec![None; self.blocks.len()];
+        dominators[0] = Some(BlockId(0));
+
+        let intersect = |dominators: &[Option<BlockId>], mut first: BlockId, mut second: BlockId| {
+            while first != second {
+                while rank[first.0] > rank[second.0] {
+                    first = dominators[first.0].unwrap();
+                }
+                while rank[second.0] > rank[first.0] {
+                    second = dominators[second.0].unwrap();
+                }
+            }
+            first
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in order.iter().skip(1) {
+                let mut processed = predecessors[block.0].iter().filter(|predecessor| dominators[predecessor.0].is_some());
+                let Some(first) = processed.next() else {
+                    continue;
+                };
+                let dominator = processed.fold(*first, |dominator, predecessor| intersect(&dominators, *predecessor, dominator));
+                if dominators[block.0] != Some(dominator) {
+                    dominators[block.0] = Some(dominator);
+                    changed = true;
+                }
+            }
+        }
+        dominators
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Program {
+    /// Execution starts at the first function.
+    pub functions: Vec<Function>,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "b{}", self.0)
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, function) in self.functions.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            let parameters: Vec<String> = (0..function.parameter_count).map(|index| format!("p{}", index)).collect();
+            writeln!(f, "fn {}({}) {{", function.name, parameters.join(", "))?;
+            for block in function.reverse_postorder() {
+                writeln!(f, "{}:", block)?;
+                for value in &function.blocks[block.0].operations {
+                    let operation = function.operation(*value);
+                    write!(f, "    ")?;
+                    if let Some(ty) = operation.ty {
+                        write!(f, "{}: {} = ", value, ty)?;
+                    }
+                    let operands: Vec<String> = operation.kind.operands().iter().map(|value| value.to_string()).collect();
+                    match &operation.kind {
+                        OperationKind::Const(number) => writeln!(f, "const {}", number)?,
+                        OperationKind::Param(index) => writeln!(f, "param p{}", index)?,
+                        OperationKind::Binary(op, left, right) => writeln!(f, "{} {}, {}", op.name(), left, right)?,
+                        OperationKind::Call { function, .. } => {
+                            writeln!(f, "call {}({})", self.functions[*function].name, operands.join(", "))?
+                        }
+                        OperationKind::Print(value) => writeln!(f, "print {}", value)?,
+                        OperationKind::Phi(incoming) => {
+                            let incoming: Vec<String> = incoming.iter().map(|(block, value)| format!("[{}: {}]", block, value)).collect();
+                            writeln!(f, "phi {}", incoming.join(", "))?
+                        }
+                    }
+                }
+                match &function.blocks[block.0].terminator {
+                    Terminator::Jump(target) => writeln!(f, "    jump {}", target)?,
+                    Terminator::Branch { condition, then_block, else_block } => {
+                        writeln!(f, "    branch {}, {}, {}", condition, then_block, else_block)?
+                    }
+                    Terminator::Return(value) => writeln!(f, "    return {}", value)?,
+                    Terminator::Halt => writeln!(f, "    halt")?,
+                }
+            }
+            writeln!(f, "}}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+//! Optimization passes. Each returns whether it changed the function, and `optimize` runs them
+//! until none does.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{BinaryOp, BlockId, Function, OperationKind, Program, Terminator, Value};
+
+/// Runs every pass over every function until nothing changes.
+pub fn optimize(program: &mut Program) {
+    for function in &mut program.functions {
+        loop {
+            let mut changed = constant_propagation(function);
+            changed |= common_subexpression_elimination(function);
+            changed |= dead_code_elimination(function);
+            if !changed {
+                break;
+            }
+        }
+    }
+}
+
+/// Folds operations on constants, replaces phis whose inputs are all the same value with that
+/// value, and turns branches on constants into jumps. Phis lose the inputs of blocks that are no
+/// longer predecessors.
+pub fn constant_propagation(function: &mut Function) -> bool {
+    let mut changed = false;
+    for block in function.reverse_postorder() {
+        for value in function.blocks[block.0].operations.clone() {
+            if let OperationKind::Binary(op, left, right) = function.operations[value.0].kind {
+                if let (Some(left), Some(right)) = (function.constant(left), function.constant(right)) {
+                    if let Some(result) = op.evaluate(left, right) {
+                        function.operations[value.0].kind = OperationKind::Const(result);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if let Terminator::Branch { condition, then_block, else_block } = function.blocks[block.0].terminator {
+            if let Some(condition) = function.constant(condition) {
+                let target = if condition != 0 { then_block } else { else_block };
+                function.blocks[block.0].terminator = Terminator::Jump(target);
+                changed = true;
+            }
+        }
+    }
+
+    let predecessors = function.predecessors();
+    for block in function.reverse_postorder() {
+        for value in function.blocks[block.0].operations.clone() {
+            let OperationKind::Phi(incoming) = &function.operations[value.0].kind else {
+                continue;
+            };
+            let live: Vec<(BlockId, Value)> = incoming.iter().filter(|(from, _)| predecessors[block.0].contains(from)).copied().collect();
+            if live.len() != incoming.len() {
+                function.operations[value.0].kind = OperationKind::Phi(live.clone());
+                changed = true;
+            }
+            // A phi can feed itself around a loop without that making it any less trivial.
+            let mut inputs = live.iter().map(|(_, input)| *input).filter(|input| *input != value);
+            if let Some(first) = inputs.next() {
+                if inputs.all(|input| input == first) {
+                    function.replace_uses(value, first);
+                    function.blocks[block.0].operations.retain(|operation| *operation != value);
+                    changed = true;
+                }
+            }
+        }
+    }
+    return changed;
+}
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+enum Expression {
+    Const(i32),
+    Binary(BinaryOp, Value, Value),
+}
+
+/// Replaces an operation with an identical one that dominates it.
+pub fn common_subexpression_elimination(function: &mut Function) -> bool {
+    let dominators = function.immediate_dominators();
+    let mut available: Vec<HashMap<Expression, Value>> = vec![HashMap::new(); function.blocks.len()];
+    let mut changed = false;
+    for block in function.reverse_postorder() {
+        // Reverse postorder visits a block's immediate dominator before it.
+        let mut expressions = match dominators[block.0] {
+            Some(dominator) if dominator != block => available[dominator.0].clone(),
+            _ => HashMap::new(),
+        };
+        for value in function.blocks[block.0].operations.clone() {
+            let expression = match function.operations[value.0].kind {
+                OperationKind::Const(number) => Expression::Const(number),
+                OperationKind::Binary(op, left, right) if op.is_commutative() && right < left => Expression::Binary(op, right, left),
+                OperationKind::Binary(op, left, right) => Expression::Binary(op, left, right),
+                _ => continue,
+            };
+            match expressions.get(&expression) {
+                Some(existing) => {
+                    function.replace_uses(value, *existing);
+                    function.blocks[block.0].operations.retain(|operation| *operation != value);
+                    changed = true;
+                }
+                None => {
+                    expressions.insert(expression, value);
+                }
+            }
+        }
+        available[block.0] = expressions;
+    }
+    return changed;
+}
+
+/// Removes operations whose results are never used and that have no side effects.
+pub fn dead_code_elimination(function: &mut Function) -> bool {
+    let blocks = function.reverse_postorder();
+    let mut live = HashSet::new();
+    let mut worklist = vec![];
+    for block in &blocks {
+        let block = &function.blocks[block.0];
+        for value in &block.operations {
+            if function.operation(*value).kind.has_side_effects(function) {
+                worklist.push(*value);
+            }
+        }
+        worklist.extend(block.terminator.operands());
+    }
+    while let Some(value) = worklist.pop() {
+        if live.insert(value) {
+            worklist.extend(function.operation(value).kind.operands());
+        }
+    }
+
+    let mut changed = false;
+    for block in blocks {
+        let operations = &mut function.blocks[block.0].operations;
+        let count = operations.len();
+        operations.retain(|value| live.contains(value));
+        changed |= operations.len() != count;
+    }
+    return changed;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{Function, Type};
+
+    #[test]
+    fn test_passes() {
+        let mut function = Function::new("main", 0);
+        let entry = BlockId(0);
+        let six = function.append(entry, OperationKind::Const(6), Some(Type::Int));
+        let seven = function.append(entry, OperationKind::Const(7), Some(Type::Int));
+        let parameter = function.append(entry, OperationKind::Param(0), Some(Type::Int));
+        let product = function.append(entry, OperationKind::Binary(BinaryOp::Mul, parameter, six), Some(Type::Int));
+        let again = function.append(entry, OperationKind::Binary(BinaryOp::Mul, six, parameter), Some(Type::Int));
+        let unused = function.append(entry, OperationKind::Binary(BinaryOp::Div, again, seven), Some(Type::Int));
+        let trap = function.append(entry, OperationKind::Binary(BinaryOp::Div, again, parameter), Some(Type::Int));
+        let truth = function.append(entry, OperationKind::Binary(BinaryOp::Lt, six, seven), Some(Type::Bool));
+        let folded = function.append(entry, OperationKind::Binary(BinaryOp::Add, six, seven), Some(Type::Int));
+        let then_block = function.add_block();
+        let else_block = function.add_block();
+        function.blocks[0].terminator = Terminator::Branch { condition: truth, then_block, else_block };
+        let sum = function.append(then_block, OperationKind::Binary(BinaryOp::Add, product, again), Some(Type::Int));
+        function.blocks[then_block.0].terminator = Terminator::Return(sum);
+        function.blocks[else_block.0].terminator = Terminator::Return(folded);
+
+        assert!(constant_propagation(&mut function));
+        assert_eq!(function.blocks[0].terminator, Terminator::Jump(then_block));
+        assert_eq!(function.constant(folded), Some(13));
+        assert!(common_subexpression_elimination(&mut function));
+        assert_eq!(function.operation(sum).kind, OperationKind::Binary(BinaryOp::Add, product, product));
+        assert!(dead_code_elimination(&mut function));
+        // The division that could trap stays, as does everything the return needs.
+        assert_eq!(function.blocks[0].operations, vec![six, parameter, product, trap]);
+        assert!(!function.blocks[0].operations.contains(&unused));
+        assert_eq!(function.blocks[then_block.0].operations, vec![sum]);
+        assert!(!constant_propagation(&mut function) && !common_subexpression_elimination(&mut function) && !dead_code_elimination(&mut function));
+    }
+
+    #[test]
+    fn test_overflow_is_kept() {
+        // The VM may be trapping on overflow, so arithmetic that could overflow is neither folded
+        // nor removed.
+        let mut function = Function::new("main", 0);
+        let entry = BlockId(0);
+        let max = function.append(entry, OperationKind::Const(i32::MAX), Some(Type::Int));
+        let one = function.append(entry, OperationKind::Const(1), Some(Type::Int));
+        let minus_one = function.append(entry, OperationKind::Const(-1), Some(Type::Int));
+        let parameter = function.append(entry, OperationKind::Param(0), Some(Type::Int));
+        let overflow = function.append(entry, OperationKind::Binary(BinaryOp::Add, max, one), Some(Type::Int));
+        let scaled = function.append(entry, OperationKind::Binary(BinaryOp::Mul, parameter, max), Some(Type::Int));
+        let negated = function.append(entry, OperationKind::Binary(BinaryOp::Div, parameter, minus_one), Some(Type::Int));
+        let unused = function.append(entry, OperationKind::Binary(BinaryOp::Lt, parameter, max), Some(Type::Bool));
+        function.blocks[0].terminator = Terminator::Return(overflow);
+
+        let mut program = Program { functions: vec![function] };
+        optimize(&mut program);
+        let function = &program.functions[0];
+        assert_eq!(function.operation(overflow).kind, OperationKind::Binary(BinaryOp::Add, max, one));
+        assert_eq!(function.blocks[0].operations, vec![max, one, minus_one, parameter, overflow, scaled, negated]);
+        assert!(!function.blocks[0].operations.contains(&unused));
+    }
+}
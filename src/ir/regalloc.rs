@@ -0,0 +1,210 @@
+//! Linear-scan register allocation.
+//!
+//! Blocks are laid out in reverse postorder and every operation is given a position. Each value
+//! then gets a single live interval from its definition to its last use, stretched over every
+//! block it is live through, which is conservative around loops. Intervals are assigned
+//! registers in order of their start, and when none are free the one ending furthest away is
+//! spilled to a slot in the function's stack frame.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{Function, OperationKind, Value};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Location {
+    Register(usize),
+    /// A stack frame slot, as an offset from the frame pointer.
+    Slot(i64),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Interval {
+    pub value: Value,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Allocation {
+    /// Where every value that is used or defined in a reachable block lives.
+    pub locations: HashMap<Value, Location>,
+    pub intervals: Vec<Interval>,
+    /// The position of each non-phi operation. Phis are at the start of their block.
+    pub positions: HashMap<Value, usize>,
+    /// How many slots spilled values need.
+    pub slots: usize,
+}
+
+impl Allocation {
+    /// The registers holding values that are still needed after `position`, which a call at
+    /// that position has to preserve.
+    pub fn live_across(&self, position: usize) -> Vec<usize> {
+        let mut registers: Vec<usize> = self
+            .intervals
+            .iter()
+            .filter(|interval| interval.start < position && position < interval.end)
+            .filter_map(|interval| match self.locations[&interval.value] {
+                Location::Register(register) => Some(register),
+                Location::Slot(_) => None,
+            })
+            .collect();
+        registers.sort();
+        registers
+    }
+}
+
+/// Allocates registers `0..register_count` to a function's values.
+pub fn allocate(function: &Function, register_count: usize) -> Allocation {
+    let order = function.reverse_postorder();
+
+    // Block ranges, with phis at a block's first position and its terminator at its last.
+    let mut positions = HashMap::new();
+    let mut ranges = vec![(0, 0); function.blocks.len()];
+    let mut position = 0;
+    for block in &order {
+        let start = position;
+        position += 1;
+        for value in &function.blocks[block.0].operations {
+            if matches!(function.operation(*value).kind, OperationKind::Phi(_)) {
+                positions.insert(*value, start);
+            } else {
+                positions.insert(*value, position);
+                position += 1;
+            }
+        }
+        ranges[block.0] = (start, position);
+        position += 1;
+    }
+
+    // Phi inputs are used at the end of the predecessor they come from, not in the phi's block.
+    let mut uses = vec![HashSet::new(); function.blocks.len()];
+    let mut definitions = vec![HashSet::new(); function.blocks.len()];
+    let mut phi_uses = vec![HashSet::new(); function.blocks.len()];
+    for block in &order {
+        let contents = &function.blocks[block.0];
+        for value in &contents.operations {
+            match &function.operation(*value).kind {
+                OperationKind::Phi(incoming) => {
+                    for (from, input) in incoming {
+                        phi_uses[from.0].insert(*input);
+                    }
+                }
+                kind => uses[block.0].extend(kind.operands().into_iter().filter(|operand| !definitions[block.0].contains(operand))),
+            }
+            definitions[block.0].insert(*value);
+        }
+        uses[block.0].extend(contents.terminator.operands().into_iter().filter(|operand| !definitions[block.0].contains(operand)));
+    }
+
+    let mut live_in: Vec<HashSet<Value>> = vec![HashSet::new(); function.blocks.len()];
+    let mut live_out: Vec<HashSet<Value>> = vec![HashSet::new(); function.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in order.iter().rev() {
+            let mut out = phi_uses[block.0].clone();
+            for successor in function.blocks[block.0].terminator.successors() {
+                out.extend(live_in[successor.0].iter().copied());
+            }
+            let mut inside = uses[block.0].clone();
+            inside.extend(out.iter().filter(|value| !definitions[block.0].contains(value)).copied());
+            if inside != live_in[block.0] || out != live_out[block.0] {
+                live_in[block.0] = inside;
+                live_out[block.0] = out;
+                changed = true;
+            }
+        }
+    }
+
+    let mut hulls: HashMap<Value, (usize, usize)> = HashMap::new();
+    let mut extend = |value: Value, start: usize, end: usize| {
+        let hull = hulls.entry(value).or_insert((start, end));
+        *hull = (hull.0.min(start), hull.1.max(end));
+    };
+    for block in &order {
+        let (start, end) = ranges[block.0];
+        for value in &live_in[block.0] {
+            extend(*value, start, start);
+        }
+        for value in &live_out[block.0] {
+            extend(*value, end, end);
+        }
+        for value in &function.blocks[block.0].operations {
+            let operation = function.operation(*value);
+            extend(*value, positions[value], positions[value]);
+            if !matches!(operation.kind, OperationKind::Phi(_)) {
+                for operand in operation.kind.operands() {
+                    extend(operand, positions[value], positions[value]);
+                }
+            }
+        }
+        for operand in function.blocks[block.0].terminator.operands() {
+            extend(operand, end, end);
+        }
+    }
+
+    let mut intervals: Vec<Interval> = hulls
+        .into_iter()
+        .filter(|(value, _)| function.operation(*value).ty.is_some())
+        .map(|(value, (start, end))| Interval { value, start, end })
+        .collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.value));
+
+    let mut locations = HashMap::new();
+    let mut free: Vec<usize> = (0..register_count).rev().collect();
+    let mut active: Vec<Interval> = vec![];
+    let mut slots = 0;
+    for interval in &intervals {
+        active.retain(|other| {
+            if other.end < interval.start {
+                if let Location::Register(register) = locations[&other.value] {
+                    free.push(register);
+                }
+                return false;
+            }
+            true
+        });
+        if let Some(register) = free.pop() {
+            locations.insert(interval.value, Location::Register(register));
+            active.push(*interval);
+            continue;
+        }
+        let furthest = active.iter().enumerate().max_by_key(|(_, other)| other.end).map(|(index, other)| (index, *other));
+        match furthest {
+            Some((index, other)) if other.end > interval.end => {
+                locations.insert(interval.value, locations[&other.value]);
+                locations.insert(other.value, Location::Slot(slots as i64));
+                active[index] = *interval;
+            }
+            _ => {
+                locations.insert(interval.value, Location::Slot(slots as i64));
+            }
+        }
+        slots += 1;
+    }
+    return Allocation { locations, intervals, positions, slots };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{BlockId, Type};
+
+    #[test]
+    fn test_linear_scan_spills_under_pressure() {
+        // Five constants that are all live until they are printed, with three registers.
+        let mut function = Function::new("main", 0);
+        let values: Vec<Value> = (0..5).map(|number| function.append(BlockId(0), OperationKind::Const(number), Some(Type::Int))).collect();
+        for value in &values {
+            function.append(BlockId(0), OperationKind::Print(*value), None);
+        }
+        let allocation = allocate(&function, 3);
+        let registers: Vec<Location> = values.iter().map(|value| allocation.locations[value]).collect();
+        assert_eq!(
+            registers,
+            vec![Location::Register(0), Location::Register(1), Location::Register(2), Location::Slot(0), Location::Slot(1)]
+        );
+        assert_eq!(allocation.slots, 2);
+        assert_eq!(allocation.live_across(6), vec![1, 2]);
+    }
+}
@@ -0,0 +1,573 @@
+//! A JIT compiler that turns hot basic blocks into x86-64 machine code. Built with the `jit`
+//! feature, on x86-64 Linux only.
+//!
+//! `Jit::run` interprets a program just as `VM::run` does, counting how often it reaches each pc.
+//! Once a pc has been reached `threshold` times, the run of integer instructions starting there,
+//! up to and including the jump that ends it, is compiled into executable memory. From then on it
+//! runs natively, directly on the VM's registers and flags.
+//!
+//! Native code never traps. An instruction that would overflow leaves the block before it writes
+//! its result, which deoptimizes: the interpreter runs that instruction instead, so the arithmetic
+//! mode decides what happens, and carries on from there.
+
+use std::{ffi::c_void, mem, ptr};
+
+use crate::{
+    instruction::{Instruction, Opcode},
+    vm::{ProgramImage, VmError, REGISTER_COUNT, VM},
+};
+
+/// How many times a pc is reached before the block starting there is compiled.
+pub const DEFAULT_THRESHOLD: u32 = 100;
+
+/// The longest block that is compiled. Longer runs are split into several blocks.
+pub const MAX_BLOCK_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct JitStats {
+    pub compiled_blocks: usize,
+    /// Instructions run by native code.
+    pub native_instructions: u64,
+    /// How many times native code handed an instruction back to the interpreter.
+    pub deoptimizations: u64,
+}
+
+enum Slot {
+    /// Reached this many times so far.
+    Cold(u32),
+    Native(Block),
+    /// The instruction here cannot start a block.
+    Interpreted,
+}
+
+pub struct Jit {
+    program: ProgramImage,
+    slots: Vec<Slot>,
+    pub threshold: u32,
+    pub stats: JitStats,
+}
+
+impl Jit {
+    pub fn new(program: ProgramImage) -> Jit {
+        let slots = (0..program.len()).map(|_| Slot::Cold(0)).collect();
+        Jit { program, slots, threshold: DEFAULT_THRESHOLD, stats: JitStats::default() }
+    }
+
+    /// Runs until every thread has finished, failing if the VM is suspended, like `VM::run`. The
+    /// VM must be running the program the JIT was created for.
+    pub fn run(&mut self, vm: &mut VM) -> Result<(), VmError> {
+        loop {
+            if let Some(block) = self.block_at(vm.pc) {
+                // As with superinstructions, a block only runs if the thread would not be
+                // preempted partway through it.
+                if vm.slice + block.length <= vm.quantum {
+                    let length = block.length;
+                    let executed = block.call(vm);
+                    vm.slice += executed;
+                    self.stats.native_instructions += executed as u64;
+                    if executed == length {
+                        if vm.slice >= vm.quantum {
+                            vm.schedule();
+                        }
+                        continue;
+                    }
+                    self.stats.deoptimizations += 1;
+                }
+            }
+            if !vm.step()? {
+                return vm.check_suspension();
+            }
+        }
+    }
+
+    /// The compiled block starting at `pc`, compiling it if it has just become hot.
+    fn block_at(&mut self, pc: usize) -> Option<&Block> {
+        let slot = self.slots.get_mut(pc)?;
+        if let Slot::Cold(count) = slot {
+            *count += 1;
+            if *count < self.threshold {
+                return None;
+            }
+            *slot = match compile(&self.program[pc..], pc) {
+                Some(block) => {
+                    self.stats.compiled_blocks += 1;
+                    Slot::Native(block)
+                }
+                None => Slot::Interpreted,
+            };
+        }
+        match slot {
+            Slot::Native(block) => Some(block),
+            _ => None,
+        }
+    }
+}
+
+/// Runs `vm`'s program with a new `Jit`.
+pub fn run(vm: &mut VM) -> Result<(), VmError> {
+    Jit::new(vm.program.clone()).run(vm)
+}
+
+/// Native code for a block, called with pointers to the VM's registers and its equal, overflow
+/// and carry flags. Returns the number of instructions it ran in the high 32 bits and the next pc,
+/// as an `i32`, in the low 32 bits.
+type NativeBlock = unsafe extern "C" fn(*mut i32, *mut bool, *mut bool, *mut bool) -> u64;
+
+struct Block {
+    memory: ExecutableMemory,
+    /// How many instructions the block runs when it does not deoptimize.
+    length: usize,
+}
+
+impl Block {
+    /// Runs the block, leaving `vm.pc` at the next instruction to run. Returns how many
+    /// instructions it ran.
+    fn call(&self, vm: &mut VM) -> usize {
+        // SAFETY: the memory holds a complete function compiled by `compile` with this signature,
+        // which only reads and writes the registers and the three flags it is given.
+        let result = unsafe {
+            let function: NativeBlock = mem::transmute(self.memory.pointer);
+            function(vm.registers.as_mut_ptr(), &mut vm.equal, &mut vm.overflow, &mut vm.carry)
+        };
+        // Like the interpreter's `address as usize`, a negative target sign extends.
+        vm.pc = result as u32 as i32 as usize;
+        return (result >> 32) as usize;
+    }
+}
+
+/// Compiles the block at the start of `program`, which is at `start`. Returns `None` if its first
+/// instruction cannot be compiled.
+fn compile(program: &[Instruction], start: usize) -> Option<Block> {
+    let mut assembler = Assembler::new();
+    // Frees `rdx` and `rcx` for `MUL`: the overflow flag pointer moves to `r8`, carry to `r9`.
+    assembler.emit(&[0x49, 0x89, 0xD0, 0x49, 0x89, 0xC9]);
+
+    let mut length = 0;
+    let mut ended = false;
+    for instruction in program.iter().take(MAX_BLOCK_LENGTH) {
+        if instruction.registers.iter().any(|register| *register >= REGISTER_COUNT) {
+            break;
+        }
+        let [first, second, third] = instruction.registers;
+        let immediate = instruction.integer_operand as i32;
+        let index = length;
+        match instruction.opcode {
+            Opcode::LOAD => assembler.store_immediate(first, immediate),
+            Opcode::ADD | Opcode::SUB | Opcode::MUL => {
+                assembler.load_eax(first);
+                assembler.load_r10(second);
+                assembler.arithmetic(instruction.opcode, third, index);
+            }
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => {
+                assembler.load_eax(first);
+                assembler.immediate_r10(immediate);
+                let opcode = match instruction.opcode {
+                    Opcode::ADDI => Opcode::ADD,
+                    Opcode::SUBI => Opcode::SUB,
+                    _ => Opcode::MUL,
+                };
+                assembler.arithmetic(opcode, third, index);
+            }
+            Opcode::INC | Opcode::DEC => {
+                assembler.load_eax(first);
+                assembler.immediate_r10(1);
+                let opcode = if instruction.opcode == Opcode::INC { Opcode::ADD } else { Opcode::SUB };
+                assembler.arithmetic(opcode, first, index);
+            }
+            Opcode::AND | Opcode::OR | Opcode::XOR => {
+                assembler.load_eax(first);
+                assembler.load_r10(second);
+                let opcode = match instruction.opcode {
+                    Opcode::AND => 0x21,
+                    Opcode::OR => 0x09,
+                    _ => 0x31,
+                };
+                assembler.emit(&[0x44, opcode, 0xD0]);
+                assembler.store_eax(third);
+            }
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ => {
+                assembler.load_eax(first);
+                assembler.load_r10(second);
+                assembler.compare(instruction.opcode);
+            }
+            Opcode::EQI | Opcode::NEQI | Opcode::GTI | Opcode::LTI | Opcode::GTQI | Opcode::LTQI => {
+                assembler.load_eax(first);
+                assembler.immediate_r10(immediate);
+                assembler.compare(instruction.opcode);
+            }
+            Opcode::JMP => {
+                assembler.exit_to_register(first, index + 1);
+                ended = true;
+            }
+            Opcode::JEQ | Opcode::JNEQ | Opcode::JZ | Opcode::JNZ => {
+                let skip = match instruction.opcode {
+                    // cmp byte [rsi], 0
+                    Opcode::JEQ | Opcode::JNEQ => {
+                        assembler.emit(&[0x80, 0x3E, 0x00]);
+                        if instruction.opcode == Opcode::JEQ { 0x84 } else { 0x85 }
+                    }
+                    // cmp dword [rdi + first], 0
+                    _ => {
+                        assembler.emit(&[0x83, 0xBF]);
+                        assembler.emit(&displacement(first));
+                        assembler.emit(&[0x00]);
+                        if instruction.opcode == Opcode::JZ { 0x85 } else { 0x84 }
+                    }
+                };
+                // Skips the jump when its condition does not hold.
+                assembler.emit(&[0x0F, skip]);
+                let patch = assembler.code.len();
+                assembler.emit(&[0; 4]);
+                let target = if matches!(instruction.opcode, Opcode::JEQ | Opcode::JNEQ) { first } else { second };
+                assembler.exit_to_register(target, index + 1);
+                let offset = (assembler.code.len() - patch - 4) as i32;
+                assembler.code[patch..patch + 4].copy_from_slice(&offset.to_le_bytes());
+                assembler.exit(index + 1, start + index + 1);
+                ended = true;
+            }
+            _ => break,
+        }
+        length += 1;
+        if ended {
+            break;
+        }
+    }
+    if length == 0 {
+        return None;
+    }
+    if !ended {
+        assembler.exit(length, start + length);
+    }
+    assembler.emit_deoptimizations(start);
+    let memory = ExecutableMemory::new(&assembler.code)?;
+    return Some(Block { memory, length });
+}
+
+/// The offset of a register from the start of the register array.
+fn displacement(register: usize) -> [u8; 4] {
+    ((register * mem::size_of::<i32>()) as i32).to_le_bytes()
+}
+
+/// Emits machine code with the registers used as follows: `rdi` points at the VM's registers,
+/// `rsi` at its equal flag, `r8` at the overflow flag and `r9` at the carry flag. `eax` and `r10d`
+/// hold operands.
+struct Assembler {
+    code: Vec<u8>,
+    /// Where the `jo` of each instruction that can deoptimize writes its offset, with the index
+    /// of the instruction in the block.
+    deoptimizations: Vec<(usize, usize)>,
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        Assembler { code: vec![], deoptimizations: vec![] }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// mov eax, [rdi + register]
+    fn load_eax(&mut self, register: usize) {
+        self.emit(&[0x8B, 0x87]);
+        self.emit(&displacement(register));
+    }
+
+    /// mov r10d, [rdi + register]
+    fn load_r10(&mut self, register: usize) {
+        self.emit(&[0x44, 0x8B, 0x97]);
+        self.emit(&displacement(register));
+    }
+
+    /// mov r10d, immediate
+    fn immediate_r10(&mut self, immediate: i32) {
+        self.emit(&[0x41, 0xBA]);
+        self.emit(&immediate.to_le_bytes());
+    }
+
+    /// mov [rdi + register], eax
+    fn store_eax(&mut self, register: usize) {
+        self.emit(&[0x89, 0x87]);
+        self.emit(&displacement(register));
+    }
+
+    /// mov dword [rdi + register], immediate
+    fn store_immediate(&mut self, register: usize, immediate: i32) {
+        self.emit(&[0xC7, 0x87]);
+        self.emit(&displacement(register));
+        self.emit(&immediate.to_le_bytes());
+    }
+
+    /// `eax` op `r10d` into `destination`, setting the carry flag and clearing the overflow flag,
+    /// or deoptimizing on signed overflow.
+    fn arithmetic(&mut self, opcode: Opcode, destination: usize, index: usize) {
+        match opcode {
+            // add eax, r10d
+            Opcode::ADD => self.emit(&[0x44, 0x01, 0xD0]),
+            // sub eax, r10d
+            Opcode::SUB => self.emit(&[0x44, 0x29, 0xD0]),
+            // The carry flag is unsigned overflow, which `mul` gives but `imul` does not, so the
+            // product is computed both ways. `mul` also writes `edx`, which is free here.
+            _ => {
+                // mov r11d, eax; mul r10d; setc byte [r9]; mov eax, r11d; imul eax, r10d
+                self.emit(&[0x41, 0x89, 0xC3, 0x41, 0xF7, 0xE2, 0x41, 0x0F, 0x92, 0x01]);
+                self.emit(&[0x44, 0x89, 0xD8, 0x41, 0x0F, 0xAF, 0xC2]);
+            }
+        }
+        // jo deoptimize
+        self.emit(&[0x0F, 0x80]);
+        self.deoptimizations.push((self.code.len(), index));
+        self.emit(&[0; 4]);
+        if opcode != Opcode::MUL {
+            // setc byte [r9]
+            self.emit(&[0x41, 0x0F, 0x92, 0x01]);
+        }
+        // mov byte [r8], 0
+        self.emit(&[0x41, 0xC6, 0x00, 0x00]);
+        self.store_eax(destination);
+    }
+
+    /// Sets the equal flag from comparing `eax` with `r10d`.
+    fn compare(&mut self, opcode: Opcode) {
+        // cmp eax, r10d
+        self.emit(&[0x44, 0x39, 0xD0]);
+        let condition = match opcode {
+            Opcode::EQ | Opcode::EQI => 0x94,
+            Opcode::NEQ | Opcode::NEQI => 0x95,
+            Opcode::GT | Opcode::GTI => 0x9F,
+            Opcode::LT | Opcode::LTI => 0x9C,
+            Opcode::GTQ | Opcode::GTQI => 0x9D,
+            _ => 0x9E,
+        };
+        // setcc byte [rsi]
+        self.emit(&[0x0F, condition, 0x06]);
+    }
+
+    /// Returns from the block having run `executed` instructions, with the next pc being `pc`.
+    fn exit(&mut self, executed: usize, pc: usize) {
+        // mov rax, imm64; ret
+        self.emit(&[0x48, 0xB8]);
+        self.emit(&((executed as u64) << 32 | pc as u32 as u64).to_le_bytes());
+        self.emit(&[0xC3]);
+    }
+
+    /// Returns from the block having run `executed` instructions, with the next pc being the
+    /// value of `register`.
+    fn exit_to_register(&mut self, register: usize, executed: usize) {
+        // mov eax, [rdi + register], which clears the high half of rax
+        self.load_eax(register);
+        // mov r10, imm64; or rax, r10; ret
+        self.emit(&[0x49, 0xBA]);
+        self.emit(&((executed as u64) << 32).to_le_bytes());
+        self.emit(&[0x4C, 0x09, 0xD0, 0xC3]);
+    }
+
+    /// Emits an exit for every instruction that can deoptimize, leaving the pc at it so the
+    /// interpreter runs it next.
+    fn emit_deoptimizations(&mut self, start: usize) {
+        for (patch, index) in mem::take(&mut self.deoptimizations) {
+            let offset = (self.code.len() - patch - 4) as i32;
+            self.code[patch..patch + 4].copy_from_slice(&offset.to_le_bytes());
+            self.exit(index, start + index);
+        }
+    }
+}
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(address: *mut c_void, length: usize, protection: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mprotect(address: *mut c_void, length: usize, protection: i32) -> i32;
+    fn munmap(address: *mut c_void, length: usize) -> i32;
+}
+
+/// Memory holding machine code, mapped readable and executable but never writable at the same
+/// time.
+struct ExecutableMemory {
+    pointer: *mut c_void,
+    length: usize,
+}
+
+impl ExecutableMemory {
+    fn new(code: &[u8]) -> Option<ExecutableMemory> {
+        let length = code.len();
+        // SAFETY: a fresh anonymous mapping is only written within its length before it is made
+        // executable, and `Drop` unmaps it.
+        unsafe {
+            let pointer = mmap(ptr::null_mut(), length, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if pointer as isize == -1 {
+                return None;
+            }
+            ptr::copy_nonoverlapping(code.as_ptr(), pointer as *mut u8, length);
+            if mprotect(pointer, length, PROT_READ | PROT_EXEC) != 0 {
+                munmap(pointer, length);
+                return None;
+            }
+            return Some(ExecutableMemory { pointer, length });
+        }
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was made by `new` and nothing refers to it once its block is gone.
+        unsafe {
+            munmap(self.pointer, self.length);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing,
+        vm::{ArithmeticMode, DEFAULT_QUANTUM},
+    };
+
+    /// Small deterministic generator, so failures can be reproduced from the seed.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+
+        fn number(&mut self) -> i32 {
+            match self.below(4) {
+                0 => [0, 1, -1, i32::MAX, i32::MIN][self.below(5)],
+                1 => self.below(16) as i32 - 8,
+                _ => self.next() as i32,
+            }
+        }
+    }
+
+    /// A loop that runs a random body of integer instructions 150 times. The body only uses
+    /// registers 0 to 7, sometimes skips an instruction, and includes some instructions the JIT
+    /// does not compile.
+    fn random_program(random: &mut Random) -> Vec<Instruction> {
+        let arithmetic = [Opcode::ADD, Opcode::SUB, Opcode::MUL, Opcode::AND, Opcode::OR, Opcode::XOR, Opcode::SHL];
+        let immediates = [Opcode::ADDI, Opcode::SUBI, Opcode::MULI, Opcode::EQI, Opcode::GTI, Opcode::LTQI];
+        let comparisons = [Opcode::EQ, Opcode::NEQ, Opcode::GT, Opcode::LT, Opcode::GTQ, Opcode::LTQ];
+        let mut program = vec![Instruction::new(Opcode::LOAD, [20, 0, 0], 150)];
+        let start = program.len();
+        for _ in 0..random.below(12) + 1 {
+            let registers = [random.below(8), random.below(8), random.below(8)];
+            let instruction = match random.below(8) {
+                0 | 1 => Instruction::new(arithmetic[random.below(arithmetic.len())], registers, 0),
+                2 => Instruction::new(immediates[random.below(immediates.len())], registers, random.number() as i64),
+                3 => Instruction::new([Opcode::INC, Opcode::DEC][random.below(2)], registers, 0),
+                4 => Instruction::new(Opcode::LOAD, registers, random.number() as i64),
+                5 => Instruction::new(Opcode::DIV, registers, 0),
+                _ => {
+                    // Compares, then skips the next instruction if the comparison held.
+                    let skip_to = program.len() + 4;
+                    program.push(Instruction::new(comparisons[random.below(comparisons.len())], registers, 0));
+                    program.push(Instruction::new(Opcode::LOAD, [22, 0, 0], skip_to as i64));
+                    program.push(Instruction::new([Opcode::JEQ, Opcode::JNEQ][random.below(2)], [22, 0, 0], 0));
+                    Instruction::new(Opcode::INC, [random.below(8), 0, 0], 0)
+                }
+            };
+            program.push(instruction);
+        }
+        program.push(Instruction::new(Opcode::DEC, [20, 0, 0], 0));
+        program.push(Instruction::new(Opcode::LOAD, [21, 0, 0], start as i64));
+        program.push(Instruction::new(Opcode::JNZ, [20, 21, 0], 0));
+        program.push(Instruction::new(Opcode::HLT, [0; 3], 0));
+        return program;
+    }
+
+    /// Runs a copy of the VM with the interpreter and with the JIT, and checks they agree.
+    fn assert_same_as_interpreter(vm: VM) -> Jit {
+        let mut interpreted = testing::copy_of(&vm);
+        let expected = interpreted.run();
+
+        let mut compiled = vm;
+        let mut jit = Jit::new(compiled.program.clone());
+        jit.threshold = 2;
+        assert_eq!(jit.run(&mut compiled), expected);
+        assert_eq!(compiled.output, interpreted.output);
+        assert_eq!(compiled.registers, interpreted.registers);
+        assert_eq!((compiled.pc, compiled.equal, compiled.overflow, compiled.carry), (interpreted.pc, interpreted.equal, interpreted.overflow, interpreted.carry));
+        assert_eq!(compiled.remainder, interpreted.remainder);
+        return jit;
+    }
+
+    #[test]
+    fn test_random_programs_match_interpreter() {
+        let mut random = Random(0x5EED);
+        let mut totals = JitStats::default();
+        for _ in 0..300 {
+            let program = random_program(&mut random);
+            for mode in [ArithmeticMode::Wrap, ArithmeticMode::Saturate, ArithmeticMode::Trap] {
+                let mut vm = VM::new();
+                vm.program = program.clone().into();
+                vm.arithmetic_mode = mode;
+                vm.quantum = [DEFAULT_QUANTUM, 7][random.below(2)];
+                for register in 0..8 {
+                    vm.registers[register] = random.number();
+                }
+                let stats = assert_same_as_interpreter(vm).stats;
+                totals.compiled_blocks += stats.compiled_blocks;
+                totals.native_instructions += stats.native_instructions;
+                totals.deoptimizations += stats.deoptimizations;
+            }
+        }
+        assert!(totals.native_instructions > 0 && totals.deoptimizations > 0);
+    }
+
+    #[test]
+    fn test_compiled_programs_match_interpreter() {
+        for program in testing::compiled_workloads() {
+            let mut vm = VM::new();
+            vm.program = program.into();
+            vm.output = Some(vec![]);
+            let jit = assert_same_as_interpreter(vm);
+            assert!(jit.stats.compiled_blocks > 0);
+        }
+    }
+
+    #[test]
+    fn test_deoptimizes_on_overflow() {
+        // Doubles $0 until it overflows, which traps in the interpreter at pc 1.
+        let program = vec![
+            Instruction::new(Opcode::LOAD, [0, 0, 0], 1),
+            Instruction::new(Opcode::MUL, [0, 1, 0], 0),
+            Instruction::new(Opcode::LOAD, [2, 0, 0], 1),
+            Instruction::new(Opcode::JMP, [2, 0, 0], 0),
+        ];
+        let mut vm = VM::new();
+        vm.program = program.into();
+        vm.registers[1] = 2;
+        vm.arithmetic_mode = ArithmeticMode::Trap;
+        let jit = assert_same_as_interpreter(vm);
+        assert_eq!(jit.stats.deoptimizations, 1);
+
+        // Wrapping carries on with the interpreter's result, and the block is reentered at the
+        // top of the next iteration.
+        let mut vm = VM::new();
+        vm.program = vec![
+            Instruction::new(Opcode::LOAD, [0, 0, 0], 1),
+            Instruction::new(Opcode::LOAD, [2, 0, 0], 100),
+            Instruction::new(Opcode::LOAD, [3, 0, 0], 2),
+            Instruction::new(Opcode::MULI, [0, 0, 0], 3),
+            Instruction::new(Opcode::DEC, [2, 0, 0], 0),
+            Instruction::new(Opcode::JNZ, [2, 3, 0], 0),
+        ]
+        .into();
+        let jit = assert_same_as_interpreter(vm);
+        assert!(jit.stats.deoptimizations > 0);
+        assert!(jit.stats.native_instructions > 200);
+    }
+}
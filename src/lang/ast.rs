@@ -0,0 +1,105 @@
+use std::fmt;
+
+use super::{types::Type, Span};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UnaryOperator {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+}
+
+impl fmt::Display for UnaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnaryOperator::Negate => write!(f, "-"),
+            UnaryOperator::Not => write!(f, "!"),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Modulo => "%",
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::Less => "<",
+            BinaryOperator::LessEqual => "<=",
+            BinaryOperator::Greater => ">",
+            BinaryOperator::GreaterEqual => ">=",
+            BinaryOperator::And => "&&",
+            BinaryOperator::Or => "||",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExpressionKind {
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Boolean(bool),
+    Array(Vec<Expression>),
+    Variable(String),
+    Unary { operator: UnaryOperator, operand: Box<Expression> },
+    Binary { operator: BinaryOperator, left: Box<Expression>, right: Box<Expression> },
+    Call { name: String, arguments: Vec<Expression> },
+    Index { target: Box<Expression>, index: Box<Expression> },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StatementKind {
+    Let { name: String, annotation: Option<Type>, value: Expression },
+    Assign { name: String, value: Expression },
+    AssignIndex { name: String, index: Expression, value: Expression },
+    If { condition: Expression, then_branch: Vec<Statement>, else_branch: Vec<Statement> },
+    While { condition: Expression, body: Vec<Statement> },
+    Print(Expression),
+    /// Only allowed at the top level of a program.
+    Function { name: String, parameters: Vec<Parameter>, result: Type, body: Vec<Statement> },
+    Return(Expression),
+    /// An expression evaluated for its side effects, such as a call.
+    Expression(Expression),
+}
+
+/// A function parameter. Parameters and results without a type annotation are `int`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Parameter {
+    pub name: String,
+    pub ty: Type,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+
+use super::{
+    ast::{BinaryOperator, Expression, ExpressionKind, Parameter, Statement, StatementKind, UnaryOperator},
+    CompileError, Span,
+};
+use crate::{
+    instruction::{Instruction, Opcode},
+    vm::REGISTER_COUNT,
+};
+
+pub use crate::vm::RETURN_REGISTER;
+
+/// Holds jump destinations. Jumps go through a register, and keeping one aside for them means
+/// a jump never has to evict a value.
+pub const JUMP_REGISTER: usize = REGISTER_COUNT - 1;
+
+/// Value register `print` converts its operand into before printing it.
+pub const PRINT_REGISTER: usize = 0;
+
+/// Generates a program from parsed statements: the top-level code ending in `HLT`, followed by
+/// each function.
+///
+/// Top-level variables live in their own register for as long as they are in scope. Function
+/// parameters and locals live in the function's stack frame instead and are loaded into a
+/// register when used. Intermediate results take the lowest free register and give it back as
+/// soon as they are used.
+///
+/// Calls use a caller-saves convention. The caller pushes every register holding a value, then
+/// the arguments in order, and calls. The callee's `ENTER` starts a frame above the arguments,
+/// and it returns with its result in `RETURN_REGISTER`. The caller then pops the arguments and
+/// its saved registers.
+pub fn generate(statements: &[Statement]) -> Result<Vec<Instruction>, CompileError> {
+    let mut generator = Generator {
+        program: vec![],
+        scopes: vec![HashMap::new()],
+        free_registers: [true; RETURN_REGISTER],
+        unwritten: [false; RETURN_REGISTER],
+        functions: HashMap::new(),
+        call_sites: vec![],
+        frame: None,
+    };
+    for statement in statements {
+        if let StatementKind::Function { name, parameters, .. } = &statement.kind {
+            if generator.functions.insert(name.clone(), (parameters.len(), 0)).is_some() {
+                return Err(CompileError::DuplicateFunction { span: statement.span, name: name.clone() });
+            }
+        }
+    }
+
+    for statement in statements {
+        if !matches!(statement.kind, StatementKind::Function { .. }) {
+            generator.statement(statement)?;
+        }
+    }
+    generator.emit(Opcode::HLT, [0; 3], 0);
+
+    for statement in statements {
+        if let StatementKind::Function { name, parameters, body, .. } = &statement.kind {
+            generator.function(name, parameters, body)?;
+        }
+    }
+    for (load, name) in std::mem::take(&mut generator.call_sites) {
+        generator.program[load].integer_operand = generator.functions[&name].1 as i64;
+    }
+    return Ok(generator.program);
+}
+
+struct Generator {
+    program: Vec<Instruction>,
+    /// Variables visible in each enclosing block, innermost last.
+    scopes: Vec<HashMap<String, Location>>,
+    free_registers: [bool; RETURN_REGISTER],
+    /// Registers allocated for a value that has not been written yet, which calls do not save.
+    /// An expression only writes its destination once it has evaluated everything else.
+    unwritten: [bool; RETURN_REGISTER],
+    /// Parameter count and address of every function.
+    functions: HashMap<String, (usize, usize)>,
+    /// Loads of a function's address to fill in once every function has been placed.
+    call_sites: Vec<(usize, String)>,
+    /// The function being generated, if any.
+    frame: Option<Frame>,
+}
+
+#[derive(Clone, Copy)]
+enum Location {
+    Register(usize),
+    /// Offset from the frame pointer.
+    Frame(i64),
+}
+
+struct Frame {
+    /// The function's `ENTER`, whose operand becomes the number of locals.
+    enter: usize,
+    locals: i64,
+}
+
+/// A register holding the value of an expression, which must be released after use if it was
+/// allocated for the purpose rather than belonging to a variable.
+#[derive(Clone, Copy)]
+struct Operand {
+    register: usize,
+    temporary: bool,
+}
+
+impl Generator {
+    fn emit(&mut self, opcode: Opcode, registers: [usize; 3], integer_operand: i64) {
+        self.program.push(Instruction::new(opcode, registers, integer_operand));
+    }
+
+    /// Emits a jump whose destination is filled in later by `patch`, returning where to patch.
+    fn jump_forward(&mut self, opcode: Opcode, registers: [usize; 3]) -> usize {
+        let load = self.program.len();
+        self.emit(Opcode::LOAD, [JUMP_REGISTER, 0, 0], 0);
+        self.emit(opcode, registers, 0);
+        load
+    }
+
+    fn jump_to(&mut self, opcode: Opcode, registers: [usize; 3], target: usize) {
+        self.emit(Opcode::LOAD, [JUMP_REGISTER, 0, 0], target as i64);
+        self.emit(opcode, registers, 0);
+    }
+
+    /// Points a jump from `jump_forward` at the next instruction to be emitted.
+    fn patch(&mut self, load: usize) {
+        self.program[load].integer_operand = self.program.len() as i64;
+    }
+
+    fn allocate(&mut self, span: Span) -> Result<usize, CompileError> {
+        match self.free_registers.iter().position(|free| *free) {
+            Some(register) => {
+                self.free_registers[register] = false;
+                self.unwritten[register] = true;
+                Ok(register)
+            }
+            None => Err(CompileError::OutOfRegisters { span }),
+        }
+    }
+
+    fn release(&mut self, operand: Operand) {
+        if operand.temporary {
+            self.free_registers[operand.register] = true;
+        }
+    }
+
+    fn lookup(&self, name: &str, span: Span) -> Result<Location, CompileError> {
+        match self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            Some(location) => Ok(*location),
+            None => Err(CompileError::UndefinedVariable { span, name: name.to_string() }),
+        }
+    }
+
+    /// Binds a name in the innermost scope, freeing the register of any variable it shadows there.
+    fn bind(&mut self, name: &str, location: Location) {
+        if let Some(Location::Register(shadowed)) = self.scopes.last_mut().unwrap().insert(name.to_string(), location) {
+            self.free_registers[shadowed] = true;
+        }
+    }
+
+    fn block(&mut self, statements: &[Statement]) -> Result<(), CompileError> {
+        self.scopes.push(HashMap::new());
+        self.statements(statements)?;
+        for location in self.scopes.pop().unwrap().into_values() {
+            if let Location::Register(register) = location {
+                self.free_registers[register] = true;
+            }
+        }
+        return Ok(());
+    }
+
+    fn function(&mut self, name: &str, parameters: &[Parameter], body: &[Statement]) -> Result<(), CompileError> {
+        self.functions.get_mut(name).unwrap().1 = self.program.len();
+        let enter = self.program.len();
+        self.emit(Opcode::ENTER, [0; 3], 0);
+        self.frame = Some(Frame { enter, locals: 0 });
+        self.free_registers = [true; RETURN_REGISTER];
+        self.unwritten = [false; RETURN_REGISTER];
+
+        // `ENTER` pushed the caller's frame pointer just above the last argument.
+        let count = parameters.len() as i64;
+        let scope = parameters
+            .iter()
+            .enumerate()
+            .map(|(index, parameter)| (parameter.name.clone(), Location::Frame(index as i64 - count - 1)))
+            .collect();
+        self.scopes = vec![scope];
+        self.statements(body)?;
+        self.emit(Opcode::LOAD, [RETURN_REGISTER, 0, 0], 0);
+        self.emit(Opcode::LEAVE, [0; 3], 0);
+        self.emit(Opcode::RET, [0; 3], 0);
+
+        let frame = self.frame.take().unwrap();
+        self.program[frame.enter].integer_operand = frame.locals;
+        return Ok(());
+    }
+
+    /// Stores a register into a variable.
+    fn store(&mut self, location: Location, register: usize) {
+        match location {
+            Location::Register(variable) if variable != register => self.emit(Opcode::ADDI, [register, 0, variable], 0),
+            Location::Register(_) => {}
+            Location::Frame(offset) => self.emit(Opcode::STF, [register, 0, 0], offset),
+        }
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<(), CompileError> {
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        return Ok(());
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        match &statement.kind {
+            // The value is evaluated before the name is bound, so `let x = x + 1;` reads any outer `x`.
+            StatementKind::Let { name, value, .. } => match &mut self.frame {
+                Some(frame) => {
+                    let location = Location::Frame(frame.locals);
+                    frame.locals += 1;
+                    let value = self.operand(value)?;
+                    self.store(location, value.register);
+                    self.release(value);
+                    self.bind(name, location);
+                }
+                None => {
+                    let register = self.allocate(statement.span)?;
+                    self.expression_into(value, register)?;
+                    self.unwritten[register] = false;
+                    self.bind(name, Location::Register(register));
+                }
+            },
+            StatementKind::Assign { name, value } => match self.lookup(name, statement.span)? {
+                Location::Register(register) => self.expression_into(value, register)?,
+                location => {
+                    let value = self.operand(value)?;
+                    self.store(location, value.register);
+                    self.release(value);
+                }
+            },
+            StatementKind::AssignIndex { .. } => return Err(unsupported(statement.span, "array assignment")),
+            StatementKind::If { condition, then_branch, else_branch } => {
+                let condition = self.operand(condition)?;
+                let to_else = self.jump_forward(Opcode::JZ, [condition.register, JUMP_REGISTER, 0]);
+                self.release(condition);
+                self.block(then_branch)?;
+                if else_branch.is_empty() {
+                    self.patch(to_else);
+                } else {
+                    let to_end = self.jump_forward(Opcode::JMP, [JUMP_REGISTER, 0, 0]);
+                    self.patch(to_else);
+                    self.block(else_branch)?;
+                    self.patch(to_end);
+                }
+            }
+            StatementKind::While { condition, body } => {
+                let start = self.program.len();
+                let condition = self.operand(condition)?;
+                let to_end = self.jump_forward(Opcode::JZ, [condition.register, JUMP_REGISTER, 0]);
+                self.release(condition);
+                self.block(body)?;
+                self.jump_to(Opcode::JMP, [JUMP_REGISTER, 0, 0], start);
+                self.patch(to_end);
+            }
+            StatementKind::Print(value) => {
+                let value = self.operand(value)?;
+                self.emit(Opcode::ITOS, [value.register, PRINT_REGISTER, 0], 0);
+                self.emit(Opcode::PRTS, [PRINT_REGISTER, 0, 0], 0);
+                self.release(value);
+            }
+            StatementKind::Function { .. } => {
+                return Err(CompileError::UnexpectedToken {
+                    span: statement.span,
+                    expected: "statement".to_string(),
+                    found: "`fn`".to_string(),
+                });
+            }
+            StatementKind::Return(value) => {
+                if self.frame.is_none() {
+                    return Err(CompileError::ReturnOutsideFunction { span: statement.span });
+                }
+                self.expression_into(value, RETURN_REGISTER)?;
+                self.emit(Opcode::LEAVE, [0; 3], 0);
+                self.emit(Opcode::RET, [0; 3], 0);
+            }
+            StatementKind::Expression(value) => self.expression_into(value, RETURN_REGISTER)?,
+        }
+        return Ok(());
+    }
+
+    /// The register holding an expression's value. Variables are used in place rather than
+    /// copied.
+    fn operand(&mut self, expression: &Expression) -> Result<Operand, CompileError> {
+        if let ExpressionKind::Variable(name) = &expression.kind {
+            if let Location::Register(register) = self.lookup(name, expression.span)? {
+                return Ok(Operand { register, temporary: false });
+            }
+        }
+        let register = self.allocate(expression.span)?;
+        self.expression_into(expression, register)?;
+        self.unwritten[register] = false;
+        return Ok(Operand { register, temporary: true });
+    }
+
+    /// Evaluates an expression into `destination`, which may be a register the expression itself
+    /// reads, so it is only written once every operand has been read.
+    fn expression_into(&mut self, expression: &Expression, destination: usize) -> Result<(), CompileError> {
+        match &expression.kind {
+            ExpressionKind::Integer(number) => {
+                if i32::try_from(*number).is_err() {
+                    return Err(CompileError::IntegerTooLarge { span: expression.span });
+                }
+                self.emit(Opcode::LOAD, [destination, 0, 0], *number);
+            }
+            ExpressionKind::Boolean(boolean) => self.emit(Opcode::LOAD, [destination, 0, 0], *boolean as i64),
+            ExpressionKind::Float(_) => return Err(unsupported(expression.span, "a float")),
+            ExpressionKind::String(_) => return Err(unsupported(expression.span, "a string")),
+            ExpressionKind::Array(_) => return Err(unsupported(expression.span, "an array")),
+            ExpressionKind::Index { .. } => return Err(unsupported(expression.span, "array indexing")),
+            ExpressionKind::Variable(name) => match self.lookup(name, expression.span)? {
+                Location::Register(register) if register != destination => {
+                    self.emit(Opcode::ADDI, [register, 0, destination], 0);
+                }
+                Location::Register(_) => {}
+                Location::Frame(offset) => self.emit(Opcode::LDF, [destination, 0, 0], offset),
+            },
+            ExpressionKind::Call { name, arguments } => self.call(name, arguments, expression.span, destination)?,
+            ExpressionKind::Unary { operator: UnaryOperator::Negate, operand } => {
+                let operand = self.operand(operand)?;
+                self.emit(Opcode::MULI, [operand.register, 0, destination], -1);
+                self.release(operand);
+            }
+            ExpressionKind::Unary { operator: UnaryOperator::Not, operand } => {
+                let operand = self.operand(operand)?;
+                self.emit(Opcode::EQI, [operand.register, 0, 0], 0);
+                self.release(operand);
+                self.materialize_equal_flag(destination);
+            }
+            ExpressionKind::Binary { operator: operator @ (BinaryOperator::And | BinaryOperator::Or), left, right } => {
+                // Short-circuits: the right operand is only evaluated if the left does not
+                // already decide the result.
+                let left = self.operand(left)?;
+                let short_circuit = match operator {
+                    BinaryOperator::And => Opcode::JZ,
+                    _ => Opcode::JNZ,
+                };
+                let to_short_circuit = self.jump_forward(short_circuit, [left.register, JUMP_REGISTER, 0]);
+                self.release(left);
+                let right = self.operand(right)?;
+                self.emit(Opcode::NEQI, [right.register, 0, 0], 0);
+                self.release(right);
+                self.materialize_equal_flag(destination);
+                let to_end = self.jump_forward(Opcode::JMP, [JUMP_REGISTER, 0, 0]);
+                self.patch(to_short_circuit);
+                self.emit(Opcode::LOAD, [destination, 0, 0], (*operator == BinaryOperator::Or) as i64);
+                self.patch(to_end);
+            }
+            ExpressionKind::Binary { operator, left, right } => {
+                let (opcode, sets_flag) = match operator {
+                    BinaryOperator::Add => (Opcode::ADD, false),
+                    BinaryOperator::Subtract => (Opcode::SUB, false),
+                    BinaryOperator::Multiply => (Opcode::MUL, false),
+                    BinaryOperator::Divide => (Opcode::DIV, false),
+                    // `%` reads back the remainder `DIV` leaves, which truncates like `/` does,
+                    // rather than using `MOD`, whose result is never negative.
+                    BinaryOperator::Modulo => (Opcode::DIV, false),
+                    BinaryOperator::Equal => (Opcode::EQ, true),
+                    BinaryOperator::NotEqual => (Opcode::NEQ, true),
+                    BinaryOperator::Less => (Opcode::LT, true),
+                    BinaryOperator::LessEqual => (Opcode::LTQ, true),
+                    BinaryOperator::Greater => (Opcode::GT, true),
+                    BinaryOperator::GreaterEqual => (Opcode::GTQ, true),
+                    BinaryOperator::And | BinaryOperator::Or => unreachable!("handled above"),
+                };
+                let result = if sets_flag { 0 } else { destination };
+                let left = self.operand(left)?;
+                match (&right.kind, opcode.immediate_form()) {
+                    (ExpressionKind::Integer(number), Some(immediate_form)) if i32::try_from(*number).is_ok() => {
+                        self.emit(immediate_form, [left.register, 0, result], *number);
+                    }
+                    _ => {
+                        let right = self.operand(right)?;
+                        self.emit(opcode, [left.register, right.register, result], 0);
+                        self.release(right);
+                    }
+                }
+                self.release(left);
+                if *operator == BinaryOperator::Modulo {
+                    self.emit(Opcode::GETREM, [destination, 0, 0], 0);
+                }
+                if sets_flag {
+                    self.materialize_equal_flag(destination);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn call(&mut self, name: &str, arguments: &[Expression], span: Span, destination: usize) -> Result<(), CompileError> {
+        let Some((parameter_count, _)) = self.functions.get(name) else {
+            return Err(CompileError::UndefinedFunction { span, name: name.to_string() });
+        };
+        if *parameter_count != arguments.len() {
+            let expected = *parameter_count;
+            return Err(CompileError::WrongArgumentCount { span, name: name.to_string(), expected, found: arguments.len() });
+        }
+
+        let saved: Vec<usize> =
+            (0..RETURN_REGISTER).filter(|register| !self.free_registers[*register] && !self.unwritten[*register]).collect();
+        for register in &saved {
+            self.emit(Opcode::PUSH, [*register, 0, 0], 0);
+        }
+        for argument in arguments {
+            let argument = self.operand(argument)?;
+            self.emit(Opcode::PUSH, [argument.register, 0, 0], 0);
+            self.release(argument);
+        }
+        let load = self.jump_forward(Opcode::CALL, [JUMP_REGISTER, 0, 0]);
+        self.call_sites.push((load, name.to_string()));
+        for _ in arguments {
+            self.emit(Opcode::POP, [JUMP_REGISTER, 0, 0], 0);
+        }
+        for register in saved.iter().rev() {
+            self.emit(Opcode::POP, [*register, 0, 0], 0);
+        }
+        if destination != RETURN_REGISTER {
+            self.emit(Opcode::ADDI, [RETURN_REGISTER, 0, destination], 0);
+        }
+        return Ok(());
+    }
+
+    /// Writes 1 to `destination` if the equal flag is set and 0 otherwise.
+    fn materialize_equal_flag(&mut self, destination: usize) {
+        self.emit(Opcode::LOAD, [destination, 0, 0], 1);
+        let to_end = self.jump_forward(Opcode::JEQ, [JUMP_REGISTER, 0, 0]);
+        self.emit(Opcode::LOAD, [destination, 0, 0], 0);
+        self.patch(to_end);
+    }
+}
+
+pub(super) fn unsupported(span: Span, feature: &str) -> CompileError {
+    CompileError::Unsupported { span, feature: feature.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lang::{
+            compile, compile_unoptimized,
+            lexer::tokenize,
+            parser::parse,
+            types::{TypeError, TypeErrorKind},
+        },
+        verifier::verify,
+        vm::VM,
+    };
+
+    fn generate_source(source: &str) -> Result<Vec<Instruction>, CompileError> {
+        generate(&parse(&tokenize(source).unwrap()).unwrap())
+    }
+
+    fn run(source: &str) -> VM {
+        let mut vm = VM::new();
+        vm.program = compile_unoptimized(source).unwrap().into();
+        assert!(verify(&vm.program, &[]).is_ok());
+        vm.run().unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_loops_and_conditionals() {
+        let vm = run("let n = 10;\nlet total = 0;\nwhile n > 0 {\n    total = total + n;\n    n = n - 1;\n}\nlet big = 0;\nif total >= 55 && !(n != 0) { big = 1; } else { big = 2; }\nprint total;");
+        assert_eq!(vm.registers[..3], [0, 55, 1]);
+    }
+
+    #[test]
+    fn test_expressions() {
+        let vm = run("let a = -7;\nlet b = a / 2 * 3 - a % 4 + 100;\nlet c = false || a < b;\nlet d = true && false;\nlet e = -a;");
+        assert_eq!(vm.registers[..5], [-7, 94, 1, 0, 7]);
+    }
+
+    #[test]
+    fn test_remainder_agrees_with_division() {
+        let vm = run("let a = -7;\nlet b = a / 2 * 2 + a % 2;\nlet c = a % -2;\nlet d = 7 % -2;");
+        assert_eq!(vm.registers[..4], [-7, -7, -1, 1]);
+    }
+
+    #[test]
+    fn test_recursive_functions() {
+        let source = "fn fib(n) {\n    if n < 2 { return n; }\n    return fib(n - 1) + fib(n - 2);\n}\n\nfn fact(n) {\n    let result = 1;\n    while n > 1 { result = result * n; n = n - 1; }\n    return result;\n}\n\nlet a = 3;\nlet b = a + fib(15) * 2;\nlet c = fact(fact(a));\nlet d = is_even(a) + is_even(10) * 10;\nnothing();\n\nfn is_even(n) { if n == 0 { return 1; } return is_odd(n - 1); }\nfn is_odd(n) { if n == 0 { return 0; } return is_even(n - 1); }\nfn nothing() { }";
+        let vm = run(source);
+        assert_eq!(vm.registers[..4], [3, 1223, 720, 10]);
+        assert!(vm.stack.is_empty());
+        // A call's result in `RETURN_REGISTER` does not count as read before it is written.
+        assert_eq!(verify(&compile_unoptimized(source).unwrap(), &[]).warnings, []);
+        assert_eq!(verify(&compile("fn add(a, b) { return a + b; }\nprint add(2, 3);").unwrap(), &[]).warnings, []);
+
+        // These are normally caught by the type checker first.
+        assert_eq!(
+            generate_source("fn f(x) { return x; }\nlet y = f(1, 2);"),
+            Err(CompileError::WrongArgumentCount { span: Span { line: 2, column: 9 }, name: "f".to_string(), expected: 1, found: 2 })
+        );
+        assert!(matches!(generate_source("let y = g();"), Err(CompileError::UndefinedFunction { .. })));
+        assert!(matches!(generate_source("return 1;"), Err(CompileError::ReturnOutsideFunction { .. })));
+        assert!(matches!(generate_source("let a = 1;\nfn f() { return a; }"), Err(CompileError::UndefinedVariable { .. })));
+        assert_eq!(
+            compile("let x = 1.5;"),
+            Err(CompileError::Unsupported { span: Span { line: 1, column: 9 }, feature: "a float".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_scopes_release_registers() {
+        let source = "let x = 1;\nif x > 0 { let y = x + 1; x = y; }\nlet z = x * 10;";
+        let vm = run(source);
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.registers[1], 20);
+
+        assert_eq!(
+            compile("if true { let y = 1; }\nprint y;"),
+            Err(CompileError::Type(vec![TypeError {
+                span: Span { line: 2, column: 7 },
+                kind: TypeErrorKind::UndefinedVariable("y".to_string())
+            }]))
+        );
+        let too_many: String = (0..32).map(|index| format!("let v{} = {};\n", index, index)).collect();
+        assert!(matches!(compile_unoptimized(&too_many), Err(CompileError::OutOfRegisters { .. })));
+    }
+}
@@ -0,0 +1,282 @@
+use std::fmt;
+
+use super::{CompileError, Span};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TokenKind {
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Identifier(String),
+    Let,
+    If,
+    Else,
+    While,
+    Print,
+    Fn,
+    Return,
+    True,
+    False,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Bang,
+    Equal,
+    EqualEqual,
+    BangEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    AndAnd,
+    OrOr,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Colon,
+    Arrow,
+    Semicolon,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            TokenKind::Integer(number) => return write!(f, "integer {}", number),
+            TokenKind::Float(number) => return write!(f, "float {:?}", number),
+            TokenKind::String(string) => return write!(f, "string {:?}", string),
+            TokenKind::Identifier(name) => return write!(f, "identifier `{}`", name),
+            TokenKind::Let => "let",
+            TokenKind::If => "if",
+            TokenKind::Else => "else",
+            TokenKind::While => "while",
+            TokenKind::Print => "print",
+            TokenKind::Fn => "fn",
+            TokenKind::Return => "return",
+            TokenKind::True => "true",
+            TokenKind::False => "false",
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            TokenKind::Percent => "%",
+            TokenKind::Bang => "!",
+            TokenKind::Equal => "=",
+            TokenKind::EqualEqual => "==",
+            TokenKind::BangEqual => "!=",
+            TokenKind::Less => "<",
+            TokenKind::LessEqual => "<=",
+            TokenKind::Greater => ">",
+            TokenKind::GreaterEqual => ">=",
+            TokenKind::AndAnd => "&&",
+            TokenKind::OrOr => "||",
+            TokenKind::LeftParen => "(",
+            TokenKind::RightParen => ")",
+            TokenKind::LeftBrace => "{",
+            TokenKind::RightBrace => "}",
+            TokenKind::LeftBracket => "[",
+            TokenKind::RightBracket => "]",
+            TokenKind::Comma => ",",
+            TokenKind::Colon => ":",
+            TokenKind::Arrow => "->",
+            TokenKind::Semicolon => ";",
+        };
+        write!(f, "`{}`", text)
+    }
+}
+
+/// Splits source code into tokens, skipping whitespace and `//` comments.
+pub fn tokenize(source: &str) -> Result<Vec<Token>, CompileError> {
+    let mut tokens = vec![];
+    for (line_index, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut index = 0;
+        while index < chars.len() {
+            let span = Span { line: line_index + 1, column: index + 1 };
+            let character = chars[index];
+            let next = chars.get(index + 1).copied();
+            index += 1;
+
+            let kind = match (character, next) {
+                (c, _) if c.is_whitespace() => continue,
+                ('/', Some('/')) => break,
+                (c, _) if c.is_ascii_digit() => {
+                    let start = index - 1;
+                    while index < chars.len() && chars[index].is_ascii_digit() {
+                        index += 1;
+                    }
+                    let is_float = chars.get(index) == Some(&'.') && chars.get(index + 1).is_some_and(|c| c.is_ascii_digit());
+                    if is_float {
+                        index += 1;
+                        while index < chars.len() && chars[index].is_ascii_digit() {
+                            index += 1;
+                        }
+                    }
+                    let digits: String = chars[start..index].iter().collect();
+                    if is_float {
+                        TokenKind::Float(digits.parse().unwrap())
+                    } else {
+                        match digits.parse() {
+                            Ok(number) => TokenKind::Integer(number),
+                            Err(_) => return Err(CompileError::IntegerTooLarge { span }),
+                        }
+                    }
+                }
+                ('"', _) => {
+                    let mut string = String::new();
+                    loop {
+                        let Some(character) = chars.get(index).copied() else {
+                            return Err(CompileError::UnterminatedString { span });
+                        };
+                        index += 1;
+                        match character {
+                            '"' => break,
+                            '\\' => {
+                                let escaped = match chars.get(index) {
+                                    Some('n') => '\n',
+                                    Some('t') => '\t',
+                                    Some('\\') => '\\',
+                                    Some('"') => '"',
+                                    Some(other) => {
+                                        let span = Span { line: span.line, column: index + 1 };
+                                        return Err(CompileError::UnexpectedCharacter { span, character: *other });
+                                    }
+                                    None => return Err(CompileError::UnterminatedString { span }),
+                                };
+                                index += 1;
+                                string.push(escaped);
+                            }
+                            character => string.push(character),
+                        }
+                    }
+                    TokenKind::String(string)
+                }
+                (c, _) if c.is_alphabetic() || c == '_' => {
+                    let start = index - 1;
+                    while index < chars.len() && (chars[index].is_alphanumeric() || chars[index] == '_') {
+                        index += 1;
+                    }
+                    let word: String = chars[start..index].iter().collect();
+                    keyword(&word).unwrap_or(TokenKind::Identifier(word))
+                }
+                ('=', Some('=')) | ('!', Some('=')) | ('<', Some('=')) | ('>', Some('=')) | ('&', Some('&')) | ('|', Some('|')) | ('-', Some('>')) => {
+                    index += 1;
+                    match character {
+                        '-' => TokenKind::Arrow,
+                        '=' => TokenKind::EqualEqual,
+                        '!' => TokenKind::BangEqual,
+                        '<' => TokenKind::LessEqual,
+                        '>' => TokenKind::GreaterEqual,
+                        '&' => TokenKind::AndAnd,
+                        _ => TokenKind::OrOr,
+                    }
+                }
+                ('+', _) => TokenKind::Plus,
+                ('-', _) => TokenKind::Minus,
+                ('*', _) => TokenKind::Star,
+                ('/', _) => TokenKind::Slash,
+                ('%', _) => TokenKind::Percent,
+                ('!', _) => TokenKind::Bang,
+                ('=', _) => TokenKind::Equal,
+                ('<', _) => TokenKind::Less,
+                ('>', _) => TokenKind::Greater,
+                ('(', _) => TokenKind::LeftParen,
+                (')', _) => TokenKind::RightParen,
+                ('{', _) => TokenKind::LeftBrace,
+                ('}', _) => TokenKind::RightBrace,
+                ('[', _) => TokenKind::LeftBracket,
+                (']', _) => TokenKind::RightBracket,
+                (',', _) => TokenKind::Comma,
+                (':', _) => TokenKind::Colon,
+                (';', _) => TokenKind::Semicolon,
+                (character, _) => return Err(CompileError::UnexpectedCharacter { span, character }),
+            };
+            tokens.push(Token { kind, span });
+        }
+    }
+    return Ok(tokens);
+}
+
+fn keyword(word: &str) -> Option<TokenKind> {
+    match word {
+        "let" => Some(TokenKind::Let),
+        "if" => Some(TokenKind::If),
+        "else" => Some(TokenKind::Else),
+        "while" => Some(TokenKind::While),
+        "print" => Some(TokenKind::Print),
+        "fn" => Some(TokenKind::Fn),
+        "return" => Some(TokenKind::Return),
+        "true" => Some(TokenKind::True),
+        "false" => Some(TokenKind::False),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize("let x_1 = 42; // comment\nif x_1 >= 7 && !x { }").unwrap();
+        let kinds: Vec<TokenKind> = tokens.iter().map(|token| token.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Let,
+                TokenKind::Identifier("x_1".to_string()),
+                TokenKind::Equal,
+                TokenKind::Integer(42),
+                TokenKind::Semicolon,
+                TokenKind::If,
+                TokenKind::Identifier("x_1".to_string()),
+                TokenKind::GreaterEqual,
+                TokenKind::Integer(7),
+                TokenKind::AndAnd,
+                TokenKind::Bang,
+                TokenKind::Identifier("x".to_string()),
+                TokenKind::LeftBrace,
+                TokenKind::RightBrace,
+            ]
+        );
+        assert_eq!(tokens[6].span, Span { line: 2, column: 4 });
+        let kinds: Vec<TokenKind> = tokenize("f(2.5, \"a \\\"b\\\"\\n\") -> [x]: 7")
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Identifier("f".to_string()),
+                TokenKind::LeftParen,
+                TokenKind::Float(2.5),
+                TokenKind::Comma,
+                TokenKind::String("a \"b\"\n".to_string()),
+                TokenKind::RightParen,
+                TokenKind::Arrow,
+                TokenKind::LeftBracket,
+                TokenKind::Identifier("x".to_string()),
+                TokenKind::RightBracket,
+                TokenKind::Colon,
+                TokenKind::Integer(7),
+            ]
+        );
+        assert_eq!(tokenize("print \"oops;"), Err(CompileError::UnterminatedString { span: Span { line: 1, column: 7 } }));
+        assert_eq!(
+            tokenize("let y = 1 $ 2;"),
+            Err(CompileError::UnexpectedCharacter { span: Span { line: 1, column: 11 }, character: '$' })
+        );
+    }
+}
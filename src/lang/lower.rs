@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+
+use super::{
+    ast::{BinaryOperator, Expression, ExpressionKind, Parameter, Statement, StatementKind, UnaryOperator},
+    codegen::unsupported,
+    types, CompileError, Span,
+};
+use crate::ir::{self, BinaryOp, BlockId, OperationKind, Terminator, Value};
+
+/// Lowers parsed statements to SSA form. The top-level code becomes the program's first
+/// function, `main`, followed by each declared function in order.
+///
+/// Variables are not stored anywhere: each name maps to the value it currently holds. Where
+/// control flow merges, names holding different values on each side get a phi. Loops give every
+/// name their body assigns a phi in the loop header, which is trivial if the name turns out not
+/// to change.
+pub fn lower(statements: &[Statement]) -> Result<ir::Program, CompileError> {
+    let mut signatures = HashMap::new();
+    for statement in statements {
+        if let StatementKind::Function { name, parameters, result, .. } = &statement.kind {
+            let signature = Signature { index: signatures.len() + 1, parameter_count: parameters.len(), result: ir_type(result, statement.span)? };
+            if signatures.insert(name.clone(), signature).is_some() {
+                return Err(CompileError::DuplicateFunction { span: statement.span, name: name.clone() });
+            }
+        }
+    }
+
+    let mut lowerer = Lowerer::new("main", 0, &signatures, false);
+    let top_level: Vec<Statement> = statements.iter().filter(|statement| !matches!(statement.kind, StatementKind::Function { .. })).cloned().collect();
+    lowerer.statements(&top_level)?;
+    if lowerer.current.is_some() {
+        lowerer.terminate(Terminator::Halt);
+    }
+    let mut program = ir::Program { functions: vec![lowerer.function] };
+
+    for statement in statements {
+        if let StatementKind::Function { name, parameters, body, .. } = &statement.kind {
+            program.functions.push(lower_function(name, parameters, body, &signatures)?);
+        }
+    }
+    return Ok(program);
+}
+
+struct Signature {
+    /// Where the function is in the program.
+    index: usize,
+    parameter_count: usize,
+    result: ir::Type,
+}
+
+fn lower_function(name: &str, parameters: &[Parameter], body: &[Statement], signatures: &HashMap<String, Signature>) -> Result<ir::Function, CompileError> {
+    let mut lowerer = Lowerer::new(name, parameters.len(), signatures, true);
+    for (index, parameter) in parameters.iter().enumerate() {
+        let value = lowerer.append(OperationKind::Param(index), Some(ir_type(&parameter.ty, parameter.span)?));
+        lowerer.scopes[0].insert(parameter.name.clone(), value);
+    }
+    lowerer.statements(body)?;
+    if lowerer.current.is_some() {
+        let zero = lowerer.constant(0, ir::Type::Int);
+        lowerer.terminate(Terminator::Return(zero));
+    }
+    return Ok(lowerer.function);
+}
+
+fn ir_type(ty: &types::Type, span: Span) -> Result<ir::Type, CompileError> {
+    match ty {
+        types::Type::Int => Ok(ir::Type::Int),
+        types::Type::Bool => Ok(ir::Type::Bool),
+        types::Type::Float => Err(unsupported(span, "a float")),
+        types::Type::String => Err(unsupported(span, "a string")),
+        types::Type::Array(_) => Err(unsupported(span, "an array")),
+        types::Type::Function { .. } => Err(unsupported(span, "a function value")),
+    }
+}
+
+/// Every name assigned anywhere in these statements, including nested blocks.
+fn assigned_names(statements: &[Statement], names: &mut Vec<String>) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Assign { name, .. } if !names.contains(name) => names.push(name.clone()),
+            StatementKind::If { then_branch, else_branch, .. } => {
+                assigned_names(then_branch, names);
+                assigned_names(else_branch, names);
+            }
+            StatementKind::While { body, .. } => assigned_names(body, names),
+            _ => {}
+        }
+    }
+}
+
+type Scopes = Vec<HashMap<String, Value>>;
+
+struct Lowerer<'a> {
+    function: ir::Function,
+    /// The block being added to, or `None` after a `return`, where code is unreachable.
+    current: Option<BlockId>,
+    /// The value each visible variable holds, innermost scope last.
+    scopes: Scopes,
+    signatures: &'a HashMap<String, Signature>,
+    in_function: bool,
+}
+
+impl<'a> Lowerer<'a> {
+    fn new(name: &str, parameter_count: usize, signatures: &'a HashMap<String, Signature>, in_function: bool) -> Lowerer<'a> {
+        Lowerer {
+            function: ir::Function::new(name, parameter_count),
+            current: Some(BlockId(0)),
+            scopes: vec![HashMap::new()],
+            signatures,
+            in_function,
+        }
+    }
+
+    fn append(&mut self, kind: OperationKind, ty: Option<ir::Type>) -> Value {
+        self.function.append(self.current.unwrap(), kind, ty)
+    }
+
+    fn constant(&mut self, number: i32, ty: ir::Type) -> Value {
+        self.append(OperationKind::Const(number), Some(ty))
+    }
+
+    fn ty(&self, value: Value) -> ir::Type {
+        self.function.operation(value).ty.unwrap()
+    }
+
+    /// Ends the current block, after which code is unreachable until a new block is started.
+    fn terminate(&mut self, terminator: Terminator) {
+        self.function.blocks[self.current.unwrap().0].terminator = terminator;
+        self.current = None;
+    }
+
+    fn lookup(&self, name: &str, span: Span) -> Result<Value, CompileError> {
+        match self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            Some(value) => Ok(*value),
+            None => Err(CompileError::UndefinedVariable { span, name: name.to_string() }),
+        }
+    }
+
+    fn assign(&mut self, name: &str, value: Value, span: Span) -> Result<(), CompileError> {
+        match self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(name)) {
+            Some(slot) => *slot = value,
+            None => return Err(CompileError::UndefinedVariable { span, name: name.to_string() }),
+        }
+        return Ok(());
+    }
+
+    fn block(&mut self, statements: &[Statement]) -> Result<(), CompileError> {
+        self.scopes.push(HashMap::new());
+        self.statements(statements)?;
+        self.scopes.pop();
+        return Ok(());
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<(), CompileError> {
+        for statement in statements {
+            if self.current.is_none() {
+                break;
+            }
+            self.statement(statement)?;
+        }
+        return Ok(());
+    }
+
+    /// Continues in a new block reached from the end of each arm that did not return, giving
+    /// every variable that differs between the arms a phi.
+    fn merge(&mut self, arms: Vec<(Option<BlockId>, Scopes)>) {
+        let arms: Vec<(BlockId, Scopes)> = arms.into_iter().filter_map(|(end, scopes)| end.map(|end| (end, scopes))).collect();
+        let Some((_, first)) = arms.first() else {
+            return;
+        };
+        let join = self.function.add_block();
+        let mut scopes = first.clone();
+        for (depth, scope) in scopes.iter_mut().enumerate() {
+            // Sorted so that phis are numbered the same way every time.
+            let mut names: Vec<String> = scope.keys().cloned().collect();
+            names.sort();
+            for name in names {
+                let value = scope[&name];
+                let incoming: Vec<(BlockId, Value)> = arms.iter().map(|(end, arm)| (*end, arm[depth][&name])).collect();
+                if incoming.iter().any(|(_, other)| *other != value) {
+                    let phi = self.function.append(join, OperationKind::Phi(incoming), Some(self.ty(value)));
+                    scope.insert(name, phi);
+                }
+            }
+        }
+        for (end, _) in &arms {
+            self.function.blocks[end.0].terminator = Terminator::Jump(join);
+        }
+        self.scopes = scopes;
+        self.current = Some(join);
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        match &statement.kind {
+            // The value is lowered before the name is bound, so `let x = x + 1;` reads any outer `x`.
+            StatementKind::Let { name, value, .. } => {
+                let value = self.expression(value)?;
+                self.scopes.last_mut().unwrap().insert(name.clone(), value);
+            }
+            StatementKind::Assign { name, value } => {
+                let value = self.expression(value)?;
+                self.assign(name, value, statement.span)?;
+            }
+            StatementKind::AssignIndex { .. } => return Err(unsupported(statement.span, "array assignment")),
+            StatementKind::If { condition, then_branch, else_branch } => {
+                let condition = self.expression(condition)?;
+                let then_block = self.function.add_block();
+                let else_block = self.function.add_block();
+                self.terminate(Terminator::Branch { condition, then_block, else_block });
+                let before = self.scopes.clone();
+
+                self.current = Some(then_block);
+                self.block(then_branch)?;
+                let then_arm = (self.current, std::mem::replace(&mut self.scopes, before));
+                self.current = Some(else_block);
+                self.block(else_branch)?;
+                let else_arm = (self.current, self.scopes.clone());
+                self.merge(vec![then_arm, else_arm]);
+            }
+            StatementKind::While { condition, body } => {
+                let entry = self.current.unwrap();
+                let header = self.function.add_block();
+                self.terminate(Terminator::Jump(header));
+                let mut names = vec![];
+                assigned_names(body, &mut names);
+                let mut phis = vec![];
+                for name in names {
+                    // Names only declared inside the body are not visible here.
+                    let Ok(value) = self.lookup(&name, statement.span) else {
+                        continue;
+                    };
+                    let phi = self.function.append(header, OperationKind::Phi(vec![(entry, value)]), Some(self.ty(value)));
+                    self.assign(&name, phi, statement.span)?;
+                    phis.push((name, phi));
+                }
+
+                self.current = Some(header);
+                let condition = self.expression(condition)?;
+                let body_block = self.function.add_block();
+                let exit = self.function.add_block();
+                self.terminate(Terminator::Branch { condition, then_block: body_block, else_block: exit });
+                let header_scopes = self.scopes.clone();
+
+                self.current = Some(body_block);
+                self.block(body)?;
+                if let Some(end) = self.current {
+                    self.terminate(Terminator::Jump(header));
+                    for (name, phi) in &phis {
+                        let value = self.lookup(name, statement.span)?;
+                        if let OperationKind::Phi(incoming) = &mut self.function.operations[phi.0].kind {
+                            incoming.push((end, value));
+                        }
+                    }
+                }
+                self.scopes = header_scopes;
+                self.current = Some(exit);
+            }
+            StatementKind::Print(value) => {
+                let value = self.expression(value)?;
+                self.append(OperationKind::Print(value), None);
+            }
+            StatementKind::Function { .. } => {
+                return Err(CompileError::UnexpectedToken {
+                    span: statement.span,
+                    expected: "statement".to_string(),
+                    found: "`fn`".to_string(),
+                });
+            }
+            StatementKind::Return(value) => {
+                if !self.in_function {
+                    return Err(CompileError::ReturnOutsideFunction { span: statement.span });
+                }
+                let value = self.expression(value)?;
+                self.terminate(Terminator::Return(value));
+            }
+            StatementKind::Expression(value) => {
+                self.expression(value)?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Result<Value, CompileError> {
+        let value = match &expression.kind {
+            ExpressionKind::Integer(number) => match i32::try_from(*number) {
+                Ok(number) => self.constant(number, ir::Type::Int),
+                Err(_) => return Err(CompileError::IntegerTooLarge { span: expression.span }),
+            },
+            ExpressionKind::Boolean(boolean) => self.constant(*boolean as i32, ir::Type::Bool),
+            ExpressionKind::Float(_) => return Err(unsupported(expression.span, "a float")),
+            ExpressionKind::String(_) => return Err(unsupported(expression.span, "a string")),
+            ExpressionKind::Array(_) => return Err(unsupported(expression.span, "an array")),
+            ExpressionKind::Index { .. } => return Err(unsupported(expression.span, "array indexing")),
+            ExpressionKind::Variable(name) => self.lookup(name, expression.span)?,
+            ExpressionKind::Call { name, arguments } => {
+                let Some(signature) = self.signatures.get(name) else {
+                    return Err(CompileError::UndefinedFunction { span: expression.span, name: name.clone() });
+                };
+                if signature.parameter_count != arguments.len() {
+                    let (expected, found) = (signature.parameter_count, arguments.len());
+                    return Err(CompileError::WrongArgumentCount { span: expression.span, name: name.clone(), expected, found });
+                }
+                let (function, ty) = (signature.index, signature.result);
+                let arguments = arguments.iter().map(|argument| self.expression(argument)).collect::<Result<Vec<_>, _>>()?;
+                self.append(OperationKind::Call { function, arguments }, Some(ty))
+            }
+            ExpressionKind::Unary { operator: UnaryOperator::Negate, operand } => {
+                let operand = self.expression(operand)?;
+                let zero = self.constant(0, ir::Type::Int);
+                self.append(OperationKind::Binary(BinaryOp::Sub, zero, operand), Some(ir::Type::Int))
+            }
+            ExpressionKind::Unary { operator: UnaryOperator::Not, operand } => {
+                let operand = self.expression(operand)?;
+                let zero = self.constant(0, ir::Type::Bool);
+                self.append(OperationKind::Binary(BinaryOp::Eq, operand, zero), Some(ir::Type::Bool))
+            }
+            ExpressionKind::Binary { operator: operator @ (BinaryOperator::And | BinaryOperator::Or), left, right } => {
+                // When the left operand decides the result it is also the result, as `false` is
+                // 0 and `true` is 1.
+                let left = self.expression(left)?;
+                let left_end = self.current.unwrap();
+                let right_block = self.function.add_block();
+                let join = self.function.add_block();
+                let (then_block, else_block) = match operator {
+                    BinaryOperator::And => (right_block, join),
+                    _ => (join, right_block),
+                };
+                self.terminate(Terminator::Branch { condition: left, then_block, else_block });
+                self.current = Some(right_block);
+                let right = self.expression(right)?;
+                let right_end = self.current.unwrap();
+                self.terminate(Terminator::Jump(join));
+                self.current = Some(join);
+                self.append(OperationKind::Phi(vec![(left_end, left), (right_end, right)]), Some(ir::Type::Bool))
+            }
+            ExpressionKind::Binary { operator, left, right } => {
+                let (op, ty) = match operator {
+                    BinaryOperator::Add => (BinaryOp::Add, ir::Type::Int),
+                    BinaryOperator::Subtract => (BinaryOp::Sub, ir::Type::Int),
+                    BinaryOperator::Multiply => (BinaryOp::Mul, ir::Type::Int),
+                    BinaryOperator::Divide => (BinaryOp::Div, ir::Type::Int),
+                    BinaryOperator::Modulo => (BinaryOp::Mod, ir::Type::Int),
+                    BinaryOperator::Equal => (BinaryOp::Eq, ir::Type::Bool),
+                    BinaryOperator::NotEqual => (BinaryOp::Ne, ir::Type::Bool),
+                    BinaryOperator::Less => (BinaryOp::Lt, ir::Type::Bool),
+                    BinaryOperator::LessEqual => (BinaryOp::Le, ir::Type::Bool),
+                    BinaryOperator::Greater => (BinaryOp::Gt, ir::Type::Bool),
+                    BinaryOperator::GreaterEqual => (BinaryOp::Ge, ir::Type::Bool),
+                    BinaryOperator::And | BinaryOperator::Or => unreachable!("handled above"),
+                };
+                let left = self.expression(left)?;
+                let right = self.expression(right)?;
+                self.append(OperationKind::Binary(op, left, right), Some(ty))
+            }
+        };
+        return Ok(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::{lexer::tokenize, parser::parse};
+
+    fn lower_source(source: &str) -> ir::Program {
+        lower(&parse(&tokenize(source).unwrap()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_lower_to_ssa() {
+        let program = lower_source("let n = 3;\nlet total = 0;\nwhile n > 0 { total = total + n; n = n - 1; }\nif total == 6 && true { print total; }");
+        let expected = "\
+fn main() {
+b0:
+    v0: int = const 3
+    v1: int = const 0
+    jump b1
+b1:
+    v2: int = phi [b0: v1], [b2: v6]
+    v3: int = phi [b0: v0], [b2: v8]
+    v4: int = const 0
+    v5: bool = gt v3, v4
+    branch v5, b2, b3
+b2:
+    v6: int = add v2, v3
+    v7: int = const 1
+    v8: int = sub v3, v7
+    jump b1
+b3:
+    v9: int = const 6
+    v10: bool = eq v2, v9
+    branch v10, b4, b5
+b4:
+    v11: bool = const 1
+    jump b5
+b5:
+    v12: bool = phi [b3: v10], [b4: v11]
+    branch v12, b6, b7
+b6:
+    print v2
+    jump b8
+b7:
+    jump b8
+b8:
+    halt
+}
+";
+        assert_eq!(program.to_string(), expected);
+    }
+}
@@ -0,0 +1,131 @@
+//! A small source language that compiles to the same `Instruction`s the assembler produces.
+//!
+//! Programs are a sequence of statements over 32-bit integers:
+//!
+//! ```text
+//! let n = 10;
+//! let total = 0;
+//! while n > 0 {
+//!     total = total + n;
+//!     n = n - 1;
+//! }
+//! if total == 55 { print total; } else { print 0; }
+//! ```
+//!
+//! Programs are type checked before code generation. Conditions must be `bool`, and `&&`, `||`
+//! and `!` only apply to `bool`s, which are represented as 1 or 0.
+//!
+//! Functions are declared at the top level and can be called before their declaration.
+//! Parameters and results without a type annotation are `int`:
+//!
+//! ```text
+//! fn fib(n: int) -> int {
+//!     if n < 2 { return n; }
+//!     return fib(n - 1) + fib(n - 2);
+//! }
+//! print fib(15);
+//! ```
+//!
+//! A function only sees its own parameters and locals, which live in its stack frame. Functions
+//! returning `int` can omit `return`, and then return 0.
+//!
+//! The type checker also understands `float` and `string` literals and arrays such as
+//! `let xs: [int] = [1, 2];` with `xs[0]` indexing, which code generation does not support yet.
+//!
+//! `compile` lowers programs to the SSA form in `crate::ir`, optimizes them and allocates
+//! registers there. `compile_unoptimized` generates code straight from the syntax tree instead.
+
+use std::fmt;
+
+use crate::{instruction::Instruction, ir};
+
+pub mod ast;
+pub mod codegen;
+pub mod lexer;
+pub mod lower;
+pub mod parser;
+pub mod types;
+
+/// A position in source code, counted from 1.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, PartialOrd, Ord)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CompileError {
+    UnexpectedCharacter { span: Span, character: char },
+    UnterminatedString { span: Span },
+    /// `found` is a description of the token, or "end of input".
+    UnexpectedToken { span: Span, expected: String, found: String },
+    IntegerTooLarge { span: Span },
+    UndefinedVariable { span: Span, name: String },
+    /// Every register is holding a variable or an intermediate result.
+    OutOfRegisters { span: Span },
+    UndefinedFunction { span: Span, name: String },
+    DuplicateFunction { span: Span, name: String },
+    WrongArgumentCount { span: Span, name: String, expected: usize, found: usize },
+    ReturnOutsideFunction { span: Span },
+    /// The program type checks but uses something code generation cannot handle yet.
+    Unsupported { span: Span, feature: String },
+    /// Every problem the type checker found, in source order.
+    Type(Vec<types::TypeError>),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::UnexpectedCharacter { span, character } => write!(f, "{}: unexpected character {:?}", span, character),
+            CompileError::UnterminatedString { span } => write!(f, "{}: string literal is missing its closing quote", span),
+            CompileError::UnexpectedToken { span, expected, found } => write!(f, "{}: expected {}, found {}", span, expected, found),
+            CompileError::IntegerTooLarge { span } => write!(f, "{}: integer literal does not fit in 32 bits", span),
+            CompileError::UndefinedVariable { span, name } => write!(f, "{}: undefined variable `{}`", span, name),
+            CompileError::OutOfRegisters { span } => write!(f, "{}: expression needs more registers than the VM has", span),
+            CompileError::UndefinedFunction { span, name } => write!(f, "{}: undefined function `{}`", span, name),
+            CompileError::DuplicateFunction { span, name } => write!(f, "{}: function `{}` is already defined", span, name),
+            CompileError::WrongArgumentCount { span, name, expected, found } => {
+                write!(f, "{}: `{}` takes {} arguments but was given {}", span, name, expected, found)
+            }
+            CompileError::ReturnOutsideFunction { span } => write!(f, "{}: `return` outside of a function", span),
+            CompileError::Unsupported { span, feature } => write!(f, "{}: {} cannot be compiled yet", span, feature),
+            CompileError::Type(errors) => {
+                let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+                write!(f, "{}", messages.join("\n"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Compiles source code to a program ending in `HLT`, type checking it first.
+pub fn compile(source: &str) -> Result<Vec<Instruction>, CompileError> {
+    let program = compile_to_ir(source)?;
+    return Ok(ir::emit::emit(&program));
+}
+
+/// Type checks source code and lowers it to optimized SSA form.
+pub fn compile_to_ir(source: &str) -> Result<ir::Program, CompileError> {
+    let tokens = lexer::tokenize(source)?;
+    let statements = parser::parse(&tokens)?;
+    types::check(&statements).map_err(CompileError::Type)?;
+    let mut program = lower::lower(&statements)?;
+    ir::passes::optimize(&mut program);
+    return Ok(program);
+}
+
+/// Compiles source code without going through the intermediate representation, keeping every
+/// variable in a register of its own.
+pub fn compile_unoptimized(source: &str) -> Result<Vec<Instruction>, CompileError> {
+    let tokens = lexer::tokenize(source)?;
+    let statements = parser::parse(&tokens)?;
+    types::check(&statements).map_err(CompileError::Type)?;
+    return codegen::generate(&statements);
+}
@@ -0,0 +1,408 @@
+use super::{
+    ast::{BinaryOperator, Expression, ExpressionKind, Parameter, Statement, StatementKind, UnaryOperator},
+    lexer::{Token, TokenKind},
+    types::Type,
+    CompileError, Span,
+};
+
+/// Parses a whole program.
+pub fn parse(tokens: &[Token]) -> Result<Vec<Statement>, CompileError> {
+    let mut parser = Parser { tokens, position: 0 };
+    let mut statements = vec![];
+    while parser.peek().is_some() {
+        match parser.peek() {
+            Some(TokenKind::Fn) => statements.push(parser.function()?),
+            _ => statements.push(parser.statement()?),
+        }
+    }
+    return Ok(statements);
+}
+
+/// Recursive descent parser. Binary operators are parsed one `PRECEDENCE` level at a time.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.position).map(|token| &token.kind)
+    }
+
+    /// Span of the next token, or just past the last one at the end of input.
+    fn span(&self) -> Span {
+        match (self.tokens.get(self.position), self.tokens.last()) {
+            (Some(token), _) => token.span,
+            (None, Some(last)) => Span { line: last.span.line, column: last.span.column + 1 },
+            (None, None) => Span { line: 1, column: 1 },
+        }
+    }
+
+    fn peek_second(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.position + 1).map(|token| &token.kind)
+    }
+
+    /// Whether the next tokens are a name followed by `(`, starting a call.
+    fn at_call(&self) -> bool {
+        self.peek_second() == Some(&TokenKind::LeftParen)
+    }
+
+    fn advance(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if self.peek() == Some(kind) {
+            self.position += 1;
+            return true;
+        }
+        return false;
+    }
+
+    fn error(&self, expected: &str) -> CompileError {
+        let found = match self.peek() {
+            Some(kind) => kind.to_string(),
+            None => "end of input".to_string(),
+        };
+        CompileError::UnexpectedToken { span: self.span(), expected: expected.to_string(), found }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<(), CompileError> {
+        if self.eat(&kind) {
+            return Ok(());
+        }
+        return Err(self.error(&kind.to_string()));
+    }
+
+    fn identifier(&mut self) -> Result<String, CompileError> {
+        match self.peek() {
+            Some(TokenKind::Identifier(name)) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.error("identifier")),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, CompileError> {
+        let span = self.span();
+        let kind = match self.peek() {
+            Some(TokenKind::Let) => {
+                self.position += 1;
+                let name = self.identifier()?;
+                let annotation = match self.eat(&TokenKind::Colon) {
+                    true => Some(self.ty()?),
+                    false => None,
+                };
+                self.expect(TokenKind::Equal)?;
+                let value = self.expression()?;
+                self.expect(TokenKind::Semicolon)?;
+                StatementKind::Let { name, annotation, value }
+            }
+            Some(TokenKind::If) => return self.if_statement(),
+            Some(TokenKind::While) => {
+                self.position += 1;
+                let condition = self.expression()?;
+                let body = self.block()?;
+                StatementKind::While { condition, body }
+            }
+            Some(TokenKind::Print) => {
+                self.position += 1;
+                let value = self.expression()?;
+                self.expect(TokenKind::Semicolon)?;
+                StatementKind::Print(value)
+            }
+            Some(TokenKind::Return) => {
+                self.position += 1;
+                let value = self.expression()?;
+                self.expect(TokenKind::Semicolon)?;
+                StatementKind::Return(value)
+            }
+            Some(TokenKind::Identifier(_)) if self.at_call() => {
+                let value = self.expression()?;
+                self.expect(TokenKind::Semicolon)?;
+                StatementKind::Expression(value)
+            }
+            Some(TokenKind::Identifier(_)) if self.peek_second() == Some(&TokenKind::LeftBracket) => {
+                let name = self.identifier()?;
+                self.expect(TokenKind::LeftBracket)?;
+                let index = self.expression()?;
+                self.expect(TokenKind::RightBracket)?;
+                self.expect(TokenKind::Equal)?;
+                let value = self.expression()?;
+                self.expect(TokenKind::Semicolon)?;
+                StatementKind::AssignIndex { name, index, value }
+            }
+            Some(TokenKind::Identifier(_)) => {
+                let name = self.identifier()?;
+                self.expect(TokenKind::Equal)?;
+                let value = self.expression()?;
+                self.expect(TokenKind::Semicolon)?;
+                StatementKind::Assign { name, value }
+            }
+            _ => return Err(self.error("statement")),
+        };
+        return Ok(Statement { kind, span });
+    }
+
+    fn function(&mut self) -> Result<Statement, CompileError> {
+        let span = self.span();
+        self.expect(TokenKind::Fn)?;
+        let name = self.identifier()?;
+        self.expect(TokenKind::LeftParen)?;
+        let mut parameters = vec![];
+        if !self.eat(&TokenKind::RightParen) {
+            loop {
+                let span = self.span();
+                let name = self.identifier()?;
+                let ty = match self.eat(&TokenKind::Colon) {
+                    true => self.ty()?,
+                    false => Type::Int,
+                };
+                parameters.push(Parameter { name, ty, span });
+                if self.eat(&TokenKind::RightParen) {
+                    break;
+                }
+                self.expect(TokenKind::Comma)?;
+            }
+        }
+        let result = match self.eat(&TokenKind::Arrow) {
+            true => self.ty()?,
+            false => Type::Int,
+        };
+        let body = self.block()?;
+        return Ok(Statement { kind: StatementKind::Function { name, parameters, result, body }, span });
+    }
+
+    /// Parses a type: `int`, `bool`, `float`, `string`, `[element]` or `fn(parameters) -> result`.
+    fn ty(&mut self) -> Result<Type, CompileError> {
+        match self.peek() {
+            Some(TokenKind::LeftBracket) => {
+                self.position += 1;
+                let element = self.ty()?;
+                self.expect(TokenKind::RightBracket)?;
+                Ok(Type::Array(Box::new(element)))
+            }
+            Some(TokenKind::Fn) => {
+                self.position += 1;
+                self.expect(TokenKind::LeftParen)?;
+                let mut parameters = vec![];
+                while !self.eat(&TokenKind::RightParen) {
+                    if !parameters.is_empty() {
+                        self.expect(TokenKind::Comma)?;
+                    }
+                    parameters.push(self.ty()?);
+                }
+                self.expect(TokenKind::Arrow)?;
+                let result = self.ty()?;
+                Ok(Type::Function { parameters, result: Box::new(result) })
+            }
+            Some(TokenKind::Identifier(name)) => {
+                let ty = match name.as_str() {
+                    "int" => Type::Int,
+                    "bool" => Type::Bool,
+                    "float" => Type::Float,
+                    "string" => Type::String,
+                    _ => return Err(self.error("type")),
+                };
+                self.position += 1;
+                Ok(ty)
+            }
+            _ => Err(self.error("type")),
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<Statement, CompileError> {
+        let span = self.span();
+        self.expect(TokenKind::If)?;
+        let condition = self.expression()?;
+        let then_branch = self.block()?;
+        let else_branch = match self.eat(&TokenKind::Else) {
+            true if self.peek() == Some(&TokenKind::If) => vec![self.if_statement()?],
+            true => self.block()?,
+            false => vec![],
+        };
+        return Ok(Statement { kind: StatementKind::If { condition, then_branch, else_branch }, span });
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, CompileError> {
+        self.expect(TokenKind::LeftBrace)?;
+        let mut statements = vec![];
+        while !self.eat(&TokenKind::RightBrace) {
+            if self.peek().is_none() {
+                return Err(self.error("`}`"));
+            }
+            statements.push(self.statement()?);
+        }
+        return Ok(statements);
+    }
+
+    fn expression(&mut self) -> Result<Expression, CompileError> {
+        self.binary(0)
+    }
+
+    /// Parses a left-associative chain of operators at `level` of `PRECEDENCE` or tighter.
+    fn binary(&mut self, level: usize) -> Result<Expression, CompileError> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let operator = PRECEDENCE[level]
+                .iter()
+                .find(|(kind, _)| self.peek() == Some(kind))
+                .map(|(_, operator)| *operator);
+            let Some(operator) = operator else {
+                return Ok(left);
+            };
+            let span = self.span();
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = Expression { kind: ExpressionKind::Binary { operator, left: Box::new(left), right: Box::new(right) }, span };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, CompileError> {
+        let span = self.span();
+        let operator = match self.peek() {
+            Some(TokenKind::Minus) => UnaryOperator::Negate,
+            Some(TokenKind::Bang) => UnaryOperator::Not,
+            _ => return self.postfix(),
+        };
+        self.position += 1;
+        let operand = self.unary()?;
+        // Fold negative literals so that `-2147483648` is representable.
+        match (operator, &operand.kind) {
+            (UnaryOperator::Negate, ExpressionKind::Integer(number)) => {
+                return Ok(Expression { kind: ExpressionKind::Integer(-number), span });
+            }
+            (UnaryOperator::Negate, ExpressionKind::Float(number)) => {
+                return Ok(Expression { kind: ExpressionKind::Float(-number), span });
+            }
+            _ => {}
+        }
+        return Ok(Expression { kind: ExpressionKind::Unary { operator, operand: Box::new(operand) }, span });
+    }
+
+    /// Parses a primary expression followed by any number of `[index]`es.
+    fn postfix(&mut self) -> Result<Expression, CompileError> {
+        let mut expression = self.primary()?;
+        while self.peek() == Some(&TokenKind::LeftBracket) {
+            let span = self.span();
+            self.position += 1;
+            let index = self.expression()?;
+            self.expect(TokenKind::RightBracket)?;
+            expression = Expression { kind: ExpressionKind::Index { target: Box::new(expression), index: Box::new(index) }, span };
+        }
+        return Ok(expression);
+    }
+
+    fn primary(&mut self) -> Result<Expression, CompileError> {
+        let span = self.span();
+        let kind = match self.peek() {
+            Some(TokenKind::Integer(number)) => ExpressionKind::Integer(*number),
+            Some(TokenKind::Float(number)) => ExpressionKind::Float(*number),
+            Some(TokenKind::String(string)) => ExpressionKind::String(string.clone()),
+            Some(TokenKind::LeftBracket) => {
+                self.position += 1;
+                let mut elements = vec![];
+                while !self.eat(&TokenKind::RightBracket) {
+                    if !elements.is_empty() {
+                        self.expect(TokenKind::Comma)?;
+                    }
+                    elements.push(self.expression()?);
+                }
+                return Ok(Expression { kind: ExpressionKind::Array(elements), span });
+            }
+            Some(TokenKind::True) => ExpressionKind::Boolean(true),
+            Some(TokenKind::False) => ExpressionKind::Boolean(false),
+            Some(TokenKind::Identifier(name)) if self.at_call() => {
+                let name = name.clone();
+                self.position += 2;
+                let mut arguments = vec![];
+                if !self.eat(&TokenKind::RightParen) {
+                    loop {
+                        arguments.push(self.expression()?);
+                        if self.eat(&TokenKind::RightParen) {
+                            break;
+                        }
+                        self.expect(TokenKind::Comma)?;
+                    }
+                }
+                return Ok(Expression { kind: ExpressionKind::Call { name, arguments }, span });
+            }
+            Some(TokenKind::Identifier(name)) => ExpressionKind::Variable(name.clone()),
+            Some(TokenKind::LeftParen) => {
+                self.position += 1;
+                let expression = self.expression()?;
+                self.expect(TokenKind::RightParen)?;
+                return Ok(expression);
+            }
+            _ => return Err(self.error("expression")),
+        };
+        self.advance();
+        return Ok(Expression { kind, span });
+    }
+}
+
+/// Binary operators from loosest to tightest binding.
+const PRECEDENCE: &[&[(TokenKind, BinaryOperator)]] = &[
+    &[(TokenKind::OrOr, BinaryOperator::Or)],
+    &[(TokenKind::AndAnd, BinaryOperator::And)],
+    &[(TokenKind::EqualEqual, BinaryOperator::Equal), (TokenKind::BangEqual, BinaryOperator::NotEqual)],
+    &[
+        (TokenKind::Less, BinaryOperator::Less),
+        (TokenKind::LessEqual, BinaryOperator::LessEqual),
+        (TokenKind::Greater, BinaryOperator::Greater),
+        (TokenKind::GreaterEqual, BinaryOperator::GreaterEqual),
+    ],
+    &[(TokenKind::Plus, BinaryOperator::Add), (TokenKind::Minus, BinaryOperator::Subtract)],
+    &[
+        (TokenKind::Star, BinaryOperator::Multiply),
+        (TokenKind::Slash, BinaryOperator::Divide),
+        (TokenKind::Percent, BinaryOperator::Modulo),
+    ],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::lexer::tokenize;
+
+    fn parse_source(source: &str) -> Result<Vec<Statement>, CompileError> {
+        parse(&tokenize(source).unwrap())
+    }
+
+    #[test]
+    fn test_precedence() {
+        let statements = parse_source("print 1 + 2 * 3 == 7 || !x;").unwrap();
+        let StatementKind::Print(expression) = &statements[0].kind else {
+            panic!("expected print");
+        };
+        let ExpressionKind::Binary { operator: BinaryOperator::Or, left, right } = &expression.kind else {
+            panic!("expected || at the top");
+        };
+        let ExpressionKind::Binary { operator: BinaryOperator::Equal, left: sum, .. } = &left.kind else {
+            panic!("expected == under ||");
+        };
+        assert!(matches!(&sum.kind, ExpressionKind::Binary { operator: BinaryOperator::Add, .. }));
+        assert!(matches!(&right.kind, ExpressionKind::Unary { operator: UnaryOperator::Not, .. }));
+        assert_eq!(right.span, Span { line: 1, column: 25 });
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(
+            parse_source("let x = 1\nprint x;"),
+            Err(CompileError::UnexpectedToken {
+                span: Span { line: 2, column: 1 },
+                expected: "`;`".to_string(),
+                found: "`print`".to_string()
+            })
+        );
+        assert!(matches!(parse_source("while x { print x;"), Err(CompileError::UnexpectedToken { found, .. }) if found == "end of input"));
+    }
+}
@@ -0,0 +1,387 @@
+use std::{collections::HashMap, fmt};
+
+use super::{
+    ast::{BinaryOperator, Expression, ExpressionKind, Parameter, Statement, StatementKind, UnaryOperator},
+    Span,
+};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Type {
+    Int,
+    Bool,
+    Float,
+    String,
+    Array(Box<Type>),
+    Function { parameters: Vec<Type>, result: Box<Type> },
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::Float => write!(f, "float"),
+            Type::String => write!(f, "string"),
+            Type::Array(element) => write!(f, "[{}]", element),
+            Type::Function { parameters, result } => {
+                let parameters: Vec<String> = parameters.iter().map(|parameter| parameter.to_string()).collect();
+                write!(f, "fn({}) -> {}", parameters.join(", "), result)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TypeErrorKind {
+    Mismatch { expected: Type, found: Type },
+    BinaryOperands { operator: BinaryOperator, left: Type, right: Type },
+    UnaryOperand { operator: UnaryOperator, operand: Type },
+    NotIndexable(Type),
+    NotPrintable(Type),
+    UndefinedVariable(String),
+    UndefinedFunction(String),
+    /// A function's name was used as a variable. Functions can only be called.
+    FunctionAsValue(String),
+    DuplicateFunction(String),
+    WrongArgumentCount { name: String, expected: usize, found: usize },
+    /// `[]` where nothing says what its elements are.
+    EmptyArray,
+    /// A function that does not return `int` can reach its end without a `return`.
+    MissingReturn { name: String, result: Type },
+    ReturnOutsideFunction,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TypeError {
+    pub span: Span,
+    pub kind: TypeErrorKind,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.span)?;
+        match &self.kind {
+            TypeErrorKind::Mismatch { expected, found } => write!(f, "expected {}, found {}", expected, found),
+            TypeErrorKind::BinaryOperands { operator, left, right } => {
+                write!(f, "`{}` cannot be applied to {} and {}", operator, left, right)
+            }
+            TypeErrorKind::UnaryOperand { operator, operand } => write!(f, "`{}` cannot be applied to {}", operator, operand),
+            TypeErrorKind::NotIndexable(ty) => write!(f, "{} cannot be indexed", ty),
+            TypeErrorKind::NotPrintable(ty) => write!(f, "{} cannot be printed", ty),
+            TypeErrorKind::UndefinedVariable(name) => write!(f, "undefined variable `{}`", name),
+            TypeErrorKind::UndefinedFunction(name) => write!(f, "undefined function `{}`", name),
+            TypeErrorKind::FunctionAsValue(name) => write!(f, "function `{}` can only be called", name),
+            TypeErrorKind::DuplicateFunction(name) => write!(f, "function `{}` is already defined", name),
+            TypeErrorKind::WrongArgumentCount { name, expected, found } => {
+                write!(f, "`{}` takes {} arguments but was given {}", name, expected, found)
+            }
+            TypeErrorKind::EmptyArray => write!(f, "cannot tell the element type of `[]`, add a type annotation"),
+            TypeErrorKind::MissingReturn { name, result } => write!(f, "`{}` must return {} on every path", name, result),
+            TypeErrorKind::ReturnOutsideFunction => write!(f, "`return` outside of a function"),
+        }
+    }
+}
+
+/// Type checks a parsed program, returning every error found.
+pub fn check(statements: &[Statement]) -> Result<(), Vec<TypeError>> {
+    let mut checker = Checker { functions: HashMap::new(), scopes: vec![HashMap::new()], result: None, errors: vec![] };
+    for statement in statements {
+        if let StatementKind::Function { name, parameters, result, .. } = &statement.kind {
+            let parameters = parameters.iter().map(|parameter| parameter.ty.clone()).collect();
+            let ty = Type::Function { parameters, result: Box::new(result.clone()) };
+            if checker.functions.insert(name.clone(), ty).is_some() {
+                checker.error(statement.span, TypeErrorKind::DuplicateFunction(name.clone()));
+            }
+        }
+    }
+
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Function { name, parameters, result, body } => {
+                checker.function(name, parameters, result, body, statement.span);
+            }
+            _ => checker.statement(statement),
+        }
+    }
+
+    if !checker.errors.is_empty() {
+        checker.errors.sort_by_key(|error| error.span);
+        return Err(checker.errors);
+    }
+    return Ok(());
+}
+
+/// Types are `Option`s throughout: `None` means the expression already produced an error, so
+/// anything depending on it is not checked to avoid reporting the same mistake repeatedly.
+struct Checker {
+    functions: HashMap<String, Type>,
+    scopes: Vec<HashMap<String, Option<Type>>>,
+    /// Result type of the function being checked, if any.
+    result: Option<Type>,
+    errors: Vec<TypeError>,
+}
+
+impl Checker {
+    fn error(&mut self, span: Span, kind: TypeErrorKind) {
+        self.errors.push(TypeError { span, kind });
+    }
+
+    fn bind(&mut self, name: &str, ty: Option<Type>) {
+        self.scopes.last_mut().unwrap().insert(name.to_string(), ty);
+    }
+
+    fn lookup(&mut self, name: &str, span: Span) -> Option<Type> {
+        match self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            Some(ty) => ty.clone(),
+            None if self.functions.contains_key(name) => {
+                self.error(span, TypeErrorKind::FunctionAsValue(name.to_string()));
+                None
+            }
+            None => {
+                self.error(span, TypeErrorKind::UndefinedVariable(name.to_string()));
+                None
+            }
+        }
+    }
+
+    fn function(&mut self, name: &str, parameters: &[Parameter], result: &Type, body: &[Statement], span: Span) {
+        let scope = parameters.iter().map(|parameter| (parameter.name.clone(), Some(parameter.ty.clone()))).collect();
+        let outer_scopes = std::mem::replace(&mut self.scopes, vec![scope]);
+        self.result = Some(result.clone());
+        self.statements(body);
+        if *result != Type::Int && !always_returns(body) {
+            self.error(span, TypeErrorKind::MissingReturn { name: name.to_string(), result: result.clone() });
+        }
+        self.result = None;
+        self.scopes = outer_scopes;
+    }
+
+    fn block(&mut self, statements: &[Statement]) {
+        self.scopes.push(HashMap::new());
+        self.statements(statements);
+        self.scopes.pop();
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match &statement.kind {
+            StatementKind::Let { name, annotation: Some(annotation), value } => {
+                self.expect(value, annotation);
+                self.bind(name, Some(annotation.clone()));
+            }
+            StatementKind::Let { name, annotation: None, value } => {
+                let ty = self.expression(value, None);
+                self.bind(name, ty);
+            }
+            StatementKind::Assign { name, value } => {
+                if let Some(ty) = self.lookup(name, statement.span) {
+                    self.expect(value, &ty);
+                }
+            }
+            StatementKind::AssignIndex { name, index, value } => {
+                self.expect(index, &Type::Int);
+                match self.lookup(name, statement.span) {
+                    Some(Type::Array(element)) => self.expect(value, &element),
+                    Some(ty) => self.error(statement.span, TypeErrorKind::NotIndexable(ty)),
+                    None => {}
+                }
+            }
+            StatementKind::If { condition, then_branch, else_branch } => {
+                self.expect(condition, &Type::Bool);
+                self.block(then_branch);
+                self.block(else_branch);
+            }
+            StatementKind::While { condition, body } => {
+                self.expect(condition, &Type::Bool);
+                self.block(body);
+            }
+            StatementKind::Print(value) => match self.expression(value, None) {
+                Some(Type::Int | Type::Bool | Type::Float | Type::String) | None => {}
+                Some(ty) => self.error(value.span, TypeErrorKind::NotPrintable(ty)),
+            },
+            // The parser only accepts functions at the top level, where `check` handles them.
+            StatementKind::Function { .. } => {}
+            StatementKind::Return(value) => match self.result.clone() {
+                Some(result) => self.expect(value, &result),
+                None => {
+                    self.error(statement.span, TypeErrorKind::ReturnOutsideFunction);
+                    self.expression(value, None);
+                }
+            },
+            StatementKind::Expression(value) => {
+                self.expression(value, None);
+            }
+        }
+    }
+
+    /// Checks that an expression has type `expected`.
+    fn expect(&mut self, expression: &Expression, expected: &Type) {
+        if let Some(found) = self.expression(expression, Some(expected)) {
+            if found != *expected {
+                self.error(expression.span, TypeErrorKind::Mismatch { expected: expected.clone(), found });
+            }
+        }
+    }
+
+    /// Infers an expression's type. `hint` is the type the context expects, which is only used to
+    /// give `[]` a type.
+    fn expression(&mut self, expression: &Expression, hint: Option<&Type>) -> Option<Type> {
+        let span = expression.span;
+        match &expression.kind {
+            ExpressionKind::Integer(_) => Some(Type::Int),
+            ExpressionKind::Float(_) => Some(Type::Float),
+            ExpressionKind::String(_) => Some(Type::String),
+            ExpressionKind::Boolean(_) => Some(Type::Bool),
+            ExpressionKind::Array(elements) => {
+                let Some((first, rest)) = elements.split_first() else {
+                    if let Some(Type::Array(_)) = hint {
+                        return hint.cloned();
+                    }
+                    self.error(span, TypeErrorKind::EmptyArray);
+                    return None;
+                };
+                let element_hint = match hint {
+                    Some(Type::Array(element)) => Some(element.as_ref()),
+                    _ => None,
+                };
+                let element = self.expression(first, element_hint)?;
+                for other in rest {
+                    self.expect(other, &element);
+                }
+                Some(Type::Array(Box::new(element)))
+            }
+            ExpressionKind::Variable(name) => self.lookup(name, span),
+            ExpressionKind::Unary { operator, operand } => {
+                let operand = self.expression(operand, None)?;
+                match (operator, &operand) {
+                    (UnaryOperator::Negate, Type::Int | Type::Float) | (UnaryOperator::Not, Type::Bool) => Some(operand),
+                    _ => {
+                        self.error(span, TypeErrorKind::UnaryOperand { operator: *operator, operand });
+                        None
+                    }
+                }
+            }
+            ExpressionKind::Binary { operator, left, right } => {
+                let left = self.expression(left, None);
+                let right = self.expression(right, None);
+                let (left, right) = (left?, right?);
+                let result = match (operator, &left, &right) {
+                    (BinaryOperator::Add, Type::String, Type::String) => Some(Type::String),
+                    (
+                        BinaryOperator::Add | BinaryOperator::Subtract | BinaryOperator::Multiply | BinaryOperator::Divide,
+                        Type::Int | Type::Float,
+                        _,
+                    ) if left == right => Some(left.clone()),
+                    (BinaryOperator::Modulo, Type::Int, Type::Int) => Some(Type::Int),
+                    (BinaryOperator::Equal | BinaryOperator::NotEqual, Type::Int | Type::Bool | Type::Float | Type::String, _)
+                        if left == right =>
+                    {
+                        Some(Type::Bool)
+                    }
+                    (
+                        BinaryOperator::Less | BinaryOperator::LessEqual | BinaryOperator::Greater | BinaryOperator::GreaterEqual,
+                        Type::Int | Type::Float,
+                        _,
+                    ) if left == right => Some(Type::Bool),
+                    (BinaryOperator::And | BinaryOperator::Or, Type::Bool, Type::Bool) => Some(Type::Bool),
+                    _ => None,
+                };
+                if result.is_none() {
+                    self.error(span, TypeErrorKind::BinaryOperands { operator: *operator, left, right });
+                }
+                result
+            }
+            ExpressionKind::Call { name, arguments } => {
+                let Some(Type::Function { parameters, result }) = self.functions.get(name).cloned() else {
+                    self.error(span, TypeErrorKind::UndefinedFunction(name.clone()));
+                    for argument in arguments {
+                        self.expression(argument, None);
+                    }
+                    return None;
+                };
+                if parameters.len() != arguments.len() {
+                    let kind = TypeErrorKind::WrongArgumentCount { name: name.clone(), expected: parameters.len(), found: arguments.len() };
+                    self.error(span, kind);
+                }
+                for (argument, parameter) in arguments.iter().zip(&parameters) {
+                    self.expect(argument, parameter);
+                }
+                Some(*result)
+            }
+            ExpressionKind::Index { target, index } => {
+                let target = self.expression(target, None);
+                self.expect(index, &Type::Int);
+                match target? {
+                    Type::Array(element) => Some(*element),
+                    ty => {
+                        self.error(span, TypeErrorKind::NotIndexable(ty));
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether every path through `statements` ends in a `return`.
+fn always_returns(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Return(_) => true,
+        StatementKind::If { then_branch, else_branch, .. } => always_returns(then_branch) && always_returns(else_branch),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::{lexer::tokenize, parser::parse};
+
+    fn check_source(source: &str) -> Result<(), Vec<TypeError>> {
+        check(&parse(&tokenize(source).unwrap()).unwrap())
+    }
+
+    fn error(line: usize, column: usize, kind: TypeErrorKind) -> TypeError {
+        TypeError { span: Span { line, column }, kind }
+    }
+
+    #[test]
+    fn test_well_typed_program() {
+        let source = "fn mean(xs: [float], count: int) -> float {\n    let total = 0.0;\n    let i = 0;\n    while i < count { total = total + xs[i]; i = i + 1; }\n    return total;\n}\nfn greet(name: string) -> string { if name == \"\" { return \"hi\"; } else { return \"hi \" + name; } }\nlet empty: [[int]] = [];\nlet grid = [[1, 2], [3]];\ngrid[1] = [4, 5];\nlet ok = mean([1.5, -2.0], 2) >= 0.0 && !(grid[0][1] == 3);\nprint greet(\"you\");";
+        assert_eq!(check_source(source), Ok(()));
+    }
+
+    #[test]
+    fn test_type_errors() {
+        let source = "fn f(x: int, flag: bool) -> bool {\n    if flag { return x; }\n}\nlet a = 1 + true;\nlet b: string = 2.5;\nif 1 { print [1]; }\nlet c = f(1);\nlet d = [1, \"two\"];\nlet e = f;\nlet g = -\"s\" + undefined;\nlet h = [];\nh = 1;\nreturn a;\nlet i = 3[0];";
+        assert_eq!(
+            check_source(source),
+            Err(vec![
+                error(1, 1, TypeErrorKind::MissingReturn { name: "f".to_string(), result: Type::Bool }),
+                error(2, 22, TypeErrorKind::Mismatch { expected: Type::Bool, found: Type::Int }),
+                error(4, 11, TypeErrorKind::BinaryOperands { operator: BinaryOperator::Add, left: Type::Int, right: Type::Bool }),
+                error(5, 17, TypeErrorKind::Mismatch { expected: Type::String, found: Type::Float }),
+                error(6, 4, TypeErrorKind::Mismatch { expected: Type::Bool, found: Type::Int }),
+                error(6, 14, TypeErrorKind::NotPrintable(Type::Array(Box::new(Type::Int)))),
+                error(7, 9, TypeErrorKind::WrongArgumentCount { name: "f".to_string(), expected: 2, found: 1 }),
+                error(8, 13, TypeErrorKind::Mismatch { expected: Type::Int, found: Type::String }),
+                error(9, 9, TypeErrorKind::FunctionAsValue("f".to_string())),
+                error(10, 9, TypeErrorKind::UnaryOperand { operator: UnaryOperator::Negate, operand: Type::String }),
+                error(10, 16, TypeErrorKind::UndefinedVariable("undefined".to_string())),
+                error(11, 9, TypeErrorKind::EmptyArray),
+                error(13, 1, TypeErrorKind::ReturnOutsideFunction),
+                error(14, 10, TypeErrorKind::NotIndexable(Type::Int)),
+            ])
+        );
+        assert_eq!(
+            error(4, 11, TypeErrorKind::BinaryOperands { operator: BinaryOperator::Add, left: Type::Int, right: Type::Bool }).to_string(),
+            "4:11: `+` cannot be applied to int and bool"
+        );
+        assert_eq!(Type::Function { parameters: vec![Type::Int, Type::Array(Box::new(Type::Float))], result: Box::new(Type::Bool) }.to_string(), "fn(int, [float]) -> bool");
+    }
+}
@@ -1,44 +1,280 @@
-use std::string;
+use crate::instruction::{Constant, Instruction, Opcode, RegisterUse};
 
-use crate::instruction::{self, Instruction, Opcode};
+use std::{collections::HashMap, fmt};
+
+/// Problems in assembly source. The lexer records them and carries on, so that every problem in
+/// a file is reported at once.
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexError {
+    /// An integer literal too big for the instruction's 32-bit operand.
+    LiteralTooWide { line: usize, literal: String },
+    UndefinedLabel { line: usize, label: String },
+    /// A `#literal` or `@label` in a slot the opcode reads from a register, such as `JMP @end`.
+    ImmediateNotAllowed { line: usize, opcode: Opcode, operand: String },
+    /// A `$` operand that is not a register number.
+    BadRegister { line: usize, operand: String },
+    /// A `#` operand, or a `.float` or `.string` value, that does not parse.
+    BadLiteral { line: usize, literal: String },
+    /// More operands than the opcode has slots.
+    TooManyOperands { line: usize, opcode: Opcode },
+    /// A line in a data section that is not a `.float` or `.string` declaration, or a `.data` or
+    /// `.code` directive followed by more tokens.
+    BadDataDeclaration { line: usize, declaration: String },
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::LiteralTooWide { line, literal } => {
+                write!(f, "{}: #{} does not fit in 32 bits, use LOADL for 64-bit values", line, literal)
+            }
+            LexError::UndefinedLabel { line, label } => write!(f, "{}: undefined label @{}", line, label),
+            LexError::ImmediateNotAllowed { line, opcode, operand } => {
+                write!(f, "{}: {:?} takes a register in place of {}, LOAD it into one first", line, opcode, operand)
+            }
+            LexError::BadRegister { line, operand } => write!(f, "{}: malformed register {}", line, operand),
+            LexError::BadLiteral { line, literal } => write!(f, "{}: malformed literal {}", line, literal),
+            LexError::TooManyOperands { line, opcode } => {
+                write!(f, "{}: too many operands, {:?} takes {}", line, opcode, opcode.register_uses().len())
+            }
+            LexError::BadDataDeclaration { line, declaration } => {
+                write!(f, "{}: unknown data declaration {}", line, declaration)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
 
 pub struct Lexer {
     lines: Vec<String>,
     lc: usize,
+    /// Whether `lc` is inside a `.data` section, whose lines declare constants instead of
+    /// instructions.
+    in_data: bool,
+    /// Instruction index each code `label:` refers to, or constant index for a data label,
+    /// collected before any line is lexed so that `@label` can refer forwards.
+    labels: HashMap<String, usize>,
+    /// The constant pool: everything declared in `.data`, followed by any float literals used
+    /// directly in instructions.
+    pub constants: Vec<Constant>,
+    /// The line, counting from 1, that each instruction lexed so far came from.
+    pub source_lines: Vec<usize>,
+    /// Problems found in the data sections and in the lines lexed so far. An instruction with a
+    /// problem is still returned, with 0 in place of the bad operand; a bad declaration adds no
+    /// constant.
+    pub errors: Vec<LexError>,
 }
 
 impl Lexer {
     pub fn new(input: String) -> Self {
-        Lexer {
-            lines: input.split("\n").map(|line| {line.to_owned()}).collect(),
-            lc: 0,
+        let lines: Vec<String> = input.split("\n").map(|line| line.to_owned()).collect();
+
+        let mut labels = HashMap::new();
+        let mut constants = vec![];
+        let mut instruction_count = 0;
+        let mut in_data = false;
+        let mut errors = vec![];
+        for (line_number, line) in lines.iter().enumerate() {
+            let (label, tokens) = split_label(line);
+            match tokens.first().copied() {
+                Some(directive @ (".data" | ".code")) => {
+                    in_data = directive == ".data";
+                    if tokens.len() > 1 {
+                        let declaration = tokens[1..].join(" ");
+                        errors.push(LexError::BadDataDeclaration { line: line_number + 1, declaration });
+                    }
+                }
+                _ if in_data => {
+                    if tokens.is_empty() {
+                        continue;
+                    }
+                    match parse_constant(&tokens, line, line_number + 1) {
+                        Ok(constant) => {
+                            if let Some(label) = label {
+                                labels.insert(label.to_owned(), constants.len());
+                            }
+                            constants.push(constant);
+                        }
+                        Err(error) => errors.push(error),
+                    }
+                }
+                _ => {
+                    if let Some(label) = label {
+                        labels.insert(label.to_owned(), instruction_count);
+                    }
+                    if !tokens.is_empty() {
+                        instruction_count += 1;
+                    }
+                }
+            }
         }
+
+        Lexer { lines, lc: 0, in_data: false, labels, constants, source_lines: vec![], errors }
     }
 
+    /// Lexes the next instruction, skipping blank and label-only lines and data sections.
     pub fn next_line(&mut self) -> Option<Instruction> {
-        if self.lc >= self.lines.len() {
-            return None;
+        let mut string_tokens: Vec<&str> = vec![];
+        while string_tokens.is_empty() {
+            if self.lc >= self.lines.len() {
+                return None;
+            }
+            string_tokens = split_label(&self.lines[self.lc]).1;
+            self.lc += 1;
+            match string_tokens.first().copied() {
+                Some(".data") => self.in_data = true,
+                Some(".code") => self.in_data = false,
+                _ if self.in_data => {}
+                _ => continue,
+            }
+            string_tokens.clear();
         }
-        let mut opcode = Opcode::IGL;
+
+        let mut opcode: Opcode = string_tokens[0].into();
         let mut registers = [0usize; 3];
         let mut integer_operand = 0;
 
-        let string_tokens: Vec<&str> = self.lines[self.lc].split_whitespace().collect();
-        for (index, string_token) in string_tokens.into_iter().enumerate() {
-            if string_token.starts_with('$') {
-                registers[index - 1] = string_token.strip_prefix('$').unwrap().parse().unwrap();
-            } else if string_token.starts_with('#') {
-                integer_operand = string_token.strip_prefix('#').unwrap().parse().unwrap();
-            } else if string_token.starts_with('.') {
-            } else if string_token.starts_with('@') {
-            } else if string_token.ends_with(':') {
-            } else {
-                opcode = string_token.into();
+        let line = self.lc;
+        for (index, string_token) in string_tokens.into_iter().enumerate().skip(1) {
+            let slot = index - 1;
+            if slot >= opcode.register_uses().len() {
+                self.errors.push(LexError::TooManyOperands { line, opcode });
+                break;
+            }
+            if let Some(register) = string_token.strip_prefix('$') {
+                match register.parse() {
+                    Ok(register) => registers[slot] = register,
+                    Err(_) => self.errors.push(LexError::BadRegister { line, operand: string_token.to_string() }),
+                }
+            } else if let Some(number) = string_token.strip_prefix('#') {
+                if opcode.register_uses().get(slot) == Some(&RegisterUse::Constant) {
+                    match number.parse() {
+                        Ok(float) => integer_operand = intern(&mut self.constants, Constant::Float(float)) as i64,
+                        Err(_) => self.errors.push(LexError::BadLiteral { line, literal: string_token.to_string() }),
+                    }
+                } else {
+                    match self.immediate_opcode(opcode, slot, string_token) {
+                        Ok(form) => opcode = form,
+                        Err(error) => self.errors.push(error),
+                    }
+                    match number.parse::<i64>() {
+                        Ok(integer) if !opcode.has_wide_immediate() && i32::try_from(integer).is_err() => {
+                            self.errors.push(LexError::LiteralTooWide { line, literal: number.to_string() });
+                        }
+                        Ok(integer) => integer_operand = integer,
+                        Err(_) => self.errors.push(LexError::BadLiteral { line, literal: string_token.to_string() }),
+                    }
+                }
+            } else if let Some(label) = string_token.strip_prefix('@') {
+                // `@to-@from` is the distance between two labels, which is what relative jumps
+                // take: `from` labels the instruction after the jump.
+                let address = match label.split_once("-@") {
+                    Some((to, from)) => self.label(to).and_then(|to| Ok(to - self.label(from)?)),
+                    None => self.label(label),
+                };
+                integer_operand = address.unwrap_or_else(|error| {
+                    self.errors.push(error);
+                    0
+                });
+                if opcode.register_uses().get(slot) != Some(&RegisterUse::Constant) {
+                    match self.immediate_opcode(opcode, slot, string_token) {
+                        Ok(form) => opcode = form,
+                        Err(error) => self.errors.push(error),
+                    }
+                }
             }
         }
-        self.lc += 1;
+        self.source_lines.push(self.lc);
         Some(Instruction { opcode, registers, integer_operand })
     }
+
+    /// The opcode that takes an immediate in `slot`: `opcode` itself, or its immediate form as
+    /// for `ADD $1 #5 $2`.
+    fn immediate_opcode(&self, opcode: Opcode, slot: usize, operand: &str) -> Result<Opcode, LexError> {
+        for candidate in [Some(opcode), opcode.immediate_form()].into_iter().flatten() {
+            if candidate.register_uses().get(slot) == Some(&RegisterUse::Immediate) {
+                return Ok(candidate);
+            }
+        }
+        return Err(LexError::ImmediateNotAllowed { line: self.lc, opcode, operand: operand.to_string() });
+    }
+
+    fn label(&self, label: &str) -> Result<i64, LexError> {
+        match self.labels.get(label) {
+            Some(address) => Ok(*address as i64),
+            None => Err(LexError::UndefinedLabel { line: self.lc, label: label.to_string() }),
+        }
+    }
+}
+
+/// Index of `constant` in the pool, adding it if it is not already there.
+fn intern(constants: &mut Vec<Constant>, constant: Constant) -> usize {
+    let existing = constants.iter().position(|candidate| match (candidate, &constant) {
+        (Constant::Float(candidate), Constant::Float(float)) => candidate.to_bits() == float.to_bits(),
+        (Constant::String(candidate), Constant::String(string)) => candidate == string,
+        _ => false,
+    });
+    match existing {
+        Some(index) => index,
+        None => {
+            constants.push(constant);
+            constants.len() - 1
+        }
+    }
+}
+
+/// Parses the directive and value of a data section line such as `pi: .float 3.14` or
+/// `greeting: .string "hello, world\n"`.
+fn parse_constant(tokens: &[&str], line: &str, line_number: usize) -> Result<Constant, LexError> {
+    let bad_literal = |literal: &str| LexError::BadLiteral { line: line_number, literal: literal.to_string() };
+    match tokens {
+        [".float", value] => value.parse().map(Constant::Float).map_err(|_| bad_literal(value)),
+        [".string", ..] => {
+            // The literal may contain whitespace, so it is read from the line rather than the tokens.
+            let literal = line[line.find(".string").unwrap() + ".string".len()..].trim();
+            parse_string_literal(literal).map(Constant::String).ok_or_else(|| bad_literal(literal))
+        }
+        _ => Err(LexError::BadDataDeclaration { line: line_number, declaration: tokens.join(" ") }),
+    }
+}
+
+/// Unescapes a double-quoted string literal, accepting the escapes Rust's `{:?}` produces so that
+/// disassembled strings read back unchanged.
+fn parse_string_literal(literal: &str) -> Option<String> {
+    let mut chars = literal.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut string = String::new();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => string.push('\n'),
+            'r' => string.push('\r'),
+            't' => string.push('\t'),
+            '0' => string.push('\0'),
+            'u' => {
+                let digits: String = chars.by_ref().skip(1).take_while(|c| *c != '}').collect();
+                string.push(char::from_u32(u32::from_str_radix(&digits, 16).ok()?)?);
+            }
+            escaped @ ('\\' | '"' | '\'') => string.push(escaped),
+            _ => return None,
+        }
+    }
+    return Some(string);
+}
+
+/// Splits a leading `label:` off a line, returning it along with the remaining tokens.
+fn split_label(line: &str) -> (Option<&str>, Vec<&str>) {
+    let mut tokens: Vec<&str> = line.split_whitespace().collect();
+    match tokens.first().and_then(|token| token.strip_suffix(':')) {
+        Some(label) => {
+            tokens.remove(0);
+            (Some(label), tokens)
+        }
+        None => (None, tokens),
+    }
 }
 
 pub enum Token {
@@ -54,11 +290,11 @@ pub fn parse_program(program: String) -> Vec<Instruction> {
         let opcode: Opcode = instruction_pieces.remove(0).into();
 
         let mut registers = [0usize; 3];
-        let mut integer_operand: i32 = 0;
+        let mut integer_operand: i64 = 0;
 
         for (index, instruction_piece) in instruction_pieces.iter().enumerate() {
             let mut instruction_chars = instruction_piece.chars();
-            let prefix = instruction_chars.nth(0).unwrap();
+            let prefix = instruction_chars.next().unwrap();
             let instruction_piece: String = instruction_chars.collect();
 
             if prefix == '$' {
@@ -81,3 +317,161 @@ pub fn parse_program(program: String) -> Vec<Instruction> {
 
     return instructions;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(input: &str) -> Vec<Instruction> {
+        let mut lexer = Lexer::new(input.to_string());
+        let mut instructions = vec![];
+        while let Some(instruction) = lexer.next_line() {
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    #[test]
+    fn test_labels() {
+        let instructions = lex("LOAD $0 #3\nLOAD $1 @end\n\nloop:\nDEC $0\nLOAD $2 @loop\nJNZ $0 $2\nend: HLT\n");
+        assert_eq!(instructions.len(), 6);
+        assert_eq!(instructions[1], Instruction::new(Opcode::LOAD, [1, 0, 0], 5));
+        assert_eq!(instructions[2], Instruction::new(Opcode::DEC, [0, 0, 0], 0));
+        assert_eq!(instructions[3], Instruction::new(Opcode::LOAD, [2, 0, 0], 2));
+        assert_eq!(instructions[4], Instruction::new(Opcode::JNZ, [0, 2, 0], 0));
+        assert_eq!(instructions[5].opcode, Opcode::HLT);
+    }
+
+    #[test]
+    fn test_relative_labels() {
+        let instructions = lex("LOAD $0 #3\nLOAD $1 @after-@loop\nloop: DEC $0\nNEQI $0 #0\nJEQB $1\nafter: HLT");
+        assert_eq!(instructions[1], Instruction::new(Opcode::LOAD, [1, 0, 0], 3));
+        let mut vm = crate::vm::VM::new();
+        vm.program = instructions.into();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_label_in_register_slot() {
+        let mut lexer = Lexer::new("JMP @end\nLOAD $1 #5\nJNZ $1 @end\nADD #1 $1 $2\nend: HLT".to_string());
+        while lexer.next_line().is_some() {}
+        assert_eq!(
+            lexer.errors,
+            vec![
+                LexError::ImmediateNotAllowed { line: 1, opcode: Opcode::JMP, operand: "@end".to_string() },
+                LexError::ImmediateNotAllowed { line: 3, opcode: Opcode::JNZ, operand: "@end".to_string() },
+                LexError::ImmediateNotAllowed { line: 4, opcode: Opcode::ADD, operand: "#1".to_string() },
+            ]
+        );
+        assert_eq!(lexer.errors[0].to_string(), "1: JMP takes a register in place of @end, LOAD it into one first");
+    }
+
+    #[test]
+    fn test_malformed_operands() {
+        let mut lexer = Lexer::new("LOAD $x #1\nLOAD $0 #abc\nLOADF $0 #abc\nADD $1 $2 $3 $4\nLOAD $0 #99999999999999999999\nHLT".to_string());
+        let mut instructions = vec![];
+        while let Some(instruction) = lexer.next_line() {
+            instructions.push(instruction);
+        }
+        assert_eq!(instructions.len(), 6);
+        assert_eq!(instructions[3], Instruction::new(Opcode::ADD, [1, 2, 3], 0));
+        assert_eq!(
+            lexer.errors,
+            vec![
+                LexError::BadRegister { line: 1, operand: "$x".to_string() },
+                LexError::BadLiteral { line: 2, literal: "#abc".to_string() },
+                LexError::BadLiteral { line: 3, literal: "#abc".to_string() },
+                LexError::TooManyOperands { line: 4, opcode: Opcode::ADD },
+                LexError::BadLiteral { line: 5, literal: "#99999999999999999999".to_string() },
+            ]
+        );
+        assert_eq!(lexer.errors[3].to_string(), "4: too many operands, ADD takes 3");
+    }
+
+    #[test]
+    fn test_bad_data_declarations() {
+        let source = ".data x: .int 4\n.data\nx: .int 4\ny: .float abc\nz: .string \"open\nw: .string \"ok\"\n.code\nLOADS $0 @w";
+        let mut lexer = Lexer::new(source.to_string());
+        assert_eq!(
+            lexer.errors,
+            vec![
+                LexError::BadDataDeclaration { line: 1, declaration: "x: .int 4".to_string() },
+                LexError::BadDataDeclaration { line: 3, declaration: ".int 4".to_string() },
+                LexError::BadLiteral { line: 4, literal: "abc".to_string() },
+                LexError::BadLiteral { line: 5, literal: "\"open".to_string() },
+            ]
+        );
+        assert_eq!(lexer.constants, vec![Constant::String("ok".to_string())]);
+        assert_eq!(lexer.next_line(), Some(Instruction::new(Opcode::LOADS, [0, 0, 0], 0)));
+    }
+
+    #[test]
+    fn test_source_lines() {
+        let mut lexer = Lexer::new(".data\nhalf: .float 0.5\n.code\nLOAD $0 #3\n\nloop:\nDEC $0\nend: HLT\n".to_string());
+        while lexer.next_line().is_some() {}
+        assert_eq!(lexer.source_lines, [4, 7, 8]);
+    }
+
+    #[test]
+    fn test_immediate_operands() {
+        let instructions = lex("ADD $1 #5 $2\nADDI $1 #5 $2\nGTQ $3 #-1\nLOAD $4 #7");
+        assert_eq!(instructions[0], Instruction::new(Opcode::ADDI, [1, 0, 2], 5));
+        assert_eq!(instructions[1], instructions[0]);
+        assert_eq!(instructions[2], Instruction::new(Opcode::GTQI, [3, 0, 0], -1));
+        assert_eq!(instructions[3], Instruction::new(Opcode::LOAD, [4, 0, 0], 7));
+    }
+
+    #[test]
+    fn test_data_section_and_float_literals() {
+        let mut lexer = Lexer::new(".data\nhalf: .float 0.5\n.code\nLOADF $0 @half\nLOADF $1 #1.5\nLOADF $2 #0.5\nHLT".to_string());
+        let mut instructions = vec![];
+        while let Some(instruction) = lexer.next_line() {
+            instructions.push(instruction);
+        }
+        assert_eq!(lexer.constants, vec![Constant::Float(0.5), Constant::Float(1.5)]);
+        assert_eq!(instructions[0], Instruction::new(Opcode::LOADF, [0, 0, 0], 0));
+        assert_eq!(instructions[1], Instruction::new(Opcode::LOADF, [1, 0, 0], 1));
+        assert_eq!(instructions[2], Instruction::new(Opcode::LOADF, [2, 0, 0], 0));
+        assert_eq!(instructions[3].opcode, Opcode::HLT);
+    }
+
+    #[test]
+    fn test_string_constants() {
+        let mut lexer = Lexer::new(".data\ngreeting: .string \"hello,  \\\"world\\\"\\n\"\nsnow: .string \"\\u{2603}\"\n.code\nLOADS $0 @snow".to_string());
+        assert_eq!(
+            lexer.constants,
+            vec![Constant::String("hello,  \"world\"\n".to_string()), Constant::String("\u{2603}".to_string())]
+        );
+        assert_eq!(lexer.next_line(), Some(Instruction::new(Opcode::LOADS, [0, 0, 0], 1)));
+    }
+
+    #[test]
+    fn test_wide_literals() {
+        let instructions = lex("LOADL $2 #5000000000\nLOADL $4 #-9223372036854775808");
+        assert_eq!(instructions[0], Instruction::new(Opcode::LOADL, [2, 0, 0], 5_000_000_000));
+        assert_eq!(instructions[1].integer_operand, i64::MIN);
+    }
+
+    #[test]
+    fn test_narrow_literal_out_of_range() {
+        let mut lexer = Lexer::new("LOAD $0 #5000000000\nADDI $1 #-5000000000 $1".to_string());
+        assert_eq!(lexer.next_line(), Some(Instruction::new(Opcode::LOAD, [0, 0, 0], 0)));
+        lexer.next_line();
+        assert_eq!(lexer.errors.len(), 2);
+        assert_eq!(lexer.errors[0].to_string(), "1: #5000000000 does not fit in 32 bits, use LOADL for 64-bit values");
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let mut lexer = Lexer::new("LOAD $0 @nowhere\nend: LOAD $1 @end-@gone".to_string());
+        while lexer.next_line().is_some() {}
+        assert_eq!(
+            lexer.errors,
+            vec![
+                LexError::UndefinedLabel { line: 1, label: "nowhere".to_string() },
+                LexError::UndefinedLabel { line: 2, label: "gone".to_string() },
+            ]
+        );
+    }
+}
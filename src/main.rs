@@ -1,30 +1,194 @@
-use std::fs;
+#![allow(clippy::needless_return)]
+
+use std::{env, fs};
 
 pub mod vm;
 pub mod instruction;
 pub mod repl;
 pub mod lexer;
+pub mod cfg;
+pub mod verifier;
+pub mod bytecode;
+pub mod heap;
+pub mod lang;
+pub mod ir;
+pub mod process;
+pub mod pool;
+pub mod snapshot;
+pub mod cluster;
+pub mod dispatch;
+pub mod bench;
+pub mod aot;
+pub mod wasm;
+pub mod profile;
+pub mod coverage;
+#[cfg(test)]
+mod testing;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
 
 fn main() {
     // let mut repl = repl::REPL::new();
     // repl.run();
 
+    // Source files ending in `.lang` are compiled, anything else is read as assembly. With
+    // `--ir`, a source file's optimized intermediate representation is printed instead of run.
+    // With `--rust`, the verified program is printed as the source of a standalone Rust binary.
+    // With `--wasm`, it is written as a WebAssembly module next to the source file instead.
+    // With `--jit`, hot blocks are compiled to machine code if the `jit` feature is enabled.
+    // With `--profile`, a report of where the time went is printed after the run, and the call
+    // stacks are written next to the source file for flame graph tools. With `--coverage`, an
+    // assembly program's annotated listing and LCOV tracefile are written next to it.
+    let arguments: Vec<String> = env::args().skip(1).collect();
+
+    // `node <address> [seed...]` runs a cluster node until the process is killed.
+    if arguments.first().map(String::as_str) == Some("node") {
+        let address = arguments.get(1).map(String::as_str).unwrap_or("127.0.0.1:7878");
+        let node = cluster::Node::start(address, &arguments[2.min(arguments.len())..], cluster::NodeConfig::default()).unwrap();
+        eprintln!("listening on {}", node.address());
+        loop {
+            std::thread::park();
+        }
+    }
+
+    // `bench [scale]` compares the two ways of running a program.
+    if arguments.first().map(String::as_str) == Some("bench") {
+        let scale = arguments.get(1).and_then(|scale| scale.parse().ok()).unwrap_or(10);
+        for benchmark in bench::run_benchmarks(scale, 3) {
+            println!(
+                "{:8} step {:>10.2?}  decoded {:>10.2?}  fused {:>10.2?}  {:.2}x",
+                benchmark.name,
+                benchmark.step,
+                benchmark.decoded,
+                benchmark.fused,
+                benchmark.speedup()
+            );
+        }
+        return;
+    }
+
+    let dump_ir = arguments.iter().any(|argument| argument == "--ir");
+    let emit_rust = arguments.iter().any(|argument| argument == "--rust");
+    let emit_wasm = arguments.iter().any(|argument| argument == "--wasm");
+    let use_jit = arguments.iter().any(|argument| argument == "--jit");
+    let use_profiler = arguments.iter().any(|argument| argument == "--profile");
+    let use_coverage = arguments.iter().any(|argument| argument == "--coverage");
+    let path = arguments.into_iter().find(|argument| !argument.starts_with("--")).unwrap_or_else(|| "test.asm".to_string());
+    let source = fs::read_to_string(&path).unwrap();
     let mut vm = vm::VM::new();
-    let asm = fs::read_to_string("test.asm").unwrap();
-    let mut lexer = lexer::Lexer::new(asm);
+    let mut source_lines = vec![];
+    if dump_ir {
+        match lang::compile_to_ir(&source) {
+            Ok(program) => print!("{}", program),
+            Err(error) => eprintln!("{}: {}", path, error),
+        }
+        return;
+    }
+    if path.ends_with(".lang") {
+        match lang::compile(&source) {
+            Ok(program) => vm.program = program.into(),
+            Err(error) => {
+                for line in error.to_string().lines() {
+                    eprintln!("{}:{}", path, line);
+                }
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let mut lexer = lexer::Lexer::new(source.clone());
+        while let Some(instruction) = lexer.next_line() {
+            vm.add_instruction(instruction)
+        }
+        for error in &lexer.errors {
+            eprintln!("{}:{}", path, error);
+        }
+        if !lexer.errors.is_empty() {
+            std::process::exit(1);
+        }
+        vm.constants = lexer.constants;
+        source_lines = lexer.source_lines;
+    }
 
-    loop {
-        match lexer.next_line() {
-            Some(instruction) => {
-                vm.add_instruction(instruction)
+    let verification = verifier::verify(&vm.program, &vm.constants);
+    for warning in &verification.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    for error in &verification.errors {
+        eprintln!("error: {}", error);
+    }
+    if !verification.is_ok() {
+        std::process::exit(1);
+    }
+
+    if emit_rust {
+        match aot::to_rust(&vm.program, &vm.constants, vm.arithmetic_mode) {
+            Ok(source) => print!("{}", source),
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                std::process::exit(1);
             }
-            None => {
-                break;
+        }
+        return;
+    }
+
+    if emit_wasm {
+        let module_path = std::path::Path::new(&path).with_extension("wasm");
+        match wasm::to_wasm(&vm.program, &vm.constants, vm.arithmetic_mode) {
+            Ok(module) => {
+                fs::write(&module_path, module).unwrap();
+                eprintln!("wrote {}", module_path.display());
             }
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if use_profiler {
+        let (profile, result) = profile::run(&mut vm);
+        if let Err(error) = result {
+            eprintln!("error: {}", error);
         }
+        eprint!("{}", profile.report(&vm.program, 10));
+        let stacks_path = std::path::Path::new(&path).with_extension("folded");
+        fs::write(&stacks_path, profile.folded_stacks()).unwrap();
+        eprintln!("wrote {}", stacks_path.display());
+        return;
     }
-    vm.run();
-    for instruction in vm.program {
+
+    if use_coverage {
+        if path.ends_with(".lang") {
+            eprintln!("{}: coverage is only reported for assembly programs", path);
+            std::process::exit(1);
+        }
+        let (coverage, result) = coverage::run(&mut vm);
+        if let Err(error) = result {
+            eprintln!("error: {}", error);
+        }
+        eprintln!("{}", coverage.summary(&vm.program));
+        let listing_path = std::path::Path::new(&path).with_extension("cov");
+        fs::write(&listing_path, coverage.listing(&vm.program, &source, &source_lines)).unwrap();
+        let lcov_path = std::path::Path::new(&path).with_extension("info");
+        fs::write(&lcov_path, coverage.lcov(&vm.program, &path, &source_lines)).unwrap();
+        eprintln!("wrote {} and {}", listing_path.display(), lcov_path.display());
+        return;
+    }
+
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    let result = if use_jit { jit::run(&mut vm) } else { dispatch::run(&mut vm) };
+    #[cfg(not(all(feature = "jit", target_arch = "x86_64", target_os = "linux")))]
+    let result = {
+        if use_jit {
+            eprintln!("warning: built without the jit feature, interpreting");
+        }
+        dispatch::run(&mut vm)
+    };
+    if let Err(error) = &result {
+        eprintln!("error: {}", error);
+    }
+    for instruction in vm.program.iter() {
         print!("{:?} {:?} {} ", instruction.opcode, instruction.registers, instruction.integer_operand);
     }
     println!();
@@ -32,4 +196,7 @@ fn main() {
         print!("{} ", register);
     }
     println!();
+    if result.is_err() {
+        std::process::exit(1);
+    }
 }
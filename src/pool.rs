@@ -0,0 +1,297 @@
+//! Runs many independent VMs in parallel on a pool of OS threads.
+//!
+//! Each worker thread has its own queue of VMs. It runs the VM at the front of its queue for a
+//! quantum of instructions and puts it back at the end if it has not finished. A worker whose
+//! queue is empty steals from the end of another worker's queue, so long-running VMs spread out
+//! over the threads instead of piling up behind one.
+//!
+//! VMs should share one `ProgramImage`, so the instructions are not copied per instance.
+
+use std::{
+    any::Any,
+    collections::VecDeque,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::vm::{VmError, DEFAULT_QUANTUM, VM};
+
+// The pool moves VMs between threads, which only works as long as a `VM` is `Send`.
+const _: () = {
+    const fn assert_send<T: Send>() {}
+    assert_send::<VM>();
+};
+
+/// Why a VM run on the pool did not finish.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Failure {
+    /// The VM trapped, or was suspended waiting for a process runtime.
+    Trapped(VmError),
+    /// Stepping the VM panicked, which only a program the verifier rejects should do.
+    Panicked(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Trapped(error) => write!(f, "{}", error),
+            Failure::Panicked(message) => write!(f, "VM panicked: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// How a VM run on the pool ended.
+#[derive(Debug)]
+pub struct Outcome {
+    pub vm: VM,
+    /// `Ok` once every thread has finished, or why the VM stopped before that.
+    pub result: Result<(), Failure>,
+    /// How many steps the VM took.
+    pub instructions: u64,
+}
+
+/// Throughput of a whole `Pool::run`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    pub instances: usize,
+    pub instructions: u64,
+    pub elapsed: Duration,
+    /// How many times a worker took a VM from another worker's queue.
+    pub steals: u64,
+    /// Instructions run by each worker thread.
+    pub thread_instructions: Vec<u64>,
+}
+
+impl Metrics {
+    pub fn instructions_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        return self.instructions as f64 / seconds;
+    }
+}
+
+/// A VM waiting for its next turn, with where its outcome goes.
+struct Task {
+    index: usize,
+    vm: VM,
+    instructions: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pool {
+    pub threads: usize,
+    /// How many instructions a VM runs before the worker moves on to the next one in its queue.
+    pub quantum: usize,
+}
+
+impl Pool {
+    /// A pool with `threads` workers, at least one.
+    pub fn new(threads: usize) -> Pool {
+        Pool { threads: threads.max(1), quantum: DEFAULT_QUANTUM }
+    }
+
+    /// Runs every VM until it finishes, traps, is suspended or panics. Outcomes are in the same
+    /// order as `vms`.
+    pub fn run(&self, vms: Vec<VM>) -> (Vec<Outcome>, Metrics) {
+        let start = Instant::now();
+        let instances = vms.len();
+        let queues: Vec<Mutex<VecDeque<Task>>> = (0..self.threads).map(|_| Mutex::new(VecDeque::new())).collect();
+        for (index, vm) in vms.into_iter().enumerate() {
+            queues[index % self.threads].lock().unwrap().push_back(Task { index, vm, instructions: 0 });
+        }
+        let remaining = AtomicUsize::new(instances);
+        let outcomes: Mutex<Vec<Option<Outcome>>> = Mutex::new((0..instances).map(|_| None).collect());
+
+        let worker_metrics: Vec<(u64, u64)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.threads)
+                .map(|id| {
+                    let (queues, remaining, outcomes) = (&queues, &remaining, &outcomes);
+                    scope.spawn(move || self.work(id, queues, remaining, outcomes))
+                })
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+
+        let outcomes: Vec<Outcome> = outcomes.into_inner().unwrap().into_iter().map(|outcome| outcome.unwrap()).collect();
+        let metrics = Metrics {
+            instances,
+            instructions: outcomes.iter().map(|outcome| outcome.instructions).sum(),
+            elapsed: start.elapsed(),
+            steals: worker_metrics.iter().map(|(_, steals)| steals).sum(),
+            thread_instructions: worker_metrics.iter().map(|(instructions, _)| *instructions).collect(),
+        };
+        return (outcomes, metrics);
+    }
+
+    /// One worker's loop. Returns how many instructions it ran and how many VMs it stole.
+    fn work(&self, id: usize, queues: &[Mutex<VecDeque<Task>>], remaining: &AtomicUsize, outcomes: &Mutex<Vec<Option<Outcome>>>) -> (u64, u64) {
+        let (mut instructions, mut steals) = (0, 0);
+        while remaining.load(Ordering::Acquire) > 0 {
+            let own = queues[id].lock().unwrap().pop_front();
+            let task = match own {
+                Some(task) => Some(task),
+                None => {
+                    let stolen = (1..queues.len()).find_map(|offset| queues[(id + offset) % queues.len()].lock().unwrap().pop_back());
+                    steals += stolen.is_some() as u64;
+                    stolen
+                }
+            };
+            let Some(mut task) = task else {
+                // Every VM left is in some worker's hands right now.
+                thread::yield_now();
+                continue;
+            };
+
+            // A VM that panics fails on its own rather than taking the worker, and with it every
+            // other worker waiting on `remaining`, down with it.
+            let mut steps = 0;
+            let slice = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut result = Ok(true);
+                for _ in 0..self.quantum {
+                    steps += 1;
+                    result = task.vm.step();
+                    if !matches!(result, Ok(true)) {
+                        break;
+                    }
+                }
+                result
+            }));
+            task.instructions += steps;
+            instructions += steps;
+            let result = match slice {
+                Ok(Ok(true)) => {
+                    queues[id].lock().unwrap().push_back(task);
+                    continue;
+                }
+                Ok(Ok(false)) => task.vm.check_suspension().map_err(Failure::Trapped),
+                Ok(Err(error)) => Err(Failure::Trapped(error)),
+                Err(payload) => Err(Failure::Panicked(panic_message(payload.as_ref()))),
+            };
+            let outcome = Outcome { vm: task.vm, result, instructions: task.instructions };
+            outcomes.lock().unwrap()[task.index] = Some(outcome);
+            remaining.fetch_sub(1, Ordering::AcqRel);
+        }
+        return (instructions, steals);
+    }
+}
+
+/// The message a panic was started with, if it was a string.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => return message.to_string(),
+        (_, Some(message)) => return message.clone(),
+        _ => return "unknown cause".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instruction::{Instruction, Opcode},
+        vm::{ProgramImage, REGISTER_COUNT},
+    };
+
+    #[test]
+    fn test_parallel_vms_share_program() {
+        // Counts $1 down from the value each VM starts with, adding it to $0.
+        let program: ProgramImage = vec![
+            Instruction::new(Opcode::LOAD, [2, 0, 0], 1),
+            Instruction::new(Opcode::ADD, [0, 1, 0], 0),
+            Instruction::new(Opcode::DEC, [1, 0, 0], 0),
+            Instruction::new(Opcode::JNZ, [1, 2, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ]
+        .into();
+        let vms: Vec<VM> = (0..64)
+            .map(|index| {
+                let mut vm = VM::new();
+                vm.program = program.clone();
+                vm.registers[1] = 100 + index;
+                vm
+            })
+            .collect();
+
+        let mut pool = Pool::new(4);
+        pool.quantum = 50;
+        let (outcomes, metrics) = pool.run(vms);
+        for (index, outcome) in outcomes.iter().enumerate() {
+            let n = 100 + index as i32;
+            assert_eq!(outcome.result, Ok(()));
+            assert_eq!(outcome.vm.registers[0], n * (n + 1) / 2);
+            // Running the program never copied it.
+            assert!(outcome.vm.program.is_shared_with(&program));
+        }
+
+        assert_eq!(metrics.instances, 64);
+        assert_eq!(metrics.instructions, outcomes.iter().map(|outcome| outcome.instructions).sum::<u64>());
+        assert_eq!(metrics.thread_instructions.len(), 4);
+        assert_eq!(metrics.thread_instructions.iter().sum::<u64>(), metrics.instructions);
+    }
+
+    #[test]
+    fn test_errors_are_reported_per_vm() {
+        let program: ProgramImage = vec![Instruction::new(Opcode::DIV, [0, 1, 2], 0), Instruction::new(Opcode::HLT, [0; 3], 0)].into();
+        let vms: Vec<VM> = (0..8)
+            .map(|index| {
+                let mut vm = VM::new();
+                vm.program = program.clone();
+                vm.registers[0] = 10;
+                vm.registers[1] = index % 2;
+                vm
+            })
+            .collect();
+        let (outcomes, _) = Pool::new(3).run(vms);
+        for (index, outcome) in outcomes.iter().enumerate() {
+            if index % 2 == 0 {
+                assert_eq!(outcome.result, Err(Failure::Trapped(VmError::DivisionByZero { pc: 0 })));
+            } else {
+                assert_eq!(outcome.result, Ok(()));
+                assert_eq!(outcome.vm.registers[2], 10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_suspended_vm_fails() {
+        let program: ProgramImage = vec![Instruction::new(Opcode::RECV, [0, 1, 0], -1), Instruction::new(Opcode::HLT, [0; 3], 0)].into();
+        let mut vm = VM::new();
+        vm.program = program;
+        let (outcomes, _) = Pool::new(2).run(vec![vm]);
+        assert_eq!(outcomes[0].result, Err(Failure::Trapped(VmError::Suspended { pc: 0 })));
+    }
+
+    #[test]
+    fn test_panicking_vm_fails_alone() {
+        // Register operands are only checked by the verifier, so stepping this indexes past the
+        // end of the registers.
+        let broken: ProgramImage = vec![Instruction::new(Opcode::LOAD, [REGISTER_COUNT, 0, 0], 1)].into();
+        let program: ProgramImage = vec![Instruction::new(Opcode::LOAD, [0, 0, 0], 7), Instruction::new(Opcode::HLT, [0; 3], 0)].into();
+        let vms: Vec<VM> = (0..6)
+            .map(|index| {
+                let mut vm = VM::new();
+                vm.program = if index == 2 { broken.clone() } else { program.clone() };
+                vm
+            })
+            .collect();
+        let (outcomes, _) = Pool::new(3).run(vms);
+        for (index, outcome) in outcomes.iter().enumerate() {
+            if index == 2 {
+                assert!(matches!(&outcome.result, Err(Failure::Panicked(message)) if message.contains("out of bounds")));
+            } else {
+                assert_eq!(outcome.result, Ok(()));
+                assert_eq!(outcome.vm.registers[0], 7);
+            }
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! Isolated processes that share nothing and talk by sending each other messages.
+//!
+//! Every process is a `VM` of its own, with its own registers, threads and heap, running the
+//! same shared program image. `SEND $pid $value` puts a message in another process's mailbox and
+//! `RECV $value $sender #timeout` takes one out, waiting if there is none. A process that traps
+//! with a `VmError` stops, and the process that spawned it, its supervisor, is sent a
+//! `MessageKind::Failure` message from it, which sets the overflow flag when `RECV` takes it.
+//!
+//! Time is counted in ticks, one per instruction any process runs. When every process is waiting
+//! for a message, the clock jumps to the earliest receive timeout.
+
+use crate::{
+    instruction::{Constant, Instruction},
+    vm::{Message, MessageKind, ProgramImage, Suspension, VmError, DEFAULT_QUANTUM, REGISTER_COUNT, VM},
+};
+
+/// The sender of messages from `Runtime::send`, which `RECV` sees as -1.
+pub const HOST_PID: usize = usize::MAX;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProcessState {
+    Runnable,
+    /// Waiting in `RECV` until a message arrives or the clock reaches the deadline.
+    Receiving { deadline: Option<u64> },
+    /// Halted or ran off the end of the program.
+    Exited,
+    Failed(VmError),
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pub vm: VM,
+    pub state: ProcessState,
+    /// The process to tell if this one fails.
+    pub supervisor: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct Runtime {
+    pub program: ProgramImage,
+    pub constants: Vec<Constant>,
+    /// Every process spawned so far, indexed by pid.
+    pub processes: Vec<Process>,
+    /// How many instructions a process runs before the next one gets a turn.
+    pub quantum: usize,
+    pub clock: u64,
+}
+
+impl Runtime {
+    pub fn new(program: Vec<Instruction>, constants: Vec<Constant>) -> Runtime {
+        Runtime { program: program.into(), constants, processes: vec![], quantum: DEFAULT_QUANTUM, clock: 0 }
+    }
+
+    /// Starts a process at `pc` with zeroed registers, returning its pid.
+    pub fn spawn(&mut self, pc: usize, supervisor: Option<usize>) -> usize {
+        self.spawn_with_registers(pc, [0; REGISTER_COUNT], supervisor)
+    }
+
+    fn spawn_with_registers(&mut self, pc: usize, registers: [i32; REGISTER_COUNT], supervisor: Option<usize>) -> usize {
+        let mut vm = VM::new();
+        vm.program = self.program.clone();
+        vm.constants = self.constants.clone();
+        vm.registers = registers;
+        vm.pc = pc;
+        vm.pid = self.processes.len();
+        self.processes.push(Process { vm, state: ProcessState::Runnable, supervisor });
+        return self.processes.len() - 1;
+    }
+
+    /// Sends a message from outside every process.
+    pub fn send(&mut self, to: usize, value: i32) {
+        self.deliver(to, Message { from: HOST_PID, value, kind: MessageKind::Sent });
+    }
+
+    /// Puts a message in a mailbox, waking the process if it is waiting for one. Messages to
+    /// processes that do not exist or have stopped are dropped.
+    fn deliver(&mut self, to: usize, message: Message) {
+        let Some(process) = self.processes.get_mut(to) else {
+            return;
+        };
+        match process.state {
+            ProcessState::Exited | ProcessState::Failed(_) => {}
+            ProcessState::Receiving { .. } => {
+                process.vm.mailbox.push_back(message);
+                process.vm.suspension = None;
+                process.state = ProcessState::Runnable;
+            }
+            ProcessState::Runnable => process.vm.mailbox.push_back(message),
+        }
+    }
+
+    /// Runs processes in turn until none can make progress: every process has stopped or is
+    /// waiting forever for a message.
+    pub fn run(&mut self) {
+        loop {
+            let mut progressed = false;
+            let mut pid = 0;
+            // Processes spawned during this round get their first turn in it too.
+            while pid < self.processes.len() {
+                if self.processes[pid].state == ProcessState::Runnable {
+                    self.run_slice(pid);
+                    progressed = true;
+                }
+                pid += 1;
+            }
+            self.time_out_receives();
+            if progressed {
+                continue;
+            }
+            let deadline = self
+                .processes
+                .iter()
+                .filter_map(|process| match process.state {
+                    ProcessState::Receiving { deadline } => deadline,
+                    _ => None,
+                })
+                .min();
+            match deadline {
+                Some(deadline) => {
+                    self.clock = self.clock.max(deadline);
+                    self.time_out_receives();
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Runs a process for up to a quantum of instructions, then acts on whatever it asked for.
+    fn run_slice(&mut self, pid: usize) {
+        let mut result = Ok(true);
+        for _ in 0..self.quantum {
+            self.clock += 1;
+            result = self.processes[pid].vm.step();
+            if !matches!(result, Ok(true)) {
+                break;
+            }
+        }
+
+        for (to, message) in std::mem::take(&mut self.processes[pid].vm.outbox) {
+            self.deliver(to, message);
+        }
+        match result {
+            Ok(true) => {}
+            Ok(false) => match self.processes[pid].vm.suspension {
+                Some(Suspension::Spawn { pc, register }) => {
+                    let registers = self.processes[pid].vm.registers;
+                    let child = self.spawn_with_registers(pc, registers, Some(pid));
+                    let vm = &mut self.processes[pid].vm;
+                    vm.registers[register] = child as i32;
+                    vm.suspension = None;
+                }
+                Some(Suspension::Receive { timeout }) => {
+                    let deadline = timeout.map(|timeout| self.clock + timeout);
+                    self.processes[pid].state = ProcessState::Receiving { deadline };
+                }
+                None => self.processes[pid].state = ProcessState::Exited,
+            },
+            Err(error) => {
+                self.processes[pid].state = ProcessState::Failed(error);
+                if let Some(supervisor) = self.processes[pid].supervisor {
+                    self.deliver(supervisor, Message { from: pid, value: 0, kind: MessageKind::Failure });
+                }
+            }
+        }
+    }
+
+    fn time_out_receives(&mut self) {
+        for process in &mut self.processes {
+            if let ProcessState::Receiving { deadline: Some(deadline) } = process.state {
+                if deadline <= self.clock {
+                    process.vm.cancel_receive();
+                    process.state = ProcessState::Runnable;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+
+    #[test]
+    fn test_message_passing() {
+        // Process 0 sends 21 to a worker it spawns, which doubles it and sends it back.
+        let mut runtime = Runtime::new(
+            vec![
+                Instruction::new(Opcode::LOAD, [1, 0, 0], 6),
+                Instruction::new(Opcode::SPAWNP, [1, 2, 0], 0),
+                Instruction::new(Opcode::LOAD, [3, 0, 0], 21),
+                Instruction::new(Opcode::SEND, [2, 3, 0], 0),
+                Instruction::new(Opcode::RECV, [4, 5, 0], -1),
+                Instruction::new(Opcode::HLT, [0; 3], 0),
+                Instruction::new(Opcode::RECV, [0, 1, 0], -1),
+                Instruction::new(Opcode::ADD, [0, 0, 0], 0),
+                Instruction::new(Opcode::SEND, [1, 0, 0], 0),
+                Instruction::new(Opcode::HLT, [0; 3], 0),
+            ],
+            vec![],
+        );
+        runtime.spawn(0, None);
+        runtime.run();
+        let main = &runtime.processes[0];
+        assert_eq!(main.vm.registers[4..6], [42, 1]);
+        assert!(main.vm.equal);
+        assert!(runtime.processes.iter().all(|process| process.state == ProcessState::Exited));
+        assert_eq!(runtime.processes[1].supervisor, Some(0));
+
+        // Nothing ever arrives, so the receive gives up once the clock passes its timeout.
+        let mut runtime = Runtime::new(vec![Instruction::new(Opcode::RECV, [0, 1, 0], 50), Instruction::new(Opcode::HLT, [0; 3], 0)], vec![]);
+        runtime.spawn(0, None);
+        runtime.run();
+        assert!(!runtime.processes[0].vm.equal);
+        assert_eq!(runtime.processes[0].state, ProcessState::Exited);
+        assert!(runtime.clock >= 50);
+
+        // Without a timeout it waits until the host sends something.
+        let mut runtime = Runtime::new(vec![Instruction::new(Opcode::RECV, [0, 1, 0], -1), Instruction::new(Opcode::HLT, [0; 3], 0)], vec![]);
+        runtime.spawn(0, None);
+        runtime.run();
+        assert_eq!(runtime.processes[0].state, ProcessState::Receiving { deadline: None });
+        runtime.send(0, 7);
+        runtime.run();
+        assert_eq!(runtime.processes[0].vm.registers[..2], [7, -1]);
+        assert_eq!(runtime.processes[0].state, ProcessState::Exited);
+    }
+
+    #[test]
+    fn test_supervisor_is_told_of_failure() {
+        let mut runtime = Runtime::new(
+            vec![
+                Instruction::new(Opcode::LOAD, [1, 0, 0], 4),
+                Instruction::new(Opcode::SPAWNP, [1, 2, 0], 0),
+                Instruction::new(Opcode::RECV, [3, 4, 0], -1),
+                Instruction::new(Opcode::HLT, [0; 3], 0),
+                Instruction::new(Opcode::LOAD, [5, 0, 0], 0),
+                Instruction::new(Opcode::DIV, [5, 5, 6], 0),
+                Instruction::new(Opcode::HLT, [0; 3], 0),
+            ],
+            vec![],
+        );
+        runtime.spawn(0, None);
+        runtime.run();
+        assert_eq!(runtime.processes[0].vm.registers[3..5], [0, 1]);
+        assert!(runtime.processes[0].vm.overflow);
+        assert_eq!(runtime.processes[1].state, ProcessState::Failed(VmError::DivisionByZero { pc: 5 }));
+    }
+
+    #[test]
+    fn test_sent_messages_are_not_failures() {
+        // The child sends its supervisor the value failure notices used to have and then fails.
+        // Only the second message the supervisor takes sets the overflow flag, so it reaches the
+        // `LOAD` of $10 rather than jumping to the `HLT`.
+        let mut runtime = Runtime::new(
+            vec![
+                Instruction::new(Opcode::LOAD, [1, 0, 0], 9),
+                Instruction::new(Opcode::SPAWNP, [1, 2, 0], 0),
+                Instruction::new(Opcode::LOAD, [9, 0, 0], 8),
+                Instruction::new(Opcode::RECV, [3, 4, 0], -1),
+                Instruction::new(Opcode::JO, [9, 0, 0], 0),
+                Instruction::new(Opcode::RECV, [5, 6, 0], -1),
+                Instruction::new(Opcode::JNO, [9, 0, 0], 0),
+                Instruction::new(Opcode::LOAD, [10, 0, 0], 1),
+                Instruction::new(Opcode::HLT, [0; 3], 0),
+                Instruction::new(Opcode::LOAD, [3, 0, 0], i32::MIN as i64),
+                Instruction::new(Opcode::SEND, [0, 3, 0], 0),
+                Instruction::new(Opcode::IGL, [0; 3], 0),
+            ],
+            vec![],
+        );
+        runtime.spawn(0, None);
+        runtime.run();
+        let supervisor = &runtime.processes[0];
+        assert_eq!(supervisor.state, ProcessState::Exited);
+        assert_eq!(supervisor.vm.registers[3..7], [i32::MIN, 1, 0, 1]);
+        assert_eq!(supervisor.vm.registers[10], 1);
+    }
+
+    #[test]
+    fn test_thousands_of_processes() {
+        // Each worker sends its pid to process 0, which adds them up.
+        let mut runtime = Runtime::new(
+            vec![
+                Instruction::new(Opcode::LOAD, [2, 0, 0], 1000),
+                Instruction::new(Opcode::LOAD, [7, 0, 0], 2),
+                Instruction::new(Opcode::RECV, [3, 4, 0], -1),
+                Instruction::new(Opcode::ADD, [1, 3, 1], 0),
+                Instruction::new(Opcode::DEC, [2, 0, 0], 0),
+                Instruction::new(Opcode::JNZ, [2, 7, 0], 0),
+                Instruction::new(Opcode::HLT, [0; 3], 0),
+                Instruction::new(Opcode::SELF, [0, 0, 0], 0),
+                Instruction::new(Opcode::LOAD, [1, 0, 0], 0),
+                Instruction::new(Opcode::SEND, [1, 0, 0], 0),
+                Instruction::new(Opcode::HLT, [0; 3], 0),
+            ],
+            vec![],
+        );
+        runtime.spawn(0, None);
+        for _ in 0..1000 {
+            runtime.spawn(7, Some(0));
+        }
+        runtime.run();
+        assert_eq!(runtime.processes[0].vm.registers[1], 500500);
+        assert!(runtime.processes.iter().all(|process| process.state == ProcessState::Exited));
+    }
+}
@@ -0,0 +1,350 @@
+//! A profiling mode for running programs. `Profiler::run` steps the VM like `VM::run`, counting
+//! and timing every instruction it runs. It also follows each thread's calls, so instructions can
+//! be attributed to the functions they ran in.
+//!
+//! Times are the wall time from one step to the next, so they include the profiler's own
+//! bookkeeping. They are best compared with each other rather than with an unprofiled run.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cfg::ControlFlowGraph,
+    instruction::{Instruction, Opcode},
+    vm::{VmError, VM},
+};
+
+/// A function call, or the start of the program, reached through a particular chain of calls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// The frame that made the call, or `None` for the root.
+    pub caller: Option<usize>,
+    /// The pc the called function starts at, or 0 for the root.
+    pub entry: usize,
+    /// Instructions run in this frame, not counting the functions it called.
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// How many times each instruction ran, by pc.
+    pub counts: Vec<u64>,
+    /// The time spent running each instruction, by pc.
+    pub times: Vec<Duration>,
+    /// Every call stack the program ran in, as a tree whose root, frame 0, is the program start.
+    pub frames: Vec<Frame>,
+    pub total_time: Duration,
+}
+
+impl Profile {
+    pub fn new(program_length: usize) -> Profile {
+        Profile {
+            counts: vec![0; program_length],
+            times: vec![Duration::ZERO; program_length],
+            frames: vec![Frame { caller: None, entry: 0, instructions: 0, time: Duration::ZERO }],
+            total_time: Duration::ZERO,
+        }
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Instructions run and time spent for every opcode that ran, most instructions first.
+    pub fn opcodes(&self, program: &[Instruction]) -> Vec<(Opcode, u64, Duration)> {
+        let mut opcodes: Vec<(Opcode, u64, Duration)> = vec![];
+        for (pc, instruction) in program.iter().enumerate() {
+            if self.counts[pc] == 0 {
+                continue;
+            }
+            match opcodes.iter_mut().find(|(opcode, ..)| *opcode == instruction.opcode) {
+                Some((_, count, time)) => {
+                    *count += self.counts[pc];
+                    *time += self.times[pc];
+                }
+                None => opcodes.push((instruction.opcode, self.counts[pc], self.times[pc])),
+            }
+        }
+        opcodes.sort_by_key(|(_, count, _)| std::cmp::Reverse(*count));
+        return opcodes;
+    }
+
+    /// The basic blocks that ran, longest time first.
+    pub fn blocks(&self, program: &[Instruction]) -> Vec<BlockProfile> {
+        let mut blocks: Vec<BlockProfile> = ControlFlowGraph::build(program)
+            .blocks
+            .iter()
+            .map(|block| BlockProfile {
+                start: block.start,
+                end: block.end,
+                entries: self.counts[block.start],
+                instructions: self.counts[block.start..block.end].iter().sum(),
+                time: self.times[block.start..block.end].iter().sum(),
+            })
+            .filter(|block| block.instructions > 0)
+            .collect();
+        blocks.sort_by_key(|block| std::cmp::Reverse(block.time));
+        return blocks;
+    }
+
+    /// Instructions run and time spent in each function, not counting its callees, by entry pc.
+    /// The longest time comes first.
+    pub fn functions(&self) -> Vec<(usize, u64, Duration)> {
+        let mut functions: Vec<(usize, u64, Duration)> = vec![];
+        for frame in &self.frames {
+            match functions.iter_mut().find(|(entry, ..)| *entry == frame.entry) {
+                Some((_, instructions, time)) => {
+                    *instructions += frame.instructions;
+                    *time += frame.time;
+                }
+                None => functions.push((frame.entry, frame.instructions, frame.time)),
+            }
+        }
+        functions.retain(|(_, instructions, _)| *instructions > 0);
+        functions.sort_by_key(|(_, _, time)| std::cmp::Reverse(*time));
+        return functions;
+    }
+
+    /// The call stacks in the folded format flame graph tools read: one line per stack, with the
+    /// functions from the outermost in, separated by `;`, then the instructions run in it.
+    /// Functions are named `main` for the program start and `@<entry pc>` otherwise.
+    pub fn folded_stacks(&self) -> String {
+        let mut lines: Vec<String> = vec![];
+        for (id, frame) in self.frames.iter().enumerate() {
+            if frame.instructions == 0 {
+                continue;
+            }
+            let mut names = vec![];
+            let mut current = Some(id);
+            while let Some(id) = current {
+                names.push(function_name(self.frames[id].entry, id));
+                current = self.frames[id].caller;
+            }
+            names.reverse();
+            lines.push(format!("{} {}", names.join(";"), frame.instructions));
+        }
+        lines.sort();
+        return lines.iter().map(|line| format!("{}\n", line)).collect();
+    }
+
+    /// A report of where the time went, listing at most `limit` entries in each section.
+    pub fn report(&self, program: &[Instruction], limit: usize) -> String {
+        let total = self.total_time.as_secs_f64().max(f64::MIN_POSITIVE);
+        let share = |time: Duration| 100.0 * time.as_secs_f64() / total;
+        let mut report = String::new();
+        writeln!(report, "{} instructions in {:.2?}", self.instructions(), self.total_time).unwrap();
+
+        writeln!(report, "\nhottest blocks").unwrap();
+        writeln!(report, "{:>10} {:>6} {:>12} {:>10}  block", "time", "%", "instructions", "entries").unwrap();
+        for block in self.blocks(program).iter().take(limit) {
+            let first = program[block.start];
+            writeln!(
+                report,
+                "{:>10.2?} {:>6.1} {:>12} {:>10}  {}..{} ({:?} ...)",
+                block.time,
+                share(block.time),
+                block.instructions,
+                block.entries,
+                block.start,
+                block.end,
+                first.opcode
+            )
+            .unwrap();
+        }
+
+        writeln!(report, "\nhottest instructions").unwrap();
+        writeln!(report, "{:>10} {:>6} {:>12} {:>6}  instruction", "time", "%", "count", "pc").unwrap();
+        let mut pcs: Vec<usize> = (0..program.len()).filter(|pc| self.counts[*pc] > 0).collect();
+        pcs.sort_by_key(|pc| std::cmp::Reverse(self.times[*pc]));
+        for pc in pcs.into_iter().take(limit) {
+            let instruction = program[pc];
+            writeln!(
+                report,
+                "{:>10.2?} {:>6.1} {:>12} {:>6}  {:?} {:?} {}",
+                self.times[pc],
+                share(self.times[pc]),
+                self.counts[pc],
+                pc,
+                instruction.opcode,
+                instruction.registers,
+                instruction.integer_operand
+            )
+            .unwrap();
+        }
+
+        writeln!(report, "\nfunctions, excluding their callees").unwrap();
+        writeln!(report, "{:>10} {:>6} {:>12}  function", "time", "%", "instructions").unwrap();
+        for (entry, instructions, time) in self.functions().into_iter().take(limit) {
+            writeln!(report, "{:>10.2?} {:>6.1} {:>12}  {}", time, share(time), instructions, function_name(entry, entry)).unwrap();
+        }
+
+        writeln!(report, "\nopcodes").unwrap();
+        writeln!(report, "{:>10} {:>6} {:>12}  opcode", "time", "%", "count").unwrap();
+        for (opcode, count, time) in self.opcodes(program).into_iter().take(limit) {
+            writeln!(report, "{:>10.2?} {:>6.1} {:>12}  {:?}", time, share(time), count, opcode).unwrap();
+        }
+        return report;
+    }
+}
+
+/// How a basic block ran.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockProfile {
+    pub start: usize,
+    /// Pc one past the last instruction in the block.
+    pub end: usize,
+    /// How many times the block was entered, which is how often its first instruction ran.
+    pub entries: u64,
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+/// `main` for the root frame, whose id is 0, and `@<entry pc>` for functions.
+fn function_name(entry: usize, id: usize) -> String {
+    if id == 0 {
+        return "main".to_string();
+    }
+    return format!("@{}", entry);
+}
+
+pub struct Profiler {
+    pub profile: Profile,
+    /// Frame ids by their caller's id and their entry pc.
+    callees: HashMap<(usize, usize), usize>,
+    /// The frames each thread is in, innermost last, by thread id.
+    stacks: HashMap<usize, Vec<usize>>,
+}
+
+impl Profiler {
+    pub fn new(program_length: usize) -> Profiler {
+        Profiler { profile: Profile::new(program_length), callees: HashMap::new(), stacks: HashMap::new() }
+    }
+
+    /// Runs until every thread has finished, failing if the VM is suspended, like `VM::run`,
+    /// adding to the profile as it goes. What ran before an error stays in the profile.
+    pub fn run(&mut self, vm: &mut VM) -> Result<(), VmError> {
+        let started = Instant::now();
+        let mut last = started;
+        let result = loop {
+            let (thread, pc, depth) = (vm.current_thread, vm.pc, vm.call_stack.len());
+            let result = vm.step();
+            let now = Instant::now();
+            if pc < self.profile.counts.len() {
+                self.record(vm, thread, pc, depth, now - last);
+            }
+            last = now;
+            match result {
+                Ok(true) => {}
+                Ok(false) => break vm.check_suspension(),
+                Err(error) => break Err(error),
+            }
+        };
+        self.profile.total_time += started.elapsed();
+        return result;
+    }
+
+    /// Adds the instruction at `pc`, which `thread` has just run with `depth` calls in progress,
+    /// then follows any call or return it made.
+    fn record(&mut self, vm: &VM, thread: usize, pc: usize, depth: usize, elapsed: Duration) {
+        self.profile.counts[pc] += 1;
+        self.profile.times[pc] += elapsed;
+        let stack = self.stacks.entry(thread).or_insert_with(|| vec![0]);
+        let frame = *stack.last().unwrap();
+        self.profile.frames[frame].instructions += 1;
+        self.profile.frames[frame].time += elapsed;
+
+        // The step may have switched threads, leaving this one's state in its context.
+        let (new_depth, new_pc) = if vm.current_thread == thread {
+            (vm.call_stack.len(), vm.pc)
+        } else {
+            let context = &vm.threads[thread].context;
+            (context.call_stack.len(), context.pc)
+        };
+        if new_depth > depth {
+            let frames = &mut self.profile.frames;
+            let callee = *self.callees.entry((frame, new_pc)).or_insert_with(|| {
+                frames.push(Frame { caller: Some(frame), entry: new_pc, instructions: 0, time: Duration::ZERO });
+                frames.len() - 1
+            });
+            stack.push(callee);
+        } else if new_depth < depth {
+            let returns = depth - new_depth;
+            stack.truncate(stack.len().saturating_sub(returns).max(1));
+        }
+    }
+}
+
+/// Runs `vm`'s program with a new `Profiler`, returning the profile along with how the run
+/// ended.
+pub fn run(vm: &mut VM) -> (Profile, Result<(), VmError>) {
+    let mut profiler = Profiler::new(vm.program.len());
+    let result = profiler.run(vm);
+    return (profiler.profile, result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang;
+
+    fn profile(program: Vec<Instruction>) -> (Vec<Instruction>, Profile) {
+        let mut vm = VM::new();
+        vm.program = program.clone().into();
+        let (profile, result) = run(&mut vm);
+        result.unwrap();
+        return (program, profile);
+    }
+
+    #[test]
+    fn test_counts_instructions_blocks_and_opcodes() {
+        // Counts $0 down from 3.
+        let (program, profile) = profile(vec![
+            Instruction::new(Opcode::LOAD, [0, 0, 0], 3),
+            Instruction::new(Opcode::LOAD, [1, 0, 0], 2),
+            Instruction::new(Opcode::DEC, [0, 0, 0], 0),
+            Instruction::new(Opcode::JNZ, [0, 1, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ]);
+        assert_eq!(profile.counts, [1, 1, 3, 3, 1]);
+        assert_eq!(profile.instructions(), 9);
+        assert!(profile.times.iter().sum::<Duration>() <= profile.total_time);
+
+        let blocks = profile.blocks(&program);
+        let block = blocks.iter().find(|block| block.start == 2).unwrap();
+        assert_eq!((block.end, block.entries, block.instructions), (4, 3, 6));
+        assert_eq!(blocks.iter().map(|block| block.instructions).sum::<u64>(), 9);
+
+        let opcodes: Vec<(Opcode, u64)> = profile.opcodes(&program).iter().map(|(opcode, count, _)| (*opcode, *count)).collect();
+        assert_eq!(opcodes[..2], [(Opcode::DEC, 3), (Opcode::JNZ, 3)]);
+        assert_eq!(opcodes[2..].iter().map(|(_, count)| count).sum::<u64>(), 3);
+
+        let report = profile.report(&program, 5);
+        assert!(report.starts_with("9 instructions in "));
+        assert!(report.contains("2..4 (DEC ...)"));
+    }
+
+    #[test]
+    fn test_folded_stacks_follow_calls() {
+        let source = "fn fib(n) {\n    if n < 2 { return n; }\n    return fib(n - 1) + fib(n - 2);\n}\nlet f = fib(4);";
+        let (program, profile) = profile(lang::compile(source).unwrap());
+        let folded = profile.folded_stacks();
+        let lines: Vec<&str> = folded.lines().collect();
+        let entry = program.iter().position(|instruction| instruction.opcode == Opcode::ENTER).unwrap();
+
+        // fib(4) calls down to fib(1) and fib(0), four calls deep.
+        let fib = format!("@{}", entry);
+        let deepest = ["main", &fib, &fib, &fib, &fib].join(";");
+        assert!(lines.iter().any(|line| line.starts_with(&format!("{} ", deepest))));
+        assert!(!lines.iter().any(|line| line.starts_with(&format!("{};{} ", deepest, fib))));
+        let total: u64 = lines.iter().map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap()).sum();
+        assert_eq!(total, profile.instructions());
+
+        let functions = profile.functions();
+        assert_eq!(functions.len(), 2);
+        assert_eq!(functions.iter().map(|(_, instructions, _)| instructions).sum::<u64>(), profile.instructions());
+    }
+}
@@ -1,12 +1,7 @@
-use crate::{
-    instruction::{self, Instruction},
-    lexer::{self, Lexer},
-    vm::VM,
-};
+use crate::{bytecode, cfg::ControlFlowGraph, lexer::Lexer, vm::VM};
 use std::{
     self,
     io::{self, Write},
-    num::ParseIntError,
 };
 
 pub struct REPL {
@@ -14,6 +9,12 @@ pub struct REPL {
     vm: VM,
 }
 
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl REPL {
     pub fn new() -> Self {
         REPL {
@@ -49,9 +50,11 @@ impl REPL {
                     }
                 }
                 "program" => {
-                    // for instruction in &self.vm.program {
-                    //     println!("{}", instruction);
-                    // }
+                    print!("{}", bytecode::disassemble(&self.vm.program, &self.vm.constants));
+                }
+                "cfg" => {
+                    let cfg = ControlFlowGraph::build(&self.vm.program);
+                    print!("{}", cfg.to_dot(&self.vm.program));
                 }
                 "registers" => {
                     for register in self.vm.registers {
@@ -60,9 +63,19 @@ impl REPL {
                     println!();
                 }
                 _ => {
-                    let instruction = Lexer::new(buffer.clone()).next_line().unwrap();
-                    self.vm.add_instruction(instruction);
-                    self.vm.run_once();
+                    // Keep float literals from earlier lines at the same constant indices.
+                    let mut lexer = Lexer::new(buffer.clone());
+                    lexer.constants = self.vm.constants.clone();
+                    let instruction = lexer.next_line().unwrap();
+                    if let Some(error) = lexer.errors.first() {
+                        println!("error: {}", error);
+                    } else {
+                        self.vm.constants = lexer.constants;
+                        self.vm.add_instruction(instruction);
+                        if let Err(error) = self.vm.run_once() {
+                            println!("error: {}", error);
+                        }
+                    }
                 }
             }
             buffer.clear();
@@ -0,0 +1,594 @@
+//! Snapshots of a paused VM as bytes, so it can be carried to another machine and resumed there.
+//!
+//! A snapshot holds the program in the `bytecode` format, every thread's registers, stacks and
+//! flags, and the heap with each object in the same slot, so `ObjectRef`s stay valid. It is taken
+//! between two instructions. A process's mailbox, outbox and suspension are not part of it.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    bytecode::{self, BytecodeError, Reader},
+    heap::{Heap, MapKey, Object, ObjectRef, Value},
+    vm::{ArithmeticMode, Context, Thread, ThreadState, REGISTER_COUNT, VM},
+};
+
+pub const MAGIC: [u8; 4] = *b"LVS\0";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SnapshotError {
+    BadHeader,
+    UnsupportedVersion(u8),
+    /// The snapshot ends partway through.
+    Truncated,
+    /// A tag byte has no meaning in the position it is in.
+    BadTag(u8),
+    /// The current thread or a thread being joined is not in the thread list.
+    BadThread(usize),
+    /// A pc or return address past the end of the program.
+    BadPc(usize),
+    /// A frame pointer past the top of its thread's stack.
+    BadFramePointer(usize),
+    /// A value refers to a heap slot that holds no object.
+    DanglingRef(usize),
+    Program(BytecodeError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::BadHeader => write!(f, "missing snapshot header"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::BadTag(tag) => write!(f, "unexpected tag {} in snapshot", tag),
+            SnapshotError::BadThread(id) => write!(f, "snapshot refers to missing thread {}", id),
+            SnapshotError::BadPc(pc) => write!(f, "snapshot has pc {} past the end of the program", pc),
+            SnapshotError::BadFramePointer(frame_pointer) => {
+                write!(f, "snapshot has frame pointer {} past the top of the stack", frame_pointer)
+            }
+            SnapshotError::DanglingRef(slot) => write!(f, "snapshot refers to missing object {}", slot),
+            SnapshotError::Program(error) => write!(f, "snapshot program: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<BytecodeError> for SnapshotError {
+    fn from(error: BytecodeError) -> Self {
+        SnapshotError::Program(error)
+    }
+}
+
+pub fn encode(vm: &VM) -> Result<Vec<u8>, SnapshotError> {
+    let mut writer = Writer(MAGIC.to_vec());
+    writer.u8(VERSION);
+    writer.bytes(&bytecode::encode(&vm.program, &vm.constants)?);
+
+    writer.context(&current_context(vm));
+    writer.u64(vm.threads.len() as u64);
+    for thread in &vm.threads {
+        writer.context(&thread.context);
+        match thread.state {
+            ThreadState::Runnable => writer.u8(0),
+            ThreadState::Joining(id) => {
+                writer.u8(1);
+                writer.u64(id as u64);
+            }
+            ThreadState::Finished => writer.u8(2),
+        }
+        writer.0.extend_from_slice(&thread.result.to_le_bytes());
+    }
+    writer.u64(vm.current_thread as u64);
+    writer.u64(vm.quantum as u64);
+    writer.u8(match vm.arithmetic_mode {
+        ArithmeticMode::Trap => 0,
+        ArithmeticMode::Wrap => 1,
+        ArithmeticMode::Saturate => 2,
+    });
+
+    let slots: Vec<Option<&Object>> = vm.heap.slots().collect();
+    writer.u64(slots.len() as u64);
+    for object in slots {
+        writer.object(object);
+    }
+    writer.u64(vm.interned_strings.len() as u64);
+    for (constant, object) in &vm.interned_strings {
+        writer.u64(*constant as u64);
+        writer.u64(object.0 as u64);
+    }
+    return Ok(writer.0);
+}
+
+pub fn decode(bytes: &[u8]) -> Result<VM, SnapshotError> {
+    if bytes.len() < MAGIC.len() + 1 || bytes[..MAGIC.len()] != MAGIC {
+        return Err(SnapshotError::BadHeader);
+    }
+    if bytes[MAGIC.len()] != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(bytes[MAGIC.len()]));
+    }
+    let mut reader = SnapshotReader(Reader::new(&bytes[MAGIC.len() + 1..]));
+    let mut vm = VM::new();
+    let (program, constants) = bytecode::decode(reader.bytes()?)?;
+    vm.program = program.into();
+    vm.constants = constants;
+
+    let context = reader.context()?;
+    set_current_context(&mut vm, context);
+    vm.threads = vec![];
+    for _ in 0..reader.u64()? {
+        let context = reader.context()?;
+        let state = match reader.u8()? {
+            0 => ThreadState::Runnable,
+            1 => ThreadState::Joining(reader.u64()? as usize),
+            2 => ThreadState::Finished,
+            tag => return Err(SnapshotError::BadTag(tag)),
+        };
+        let result = i32::from_le_bytes(reader.take()?);
+        vm.threads.push(Thread { context, state, result });
+    }
+    vm.current_thread = reader.u64()? as usize;
+    if vm.current_thread >= vm.threads.len() {
+        return Err(SnapshotError::BadThread(vm.current_thread));
+    }
+    for thread in &vm.threads {
+        if let ThreadState::Joining(id) = thread.state {
+            if id >= vm.threads.len() {
+                return Err(SnapshotError::BadThread(id));
+            }
+        }
+    }
+    vm.quantum = reader.u64()? as usize;
+    vm.arithmetic_mode = match reader.u8()? {
+        0 => ArithmeticMode::Trap,
+        1 => ArithmeticMode::Wrap,
+        2 => ArithmeticMode::Saturate,
+        tag => return Err(SnapshotError::BadTag(tag)),
+    };
+
+    let mut slots = vec![];
+    for _ in 0..reader.u64()? {
+        slots.push(reader.object()?);
+    }
+    vm.heap = Heap::from_slots(slots);
+    for _ in 0..reader.u64()? {
+        let constant = reader.u64()? as usize;
+        vm.interned_strings.insert(constant, ObjectRef(reader.u64()? as usize));
+    }
+    check_references(&vm)?;
+    return Ok(vm);
+}
+
+/// Checks that every pc and return address is in the program, every frame pointer is in its
+/// stack and every ref is to a live object, which the VM otherwise trusts.
+fn check_references(vm: &VM) -> Result<(), SnapshotError> {
+    let mut values = vec![];
+    for context in std::iter::once(current_context(vm)).chain(vm.threads.iter().map(|thread| thread.context.clone())) {
+        // A pc at the end of the program is one that has run off it.
+        if let Some(pc) = std::iter::once(context.pc).chain(context.call_stack).find(|pc| *pc > vm.program.len()) {
+            return Err(SnapshotError::BadPc(pc));
+        }
+        if context.frame_pointer > context.stack.len() {
+            return Err(SnapshotError::BadFramePointer(context.frame_pointer));
+        }
+        values.extend(context.value_registers);
+        values.extend(context.value_stack);
+    }
+    for object in vm.heap.slots().flatten() {
+        match object {
+            Object::Array(elements) => values.extend(elements),
+            Object::Map(entries) => values.extend(entries.values().flat_map(|(key, value)| [key, value])),
+            Object::String(_) | Object::Bytes(_) => {}
+        }
+    }
+    let refs = values.into_iter().filter_map(|value| match value {
+        Value::Ref(object) => Some(object),
+        _ => None,
+    });
+    for object in refs.chain(vm.interned_strings.values().copied()) {
+        if vm.heap.get(object).is_none() {
+            return Err(SnapshotError::DanglingRef(object.0));
+        }
+    }
+    return Ok(());
+}
+
+/// The running thread's context, which lives in the VM's own fields.
+fn current_context(vm: &VM) -> Context {
+    Context {
+        registers: vm.registers,
+        float_registers: vm.float_registers,
+        pc: vm.pc,
+        value_registers: vm.value_registers,
+        value_stack: vm.value_stack.clone(),
+        stack: vm.stack.clone(),
+        frame_pointer: vm.frame_pointer,
+        call_stack: vm.call_stack.clone(),
+        remainder: vm.remainder,
+        equal: vm.equal,
+        overflow: vm.overflow,
+        carry: vm.carry,
+    }
+}
+
+fn set_current_context(vm: &mut VM, context: Context) {
+    vm.registers = context.registers;
+    vm.float_registers = context.float_registers;
+    vm.pc = context.pc;
+    vm.value_registers = context.value_registers;
+    vm.value_stack = context.value_stack;
+    vm.stack = context.stack;
+    vm.frame_pointer = context.frame_pointer;
+    vm.call_stack = context.call_stack;
+    vm.remainder = context.remainder;
+    vm.equal = context.equal;
+    vm.overflow = context.overflow;
+    vm.carry = context.carry;
+}
+
+const NIL_TAG: u8 = 0;
+const INT_TAG: u8 = 1;
+const FLOAT_TAG: u8 = 2;
+const BOOL_TAG: u8 = 3;
+const REF_TAG: u8 = 4;
+/// Map keys use the value tags, plus this one for strings compared by contents.
+const STRING_KEY_TAG: u8 = 5;
+
+const FREE_SLOT_TAG: u8 = 0;
+const STRING_OBJECT_TAG: u8 = 1;
+const ARRAY_OBJECT_TAG: u8 = 2;
+const MAP_OBJECT_TAG: u8 = 3;
+const BYTES_OBJECT_TAG: u8 = 4;
+
+/// Little-endian writer for everything after the header. Lengths and indices are `u64`.
+pub(crate) struct Writer(pub(crate) Vec<u8>);
+
+impl Writer {
+    pub(crate) fn u8(&mut self, byte: u8) {
+        self.0.push(byte);
+    }
+
+    pub(crate) fn u64(&mut self, number: u64) {
+        self.0.extend_from_slice(&number.to_le_bytes());
+    }
+
+    /// A length followed by the bytes.
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn value(&mut self, value: Value) {
+        match value {
+            Value::Nil => self.u8(NIL_TAG),
+            Value::Int(number) => {
+                self.u8(INT_TAG);
+                self.0.extend_from_slice(&number.to_le_bytes());
+            }
+            Value::Float(number) => {
+                self.u8(FLOAT_TAG);
+                self.0.extend_from_slice(&number.to_le_bytes());
+            }
+            Value::Bool(boolean) => {
+                self.u8(BOOL_TAG);
+                self.u8(boolean as u8);
+            }
+            Value::Ref(object) => {
+                self.u8(REF_TAG);
+                self.u64(object.0 as u64);
+            }
+        }
+    }
+
+    fn map_key(&mut self, key: &MapKey) {
+        match key {
+            MapKey::Nil => self.u8(NIL_TAG),
+            MapKey::Int(number) => {
+                self.u8(INT_TAG);
+                self.0.extend_from_slice(&number.to_le_bytes());
+            }
+            MapKey::Float(bits) => {
+                self.u8(FLOAT_TAG);
+                self.u64(*bits);
+            }
+            MapKey::Bool(boolean) => {
+                self.u8(BOOL_TAG);
+                self.u8(*boolean as u8);
+            }
+            MapKey::Ref(object) => {
+                self.u8(REF_TAG);
+                self.u64(object.0 as u64);
+            }
+            MapKey::String(string) => {
+                self.u8(STRING_KEY_TAG);
+                self.bytes(string.as_bytes());
+            }
+        }
+    }
+
+    fn object(&mut self, object: Option<&Object>) {
+        match object {
+            None => self.u8(FREE_SLOT_TAG),
+            Some(Object::String(string)) => {
+                self.u8(STRING_OBJECT_TAG);
+                self.bytes(string.as_bytes());
+            }
+            Some(Object::Array(values)) => {
+                self.u8(ARRAY_OBJECT_TAG);
+                self.u64(values.len() as u64);
+                for value in values {
+                    self.value(*value);
+                }
+            }
+            Some(Object::Map(entries)) => {
+                self.u8(MAP_OBJECT_TAG);
+                self.u64(entries.len() as u64);
+                for (key, (key_value, value)) in entries {
+                    self.map_key(key);
+                    self.value(*key_value);
+                    self.value(*value);
+                }
+            }
+            Some(Object::Bytes(bytes)) => {
+                self.u8(BYTES_OBJECT_TAG);
+                self.bytes(bytes);
+            }
+        }
+    }
+
+    fn context(&mut self, context: &Context) {
+        for register in context.registers {
+            self.0.extend_from_slice(&register.to_le_bytes());
+        }
+        for register in context.float_registers {
+            self.0.extend_from_slice(&register.to_le_bytes());
+        }
+        self.u64(context.pc as u64);
+        for value in context.value_registers {
+            self.value(value);
+        }
+        self.u64(context.value_stack.len() as u64);
+        for value in &context.value_stack {
+            self.value(*value);
+        }
+        self.u64(context.stack.len() as u64);
+        for number in &context.stack {
+            self.0.extend_from_slice(&number.to_le_bytes());
+        }
+        self.u64(context.frame_pointer as u64);
+        self.u64(context.call_stack.len() as u64);
+        for address in &context.call_stack {
+            self.u64(*address as u64);
+        }
+        self.0.extend_from_slice(&context.remainder.to_le_bytes());
+        self.u8(context.equal as u8 | (context.overflow as u8) << 1 | (context.carry as u8) << 2);
+    }
+}
+
+/// Reads what `Writer` wrote, turning running out of bytes into `SnapshotError::Truncated`.
+pub(crate) struct SnapshotReader<'a>(pub(crate) Reader<'a>);
+
+impl SnapshotReader<'_> {
+    pub(crate) fn take<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        self.0.take::<N>().ok_or(SnapshotError::Truncated)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&[u8], SnapshotError> {
+        let length = usize::try_from(self.u64()?).map_err(|_| SnapshotError::Truncated)?;
+        self.0.take_slice(length).ok_or(SnapshotError::Truncated)
+    }
+
+    fn string(&mut self) -> Result<String, SnapshotError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| SnapshotError::BadTag(STRING_OBJECT_TAG))
+    }
+
+    fn value(&mut self) -> Result<Value, SnapshotError> {
+        let value = match self.u8()? {
+            NIL_TAG => Value::Nil,
+            INT_TAG => Value::Int(i64::from_le_bytes(self.take()?)),
+            FLOAT_TAG => Value::Float(f64::from_le_bytes(self.take()?)),
+            BOOL_TAG => Value::Bool(self.u8()? != 0),
+            REF_TAG => Value::Ref(ObjectRef(self.u64()? as usize)),
+            tag => return Err(SnapshotError::BadTag(tag)),
+        };
+        return Ok(value);
+    }
+
+    fn map_key(&mut self) -> Result<MapKey, SnapshotError> {
+        let key = match self.u8()? {
+            NIL_TAG => MapKey::Nil,
+            INT_TAG => MapKey::Int(i64::from_le_bytes(self.take()?)),
+            FLOAT_TAG => MapKey::Float(self.u64()?),
+            BOOL_TAG => MapKey::Bool(self.u8()? != 0),
+            REF_TAG => MapKey::Ref(ObjectRef(self.u64()? as usize)),
+            STRING_KEY_TAG => MapKey::String(self.string()?),
+            tag => return Err(SnapshotError::BadTag(tag)),
+        };
+        return Ok(key);
+    }
+
+    fn object(&mut self) -> Result<Option<Object>, SnapshotError> {
+        let object = match self.u8()? {
+            FREE_SLOT_TAG => None,
+            STRING_OBJECT_TAG => Some(Object::String(self.string()?)),
+            ARRAY_OBJECT_TAG => {
+                let mut values = vec![];
+                for _ in 0..self.u64()? {
+                    values.push(self.value()?);
+                }
+                Some(Object::Array(values))
+            }
+            MAP_OBJECT_TAG => {
+                let mut entries = HashMap::new();
+                for _ in 0..self.u64()? {
+                    let key = self.map_key()?;
+                    entries.insert(key, (self.value()?, self.value()?));
+                }
+                Some(Object::Map(entries))
+            }
+            BYTES_OBJECT_TAG => Some(Object::Bytes(self.bytes()?.to_vec())),
+            tag => return Err(SnapshotError::BadTag(tag)),
+        };
+        return Ok(object);
+    }
+
+    fn context(&mut self) -> Result<Context, SnapshotError> {
+        let mut context = Context::new([0; REGISTER_COUNT], 0);
+        for register in &mut context.registers {
+            *register = i32::from_le_bytes(self.take()?);
+        }
+        for register in &mut context.float_registers {
+            *register = f64::from_le_bytes(self.take()?);
+        }
+        context.pc = self.u64()? as usize;
+        for register in &mut context.value_registers {
+            *register = self.value()?;
+        }
+        for _ in 0..self.u64()? {
+            context.value_stack.push(self.value()?);
+        }
+        for _ in 0..self.u64()? {
+            context.stack.push(i32::from_le_bytes(self.take()?));
+        }
+        context.frame_pointer = self.u64()? as usize;
+        for _ in 0..self.u64()? {
+            context.call_stack.push(self.u64()? as usize);
+        }
+        context.remainder = u32::from_le_bytes(self.take()?);
+        let flags = self.u8()?;
+        context.equal = flags & 1 != 0;
+        context.overflow = flags & 2 != 0;
+        context.carry = flags & 4 != 0;
+        return Ok(context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::{Constant, Instruction, Opcode};
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        // Sums 1 to 100 in $0 while a second thread is spawned and joined halfway.
+        let program = vec![
+            Instruction::new(Opcode::LOAD, [1, 0, 0], 100),
+            Instruction::new(Opcode::LOAD, [2, 0, 0], 2),
+            Instruction::new(Opcode::ADD, [0, 1, 0], 0),
+            Instruction::new(Opcode::DEC, [1, 0, 0], 0),
+            Instruction::new(Opcode::JNZ, [1, 2, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ];
+        let mut original = VM::new();
+        original.program = program.into();
+        original.constants = vec![Constant::Float(1.5), Constant::String("kept".to_string())];
+        original.float_registers[4] = -0.25;
+        original.stack = vec![7, 8];
+        original.call_stack = vec![3];
+        original.arithmetic_mode = ArithmeticMode::Saturate;
+        let string = original.heap.allocate(Object::String("hello".to_string()));
+        let freed = original.heap.allocate(Object::Bytes(vec![1, 2]));
+        let array = original.heap.allocate(Object::Array(vec![Value::Ref(string), Value::Float(2.0)]));
+        let key = original.heap.map_key(Value::Ref(string));
+        let mut entries = HashMap::new();
+        entries.insert(key, (Value::Ref(string), Value::Int(-3)));
+        let map = original.heap.allocate(Object::Map(entries));
+        original.value_registers[1] = Value::Ref(array);
+        original.value_stack = vec![Value::Ref(map), Value::Bool(true)];
+        original.heap.collect([Value::Ref(array), Value::Ref(map)]);
+        original.interned_strings.insert(1, string);
+        original.threads.push(Thread { context: Context::new([5; REGISTER_COUNT], 2), state: ThreadState::Joining(0), result: 9 });
+        for _ in 0..50 {
+            original.step().unwrap();
+        }
+
+        let mut resumed = decode(&encode(&original).unwrap()).unwrap();
+        assert_eq!(resumed.program, original.program);
+        assert_eq!(resumed.constants, original.constants);
+        assert_eq!((resumed.registers, resumed.pc), (original.registers, original.pc));
+        assert_eq!(resumed.float_registers[4], -0.25);
+        assert_eq!((&resumed.stack, &resumed.call_stack), (&original.stack, &original.call_stack));
+        assert_eq!(resumed.value_registers, original.value_registers);
+        assert_eq!(resumed.value_stack, original.value_stack);
+        assert_eq!(resumed.arithmetic_mode, ArithmeticMode::Saturate);
+        assert_eq!(resumed.threads[1].state, ThreadState::Joining(0));
+        assert_eq!(resumed.threads[1].context.registers, [5; REGISTER_COUNT]);
+        assert_eq!(resumed.interned_strings, original.interned_strings);
+        for object in [string, array, map] {
+            assert_eq!(resumed.heap.get(object), original.heap.get(object));
+        }
+        assert_eq!(resumed.heap.get(freed), None);
+        assert_eq!(resumed.heap.stats().live_objects, 3);
+        // The freed slot is handed out again, just as it would have been before.
+        assert_eq!(resumed.heap.allocate(Object::Bytes(vec![])), freed);
+
+        resumed.threads.truncate(1);
+        original.threads.truncate(1);
+        resumed.run().unwrap();
+        original.run().unwrap();
+        assert_eq!(resumed.registers[0], 5050);
+        assert_eq!(resumed.registers, original.registers);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode(b"LVM\0\x04").err(), Some(SnapshotError::BadHeader));
+        assert_eq!(decode(b"LVS\0\x09").err(), Some(SnapshotError::UnsupportedVersion(9)));
+        let mut bytes = encode(&VM::new()).unwrap();
+        bytes.pop();
+        assert_eq!(decode(&bytes).err(), Some(SnapshotError::Truncated));
+    }
+
+    #[test]
+    fn test_decode_rejects_missing_threads() {
+        let mut vm = VM::new();
+        vm.current_thread = 3;
+        assert_eq!(decode(&encode(&vm).unwrap()).err(), Some(SnapshotError::BadThread(3)));
+
+        let mut vm = VM::new();
+        vm.threads.clear();
+        assert_eq!(decode(&encode(&vm).unwrap()).err(), Some(SnapshotError::BadThread(0)));
+
+        let mut vm = VM::new();
+        vm.threads.push(Thread { context: Context::new([0; REGISTER_COUNT], 0), state: ThreadState::Joining(7), result: 0 });
+        assert_eq!(decode(&encode(&vm).unwrap()).err(), Some(SnapshotError::BadThread(7)));
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_references() {
+        let program = vec![Instruction::new(Opcode::HLT, [0; 3], 0)];
+
+        let mut vm = VM::new();
+        vm.program = program.clone().into();
+        vm.pc = 1;
+        assert!(decode(&encode(&vm).unwrap()).is_ok());
+        vm.pc = 2;
+        assert_eq!(decode(&encode(&vm).unwrap()).err(), Some(SnapshotError::BadPc(2)));
+
+        let mut vm = VM::new();
+        vm.program = program.clone().into();
+        vm.threads.push(Thread { context: Context { call_stack: vec![9], ..Context::new([0; REGISTER_COUNT], 0) }, state: ThreadState::Runnable, result: 0 });
+        assert_eq!(decode(&encode(&vm).unwrap()).err(), Some(SnapshotError::BadPc(9)));
+
+        let mut vm = VM::new();
+        vm.stack = vec![1];
+        vm.frame_pointer = 2;
+        assert_eq!(decode(&encode(&vm).unwrap()).err(), Some(SnapshotError::BadFramePointer(2)));
+
+        let mut vm = VM::new();
+        vm.value_registers[3] = Value::Ref(ObjectRef(0));
+        assert_eq!(decode(&encode(&vm).unwrap()).err(), Some(SnapshotError::DanglingRef(0)));
+
+        let mut vm = VM::new();
+        vm.heap.allocate(Object::Array(vec![Value::Ref(ObjectRef(5))]));
+        assert_eq!(decode(&encode(&vm).unwrap()).err(), Some(SnapshotError::DanglingRef(5)));
+    }
+}
@@ -0,0 +1,81 @@
+//! Helpers for the tests that check another way of running programs, such as `dispatch`, `jit`,
+//! `aot` or `wasm`, against `VM::run`.
+
+use std::{
+    env,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    instruction::{Constant, Instruction},
+    lang,
+    lexer::Lexer,
+    vm::{ArithmeticMode, VmError, VM},
+};
+
+/// Recursive calls and a loop with branches and arithmetic, printing `111144` and then `610`.
+pub const WORKLOAD: &str = "fn fib(n) {\n    if n < 2 { return n; }\n    return fib(n - 1) + fib(n - 2);\n}\nlet total = 0;\nlet i = 0;\nwhile i < 100 {\n    if i % 3 == 0 { total = total + i * i; } else { total = total - i / 2; }\n    i = i + 1;\n}\nprint total;\nprint fib(15);";
+
+/// `MOD` and `MODL` with operands of both signs and the most negative numbers, printing each
+/// remainder and both halves of each 64-bit one: `2`, `2`, `0`, `2147483647`, `0`, then `-1` and
+/// `2147483647`, then `993` and `0`.
+pub const REMAINDERS: &str = "LOAD $0 #-7\nLOAD $1 #3\nLOAD $2 #-3\nLOAD $3 #-2147483648\nLOAD $4 #-1\n\
+    MOD $0 $1 $10\nITOS $10 $5\nPRTS $5\nMOD $0 $2 $10\nITOS $10 $5\nPRTS $5\nMOD $1 $2 $10\nITOS $10 $5\nPRTS $5\n\
+    MOD $4 $3 $10\nITOS $10 $5\nPRTS $5\nMOD $3 $4 $10\nITOS $10 $5\nPRTS $5\n\
+    LOADL $6 #-9223372036854775808\nLOADL $8 #-1\nMODL $8 $6 $16\nITOS $16 $5\nPRTS $5\nITOS $17 $5\nPRTS $5\n\
+    LOADL $12 #-5000000007\nLOADL $14 #1000\nMODL $12 $14 $16\nITOS $16 $5\nPRTS $5\nITOS $17 $5\nPRTS $5\nHLT";
+
+/// `WORKLOAD` compiled both with and without the optimizer.
+pub fn compiled_workloads() -> [Vec<Instruction>; 2] {
+    return [lang::compile(WORKLOAD).unwrap(), lang::compile_unoptimized(WORKLOAD).unwrap()];
+}
+
+pub fn assemble(source: &str) -> (Vec<Instruction>, Vec<Constant>) {
+    let mut lexer = Lexer::new(source.to_string());
+    let mut program = vec![];
+    while let Some(instruction) = lexer.next_line() {
+        program.push(instruction);
+    }
+    assert!(lexer.errors.is_empty(), "{:?}", lexer.errors);
+    return (program, lexer.constants);
+}
+
+/// A path in the temporary directory that no other test in this process uses.
+pub fn temp_path(prefix: &str, extension: &str) -> PathBuf {
+    static PATHS: AtomicUsize = AtomicUsize::new(0);
+    let name = format!("lang-vm-{}-{}-{}{}", prefix, std::process::id(), PATHS.fetch_add(1, Ordering::SeqCst), extension);
+    return env::temp_dir().join(name);
+}
+
+/// A new VM set up to run `vm`'s program the same way, to compare against.
+pub fn copy_of(vm: &VM) -> VM {
+    let mut copy = VM::new();
+    copy.program = vm.program.clone();
+    copy.constants = vm.constants.clone();
+    copy.registers = vm.registers;
+    copy.quantum = vm.quantum;
+    copy.arithmetic_mode = vm.arithmetic_mode;
+    copy.output = vm.output.clone();
+    return copy;
+}
+
+/// What the interpreter did with a program.
+pub struct Interpreted {
+    /// The lines `PRTS` printed.
+    pub output: Vec<String>,
+    pub result: Result<(), VmError>,
+    /// Whether the last thread to finish stopped at a `HLT`.
+    pub halted: bool,
+}
+
+pub fn interpret(program: Vec<Instruction>, constants: Vec<Constant>, mode: ArithmeticMode) -> Interpreted {
+    let mut vm = VM::new();
+    vm.program = program.into();
+    vm.constants = constants;
+    vm.arithmetic_mode = mode;
+    vm.output = Some(vec![]);
+    let result = vm.run();
+    let halted = result.is_ok() && vm.halted;
+    return Interpreted { output: vm.output.take().unwrap(), result, halted };
+}
@@ -0,0 +1,273 @@
+use std::fmt;
+
+use crate::{
+    cfg::ControlFlowGraph,
+    instruction::{Constant, Instruction, Opcode, RegisterUse},
+    vm::{REGISTER_COUNT, RETURN_REGISTER},
+};
+
+/// Problems that make a program unsafe to run.
+#[derive(Debug, PartialEq, Clone)]
+pub enum VerifyError {
+    RegisterOutOfRange { pc: usize, register: usize },
+    JumpOutOfRange { pc: usize, target: i64 },
+    IllegalOpcode { pc: usize },
+    /// The constant pool has no entry of the right type at the index an instruction refers to.
+    BadConstant { pc: usize, index: i64 },
+    /// Execution can run past the last instruction without reaching a `HLT`.
+    MissingHalt { pc: usize },
+    /// An operand too big for an opcode that only reads 32 bits of it, as only `LOADL` takes a
+    /// 64-bit operand.
+    OperandOutOfRange { pc: usize, operand: i64 },
+}
+
+/// Suspicious code that is still safe to run.
+#[derive(Debug, PartialEq, Clone)]
+pub enum VerifyWarning {
+    /// A register is read before any path has written it, so it still holds its initial zero.
+    UninitializedRead { pc: usize, register: usize },
+    UninitializedFloatRead { pc: usize, register: usize },
+    /// A jump through a register that does not hold a known constant, which cannot be checked.
+    UnresolvedJump { pc: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::RegisterOutOfRange { pc, register } => {
+                write!(f, "{}: register ${} is out of range (there are {} registers)", pc, register, REGISTER_COUNT)
+            }
+            VerifyError::JumpOutOfRange { pc, target } => write!(f, "{}: jump target {} is outside the program", pc, target),
+            VerifyError::IllegalOpcode { pc } => write!(f, "{}: illegal opcode", pc),
+            VerifyError::BadConstant { pc, index } => write!(f, "{}: no suitable constant at index {}", pc, index),
+            VerifyError::MissingHalt { pc } => write!(f, "{}: execution falls off the end of the program without HLT", pc),
+            VerifyError::OperandOutOfRange { pc, operand } => write!(f, "{}: operand {} does not fit in 32 bits", pc, operand),
+        }
+    }
+}
+
+impl fmt::Display for VerifyWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyWarning::UninitializedRead { pc, register } => write!(f, "{}: register ${} is read before it is written", pc, register),
+            VerifyWarning::UninitializedFloatRead { pc, register } => {
+                write!(f, "{}: float register ${} is read before it is written", pc, register)
+            }
+            VerifyWarning::UnresolvedJump { pc } => write!(f, "{}: jump target is not a constant and cannot be checked", pc),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Verification {
+    pub errors: Vec<VerifyError>,
+    pub warnings: Vec<VerifyWarning>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Statically checks a program before it is handed to `VM::run`.
+pub fn verify(program: &[Instruction], constants: &[Constant]) -> Verification {
+    let mut verification = Verification::default();
+
+    for (pc, instruction) in program.iter().enumerate() {
+        if instruction.opcode == Opcode::IGL {
+            verification.errors.push(VerifyError::IllegalOpcode { pc });
+        }
+        if !instruction.opcode.has_wide_immediate() && i32::try_from(instruction.integer_operand).is_err() {
+            verification.errors.push(VerifyError::OperandOutOfRange { pc, operand: instruction.integer_operand });
+        }
+        for (register_use, register) in instruction.opcode.register_uses().iter().zip(instruction.registers) {
+            match register_use {
+                RegisterUse::Immediate => {}
+                RegisterUse::Constant => {
+                    let index = instruction.integer_operand;
+                    let constant = constants.get(index as usize);
+                    if !constant.is_some_and(|constant| instruction.opcode.accepts_constant(constant)) {
+                        verification.errors.push(VerifyError::BadConstant { pc, index });
+                    }
+                }
+                RegisterUse::PairRead | RegisterUse::PairWrite if register + 1 >= REGISTER_COUNT => {
+                    verification.errors.push(VerifyError::RegisterOutOfRange { pc, register: register + 1 });
+                }
+                _ if register >= REGISTER_COUNT => {
+                    verification.errors.push(VerifyError::RegisterOutOfRange { pc, register });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let cfg = ControlFlowGraph::build(program);
+    for (pc, target) in &cfg.out_of_range_jumps {
+        verification.errors.push(VerifyError::JumpOutOfRange { pc: *pc, target: *target });
+    }
+    for pc in &cfg.unresolved_jumps {
+        verification.warnings.push(VerifyWarning::UnresolvedJump { pc: *pc });
+    }
+
+    // Forward dataflow over the set of registers that may have been written on some path into
+    // each block, with integer registers in the low half of the mask and float registers in the
+    // high half. Blocks that are never reached from the entry keep `None` and are not checked.
+    let mut written_on_entry: Vec<Option<u64>> = vec![None; cfg.blocks.len()];
+    if !cfg.blocks.is_empty() {
+        written_on_entry[0] = Some(0);
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (index, block) in cfg.blocks.iter().enumerate() {
+            let Some(mut written) = written_on_entry[index] else {
+                continue;
+            };
+            for instruction in &program[block.start..block.end] {
+                written |= written_mask(instruction);
+            }
+            for successor in &block.successors {
+                let merged = written_on_entry[*successor].unwrap_or(0) | written;
+                if written_on_entry[*successor] != Some(merged) {
+                    written_on_entry[*successor] = Some(merged);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    for (index, block) in cfg.blocks.iter().enumerate() {
+        let Some(mut written) = written_on_entry[index] else {
+            continue;
+        };
+        for (pc, instruction) in program.iter().enumerate().take(block.end).skip(block.start) {
+            for register in instruction.reads() {
+                if register < REGISTER_COUNT && written & (1 << register) == 0 {
+                    verification.warnings.push(VerifyWarning::UninitializedRead { pc, register });
+                }
+            }
+            for register in instruction.float_reads() {
+                if register < REGISTER_COUNT && written & (1 << (REGISTER_COUNT + register)) == 0 {
+                    verification.warnings.push(VerifyWarning::UninitializedFloatRead { pc, register });
+                }
+            }
+            written |= written_mask(instruction);
+        }
+    }
+
+    // Anything could be reachable through an unresolved jump, so only trust reachability when
+    // every jump was resolved.
+    for (index, block) in cfg.blocks.iter().enumerate() {
+        let reachable = written_on_entry[index].is_some() || !cfg.unresolved_jumps.is_empty();
+        if block.falls_off_end && reachable {
+            verification.errors.push(VerifyError::MissingHalt { pc: block.end - 1 });
+        }
+    }
+
+    return verification;
+}
+
+/// Registers the instruction writes, with integer registers in the low half of the mask and float
+/// registers in the high half. Execution only carries on after a `CALL` once the callee has
+/// returned, so a `CALL` also writes `RETURN_REGISTER`.
+fn written_mask(instruction: &Instruction) -> u64 {
+    let returned = (instruction.opcode == Opcode::CALL).then_some(RETURN_REGISTER);
+    let integers = instruction.writes().into_iter().chain(returned).filter(|register| *register < REGISTER_COUNT);
+    let floats = instruction
+        .float_writes()
+        .into_iter()
+        .filter(|register| *register < REGISTER_COUNT)
+        .map(|register| REGISTER_COUNT + register);
+    integers.chain(floats).fold(0, |mask, bit| mask | (1 << bit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instruction(opcode: Opcode, registers: [usize; 3], integer_operand: i64) -> Instruction {
+        Instruction::new(opcode, registers, integer_operand)
+    }
+
+    #[test]
+    fn test_valid_program() {
+        let program = vec![
+            instruction(Opcode::LOAD, [0, 0, 0], 10),
+            instruction(Opcode::LOAD, [1, 0, 0], 2),
+            instruction(Opcode::DEC, [0, 0, 0], 0),
+            instruction(Opcode::GT, [0, 1, 0], 0),
+            instruction(Opcode::JEQ, [1, 0, 0], 0),
+            instruction(Opcode::HLT, [0; 3], 0),
+        ];
+        assert_eq!(verify(&program, &[]), Verification::default());
+    }
+
+    #[test]
+    fn test_rejects_bad_programs() {
+        let program = vec![
+            instruction(Opcode::LOAD, [40, 0, 0], 1),
+            instruction(Opcode::LOAD, [0, 0, 0], 99),
+            instruction(Opcode::JEQ, [0, 0, 0], 0),
+            instruction(Opcode::IGL, [0; 3], 0),
+        ];
+        let verification = verify(&program, &[]);
+        assert!(!verification.is_ok());
+        assert_eq!(
+            verification.errors,
+            vec![
+                VerifyError::RegisterOutOfRange { pc: 0, register: 40 },
+                VerifyError::IllegalOpcode { pc: 3 },
+                VerifyError::JumpOutOfRange { pc: 2, target: 99 },
+            ]
+        );
+
+        let program = vec![instruction(Opcode::LOAD, [0, 0, 0], 1), instruction(Opcode::INC, [0, 0, 0], 0)];
+        assert_eq!(verify(&program, &[]).errors, vec![VerifyError::MissingHalt { pc: 1 }]);
+
+        let program = vec![instruction(Opcode::LOADF, [0, 0, 0], 1), instruction(Opcode::HLT, [0; 3], 0)];
+        assert_eq!(
+            verify(&program, &[Constant::Float(1.0)]).errors,
+            vec![VerifyError::BadConstant { pc: 0, index: 1 }]
+        );
+        let program = vec![instruction(Opcode::LOADS, [0, 0, 0], 0), instruction(Opcode::HLT, [0; 3], 0)];
+        assert_eq!(
+            verify(&program, &[Constant::Float(1.0)]).errors,
+            vec![VerifyError::BadConstant { pc: 0, index: 0 }]
+        );
+
+        let program = vec![
+            instruction(Opcode::LOAD, [0, 0, 0], 1 << 32),
+            instruction(Opcode::LOADL, [0, 0, 0], 1 << 32),
+            instruction(Opcode::ADDI, [0, 0, 0], -(1 << 40)),
+            instruction(Opcode::HLT, [0; 3], 0),
+        ];
+        assert_eq!(
+            verify(&program, &[]).errors,
+            vec![
+                VerifyError::OperandOutOfRange { pc: 0, operand: 1 << 32 },
+                VerifyError::OperandOutOfRange { pc: 2, operand: -(1 << 40) },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_warns_on_uninitialized_read() {
+        let program = vec![
+            instruction(Opcode::LOAD, [0, 0, 0], 1),
+            instruction(Opcode::ADD, [0, 5, 2], 0),
+            instruction(Opcode::ITOF, [0, 1, 0], 0),
+            instruction(Opcode::ADDF, [1, 2, 3], 0),
+            instruction(Opcode::HLT, [0; 3], 0),
+        ];
+        let verification = verify(&program, &[]);
+        assert!(verification.is_ok());
+        assert_eq!(
+            verification.warnings,
+            vec![
+                VerifyWarning::UninitializedRead { pc: 1, register: 5 },
+                VerifyWarning::UninitializedFloatRead { pc: 3, register: 2 },
+            ]
+        );
+    }
+}
@@ -1,50 +1,398 @@
-use crate::instruction::{Instruction, Opcode, self};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, mem,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use crate::{
+    heap::{Heap, Object, ObjectRef, Value},
+    instruction::{Constant, Instruction, Opcode},
+};
+
+pub const REGISTER_COUNT: usize = 32;
+
+/// How deeply `CALL`s can nest before the VM reports a stack overflow.
+pub const MAX_CALL_DEPTH: usize = 1 << 16;
+
+/// How large `ENTER` can grow the integer stack before the VM reports a stack overflow.
+pub const MAX_STACK_SIZE: usize = 1 << 24;
+
+/// Where, by convention, a function called with `CALL` leaves its result. The verifier counts a
+/// `CALL` as writing it.
+pub const RETURN_REGISTER: usize = REGISTER_COUNT - 2;
+
+/// What integer arithmetic and `FTOI` do when the result does not fit in an `i32`, or in an `i64`
+/// for the register pair opcodes. The `overflow` flag is set either way.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ArithmeticMode {
+    /// Stop with `VmError::IntegerOverflow`.
+    Trap,
+    /// Keep the two's complement wrapped result.
+    #[default]
+    Wrap,
+    /// Clamp the result to the smallest or largest value of the type.
+    Saturate,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+    IntegerOverflow { pc: usize },
+    DivisionByZero { pc: usize },
+    IllegalOpcode { pc: usize },
+    /// The constant pool has no entry of the right type at the index an instruction refers to.
+    BadConstant { pc: usize, index: i64 },
+    /// A value register held the wrong kind of value for the instruction.
+    TypeError { pc: usize },
+    IndexOutOfBounds { pc: usize },
+    NegativeSize { pc: usize },
+    StackUnderflow { pc: usize },
+    StackOverflow { pc: usize },
+    /// `JOIN` named a thread that was never spawned.
+    UnknownThread { pc: usize },
+    /// Every thread that has not finished is waiting to join another.
+    Deadlock { pc: usize },
+    /// A relative jump would land before the start of the program.
+    JumpOutOfRange { pc: usize },
+    /// `SPAWNP` or `RECV` suspended a VM that was not running as a `process::Runtime`'s process,
+    /// so nothing can resume it.
+    Suspended { pc: usize },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::IntegerOverflow { pc } => write!(f, "{}: integer overflow", pc),
+            VmError::DivisionByZero { pc } => write!(f, "{}: division by zero", pc),
+            VmError::IllegalOpcode { pc } => write!(f, "{}: illegal opcode", pc),
+            VmError::BadConstant { pc, index } => write!(f, "{}: no suitable constant at index {}", pc, index),
+            VmError::TypeError { pc } => write!(f, "{}: value has the wrong type", pc),
+            VmError::IndexOutOfBounds { pc } => write!(f, "{}: index out of bounds", pc),
+            VmError::NegativeSize { pc } => write!(f, "{}: negative allocation size", pc),
+            VmError::StackUnderflow { pc } => write!(f, "{}: pop from an empty stack", pc),
+            VmError::StackOverflow { pc } => {
+                write!(f, "{}: calls are nested more than {} deep or a frame overflows the stack", pc, MAX_CALL_DEPTH)
+            }
+            VmError::UnknownThread { pc } => write!(f, "{}: no such thread", pc),
+            VmError::Deadlock { pc } => write!(f, "{}: every remaining thread is waiting to join another", pc),
+            VmError::JumpOutOfRange { pc } => write!(f, "{}: relative jump lands before the start of the program", pc),
+            VmError::Suspended { pc } => write!(f, "{}: suspended waiting for a process runtime", pc),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// How many instructions a thread runs before the scheduler moves on to the next one, unless it
+/// yields, joins or halts first.
+pub const DEFAULT_QUANTUM: usize = 1000;
+
+/// A program's instructions, shared between every VM running it. Changing the instructions
+/// through `DerefMut` first makes a copy for this VM if any other VM shares them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProgramImage(Arc<Vec<Instruction>>);
+
+impl ProgramImage {
+    /// Whether both images share the same instructions rather than equal copies of them.
+    pub fn is_shared_with(&self, other: &ProgramImage) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Deref for ProgramImage {
+    type Target = Vec<Instruction>;
+
+    fn deref(&self) -> &Vec<Instruction> {
+        &self.0
+    }
+}
+
+impl DerefMut for ProgramImage {
+    fn deref_mut(&mut self) -> &mut Vec<Instruction> {
+        Arc::make_mut(&mut self.0)
+    }
+}
+
+impl From<Vec<Instruction>> for ProgramImage {
+    fn from(program: Vec<Instruction>) -> Self {
+        ProgramImage(Arc::new(program))
+    }
+}
+
+/// A message between processes, as `SEND` sends and `RECV` receives it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Message {
+    pub from: usize,
+    pub value: i32,
+    pub kind: MessageKind,
+}
+
+/// Who a message came from, which `RECV` reports in the overflow flag rather than in the value, so
+/// no process can pass its own message off as a failure.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MessageKind {
+    /// Sent by `SEND` or `process::Runtime::send`.
+    Sent,
+    /// Sent by the runtime to tell a supervisor that `from` failed. The value is 0.
+    Failure,
+}
+
+/// Why a VM stopped partway through its program to wait for the `process::Runtime` running it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Suspension {
+    /// `RECV` found the mailbox empty. It runs again once a message arrives, unless the runtime
+    /// gives up after `timeout` ticks and calls `cancel_receive`. `None` waits forever.
+    Receive { timeout: Option<u64> },
+    /// `SPAWNP` asked for a process starting at `pc`, whose pid goes in `register`.
+    Spawn { pc: usize, register: usize },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ThreadState {
+    Runnable,
+    /// Waiting for the thread with this id to finish.
+    Joining(usize),
+    Finished,
+}
+
+/// Everything a thread does not share with the others. The running thread's context lives in
+/// the `VM`'s own fields, and is swapped with the copy in its `Thread` when it stops running.
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub registers: [i32; REGISTER_COUNT],
+    pub float_registers: [f64; REGISTER_COUNT],
+    pub pc: usize,
+    pub value_registers: [Value; REGISTER_COUNT],
+    pub value_stack: Vec<Value>,
+    pub stack: Vec<i32>,
+    pub frame_pointer: usize,
+    pub call_stack: Vec<usize>,
+    pub remainder: u32,
+    pub equal: bool,
+    pub overflow: bool,
+    pub carry: bool,
+}
+
+impl Context {
+    pub fn new(registers: [i32; REGISTER_COUNT], pc: usize) -> Context {
+        Context {
+            registers,
+            float_registers: [0.0; REGISTER_COUNT],
+            pc,
+            value_registers: [Value::Nil; REGISTER_COUNT],
+            value_stack: vec![],
+            stack: vec![],
+            frame_pointer: 0,
+            call_stack: vec![],
+            remainder: 0,
+            equal: false,
+            overflow: false,
+            carry: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Thread {
+    /// The thread's registers and stacks while it is not running.
+    pub context: Context,
+    pub state: ThreadState,
+    /// `$0` when the thread finished, which `JOIN` hands to the threads waiting for it.
+    pub result: i32,
+}
 
 #[derive(Debug)]
 pub struct VM {
-    pub registers: [i32; 32],
+    pub registers: [i32; REGISTER_COUNT],
+    pub float_registers: [f64; REGISTER_COUNT],
     pub pc: usize,
-    pub program: Vec<Instruction>,
-    pub heap: Vec<u8>,
+    pub program: ProgramImage,
+    pub constants: Vec<Constant>,
+    /// Tagged values for dynamically typed code. These and `value_stack` are the garbage
+    /// collector's roots.
+    pub value_registers: [Value; REGISTER_COUNT],
+    pub value_stack: Vec<Value>,
+    pub heap: Heap,
+    /// The heap string for each string constant that `LOADS` has used, so every load of a
+    /// constant shares one object. These are also roots, as they can be reloaded at any time.
+    pub interned_strings: HashMap<usize, ObjectRef>,
+    /// Integer stack for `PUSH`, `POP` and stack frames.
+    pub stack: Vec<i32>,
+    /// Index in `stack` of the current frame's first local, set by `ENTER`. `LDF` and `STF`
+    /// address the stack relative to it, so a function's arguments are at negative offsets.
+    pub frame_pointer: usize,
+    /// Return addresses of the `CALL`s in progress, innermost last.
+    pub call_stack: Vec<usize>,
     pub remainder: u32,
     pub equal: bool,
+    /// Set when the last arithmetic instruction overflowed as a signed operation.
+    pub overflow: bool,
+    /// Set when the last arithmetic instruction carried or borrowed as an unsigned operation.
+    pub carry: bool,
+    pub arithmetic_mode: ArithmeticMode,
+    /// Collects the lines `PRTS` prints instead of writing them to standard output, if set.
+    pub output: Option<Vec<String>>,
+    /// Every thread spawned so far, indexed by id. The main thread is 0.
+    pub threads: Vec<Thread>,
+    pub current_thread: usize,
+    /// How many instructions a thread runs before it is preempted.
+    pub quantum: usize,
+    /// Instructions the current thread has run since it was scheduled.
+    pub(crate) slice: usize,
+    /// This VM's process id when it runs as one of a runtime's processes.
+    pub pid: usize,
+    /// Messages waiting for `RECV`, oldest first.
+    pub mailbox: VecDeque<Message>,
+    /// Messages `SEND` has sent and the runtime has not delivered yet, with their destination.
+    pub outbox: Vec<(usize, Message)>,
+    pub suspension: Option<Suspension>,
+    /// Whether the last thread to finish did so at a `HLT`, rather than by running off the end
+    /// of the program.
+    pub halted: bool,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            registers: [0; 32],
+            registers: [0; REGISTER_COUNT],
+            float_registers: [0.0; REGISTER_COUNT],
             pc: 0,
-            program: vec![],
-            heap: vec![],
+            program: ProgramImage::default(),
+            constants: vec![],
+            value_registers: [Value::Nil; REGISTER_COUNT],
+            value_stack: vec![],
+            heap: Heap::new(),
+            interned_strings: HashMap::new(),
+            stack: vec![],
+            frame_pointer: 0,
+            call_stack: vec![],
             remainder: 0,
             equal: false,
+            overflow: false,
+            carry: false,
+            arithmetic_mode: ArithmeticMode::default(),
+            output: None,
+            threads: vec![Thread { context: Context::new([0; REGISTER_COUNT], 0), state: ThreadState::Runnable, result: 0 }],
+            current_thread: 0,
+            quantum: DEFAULT_QUANTUM,
+            slice: 0,
+            pid: 0,
+            mailbox: VecDeque::new(),
+            outbox: vec![],
+            suspension: None,
+            halted: false,
         }
     }
 
-    pub fn run(&mut self) {
-        let mut successful = true;
-        while successful {
-            let instruction = self.read_next_instruction();
-            match instruction {
-                Some(instruction) => {
-                    successful = self.execute_instruction(instruction);
-                }
-                None => {
-                    successful = false;
-                }
-            }
+    /// Runs until every thread has finished, by halting or running off the end of the program.
+    /// Stopping because the VM was suspended is a `VmError::Suspended`, as only a
+    /// `process::Runtime` stepping the VM can resume it.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        while self.step()? {}
+        self.check_suspension()
+    }
+
+    /// The error for a run that stopped because the VM was suspended, if it was.
+    pub fn check_suspension(&self) -> Result<(), VmError> {
+        match self.suspension {
+            None => Ok(()),
+            // `RECV` leaves the pc on itself, to run again once a message arrives.
+            Some(Suspension::Receive { .. }) => Err(VmError::Suspended { pc: self.pc }),
+            Some(Suspension::Spawn { .. }) => Err(VmError::Suspended { pc: self.pc - 1 }),
         }
     }
 
-    pub fn run_once(&mut self) {
-        let instruction = self.read_next_instruction();
-        match instruction {
-            Some(instruction) => {
-                self.execute_instruction(instruction);
+    pub fn run_once(&mut self) -> Result<(), VmError> {
+        self.step()?;
+        Ok(())
+    }
+
+    /// Gives up on the `RECV` the VM is suspended in, which then finishes with the equal and
+    /// overflow flags cleared.
+    pub fn cancel_receive(&mut self) {
+        if let Some(Suspension::Receive { .. }) = self.suspension {
+            self.suspension = None;
+            self.equal = false;
+            self.overflow = false;
+            self.pc += 1;
+        }
+    }
+
+    /// Runs one instruction of the current thread, then switches threads if its quantum is used
+    /// up. Returns whether any thread can still run, which is false while the VM is suspended.
+    pub fn step(&mut self) -> Result<bool, VmError> {
+        let Some(instruction) = self.read_next_instruction() else {
+            return self.finish_thread(false);
+        };
+        self.slice += 1;
+        let running = self.execute_instruction(instruction)?;
+        if running && self.slice >= self.quantum {
+            self.schedule();
+        }
+        Ok(running)
+    }
+
+    /// Switches to the next runnable thread after the current one, which can be the current one
+    /// again. Returns whether any thread was runnable.
+    pub(crate) fn schedule(&mut self) -> bool {
+        let count = self.threads.len();
+        let next = (1..=count)
+            .map(|offset| (self.current_thread + offset) % count)
+            .find(|id| self.threads[*id].state == ThreadState::Runnable);
+        let Some(next) = next else {
+            return false;
+        };
+        if next != self.current_thread {
+            let mut context = mem::replace(&mut self.threads[next].context, Context::new([0; REGISTER_COUNT], 0));
+            self.swap_context(&mut context);
+            self.threads[self.current_thread].context = context;
+            self.current_thread = next;
+        }
+        self.slice = 0;
+        return true;
+    }
+
+    /// Exchanges the VM's per-thread fields with `context`.
+    fn swap_context(&mut self, context: &mut Context) {
+        mem::swap(&mut self.registers, &mut context.registers);
+        mem::swap(&mut self.float_registers, &mut context.float_registers);
+        mem::swap(&mut self.pc, &mut context.pc);
+        mem::swap(&mut self.value_registers, &mut context.value_registers);
+        mem::swap(&mut self.value_stack, &mut context.value_stack);
+        mem::swap(&mut self.stack, &mut context.stack);
+        mem::swap(&mut self.frame_pointer, &mut context.frame_pointer);
+        mem::swap(&mut self.call_stack, &mut context.call_stack);
+        mem::swap(&mut self.remainder, &mut context.remainder);
+        mem::swap(&mut self.equal, &mut context.equal);
+        mem::swap(&mut self.overflow, &mut context.overflow);
+        mem::swap(&mut self.carry, &mut context.carry);
+    }
+
+    /// Ends the current thread, at a `HLT` if `halted`, waking any threads joining it, and moves
+    /// on to another. Returns whether any thread can still run.
+    pub(crate) fn finish_thread(&mut self, halted: bool) -> Result<bool, VmError> {
+        let id = self.current_thread;
+        self.halted = halted;
+        self.threads[id].state = ThreadState::Finished;
+        self.threads[id].result = self.registers[0];
+        for thread in &mut self.threads {
+            if thread.state == ThreadState::Joining(id) {
+                thread.state = ThreadState::Runnable;
             }
-            None => {}
         }
+        if self.schedule() {
+            return Ok(true);
+        }
+        if self.threads.iter().any(|thread| matches!(thread.state, ThreadState::Joining(_))) {
+            return Err(VmError::Deadlock { pc: self.pc.saturating_sub(1) });
+        }
+        return Ok(false);
     }
 
     pub fn add_instruction(&mut self, instruction: Instruction) {
@@ -60,86 +408,200 @@ impl VM {
         return Some(instruction);
     }
 
-    fn execute_instruction(&mut self, instruction: Instruction) -> bool {
+    /// The two inputs of a binary operation, taking the second from `integer_operand` for the
+    /// immediate forms.
+    fn operands(&self, instruction: Instruction) -> (i32, i32) {
+        let first_number = self.registers[instruction.registers[0]];
+        if instruction.opcode.has_integer_operand() {
+            return (first_number, instruction.integer_operand as i32);
+        }
+        return (first_number, self.registers[instruction.registers[1]]);
+    }
+
+    fn float_operands(&self, instruction: Instruction) -> (f64, f64) {
+        (
+            self.float_registers[instruction.registers[0]],
+            self.float_registers[instruction.registers[1]],
+        )
+    }
+
+    /// Frees every heap object that is not reachable from any thread's value registers or value
+    /// stack, or from the interned strings.
+    pub fn collect_garbage(&mut self) {
+        let interned = self.interned_strings.values().map(|object| Value::Ref(*object));
+        let suspended = self.threads.iter().flat_map(|thread| thread.context.value_registers.iter().chain(thread.context.value_stack.iter()));
+        let roots = self.value_registers.iter().chain(self.value_stack.iter()).chain(suspended).copied().chain(interned);
+        self.heap.collect(roots);
+    }
+
+    /// Allocates on the heap, collecting first if enough has been allocated since the last
+    /// collection. Anything the instruction still needs must be in a root when this is called.
+    fn allocate(&mut self, object: Object) -> ObjectRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.allocate(object)
+    }
+
+    /// The heap object a value register refers to.
+    fn object(&self, value: Value) -> Result<&Object, VmError> {
+        match value {
+            Value::Ref(object) => self.heap.get(object).ok_or(VmError::TypeError { pc: self.pc - 1 }),
+            _ => Err(VmError::TypeError { pc: self.pc - 1 }),
+        }
+    }
+
+    /// Index in `stack` of the slot `offset` away from the frame pointer.
+    pub(crate) fn frame_slot(&self, offset: i64) -> Result<usize, VmError> {
+        match usize::try_from(self.frame_pointer as i64 + offset) {
+            Ok(slot) if slot < self.stack.len() => Ok(slot),
+            _ => Err(VmError::IndexOutOfBounds { pc: self.pc - 1 }),
+        }
+    }
+
+    /// Where a relative jump by `offset` lands, counting from the instruction after the jump.
+    /// A negative offset goes the other way.
+    fn relative_target(&self, offset: i32, forward: bool) -> Result<usize, VmError> {
+        let offset = if forward { offset as i64 } else { -(offset as i64) };
+        match (self.pc as i64).checked_add(offset).map(usize::try_from) {
+            Some(Ok(target)) => Ok(target),
+            _ => Err(VmError::JumpOutOfRange { pc: self.pc - 1 }),
+        }
+    }
+
+    /// The contents of a value register that must hold a string.
+    fn string(&self, value: Value) -> Result<&str, VmError> {
+        match self.object(value)? {
+            Object::String(string) => Ok(string),
+            _ => Err(VmError::TypeError { pc: self.pc - 1 }),
+        }
+    }
+
+    fn object_mut(&mut self, value: Value) -> Result<&mut Object, VmError> {
+        let pc = self.pc - 1;
+        match value {
+            Value::Ref(object) => self.heap.get_mut(object).ok_or(VmError::TypeError { pc }),
+            _ => Err(VmError::TypeError { pc }),
+        }
+    }
+
+    /// The 64-bit value held in `register` (low half) and `register + 1` (high half).
+    fn read_pair(&self, register: usize) -> i64 {
+        ((self.registers[register + 1] as i64) << 32) | (self.registers[register] as u32 as i64)
+    }
+
+    fn write_pair(&mut self, register: usize, value: i64) {
+        self.registers[register] = value as i32;
+        self.registers[register + 1] = (value >> 32) as i32;
+    }
+
+    fn pair_operands(&self, instruction: Instruction) -> (i64, i64) {
+        (self.read_pair(instruction.registers[0]), self.read_pair(instruction.registers[1]))
+    }
+
+    /// Applies the arithmetic mode to the outcome of an operation that has already been
+    /// computed as `(wrapped, overflowed)` and as a saturating result.
+    pub(crate) fn arithmetic_result<T>(&mut self, (wrapped, overflowed): (T, bool), saturated: T, carry: bool) -> Result<T, VmError> {
+        self.overflow = overflowed;
+        self.carry = carry;
+        if !overflowed {
+            return Ok(wrapped);
+        }
+        match self.arithmetic_mode {
+            ArithmeticMode::Trap => Err(VmError::IntegerOverflow { pc: self.pc - 1 }),
+            ArithmeticMode::Wrap => Ok(wrapped),
+            ArithmeticMode::Saturate => Ok(saturated),
+        }
+    }
+
+    pub(crate) fn execute_instruction(&mut self, instruction: Instruction) -> Result<bool, VmError> {
         match instruction.opcode {
             Opcode::LOAD => {
                 let address = instruction.registers[0];
                 let number = instruction.integer_operand;
                 self.registers[address] = number as i32;
             }
+            // Only ends the current thread. The VM stops once no thread is left to run.
             Opcode::HLT => {
                 println!("HLT encountered");
-                return false;
+                return self.finish_thread(true);
             }
-            Opcode::ADD => {
-                let first_number = self.registers[instruction.registers[0]];
-                let second_number = self.registers[instruction.registers[1]];
+            Opcode::ADD | Opcode::ADDI => {
+                let (first_number, second_number) = self.operands(instruction);
 
                 let address = instruction.registers[2];
-                self.registers[address] = first_number + second_number;
+                self.registers[address] = self.arithmetic_result(
+                    first_number.overflowing_add(second_number),
+                    first_number.saturating_add(second_number),
+                    (first_number as u32).overflowing_add(second_number as u32).1,
+                )?;
             }
-            Opcode::SUB => {
-                let first_number = self.registers[instruction.registers[0]];
-                let second_number = self.registers[instruction.registers[1]];
+            Opcode::SUB | Opcode::SUBI => {
+                let (first_number, second_number) = self.operands(instruction);
 
                 let address = instruction.registers[2];
-                self.registers[address] = first_number - second_number;
+                self.registers[address] = self.arithmetic_result(
+                    first_number.overflowing_sub(second_number),
+                    first_number.saturating_sub(second_number),
+                    (first_number as u32).overflowing_sub(second_number as u32).1,
+                )?;
             }
-            Opcode::MUL => {
-                let first_number = self.registers[instruction.registers[0]];
-                let second_number = self.registers[instruction.registers[1]];
+            Opcode::MUL | Opcode::MULI => {
+                let (first_number, second_number) = self.operands(instruction);
 
                 let address = instruction.registers[2];
-                self.registers[address] = first_number * second_number;
+                self.registers[address] = self.arithmetic_result(
+                    first_number.overflowing_mul(second_number),
+                    first_number.saturating_mul(second_number),
+                    (first_number as u32).overflowing_mul(second_number as u32).1,
+                )?;
             }
-            Opcode::DIV => {
-                let first_number = self.registers[instruction.registers[0]];
-                let second_number = self.registers[instruction.registers[1]];
+            Opcode::DIV | Opcode::DIVI => {
+                let (first_number, second_number) = self.operands(instruction);
+                if second_number == 0 {
+                    return Err(VmError::DivisionByZero { pc: self.pc - 1 });
+                }
 
                 let address = instruction.registers[2];
-                self.registers[address] = first_number / second_number;
-                self.remainder = (first_number % second_number) as u32;
+                self.registers[address] = self.arithmetic_result(
+                    first_number.overflowing_div(second_number),
+                    first_number.saturating_div(second_number),
+                    false,
+                )?;
+                self.remainder = first_number.wrapping_rem(second_number) as u32;
             }
             Opcode::JMP => {
                 let address = self.registers[instruction.registers[0]];
                 self.pc = address as usize;
             }
             Opcode::JMPF => {
-                let address = self.registers[instruction.registers[0]];
-                self.pc += address as usize;
+                self.pc = self.relative_target(self.registers[instruction.registers[0]], true)?;
             }
             Opcode::JMPB => {
-                let address = self.registers[instruction.registers[0]];
-                self.pc -= address as usize;
+                self.pc = self.relative_target(self.registers[instruction.registers[0]], false)?;
             }
-            Opcode::EQ => {
-                let first_number = self.registers[instruction.registers[0]];
-                let second_number = self.registers[instruction.registers[1]];
+            Opcode::EQ | Opcode::EQI => {
+                let (first_number, second_number) = self.operands(instruction);
                 self.equal = first_number == second_number;
             }
-            Opcode::NEQ => {
-                let first_number = self.registers[instruction.registers[0]];
-                let second_number = self.registers[instruction.registers[1]];
+            Opcode::NEQ | Opcode::NEQI => {
+                let (first_number, second_number) = self.operands(instruction);
                 self.equal = first_number != second_number;
             }
-            Opcode::GT => {
-                let first_number = self.registers[instruction.registers[0]];
-                let second_number = self.registers[instruction.registers[1]];
+            Opcode::GT | Opcode::GTI => {
+                let (first_number, second_number) = self.operands(instruction);
                 self.equal = first_number > second_number;
             }
-            Opcode::LT => {
-                let first_number = self.registers[instruction.registers[0]];
-                let second_number = self.registers[instruction.registers[1]];
+            Opcode::LT | Opcode::LTI => {
+                let (first_number, second_number) = self.operands(instruction);
                 self.equal = first_number < second_number;
             }
-            Opcode::GTQ => {
-                let first_number = self.registers[instruction.registers[0]];
-                let second_number = self.registers[instruction.registers[1]];
+            Opcode::GTQ | Opcode::GTQI => {
+                let (first_number, second_number) = self.operands(instruction);
                 self.equal = first_number >= second_number;
             }
-            Opcode::LTQ => {
-                let first_number = self.registers[instruction.registers[0]];
-                let second_number = self.registers[instruction.registers[1]];
+            Opcode::LTQ | Opcode::LTQI => {
+                let (first_number, second_number) = self.operands(instruction);
                 self.equal = first_number <= second_number;
             }
             Opcode::JEQ => {
@@ -148,22 +610,1022 @@ impl VM {
                     self.pc = address as usize;
                 }
             }
+            Opcode::JNEQ => {
+                let address = self.registers[instruction.registers[0]];
+                if !self.equal {
+                    self.pc = address as usize;
+                }
+            }
+            Opcode::JZ => {
+                let number = self.registers[instruction.registers[0]];
+                let address = self.registers[instruction.registers[1]];
+                if number == 0 {
+                    self.pc = address as usize;
+                }
+            }
+            Opcode::JNZ => {
+                let number = self.registers[instruction.registers[0]];
+                let address = self.registers[instruction.registers[1]];
+                if number != 0 {
+                    self.pc = address as usize;
+                }
+            }
+            Opcode::JEQF | Opcode::JEQB | Opcode::JNEQF | Opcode::JNEQB => {
+                let offset = self.registers[instruction.registers[0]];
+                if self.equal == matches!(instruction.opcode, Opcode::JEQF | Opcode::JEQB) {
+                    self.pc = self.relative_target(offset, matches!(instruction.opcode, Opcode::JEQF | Opcode::JNEQF))?;
+                }
+            }
             Opcode::ALOC => {
                 let number_of_bytes = self.registers[instruction.registers[0]];
-                let new_len = self.heap.len() + number_of_bytes as usize;
-                self.heap.resize(new_len, 0);
+                if number_of_bytes < 0 {
+                    return Err(VmError::NegativeSize { pc: self.pc - 1 });
+                }
+                let object = self.allocate(Object::Bytes(vec![0; number_of_bytes as usize]));
+                self.value_registers[instruction.registers[1]] = Value::Ref(object);
             }
             Opcode::INC => {
-                self.registers[instruction.registers[0]] += 1;
+                let number = self.registers[instruction.registers[0]];
+                self.registers[instruction.registers[0]] = self.arithmetic_result(
+                    number.overflowing_add(1),
+                    number.saturating_add(1),
+                    number == -1,
+                )?;
             }
             Opcode::DEC => {
-                self.registers[instruction.registers[0]] -= 1;
+                let number = self.registers[instruction.registers[0]];
+                self.registers[instruction.registers[0]] = self.arithmetic_result(
+                    number.overflowing_sub(1),
+                    number.saturating_sub(1),
+                    number == 0,
+                )?;
+            }
+            Opcode::JO => {
+                let address = self.registers[instruction.registers[0]];
+                if self.overflow {
+                    self.pc = address as usize;
+                }
+            }
+            Opcode::JNO => {
+                let address = self.registers[instruction.registers[0]];
+                if !self.overflow {
+                    self.pc = address as usize;
+                }
+            }
+            Opcode::AND => {
+                let first_number = self.registers[instruction.registers[0]];
+                let second_number = self.registers[instruction.registers[1]];
+                self.registers[instruction.registers[2]] = first_number & second_number;
+            }
+            Opcode::OR => {
+                let first_number = self.registers[instruction.registers[0]];
+                let second_number = self.registers[instruction.registers[1]];
+                self.registers[instruction.registers[2]] = first_number | second_number;
+            }
+            Opcode::XOR => {
+                let first_number = self.registers[instruction.registers[0]];
+                let second_number = self.registers[instruction.registers[1]];
+                self.registers[instruction.registers[2]] = first_number ^ second_number;
             }
-            _ => {
-                return false;
+            Opcode::NOT => {
+                let number = self.registers[instruction.registers[0]];
+                self.registers[instruction.registers[1]] = !number;
             }
+            // Shift amounts only use their low five bits, so shifting by 32 or more never panics.
+            Opcode::SHL => {
+                let number = self.registers[instruction.registers[0]];
+                let amount = self.registers[instruction.registers[1]] as u32;
+                self.registers[instruction.registers[2]] = number.wrapping_shl(amount);
+            }
+            Opcode::SHR => {
+                let number = self.registers[instruction.registers[0]] as u32;
+                let amount = self.registers[instruction.registers[1]] as u32;
+                self.registers[instruction.registers[2]] = number.wrapping_shr(amount) as i32;
+            }
+            Opcode::SAR => {
+                let number = self.registers[instruction.registers[0]];
+                let amount = self.registers[instruction.registers[1]] as u32;
+                self.registers[instruction.registers[2]] = number.wrapping_shr(amount);
+            }
+            Opcode::MOD => {
+                let first_number = self.registers[instruction.registers[0]];
+                let second_number = self.registers[instruction.registers[1]];
+                if second_number == 0 {
+                    return Err(VmError::DivisionByZero { pc: self.pc - 1 });
+                }
+                self.registers[instruction.registers[2]] = first_number.wrapping_rem_euclid(second_number);
+            }
+            Opcode::GETREM => {
+                self.registers[instruction.registers[0]] = self.remainder as i32;
+            }
+            Opcode::LOADF => {
+                let index = instruction.integer_operand;
+                match self.constants.get(index as usize) {
+                    Some(Constant::Float(number)) => self.float_registers[instruction.registers[0]] = *number,
+                    _ => return Err(VmError::BadConstant { pc: self.pc - 1, index }),
+                }
+            }
+            // Float arithmetic follows IEEE 754: division by zero gives an infinity and NaN
+            // propagates, so none of these trap.
+            Opcode::ADDF => {
+                let (first_number, second_number) = self.float_operands(instruction);
+                self.float_registers[instruction.registers[2]] = first_number + second_number;
+            }
+            Opcode::SUBF => {
+                let (first_number, second_number) = self.float_operands(instruction);
+                self.float_registers[instruction.registers[2]] = first_number - second_number;
+            }
+            Opcode::MULF => {
+                let (first_number, second_number) = self.float_operands(instruction);
+                self.float_registers[instruction.registers[2]] = first_number * second_number;
+            }
+            Opcode::DIVF => {
+                let (first_number, second_number) = self.float_operands(instruction);
+                self.float_registers[instruction.registers[2]] = first_number / second_number;
+            }
+            // Every comparison with NaN is false except `NEQF`.
+            Opcode::EQF => {
+                let (first_number, second_number) = self.float_operands(instruction);
+                self.equal = first_number == second_number;
+            }
+            Opcode::NEQF => {
+                let (first_number, second_number) = self.float_operands(instruction);
+                self.equal = first_number != second_number;
+            }
+            Opcode::GTF => {
+                let (first_number, second_number) = self.float_operands(instruction);
+                self.equal = first_number > second_number;
+            }
+            Opcode::LTF => {
+                let (first_number, second_number) = self.float_operands(instruction);
+                self.equal = first_number < second_number;
+            }
+            Opcode::GTQF => {
+                let (first_number, second_number) = self.float_operands(instruction);
+                self.equal = first_number >= second_number;
+            }
+            Opcode::LTQF => {
+                let (first_number, second_number) = self.float_operands(instruction);
+                self.equal = first_number <= second_number;
+            }
+            Opcode::ITOF => {
+                let number = self.registers[instruction.registers[0]];
+                self.float_registers[instruction.registers[1]] = number as f64;
+            }
+            // Truncates toward zero. NaN and values outside the `i32` range overflow; `as` gives
+            // the saturated result (NaN becomes 0) and the wrapped result is taken to be the same.
+            Opcode::FTOI => {
+                let number = self.float_registers[instruction.registers[0]].trunc();
+                let converted = number as i32;
+                let overflowed = number.is_nan() || number < i32::MIN as f64 || number > i32::MAX as f64;
+                self.registers[instruction.registers[1]] =
+                    self.arithmetic_result((converted, overflowed), converted, false)?;
+            }
+            Opcode::LOADL => {
+                self.write_pair(instruction.registers[0], instruction.integer_operand);
+            }
+            Opcode::ADDL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                let result = self.arithmetic_result(
+                    first_number.overflowing_add(second_number),
+                    first_number.saturating_add(second_number),
+                    (first_number as u64).overflowing_add(second_number as u64).1,
+                )?;
+                self.write_pair(instruction.registers[2], result);
+            }
+            Opcode::SUBL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                let result = self.arithmetic_result(
+                    first_number.overflowing_sub(second_number),
+                    first_number.saturating_sub(second_number),
+                    (first_number as u64).overflowing_sub(second_number as u64).1,
+                )?;
+                self.write_pair(instruction.registers[2], result);
+            }
+            Opcode::MULL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                let result = self.arithmetic_result(
+                    first_number.overflowing_mul(second_number),
+                    first_number.saturating_mul(second_number),
+                    (first_number as u64).overflowing_mul(second_number as u64).1,
+                )?;
+                self.write_pair(instruction.registers[2], result);
+            }
+            Opcode::DIVL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                if second_number == 0 {
+                    return Err(VmError::DivisionByZero { pc: self.pc - 1 });
+                }
+                let result = self.arithmetic_result(
+                    first_number.overflowing_div(second_number),
+                    first_number.saturating_div(second_number),
+                    false,
+                )?;
+                self.write_pair(instruction.registers[2], result);
+            }
+            Opcode::MODL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                if second_number == 0 {
+                    return Err(VmError::DivisionByZero { pc: self.pc - 1 });
+                }
+                self.write_pair(instruction.registers[2], first_number.wrapping_rem_euclid(second_number));
+            }
+            Opcode::EQL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                self.equal = first_number == second_number;
+            }
+            Opcode::NEQL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                self.equal = first_number != second_number;
+            }
+            Opcode::GTL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                self.equal = first_number > second_number;
+            }
+            Opcode::LTL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                self.equal = first_number < second_number;
+            }
+            Opcode::GTQL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                self.equal = first_number >= second_number;
+            }
+            Opcode::LTQL => {
+                let (first_number, second_number) = self.pair_operands(instruction);
+                self.equal = first_number <= second_number;
+            }
+            Opcode::SEXT => {
+                let number = self.registers[instruction.registers[0]];
+                self.write_pair(instruction.registers[1], number as i64);
+            }
+            Opcode::NIL => {
+                self.value_registers[instruction.registers[0]] = Value::Nil;
+            }
+            Opcode::BOXI => {
+                let number = self.registers[instruction.registers[0]];
+                self.value_registers[instruction.registers[1]] = Value::Int(number as i64);
+            }
+            Opcode::BOXF => {
+                let number = self.float_registers[instruction.registers[0]];
+                self.value_registers[instruction.registers[1]] = Value::Float(number);
+            }
+            Opcode::BOXB => {
+                self.value_registers[instruction.registers[0]] = Value::Bool(self.equal);
+            }
+            Opcode::UNBOX => {
+                let number = match self.value_registers[instruction.registers[0]] {
+                    Value::Int(number) => {
+                        let saturated = number.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+                        self.arithmetic_result((number as i32, saturated as i64 != number), saturated, false)?
+                    }
+                    Value::Bool(boolean) => boolean as i32,
+                    _ => return Err(VmError::TypeError { pc: self.pc - 1 }),
+                };
+                self.registers[instruction.registers[1]] = number;
+            }
+            Opcode::UNBOXF => {
+                let number = match self.value_registers[instruction.registers[0]] {
+                    Value::Float(number) => number,
+                    Value::Int(number) => number as f64,
+                    _ => return Err(VmError::TypeError { pc: self.pc - 1 }),
+                };
+                self.float_registers[instruction.registers[1]] = number;
+            }
+            Opcode::MOVV => {
+                self.value_registers[instruction.registers[1]] = self.value_registers[instruction.registers[0]];
+            }
+            Opcode::TAG => {
+                let value = self.value_registers[instruction.registers[0]];
+                self.registers[instruction.registers[1]] = self.heap.type_tag(value);
+            }
+            Opcode::EQV => {
+                let first_value = self.value_registers[instruction.registers[0]];
+                let second_value = self.value_registers[instruction.registers[1]];
+                self.equal = self.heap.values_equal(first_value, second_value);
+            }
+            Opcode::NEWARR => {
+                let length = self.registers[instruction.registers[0]];
+                if length < 0 {
+                    return Err(VmError::NegativeSize { pc: self.pc - 1 });
+                }
+                let object = self.allocate(Object::Array(vec![Value::Nil; length as usize]));
+                self.value_registers[instruction.registers[1]] = Value::Ref(object);
+            }
+            Opcode::NEWMAP => {
+                let object = self.allocate(Object::Map(HashMap::new()));
+                self.value_registers[instruction.registers[0]] = Value::Ref(object);
+            }
+            Opcode::GETV => {
+                let pc = self.pc - 1;
+                let container = self.value_registers[instruction.registers[0]];
+                let key = self.value_registers[instruction.registers[1]];
+                let map_key = self.heap.map_key(key);
+                let value = match (self.object(container)?, key) {
+                    (Object::Array(values), Value::Int(index)) => {
+                        *values.get(index as usize).ok_or(VmError::IndexOutOfBounds { pc })?
+                    }
+                    (Object::Bytes(bytes), Value::Int(index)) => {
+                        Value::Int(*bytes.get(index as usize).ok_or(VmError::IndexOutOfBounds { pc })? as i64)
+                    }
+                    (Object::Map(entries), _) => entries.get(&map_key).map_or(Value::Nil, |(_, value)| *value),
+                    _ => return Err(VmError::TypeError { pc }),
+                };
+                self.value_registers[instruction.registers[2]] = value;
+            }
+            Opcode::SETV => {
+                let pc = self.pc - 1;
+                let container = self.value_registers[instruction.registers[0]];
+                let key = self.value_registers[instruction.registers[1]];
+                let value = self.value_registers[instruction.registers[2]];
+                let map_key = self.heap.map_key(key);
+                match (self.object_mut(container)?, key, value) {
+                    (Object::Array(values), Value::Int(index), _) => {
+                        *values.get_mut(index as usize).ok_or(VmError::IndexOutOfBounds { pc })? = value;
+                    }
+                    (Object::Bytes(bytes), Value::Int(index), Value::Int(byte)) => {
+                        let byte = u8::try_from(byte).map_err(|_| VmError::TypeError { pc })?;
+                        *bytes.get_mut(index as usize).ok_or(VmError::IndexOutOfBounds { pc })? = byte;
+                    }
+                    (Object::Map(entries), _, _) => {
+                        entries.insert(map_key, (key, value));
+                    }
+                    _ => return Err(VmError::TypeError { pc }),
+                }
+            }
+            Opcode::LENV => {
+                let length = match self.object(self.value_registers[instruction.registers[0]])? {
+                    Object::String(string) => string.chars().count(),
+                    Object::Array(values) => values.len(),
+                    Object::Map(entries) => entries.len(),
+                    Object::Bytes(bytes) => bytes.len(),
+                };
+                self.registers[instruction.registers[1]] = length as i32;
+            }
+            Opcode::APPV => {
+                let value = self.value_registers[instruction.registers[1]];
+                match self.object_mut(self.value_registers[instruction.registers[0]])? {
+                    Object::Array(values) => values.push(value),
+                    _ => return Err(VmError::TypeError { pc: self.pc - 1 }),
+                }
+            }
+            Opcode::PUSHV => {
+                self.value_stack.push(self.value_registers[instruction.registers[0]]);
+            }
+            Opcode::POPV => {
+                let value = self.value_stack.pop().ok_or(VmError::StackUnderflow { pc: self.pc - 1 })?;
+                self.value_registers[instruction.registers[0]] = value;
+            }
+            Opcode::LOADS => {
+                let index = instruction.integer_operand;
+                let object = match (self.interned_strings.get(&(index as usize)), self.constants.get(index as usize)) {
+                    (Some(object), _) => *object,
+                    (None, Some(Constant::String(string))) => {
+                        let object = self.allocate(Object::String(string.clone()));
+                        self.interned_strings.insert(index as usize, object);
+                        object
+                    }
+                    _ => return Err(VmError::BadConstant { pc: self.pc - 1, index }),
+                };
+                self.value_registers[instruction.registers[0]] = Value::Ref(object);
+            }
+            Opcode::CONCAT => {
+                let first_string = self.string(self.value_registers[instruction.registers[0]])?;
+                let second_string = self.string(self.value_registers[instruction.registers[1]])?;
+                let concatenated = [first_string, second_string].concat();
+                let object = self.allocate(Object::String(concatenated));
+                self.value_registers[instruction.registers[2]] = Value::Ref(object);
+            }
+            Opcode::SLEN => {
+                let length = self.string(self.value_registers[instruction.registers[0]])?.chars().count();
+                self.registers[instruction.registers[1]] = length as i32;
+            }
+            // Indices count characters rather than bytes, with the end of the range exclusive.
+            Opcode::SUBSTR => {
+                let pc = self.pc - 1;
+                let start = self.registers[instruction.registers[1]];
+                let end = self.registers[instruction.registers[1] + 1];
+                let string = self.string(self.value_registers[instruction.registers[0]])?;
+                if start < 0 || end < start || end as usize > string.chars().count() {
+                    return Err(VmError::IndexOutOfBounds { pc });
+                }
+                let substring = string.chars().skip(start as usize).take((end - start) as usize).collect();
+                let object = self.allocate(Object::String(substring));
+                self.value_registers[instruction.registers[2]] = Value::Ref(object);
+            }
+            Opcode::SCMP => {
+                let first_string = self.string(self.value_registers[instruction.registers[0]])?;
+                let second_string = self.string(self.value_registers[instruction.registers[1]])?;
+                let ordering = first_string.cmp(second_string) as i32;
+                self.equal = ordering == 0;
+                self.registers[instruction.registers[2]] = ordering;
+            }
+            Opcode::CHARAT => {
+                let index = self.registers[instruction.registers[1]];
+                let string = self.string(self.value_registers[instruction.registers[0]])?;
+                let character = usize::try_from(index).ok().and_then(|index| string.chars().nth(index));
+                match character {
+                    Some(character) => self.registers[instruction.registers[2]] = character as i32,
+                    None => return Err(VmError::IndexOutOfBounds { pc: self.pc - 1 }),
+                }
+            }
+            Opcode::ITOS => {
+                let string = self.registers[instruction.registers[0]].to_string();
+                let object = self.allocate(Object::String(string));
+                self.value_registers[instruction.registers[1]] = Value::Ref(object);
+            }
+            // Sets the equal flag if the whole string, ignoring surrounding whitespace, is a
+            // number, so that programs can reject bad input without trapping.
+            Opcode::STOI => {
+                let parsed = self.string(self.value_registers[instruction.registers[0]])?.trim().parse::<i32>();
+                self.equal = parsed.is_ok();
+                self.registers[instruction.registers[1]] = parsed.unwrap_or(0);
+            }
+            Opcode::PRTS => {
+                let string = self.string(self.value_registers[instruction.registers[0]])?.to_string();
+                match &mut self.output {
+                    Some(output) => output.push(string),
+                    None => println!("{}", string),
+                }
+            }
+            Opcode::CALL => {
+                if self.call_stack.len() >= MAX_CALL_DEPTH {
+                    return Err(VmError::StackOverflow { pc: self.pc - 1 });
+                }
+                self.call_stack.push(self.pc);
+                self.pc = self.registers[instruction.registers[0]] as usize;
+            }
+            Opcode::RET => {
+                self.pc = self.call_stack.pop().ok_or(VmError::StackUnderflow { pc: self.pc - 1 })?;
+            }
+            Opcode::PUSH => {
+                self.stack.push(self.registers[instruction.registers[0]]);
+            }
+            Opcode::POP => {
+                let number = self.stack.pop().ok_or(VmError::StackUnderflow { pc: self.pc - 1 })?;
+                self.registers[instruction.registers[0]] = number;
+            }
+            // Saves the caller's frame pointer on the stack and reserves `integer_operand` zeroed
+            // locals for the new frame.
+            Opcode::ENTER => {
+                if instruction.integer_operand < 0 {
+                    return Err(VmError::NegativeSize { pc: self.pc - 1 });
+                }
+                if self.stack.len() + 1 + instruction.integer_operand as usize > MAX_STACK_SIZE {
+                    return Err(VmError::StackOverflow { pc: self.pc - 1 });
+                }
+                self.stack.push(self.frame_pointer as i32);
+                self.frame_pointer = self.stack.len();
+                self.stack.resize(self.frame_pointer + instruction.integer_operand as usize, 0);
+            }
+            Opcode::LEAVE => {
+                let pc = self.pc - 1;
+                if self.frame_pointer == 0 || self.frame_pointer > self.stack.len() {
+                    return Err(VmError::StackUnderflow { pc });
+                }
+                self.stack.truncate(self.frame_pointer);
+                self.frame_pointer = self.stack.pop().ok_or(VmError::StackUnderflow { pc })? as usize;
+            }
+            Opcode::LDF => {
+                let slot = self.frame_slot(instruction.integer_operand)?;
+                self.registers[instruction.registers[0]] = self.stack[slot];
+            }
+            Opcode::STF => {
+                let slot = self.frame_slot(instruction.integer_operand)?;
+                self.stack[slot] = self.registers[instruction.registers[0]];
+            }
+            // Starts a thread at the address in the first register, with a copy of this thread's
+            // integer registers and empty stacks, and writes its id to the second.
+            Opcode::SPAWN => {
+                let pc = self.registers[instruction.registers[0]] as usize;
+                let context = Context::new(self.registers, pc);
+                self.threads.push(Thread { context, state: ThreadState::Runnable, result: 0 });
+                self.registers[instruction.registers[1]] = (self.threads.len() - 1) as i32;
+            }
+            Opcode::YIELD => {
+                self.schedule();
+            }
+            // Waits for the thread whose id is in the first register to finish, then writes its
+            // result to the second.
+            Opcode::JOIN => {
+                let pc = self.pc - 1;
+                let id = self.registers[instruction.registers[0]];
+                let Some(thread) = usize::try_from(id).ok().and_then(|id| self.threads.get(id)) else {
+                    return Err(VmError::UnknownThread { pc });
+                };
+                if thread.state == ThreadState::Finished {
+                    self.registers[instruction.registers[1]] = thread.result;
+                } else if id as usize == self.current_thread {
+                    return Err(VmError::Deadlock { pc });
+                } else {
+                    // Runs the `JOIN` again once the thread has finished.
+                    self.pc = pc;
+                    self.threads[self.current_thread].state = ThreadState::Joining(id as usize);
+                    if !self.schedule() {
+                        return Err(VmError::Deadlock { pc });
+                    }
+                }
+            }
+            // Processes are started by the runtime, so this suspends the VM until it has.
+            Opcode::SPAWNP => {
+                let pc = self.registers[instruction.registers[0]] as usize;
+                self.suspension = Some(Suspension::Spawn { pc, register: instruction.registers[1] });
+                return Ok(false);
+            }
+            Opcode::SEND => {
+                let to = self.registers[instruction.registers[0]] as usize;
+                let message = Message { from: self.pid, value: self.registers[instruction.registers[1]], kind: MessageKind::Sent };
+                self.outbox.push((to, message));
+            }
+            // Takes the oldest message, writing its value and sender, setting the equal flag and
+            // setting the overflow flag only for a failure notice. With an empty mailbox, either
+            // clears both flags if the timeout is 0, or suspends the VM to wait for a message,
+            // forever if the timeout is negative.
+            Opcode::RECV => match self.mailbox.pop_front() {
+                Some(message) => {
+                    self.registers[instruction.registers[0]] = message.value;
+                    self.registers[instruction.registers[1]] = message.from as i32;
+                    self.equal = true;
+                    self.overflow = message.kind == MessageKind::Failure;
+                }
+                None if instruction.integer_operand == 0 => {
+                    self.equal = false;
+                    self.overflow = false;
+                }
+                None => {
+                    let timeout = u64::try_from(instruction.integer_operand).ok();
+                    self.suspension = Some(Suspension::Receive { timeout });
+                    self.pc -= 1;
+                    return Ok(false);
+                }
+            },
+            Opcode::SELF => {
+                self.registers[instruction.registers[0]] = self.pid as i32;
+            }
+            Opcode::IGL => {
+                return Err(VmError::IllegalOpcode { pc: self.pc - 1 });
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_arithmetic(opcode: Opcode, first_number: i32, second_number: i32, mode: ArithmeticMode) -> (Result<(), VmError>, VM) {
+        let mut test_vm = VM::new();
+        test_vm.arithmetic_mode = mode;
+        test_vm.registers[0] = first_number;
+        test_vm.registers[1] = second_number;
+        test_vm.add_instruction(Instruction::new(opcode, [0, 1, 2], 0));
+        let result = test_vm.run();
+        (result, test_vm)
+    }
+
+    #[test]
+    fn test_add_overflow_modes() {
+        let (result, test_vm) = run_arithmetic(Opcode::ADD, i32::MAX, 1, ArithmeticMode::Wrap);
+        assert_eq!(result, Ok(()));
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert!(test_vm.overflow);
+        assert!(!test_vm.carry);
+
+        let (_, test_vm) = run_arithmetic(Opcode::ADD, i32::MAX, 1, ArithmeticMode::Saturate);
+        assert_eq!(test_vm.registers[2], i32::MAX);
+
+        let (result, test_vm) = run_arithmetic(Opcode::ADD, i32::MAX, 1, ArithmeticMode::Trap);
+        assert_eq!(result, Err(VmError::IntegerOverflow { pc: 0 }));
+        assert_eq!(test_vm.registers[2], 0);
+
+        let (_, test_vm) = run_arithmetic(Opcode::ADD, -1, 1, ArithmeticMode::Trap);
+        assert_eq!(test_vm.registers[2], 0);
+        assert!(!test_vm.overflow);
+        assert!(test_vm.carry);
+    }
+
+    #[test]
+    fn test_mul_and_div_overflow() {
+        let (_, test_vm) = run_arithmetic(Opcode::MUL, i32::MIN, 2, ArithmeticMode::Saturate);
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert!(test_vm.overflow);
+
+        let (_, test_vm) = run_arithmetic(Opcode::DIV, i32::MIN, -1, ArithmeticMode::Wrap);
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert_eq!(test_vm.remainder, 0);
+        assert!(test_vm.overflow);
+
+        let (result, _) = run_arithmetic(Opcode::DIV, 7, 0, ArithmeticMode::Wrap);
+        assert_eq!(result, Err(VmError::DivisionByZero { pc: 0 }));
+    }
+
+    #[test]
+    fn test_bitwise_opcodes() {
+        let (_, test_vm) = run_arithmetic(Opcode::AND, 0b1100, 0b1010, ArithmeticMode::Trap);
+        assert_eq!(test_vm.registers[2], 0b1000);
+        let (_, test_vm) = run_arithmetic(Opcode::OR, 0b1100, 0b1010, ArithmeticMode::Trap);
+        assert_eq!(test_vm.registers[2], 0b1110);
+        let (_, test_vm) = run_arithmetic(Opcode::XOR, 0b1100, 0b1010, ArithmeticMode::Trap);
+        assert_eq!(test_vm.registers[2], 0b0110);
+        let (_, test_vm) = run_arithmetic(Opcode::SHL, 1, 33, ArithmeticMode::Trap);
+        assert_eq!(test_vm.registers[2], 2);
+        let (_, test_vm) = run_arithmetic(Opcode::SHR, -8, 1, ArithmeticMode::Trap);
+        assert_eq!(test_vm.registers[2], 0x7FFF_FFFC);
+        let (_, test_vm) = run_arithmetic(Opcode::SAR, -8, 1, ArithmeticMode::Trap);
+        assert_eq!(test_vm.registers[2], -4);
+        // Unlike `DIV`'s remainder, `MOD` is never negative.
+        for (first, second, remainder) in [(7, 3, 1), (-7, 3, 2), (7, -3, 1), (-7, -3, 2), (i32::MIN, -1, 0), (-1, i32::MIN, i32::MAX)] {
+            let (_, test_vm) = run_arithmetic(Opcode::MOD, first, second, ArithmeticMode::Trap);
+            assert_eq!(test_vm.registers[2], remainder);
         }
-        true
+        let (result, _) = run_arithmetic(Opcode::MOD, -7, 0, ArithmeticMode::Trap);
+        assert_eq!(result, Err(VmError::DivisionByZero { pc: 0 }));
+
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 17;
+        test_vm.program = vec![
+            Instruction::new(Opcode::NOT, [0, 2, 0], 0),
+            Instruction::new(Opcode::DIV, [1, 0, 3], 0),
+            Instruction::new(Opcode::GETREM, [4, 0, 0], 0),
+        ]
+        .into();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], !5);
+        assert_eq!(test_vm.registers[3], 3);
+        assert_eq!(test_vm.registers[4], 2);
+    }
+
+    #[test]
+    fn test_conditional_jumps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 3;
+        test_vm.registers[1] = 1;
+        test_vm.registers[5] = 5;
+        test_vm.program = vec![
+            Instruction::new(Opcode::DEC, [0, 0, 0], 0),
+            Instruction::new(Opcode::INC, [2, 0, 0], 0),
+            Instruction::new(Opcode::JZ, [0, 5, 0], 0),
+            Instruction::new(Opcode::LOAD, [3, 0, 0], 5),
+            Instruction::new(Opcode::JMPB, [3, 0, 0], 0),
+            Instruction::new(Opcode::EQ, [2, 1, 0], 0),
+            Instruction::new(Opcode::JNEQF, [1, 0, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+            Instruction::new(Opcode::LOAD, [4, 0, 0], 7),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ]
+        .into();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 3);
+        assert_eq!(test_vm.registers[4], 7);
+    }
+
+    #[test]
+    fn test_relative_jump_out_of_range() {
+        let program = vec![
+            Instruction::new(Opcode::LOAD, [0, 0, 0], 50),
+            Instruction::new(Opcode::LOAD, [1, 0, 0], 1),
+            Instruction::new(Opcode::ADD, [0, 1, 0], 0),
+            Instruction::new(Opcode::EQ, [0, 0, 0], 0),
+            Instruction::new(Opcode::JEQB, [0, 0, 0], 0),
+        ];
+        let mut test_vm = VM::new();
+        test_vm.program = program.clone().into();
+        assert_eq!(test_vm.run(), Err(VmError::JumpOutOfRange { pc: 4 }));
+        let mut test_vm = VM::new();
+        test_vm.program = program.into();
+        assert_eq!(crate::dispatch::run(&mut test_vm), Err(VmError::JumpOutOfRange { pc: 4 }));
+
+        // A negative offset jumps the other way.
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            Instruction::new(Opcode::LOAD, [0, 0, 0], -2),
+            Instruction::new(Opcode::JMPF, [0, 0, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ]
+        .into();
+        test_vm.step().unwrap();
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_suspended_without_runtime() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            Instruction::new(Opcode::RECV, [0, 1, 0], -1),
+            Instruction::new(Opcode::LOAD, [2, 0, 0], 9),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ]
+        .into();
+        assert_eq!(test_vm.run(), Err(VmError::Suspended { pc: 0 }));
+        assert_eq!(test_vm.registers[2], 0);
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Instruction::new(Opcode::LOAD, [0, 0, 0], 2), Instruction::new(Opcode::SPAWNP, [0, 1, 0], 0)].into();
+        assert_eq!(crate::dispatch::run(&mut test_vm), Err(VmError::Suspended { pc: 1 }));
+    }
+
+    #[test]
+    fn test_immediate_operands() {
+        let mut test_vm = VM::new();
+        test_vm.arithmetic_mode = ArithmeticMode::Trap;
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![
+            Instruction::new(Opcode::ADDI, [0, 0, 1], 5),
+            Instruction::new(Opcode::MULI, [1, 0, 2], -2),
+            Instruction::new(Opcode::DIVI, [2, 0, 3], 4),
+            Instruction::new(Opcode::GTQI, [1, 0, 0], 15),
+        ]
+        .into();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 15);
+        assert_eq!(test_vm.registers[2], -30);
+        assert_eq!(test_vm.registers[3], -7);
+        assert!(test_vm.equal);
+
+        let mut test_vm = VM::new();
+        test_vm.arithmetic_mode = ArithmeticMode::Trap;
+        test_vm.registers[0] = i32::MIN;
+        test_vm.add_instruction(Instruction::new(Opcode::SUBI, [0, 0, 1], 1));
+        assert_eq!(test_vm.run(), Err(VmError::IntegerOverflow { pc: 0 }));
+    }
+
+    #[test]
+    fn test_float_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.arithmetic_mode = ArithmeticMode::Trap;
+        test_vm.constants = vec![Constant::Float(1.5), Constant::Float(0.0)];
+        test_vm.registers[0] = 3;
+        test_vm.program = vec![
+            Instruction::new(Opcode::LOADF, [0, 0, 0], 0),
+            Instruction::new(Opcode::ITOF, [0, 1, 0], 0),
+            Instruction::new(Opcode::MULF, [0, 1, 2], 0),
+            Instruction::new(Opcode::FTOI, [2, 1, 0], 0),
+            Instruction::new(Opcode::LOADF, [3, 0, 0], 1),
+            Instruction::new(Opcode::DIVF, [0, 3, 4], 0),
+            Instruction::new(Opcode::DIVF, [3, 3, 5], 0),
+            Instruction::new(Opcode::EQF, [5, 5, 0], 0),
+        ]
+        .into();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[2], 4.5);
+        assert_eq!(test_vm.registers[1], 4);
+        assert_eq!(test_vm.float_registers[4], f64::INFINITY);
+        assert!(test_vm.float_registers[5].is_nan());
+        assert!(!test_vm.equal);
+
+        test_vm.program.push(Instruction::new(Opcode::FTOI, [5, 1, 0], 0));
+        assert_eq!(test_vm.run(), Err(VmError::IntegerOverflow { pc: 8 }));
+        test_vm.program.push(Instruction::new(Opcode::LOADF, [0, 0, 0], 7));
+        test_vm.pc = 9;
+        assert_eq!(test_vm.run(), Err(VmError::BadConstant { pc: 9, index: 7 }));
+    }
+
+    #[test]
+    fn test_register_pair_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.arithmetic_mode = ArithmeticMode::Trap;
+        test_vm.registers[0] = -3;
+        test_vm.program = vec![
+            Instruction::new(Opcode::LOADL, [2, 0, 0], 5_000_000_000),
+            Instruction::new(Opcode::SEXT, [0, 4, 0], 0),
+            Instruction::new(Opcode::MULL, [2, 4, 6], 0),
+            Instruction::new(Opcode::LOADL, [8, 0, 0], 7),
+            Instruction::new(Opcode::MODL, [2, 8, 10], 0),
+            Instruction::new(Opcode::MODL, [6, 8, 12], 0),
+            Instruction::new(Opcode::LTL, [6, 2, 0], 0),
+        ]
+        .into();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.read_pair(2), 5_000_000_000);
+        assert_eq!(test_vm.read_pair(4), -3);
+        assert_eq!(test_vm.read_pair(6), -15_000_000_000);
+        assert_eq!(test_vm.read_pair(10), 5_000_000_000 % 7);
+        assert_eq!(test_vm.read_pair(12), 1);
+        assert!(test_vm.equal);
+
+        test_vm.program.push(Instruction::new(Opcode::LOADL, [8, 0, 0], i64::MAX));
+        test_vm.program.push(Instruction::new(Opcode::ADDL, [8, 2, 10], 0));
+        assert_eq!(test_vm.run(), Err(VmError::IntegerOverflow { pc: 8 }));
+        assert!(test_vm.overflow);
+    }
+
+    #[test]
+    fn test_tagged_values_and_heap_objects() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 2;
+        test_vm.registers[1] = 42;
+        test_vm.program = vec![
+            Instruction::new(Opcode::NEWMAP, [0, 0, 0], 0),
+            Instruction::new(Opcode::NEWARR, [0, 1, 0], 0),
+            Instruction::new(Opcode::BOXI, [1, 2, 0], 0),
+            Instruction::new(Opcode::SETV, [0, 2, 1], 0),
+            Instruction::new(Opcode::GETV, [0, 2, 3], 0),
+            Instruction::new(Opcode::APPV, [3, 2, 0], 0),
+            Instruction::new(Opcode::LENV, [3, 4, 0], 0),
+            Instruction::new(Opcode::TAG, [3, 5, 0], 0),
+            Instruction::new(Opcode::GETV, [0, 8, 6], 0),
+        ]
+        .into();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[4], 3);
+        assert_eq!(test_vm.registers[5], 5);
+        assert_eq!(test_vm.value_registers[6], Value::Nil);
+
+        test_vm.value_registers[8] = Value::Int(2);
+        test_vm.program.push(Instruction::new(Opcode::GETV, [3, 8, 6], 0));
+        test_vm.program.push(Instruction::new(Opcode::UNBOX, [6, 7, 0], 0));
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[7], 42);
+
+        test_vm.program.push(Instruction::new(Opcode::GETV, [2, 2, 6], 0));
+        assert_eq!(test_vm.run(), Err(VmError::TypeError { pc: 11 }));
+    }
+
+    #[test]
+    fn test_string_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.constants = vec![Constant::String("héllo ".to_string()), Constant::String(" -42 ".to_string())];
+        test_vm.registers[0] = 1;
+        test_vm.registers[1] = 4;
+        test_vm.registers[2] = 1234;
+        test_vm.program = vec![
+            Instruction::new(Opcode::LOADS, [0, 0, 0], 0),
+            Instruction::new(Opcode::ITOS, [2, 1, 0], 0),
+            Instruction::new(Opcode::CONCAT, [0, 1, 2], 0),
+            Instruction::new(Opcode::SLEN, [2, 3, 0], 0),
+            Instruction::new(Opcode::SUBSTR, [2, 0, 3], 0),
+            Instruction::new(Opcode::CHARAT, [3, 0, 4], 0),
+            Instruction::new(Opcode::LOADS, [4, 0, 0], 0),
+            Instruction::new(Opcode::SCMP, [0, 4, 5], 0),
+            Instruction::new(Opcode::LOADS, [5, 0, 0], 1),
+            Instruction::new(Opcode::STOI, [5, 6, 0], 0),
+        ]
+        .into();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap.get(ObjectRef(2)), Some(&Object::String("héllo 1234".to_string())));
+        assert_eq!(test_vm.registers[3], 10);
+        assert_eq!(test_vm.heap.type_tag(test_vm.value_registers[3]), 4);
+        assert_eq!(test_vm.registers[4], 'l' as i32);
+        assert_eq!(test_vm.value_registers[4], test_vm.value_registers[0]);
+        assert_eq!(test_vm.registers[5], 0);
+        assert_eq!(test_vm.registers[6], -42);
+        assert!(test_vm.equal);
+
+        test_vm.program.push(Instruction::new(Opcode::STOI, [0, 6, 0], 0));
+        test_vm.program.push(Instruction::new(Opcode::SCMP, [1, 2, 7], 0));
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[7], -1);
+        assert!(!test_vm.equal);
+
+        test_vm.registers[1] = 11;
+        test_vm.program.push(Instruction::new(Opcode::SUBSTR, [2, 0, 3], 0));
+        assert_eq!(test_vm.run(), Err(VmError::IndexOutOfBounds { pc: 12 }));
+    }
+
+    #[test]
+    fn test_calls_and_stack_frames() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            Instruction::new(Opcode::LOAD, [0, 0, 0], 20),
+            Instruction::new(Opcode::PUSH, [0, 0, 0], 0),
+            Instruction::new(Opcode::LOAD, [1, 0, 0], 6),
+            Instruction::new(Opcode::CALL, [1, 0, 0], 0),
+            Instruction::new(Opcode::POP, [3, 0, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+            // Doubles its argument into a local, then returns it in $2.
+            Instruction::new(Opcode::ENTER, [0, 0, 0], 1),
+            Instruction::new(Opcode::LDF, [2, 0, 0], -2),
+            Instruction::new(Opcode::ADD, [2, 2, 2], 0),
+            Instruction::new(Opcode::STF, [2, 0, 0], 0),
+            Instruction::new(Opcode::LDF, [2, 0, 0], 0),
+            Instruction::new(Opcode::LEAVE, [0; 3], 0),
+            Instruction::new(Opcode::RET, [0; 3], 0),
+        ]
+        .into();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 40);
+        assert_eq!(test_vm.registers[3], 20);
+        assert!(test_vm.stack.is_empty() && test_vm.call_stack.is_empty());
+        assert_eq!(test_vm.frame_pointer, 0);
+
+        test_vm.pc = 0;
+        test_vm.program[6] = Instruction::new(Opcode::CALL, [1, 0, 0], 0);
+        assert_eq!(test_vm.run(), Err(VmError::StackOverflow { pc: 6 }));
+        assert_eq!(test_vm.call_stack.len(), MAX_CALL_DEPTH);
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Instruction::new(Opcode::ENTER, [0, 0, 0], 2_000_000_000), Instruction::new(Opcode::HLT, [0; 3], 0)].into();
+        assert_eq!(test_vm.run(), Err(VmError::StackOverflow { pc: 0 }));
+        assert!(test_vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_garbage_collection_roots() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 64;
+        test_vm.program = vec![
+            Instruction::new(Opcode::ALOC, [0, 1, 0], 0),
+            Instruction::new(Opcode::PUSHV, [1, 0, 0], 0),
+            Instruction::new(Opcode::ALOC, [0, 1, 0], 0),
+            Instruction::new(Opcode::ALOC, [0, 2, 0], 0),
+            Instruction::new(Opcode::NIL, [2, 0, 0], 0),
+        ]
+        .into();
+        test_vm.run().unwrap();
+        test_vm.collect_garbage();
+        let stats = test_vm.heap.stats();
+        assert_eq!(stats.allocations, 3);
+        assert_eq!(stats.live_objects, 2);
+        assert_eq!(stats.freed_objects, 1);
+
+        test_vm.program.push(Instruction::new(Opcode::POPV, [3, 0, 0], 0));
+        test_vm.program.push(Instruction::new(Opcode::POPV, [3, 0, 0], 0));
+        assert_eq!(test_vm.run(), Err(VmError::StackUnderflow { pc: 6 }));
+    }
+
+    #[test]
+    fn test_jump_on_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        test_vm.registers[3] = 4;
+        test_vm.program = vec![
+            Instruction::new(Opcode::ADD, [0, 1, 2], 0),
+            Instruction::new(Opcode::JO, [3, 0, 0], 0),
+            Instruction::new(Opcode::LOAD, [4, 0, 0], 1),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+            Instruction::new(Opcode::LOAD, [4, 0, 0], 2),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ]
+        .into();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[4], 2);
+    }
+
+    #[test]
+    fn test_halted() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Instruction::new(Opcode::LOAD, [0, 0, 0], 1), Instruction::new(Opcode::HLT, [0; 3], 0)].into();
+        test_vm.run().unwrap();
+        assert!(test_vm.halted);
+
+        // Jumping just past the final `HLT` runs off the end instead.
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Instruction::new(Opcode::LOAD, [0, 0, 0], 3), Instruction::new(Opcode::JMP, [0; 3], 0), Instruction::new(Opcode::HLT, [0; 3], 0)].into();
+        test_vm.run().unwrap();
+        assert!(!test_vm.halted);
+    }
+
+    #[test]
+    fn test_green_threads() {
+        // Spawns two workers that each print their argument three times, then joins them.
+        let program = vec![
+            Instruction::new(Opcode::LOAD, [1, 0, 0], 8),
+            Instruction::new(Opcode::LOAD, [0, 0, 0], 1),
+            Instruction::new(Opcode::SPAWN, [1, 2, 0], 0),
+            Instruction::new(Opcode::LOAD, [0, 0, 0], 2),
+            Instruction::new(Opcode::SPAWN, [1, 3, 0], 0),
+            Instruction::new(Opcode::JOIN, [2, 4, 0], 0),
+            Instruction::new(Opcode::JOIN, [3, 5, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+            Instruction::new(Opcode::LOAD, [6, 0, 0], 3),
+            Instruction::new(Opcode::LOAD, [7, 0, 0], 10),
+            Instruction::new(Opcode::ITOS, [0, 0, 0], 0),
+            Instruction::new(Opcode::PRTS, [0, 0, 0], 0),
+            Instruction::new(Opcode::YIELD, [0; 3], 0),
+            Instruction::new(Opcode::DEC, [6, 0, 0], 0),
+            Instruction::new(Opcode::JNZ, [6, 7, 0], 0),
+            Instruction::new(Opcode::MULI, [0, 0, 0], 10),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ];
+        let mut test_vm = VM::new();
+        test_vm.program = program.clone().into();
+        test_vm.output = Some(vec![]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.output.unwrap(), ["1", "2", "1", "2", "1", "2"]);
+        assert_eq!(test_vm.current_thread, 0);
+        assert_eq!(test_vm.registers[4..6], [10, 20]);
+        assert!(test_vm.threads.iter().all(|thread| thread.state == ThreadState::Finished));
+        assert!(test_vm.halted);
+
+        // Without yielding, the workers are preempted once their quantum runs out.
+        let mut test_vm = VM::new();
+        test_vm.program = program.into();
+        test_vm.program[12] = Instruction::new(Opcode::LOAD, [8, 0, 0], 0);
+        test_vm.quantum = 4;
+        test_vm.output = Some(vec![]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.output.unwrap(), ["1", "2", "1", "1", "2", "2"]);
+        assert_eq!(test_vm.registers[4..6], [10, 20]);
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Instruction::new(Opcode::JOIN, [0, 1, 0], 0)].into();
+        assert_eq!(test_vm.run(), Err(VmError::Deadlock { pc: 0 }));
+        test_vm.registers[0] = 7;
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run(), Err(VmError::UnknownThread { pc: 0 }));
     }
 }
 
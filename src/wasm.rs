@@ -0,0 +1,1626 @@
+//! Translation of a verified program into a WebAssembly binary module, for running programs in
+//! browsers and other wasm runtimes.
+//!
+//! The module imports two functions from `lang_vm`:
+//! - `print(address, length)`, for `PRTS`: the UTF-8 string at `address` in the module's
+//!   memory.
+//! - `fail(kind, pc, detail)`, for a `VmError`, which `error` turns back into one.
+//!
+//! It exports its `memory` and `run() -> i32`, which returns `0` if the program ran off its end,
+//! `1` after `HLT` (where the VM would print "HLT encountered"), and `2` after calling `fail`.
+//!
+//! Inside `run`, registers, flags and the VM's bookkeeping are locals. Instructions are laid out
+//! in order inside a `loop` with a `br_table` over `pc` at its top, so straight-line code falls
+//! through and a jump sets `pc` and branches back to the table. The integer stack, the call stack
+//! and strings live in linear memory. Strings are never freed.
+//!
+//! The same programs are rejected as by `aot`, whose module docs give the limits, along with the
+//! string instructions that compiled programs do not need yet. The carry flag is not kept, as no
+//! instruction reads it. The integer stack holds at most `STACK_LIMIT` values; pushing more fails
+//! with `VmError::StackOverflow`.
+
+use crate::{
+    aot::AotError,
+    instruction::{Constant, Instruction, Opcode},
+    verifier,
+    vm::{ArithmeticMode, VmError, MAX_CALL_DEPTH, REGISTER_COUNT},
+};
+
+/// How many values the integer stack can hold.
+pub const STACK_LIMIT: usize = 1 << 20;
+
+const PAGE_SIZE: usize = 1 << 16;
+
+// Value types.
+const I32: u8 = 0x7F;
+const I64: u8 = 0x7E;
+const F64: u8 = 0x7C;
+
+// The tags of values in value registers.
+const NIL: i32 = 0;
+const INT: i32 = 1;
+const FLOAT: i32 = 2;
+const BOOL: i32 = 3;
+const STRING: i32 = 4;
+
+// Function indices: the two imports, then the helpers, then `run`.
+const PRINT: u32 = 0;
+const FAIL: u32 = 1;
+const ALLOCATE: u32 = 2;
+const CHARACTER_COUNT: u32 = 3;
+const INTEGER_TO_STRING: u32 = 4;
+const CONCATENATE: u32 = 5;
+const RUN: u32 = 6;
+
+// The locals of `run`. Each value register is a tag and a payload, which holds a float's bits
+// or a string's address.
+const fn register(register: usize) -> u32 {
+    register as u32
+}
+const fn float_register(register: usize) -> u32 {
+    (REGISTER_COUNT + register) as u32
+}
+const fn value_tag(register: usize) -> u32 {
+    (2 * REGISTER_COUNT + register) as u32
+}
+const fn value_payload(register: usize) -> u32 {
+    (3 * REGISTER_COUNT + register) as u32
+}
+const PC: u32 = 4 * REGISTER_COUNT as u32;
+const EQUAL: u32 = PC + 1;
+const OVERFLOW: u32 = PC + 2;
+const REMAINDER: u32 = PC + 3;
+const STACK_LENGTH: u32 = PC + 4;
+const FRAME_POINTER: u32 = PC + 5;
+const CALL_DEPTH: u32 = PC + 6;
+const FIRST: u32 = PC + 7;
+const SECOND: u32 = PC + 8;
+const FIRST_LONG: u32 = PC + 9;
+const SECOND_LONG: u32 = PC + 10;
+const RESULT_LONG: u32 = PC + 11;
+const TRUNCATED: u32 = PC + 12;
+
+/// Translates a program into a WebAssembly module that runs it with the given arithmetic mode.
+pub fn to_wasm(program: &[Instruction], constants: &[Constant], mode: ArithmeticMode) -> Result<Vec<u8>, AotError> {
+    let verification = verifier::verify(program, constants);
+    if !verification.is_ok() {
+        return Err(AotError::Invalid(verification.errors));
+    }
+
+    // String constants go at the start of memory, each as its length followed by its bytes.
+    let mut data = vec![];
+    let mut strings = vec![None; constants.len()];
+    for (index, constant) in constants.iter().enumerate() {
+        if let Constant::String(string) = constant {
+            strings[index] = Some(data.len() as u32);
+            data.extend_from_slice(&(string.len() as u32).to_le_bytes());
+            data.extend_from_slice(string.as_bytes());
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+    }
+    let layout = Layout::new(data.len());
+
+    let mut run = Function::new(program.len());
+    run.block_type(0x03);
+    run.block_type(0x02);
+    for _ in 0..program.len() {
+        run.block_type(0x02);
+    }
+    run.local_get(PC);
+    run.op(0x0E);
+    unsigned(&mut run.code, program.len() as u64);
+    for target in 0..=program.len() {
+        unsigned(&mut run.code, target as u64);
+    }
+    for (pc, instruction) in program.iter().enumerate() {
+        run.end();
+        run.pc = pc;
+        run.nesting = 0;
+        run.instruction(*instruction, constants, &strings, &layout, mode)?;
+    }
+    run.end();
+    run.end();
+    run.i32_const(0);
+
+    let mut module = b"\0asm".to_vec();
+    module.extend_from_slice(&1u32.to_le_bytes());
+    section(&mut module, 1, |types| {
+        vector(types, 5);
+        for (parameters, results) in [(&[I32, I32][..], &[][..]), (&[I32, I32, I32], &[]), (&[I32], &[I32]), (&[I32, I32], &[I32]), (&[], &[I32])] {
+            types.push(0x60);
+            vector(types, parameters.len());
+            types.extend_from_slice(parameters);
+            vector(types, results.len());
+            types.extend_from_slice(results);
+        }
+    });
+    section(&mut module, 2, |imports| {
+        vector(imports, 2);
+        for (field, type_index) in [("print", 0), ("fail", 1)] {
+            name(imports, "lang_vm");
+            name(imports, field);
+            imports.extend_from_slice(&[0x00, type_index]);
+        }
+    });
+    section(&mut module, 3, |functions| {
+        vector(functions, 5);
+        functions.extend_from_slice(&[2, 2, 2, 3, 4]);
+    });
+    section(&mut module, 5, |memories| {
+        vector(memories, 1);
+        memories.push(0x00);
+        unsigned(memories, layout.heap.div_ceil(PAGE_SIZE) as u64 + 1);
+    });
+    section(&mut module, 6, |globals| {
+        // The address the next allocation starts at.
+        vector(globals, 1);
+        globals.extend_from_slice(&[I32, 0x01, 0x41]);
+        signed(globals, layout.heap as i64);
+        globals.push(0x0B);
+    });
+    section(&mut module, 7, |exports| {
+        vector(exports, 2);
+        name(exports, "memory");
+        exports.extend_from_slice(&[0x02, 0x00]);
+        name(exports, "run");
+        exports.push(0x00);
+        unsigned(exports, RUN as u64);
+    });
+    section(&mut module, 10, |code| {
+        vector(code, 5);
+        body(code, &[(2, I32)], &allocate());
+        body(code, &[(3, I32)], &character_count());
+        body(code, &[(2, I32), (1, I64)], &integer_to_string());
+        body(code, &[(3, I32)], &concatenate());
+        let locals = [(REGISTER_COUNT, I32), (REGISTER_COUNT, F64), (REGISTER_COUNT, I32), (REGISTER_COUNT, I64), (9, I32), (3, I64), (1, F64)];
+        body(code, &locals, &run.code);
+    });
+    section(&mut module, 11, |segments| {
+        vector(segments, 1);
+        segments.extend_from_slice(&[0x00, 0x41, 0x00, 0x0B]);
+        vector(segments, data.len());
+        segments.extend_from_slice(&data);
+    });
+    return Ok(module);
+}
+
+/// The error a module reported through its `fail` import.
+pub fn error(kind: i32, pc: usize, detail: i64) -> Option<VmError> {
+    let error = match kind {
+        0 => VmError::IntegerOverflow { pc },
+        1 => VmError::DivisionByZero { pc },
+        2 => VmError::IllegalOpcode { pc },
+        3 => VmError::BadConstant { pc, index: detail },
+        4 => VmError::TypeError { pc },
+        5 => VmError::IndexOutOfBounds { pc },
+        6 => VmError::NegativeSize { pc },
+        7 => VmError::StackUnderflow { pc },
+        8 => VmError::StackOverflow { pc },
+        9 => VmError::JumpOutOfRange { pc },
+        _ => return None,
+    };
+    return Some(error);
+}
+
+/// The `kind` and `detail` a module passes to `fail` for `error`.
+fn error_code(error: &VmError) -> (i32, i32) {
+    match *error {
+        VmError::IntegerOverflow { .. } => (0, 0),
+        VmError::DivisionByZero { .. } => (1, 0),
+        VmError::IllegalOpcode { .. } => (2, 0),
+        VmError::BadConstant { index, .. } => (3, index as i32),
+        VmError::TypeError { .. } => (4, 0),
+        VmError::IndexOutOfBounds { .. } => (5, 0),
+        VmError::NegativeSize { .. } => (6, 0),
+        VmError::StackUnderflow { .. } => (7, 0),
+        VmError::StackOverflow { .. } => (8, 0),
+        VmError::JumpOutOfRange { .. } => (9, 0),
+        VmError::UnknownThread { .. } | VmError::Deadlock { .. } => unreachable!("compiled programs have one thread"),
+        VmError::Suspended { .. } => unreachable!("compiled programs have no processes"),
+    }
+}
+
+/// Where things are in linear memory, after the string constants.
+struct Layout {
+    call_stack: usize,
+    stack: usize,
+    heap: usize,
+}
+
+impl Layout {
+    fn new(data_length: usize) -> Layout {
+        let call_stack = data_length.next_multiple_of(16);
+        let stack = call_stack + MAX_CALL_DEPTH * 4;
+        Layout { call_stack, stack, heap: stack + STACK_LIMIT * 4 }
+    }
+}
+
+/// The body of a function being emitted. For `run`, also tracks how to branch back to the
+/// dispatch table from the instruction at `pc`.
+struct Function {
+    code: Vec<u8>,
+    length: usize,
+    pc: usize,
+    /// Blocks opened by the current instruction.
+    nesting: usize,
+}
+
+impl Function {
+    fn new(length: usize) -> Function {
+        Function { code: vec![], length, pc: 0, nesting: 0 }
+    }
+
+    fn op(&mut self, op: u8) {
+        self.code.push(op);
+    }
+
+    /// Opens a `block` (0x02), `loop` (0x03) or `if` (0x04) without results.
+    fn block_type(&mut self, op: u8) {
+        self.code.extend_from_slice(&[op, 0x40]);
+        self.nesting += 1;
+    }
+
+    /// Opens an `if` with one result.
+    fn if_result(&mut self, value_type: u8) {
+        self.code.extend_from_slice(&[0x04, value_type]);
+        self.nesting += 1;
+    }
+
+    fn else_(&mut self) {
+        self.op(0x05);
+    }
+
+    fn end(&mut self) {
+        self.op(0x0B);
+        self.nesting = self.nesting.saturating_sub(1);
+    }
+
+    fn local_get(&mut self, local: u32) {
+        self.op(0x20);
+        unsigned(&mut self.code, local as u64);
+    }
+
+    fn local_set(&mut self, local: u32) {
+        self.op(0x21);
+        unsigned(&mut self.code, local as u64);
+    }
+
+    fn local_tee(&mut self, local: u32) {
+        self.op(0x22);
+        unsigned(&mut self.code, local as u64);
+    }
+
+    fn call(&mut self, function: u32) {
+        self.op(0x10);
+        unsigned(&mut self.code, function as u64);
+    }
+
+    fn i32_const(&mut self, value: i32) {
+        self.op(0x41);
+        signed(&mut self.code, value as i64);
+    }
+
+    fn i64_const(&mut self, value: i64) {
+        self.op(0x42);
+        signed(&mut self.code, value);
+    }
+
+    fn f64_const(&mut self, value: f64) {
+        self.op(0x44);
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A load or store with the natural alignment `align` (as a power of two) and no offset.
+    fn memory(&mut self, op: u8, align: u8) {
+        self.code.extend_from_slice(&[op, align, 0x00]);
+    }
+
+    /// `memory.copy` or `memory.fill`.
+    fn bulk_memory(&mut self, op: u8) {
+        self.code.extend_from_slice(&[0xFC, op, 0x00]);
+        if op == 0x0A {
+            self.op(0x00);
+        }
+    }
+
+    /// Branches to the `loop` around the dispatch table, to continue at the instruction `PC`
+    /// holds. Inside the instruction at `pc`, the blocks of every later instruction and the exit
+    /// block are between it and the loop.
+    fn dispatch(&mut self) {
+        self.op(0x0C);
+        unsigned(&mut self.code, (self.length - self.pc + self.nesting) as u64);
+    }
+
+    /// Jumps `offset`, held in `local`, forwards or backwards from the next instruction. A target
+    /// before the start fails, and one past the end is clamped to the end, where `run` returns.
+    fn relative_jump(&mut self, local: u32, forward: bool) {
+        self.i64_const(self.pc as i64 + 1);
+        self.local_get(local);
+        self.op(0xAC);
+        self.op(if forward { 0x7C } else { 0x7D });
+        self.local_tee(RESULT_LONG);
+        self.i64_const(0);
+        self.op(0x53);
+        self.fail_if(VmError::JumpOutOfRange { pc: self.pc });
+        self.i64_const(self.length as i64);
+        self.local_get(RESULT_LONG);
+        self.local_get(RESULT_LONG);
+        self.i64_const(self.length as i64);
+        self.op(0x55);
+        self.op(0x1B);
+        self.op(0xA7);
+        self.local_set(PC);
+        self.dispatch();
+    }
+
+    /// Reports `error` through the `fail` import and returns `2`.
+    fn fail(&mut self, error: VmError) {
+        let (kind, detail) = error_code(&error);
+        self.i32_const(kind);
+        self.i32_const(self.pc as i32);
+        self.i32_const(detail);
+        self.call(FAIL);
+        self.i32_const(2);
+        self.op(0x0F);
+    }
+
+    /// Turns the remainder on the stack, which `rem_s` gives the sign of the dividend, into the
+    /// Euclidean one by adding the magnitude of `divisor` if it is negative. The additions wrap,
+    /// which gives the right answer even for a divisor of `MIN`. `scratch` is overwritten.
+    fn euclidean_remainder(&mut self, divisor: u32, scratch: u32, long: bool) {
+        let zero = |function: &mut Function| if long { function.i64_const(0) } else { function.i32_const(0) };
+        let (add, sub, less) = if long { (0x7C, 0x7D, 0x53) } else { (0x6A, 0x6B, 0x48) };
+        self.local_set(scratch);
+        self.local_get(scratch);
+        zero(self);
+        self.local_get(divisor);
+        self.op(sub);
+        self.local_get(divisor);
+        self.local_get(divisor);
+        zero(self);
+        self.op(less);
+        self.op(0x1B);
+        self.op(add);
+        self.local_get(scratch);
+        self.local_get(scratch);
+        zero(self);
+        self.op(less);
+        self.op(0x1B);
+    }
+
+    /// Fails with `error` if the condition on the stack holds.
+    fn fail_if(&mut self, error: VmError) {
+        self.block_type(0x04);
+        self.fail(error);
+        self.end();
+    }
+
+    /// Finishes an integer operation whose exact `i64` result is in `RESULT_LONG` and must fit
+    /// in an `i32`, storing it in `destination` following the arithmetic mode.
+    fn narrow(&mut self, destination: u32, mode: ArithmeticMode) {
+        self.local_get(RESULT_LONG);
+        self.local_get(RESULT_LONG);
+        self.op(0xA7);
+        self.op(0xAC);
+        self.op(0x52);
+        self.local_tee(OVERFLOW);
+        match mode {
+            ArithmeticMode::Trap => {
+                self.fail_if(VmError::IntegerOverflow { pc: self.pc });
+                self.local_get(RESULT_LONG);
+                self.op(0xA7);
+            }
+            ArithmeticMode::Wrap => {
+                self.op(0x1A);
+                self.local_get(RESULT_LONG);
+                self.op(0xA7);
+            }
+            ArithmeticMode::Saturate => {
+                self.op(0x1A);
+                self.i32_const(i32::MIN);
+                self.i32_const(i32::MAX);
+                self.local_get(RESULT_LONG);
+                self.i64_const(0);
+                self.op(0x53);
+                self.op(0x1B);
+                self.local_get(RESULT_LONG);
+                self.op(0xA7);
+                self.local_get(OVERFLOW);
+                self.op(0x1B);
+            }
+        }
+        self.local_set(destination);
+    }
+
+    /// Pushes the `i64` held by the register pair starting at `first`.
+    fn read_pair(&mut self, first: usize) {
+        self.local_get(register(first + 1));
+        self.op(0xAC);
+        self.i64_const(32);
+        self.op(0x86);
+        self.local_get(register(first));
+        self.op(0xAD);
+        self.op(0x84);
+    }
+
+    /// Writes `RESULT_LONG` to the register pair starting at `first`.
+    fn write_pair(&mut self, first: usize) {
+        self.local_get(RESULT_LONG);
+        self.op(0xA7);
+        self.local_set(register(first));
+        self.local_get(RESULT_LONG);
+        self.i64_const(32);
+        self.op(0x87);
+        self.op(0xA7);
+        self.local_set(register(first + 1));
+    }
+
+    /// Pushes the result of a 64-bit division that overflowed, for the modes that carry on.
+    fn overflowed_division(&mut self, mode: ArithmeticMode) {
+        match mode {
+            ArithmeticMode::Saturate => self.i64_const(i64::MAX),
+            _ => self.i64_const(i64::MIN),
+        }
+    }
+
+    /// Fails unless the value register holds a string, leaving the string's address in `local`.
+    fn string(&mut self, value_register: usize, local: u32) {
+        self.local_get(value_tag(value_register));
+        self.i32_const(STRING);
+        self.op(0x47);
+        self.fail_if(VmError::TypeError { pc: self.pc });
+        self.local_get(value_payload(value_register));
+        self.op(0xA7);
+        self.local_set(local);
+    }
+
+    /// Pushes the address of slot `index` of the stack starting at `base`, for an index on the
+    /// stack.
+    fn slot_address(&mut self, base: usize) {
+        self.i32_const(4);
+        self.op(0x6C);
+        self.i32_const(base as i32);
+        self.op(0x6A);
+    }
+
+    fn instruction(&mut self, instruction: Instruction, constants: &[Constant], strings: &[Option<u32>], layout: &Layout, mode: ArithmeticMode) -> Result<(), AotError> {
+        let pc = self.pc;
+        let [first, second, third] = instruction.registers;
+        let operand = instruction.integer_operand;
+        let push_second = |function: &mut Function| {
+            if instruction.opcode.has_integer_operand() {
+                function.i32_const(operand as i32);
+            } else {
+                function.local_get(register(second));
+            }
+        };
+
+        match instruction.opcode {
+            Opcode::LOAD => {
+                self.i32_const(operand as i32);
+                self.local_set(register(first));
+            }
+            Opcode::HLT => {
+                self.i32_const(1);
+                self.op(0x0F);
+            }
+            Opcode::ADD | Opcode::ADDI | Opcode::SUB | Opcode::SUBI | Opcode::MUL | Opcode::MULI => {
+                self.local_get(register(first));
+                self.op(0xAC);
+                push_second(self);
+                self.op(0xAC);
+                self.op(match instruction.opcode {
+                    Opcode::ADD | Opcode::ADDI => 0x7C,
+                    Opcode::SUB | Opcode::SUBI => 0x7D,
+                    _ => 0x7E,
+                });
+                self.local_set(RESULT_LONG);
+                self.narrow(register(third), mode);
+            }
+            Opcode::INC | Opcode::DEC => {
+                self.local_get(register(first));
+                self.op(0xAC);
+                self.i64_const(1);
+                self.op(if instruction.opcode == Opcode::INC { 0x7C } else { 0x7D });
+                self.local_set(RESULT_LONG);
+                self.narrow(register(first), mode);
+            }
+            Opcode::DIV | Opcode::DIVI => {
+                self.local_get(register(first));
+                self.local_set(FIRST);
+                push_second(self);
+                self.local_tee(SECOND);
+                self.op(0x45);
+                self.fail_if(VmError::DivisionByZero { pc });
+                // Only `i32::MIN / -1` overflows, and `i32.div_s` would trap on it.
+                self.local_get(FIRST);
+                self.i32_const(i32::MIN);
+                self.op(0x46);
+                self.local_get(SECOND);
+                self.i32_const(-1);
+                self.op(0x46);
+                self.op(0x71);
+                self.local_tee(OVERFLOW);
+                if mode == ArithmeticMode::Trap {
+                    self.fail_if(VmError::IntegerOverflow { pc });
+                    self.local_get(OVERFLOW);
+                }
+                self.if_result(I32);
+                self.i32_const(if mode == ArithmeticMode::Saturate { i32::MAX } else { i32::MIN });
+                self.else_();
+                self.local_get(FIRST);
+                self.local_get(SECOND);
+                self.op(0x6D);
+                self.end();
+                self.local_set(register(third));
+                self.local_get(FIRST);
+                self.local_get(SECOND);
+                self.op(0x6F);
+                self.local_set(REMAINDER);
+            }
+            Opcode::JMP => {
+                self.local_get(register(first));
+                self.local_set(PC);
+                self.dispatch();
+            }
+            Opcode::JMPF | Opcode::JMPB => self.relative_jump(register(first), instruction.opcode == Opcode::JMPF),
+            Opcode::EQ
+            | Opcode::EQI
+            | Opcode::NEQ
+            | Opcode::NEQI
+            | Opcode::GT
+            | Opcode::GTI
+            | Opcode::LT
+            | Opcode::LTI
+            | Opcode::GTQ
+            | Opcode::GTQI
+            | Opcode::LTQ
+            | Opcode::LTQI => {
+                self.local_get(register(first));
+                push_second(self);
+                self.op(comparison(instruction.opcode, 0x46));
+                self.local_set(EQUAL);
+            }
+            Opcode::JEQ | Opcode::JNEQ | Opcode::JZ | Opcode::JNZ | Opcode::JO | Opcode::JNO => {
+                let target = match instruction.opcode {
+                    Opcode::JEQ | Opcode::JNEQ => {
+                        self.local_get(EQUAL);
+                        first
+                    }
+                    Opcode::JO | Opcode::JNO => {
+                        self.local_get(OVERFLOW);
+                        first
+                    }
+                    _ => {
+                        self.local_get(register(first));
+                        second
+                    }
+                };
+                if matches!(instruction.opcode, Opcode::JNEQ | Opcode::JZ | Opcode::JNO) {
+                    self.op(0x45);
+                }
+                self.block_type(0x04);
+                self.local_get(register(target));
+                self.local_set(PC);
+                self.dispatch();
+                self.end();
+            }
+            Opcode::JEQF | Opcode::JEQB | Opcode::JNEQF | Opcode::JNEQB => {
+                self.local_get(EQUAL);
+                if matches!(instruction.opcode, Opcode::JNEQF | Opcode::JNEQB) {
+                    self.op(0x45);
+                }
+                self.block_type(0x04);
+                self.relative_jump(register(first), matches!(instruction.opcode, Opcode::JEQF | Opcode::JNEQF));
+                self.end();
+            }
+            Opcode::AND | Opcode::OR | Opcode::XOR | Opcode::SHL | Opcode::SHR | Opcode::SAR => {
+                // Like the VM's wrapping shifts, wasm shifts only use the low five bits.
+                self.local_get(register(first));
+                self.local_get(register(second));
+                self.op(match instruction.opcode {
+                    Opcode::AND => 0x71,
+                    Opcode::OR => 0x72,
+                    Opcode::XOR => 0x73,
+                    Opcode::SHL => 0x74,
+                    Opcode::SHR => 0x76,
+                    _ => 0x75,
+                });
+                self.local_set(register(third));
+            }
+            Opcode::NOT => {
+                self.local_get(register(first));
+                self.i32_const(-1);
+                self.op(0x73);
+                self.local_set(register(second));
+            }
+            Opcode::MOD => {
+                self.local_get(register(second));
+                self.op(0x45);
+                self.fail_if(VmError::DivisionByZero { pc });
+                // Unlike `i32.div_s`, `i32.rem_s` gives 0 for `i32::MIN % -1`, as `wrapping_rem_euclid` does.
+                self.local_get(register(first));
+                self.local_get(register(second));
+                self.op(0x6F);
+                self.euclidean_remainder(register(second), FIRST, false);
+                self.local_set(register(third));
+            }
+            Opcode::GETREM => {
+                self.local_get(REMAINDER);
+                self.local_set(register(first));
+            }
+            Opcode::LOADF => match constants.get(operand as usize) {
+                Some(Constant::Float(number)) => {
+                    self.f64_const(*number);
+                    self.local_set(float_register(first));
+                }
+                _ => self.fail(VmError::BadConstant { pc, index: operand }),
+            },
+            Opcode::ADDF | Opcode::SUBF | Opcode::MULF | Opcode::DIVF => {
+                self.local_get(float_register(first));
+                self.local_get(float_register(second));
+                self.op(match instruction.opcode {
+                    Opcode::ADDF => 0xA0,
+                    Opcode::SUBF => 0xA1,
+                    Opcode::MULF => 0xA2,
+                    _ => 0xA3,
+                });
+                self.local_set(float_register(third));
+            }
+            Opcode::EQF | Opcode::NEQF | Opcode::GTF | Opcode::LTF | Opcode::GTQF | Opcode::LTQF => {
+                self.local_get(float_register(first));
+                self.local_get(float_register(second));
+                self.op(match instruction.opcode {
+                    Opcode::EQF => 0x61,
+                    Opcode::NEQF => 0x62,
+                    Opcode::GTF => 0x64,
+                    Opcode::LTF => 0x63,
+                    Opcode::GTQF => 0x66,
+                    _ => 0x65,
+                });
+                self.local_set(EQUAL);
+            }
+            Opcode::ITOF => {
+                self.local_get(register(first));
+                self.op(0xB7);
+                self.local_set(float_register(second));
+            }
+            Opcode::FTOI => {
+                self.local_get(float_register(first));
+                self.op(0x9D);
+                self.local_tee(TRUNCATED);
+                self.local_get(TRUNCATED);
+                self.op(0x62);
+                self.local_get(TRUNCATED);
+                self.f64_const(i32::MIN as f64);
+                self.op(0x63);
+                self.op(0x72);
+                self.local_get(TRUNCATED);
+                self.f64_const(i32::MAX as f64);
+                self.op(0x64);
+                self.op(0x72);
+                self.local_tee(OVERFLOW);
+                if mode == ArithmeticMode::Trap {
+                    self.fail_if(VmError::IntegerOverflow { pc });
+                } else {
+                    self.op(0x1A);
+                }
+                // `i32.trunc_sat_f64_s` saturates and turns NaN into 0, as `as` does.
+                self.local_get(TRUNCATED);
+                self.code.extend_from_slice(&[0xFC, 0x02]);
+                self.local_set(register(second));
+            }
+            Opcode::LOADL => {
+                self.i64_const(operand);
+                self.local_set(RESULT_LONG);
+                self.write_pair(first);
+            }
+            Opcode::ADDL | Opcode::SUBL | Opcode::MULL => {
+                self.read_pair(first);
+                self.local_set(FIRST_LONG);
+                self.read_pair(second);
+                self.local_set(SECOND_LONG);
+                self.local_get(FIRST_LONG);
+                self.local_get(SECOND_LONG);
+                self.op(match instruction.opcode {
+                    Opcode::ADDL => 0x7C,
+                    Opcode::SUBL => 0x7D,
+                    _ => 0x7E,
+                });
+                self.local_set(RESULT_LONG);
+                match instruction.opcode {
+                    // Overflowed if both inputs have the sign the result does not.
+                    Opcode::ADDL => {
+                        self.local_get(FIRST_LONG);
+                        self.local_get(RESULT_LONG);
+                        self.op(0x85);
+                        self.local_get(SECOND_LONG);
+                        self.local_get(RESULT_LONG);
+                        self.op(0x85);
+                        self.op(0x83);
+                        self.i64_const(0);
+                        self.op(0x53);
+                    }
+                    // Overflowed if the inputs' signs differ and the result's differs from the first.
+                    Opcode::SUBL => {
+                        self.local_get(FIRST_LONG);
+                        self.local_get(SECOND_LONG);
+                        self.op(0x85);
+                        self.local_get(FIRST_LONG);
+                        self.local_get(RESULT_LONG);
+                        self.op(0x85);
+                        self.op(0x83);
+                        self.i64_const(0);
+                        self.op(0x53);
+                    }
+                    // Overflowed if dividing the result by a nonzero first input does not give
+                    // back the second, checking `-1 * i64::MIN` first as that division traps.
+                    _ => {
+                        self.local_get(FIRST_LONG);
+                        self.op(0x50);
+                        self.if_result(I32);
+                        self.i32_const(0);
+                        self.else_();
+                        self.local_get(FIRST_LONG);
+                        self.i64_const(-1);
+                        self.op(0x51);
+                        self.if_result(I32);
+                        self.local_get(SECOND_LONG);
+                        self.i64_const(i64::MIN);
+                        self.op(0x51);
+                        self.else_();
+                        self.local_get(RESULT_LONG);
+                        self.local_get(FIRST_LONG);
+                        self.op(0x7F);
+                        self.local_get(SECOND_LONG);
+                        self.op(0x52);
+                        self.end();
+                        self.end();
+                    }
+                }
+                self.local_tee(OVERFLOW);
+                match mode {
+                    ArithmeticMode::Trap => self.fail_if(VmError::IntegerOverflow { pc }),
+                    ArithmeticMode::Wrap => self.op(0x1A),
+                    ArithmeticMode::Saturate => {
+                        self.block_type(0x04);
+                        self.i64_const(i64::MIN);
+                        self.i64_const(i64::MAX);
+                        if instruction.opcode == Opcode::MULL {
+                            self.local_get(FIRST_LONG);
+                            self.local_get(SECOND_LONG);
+                            self.op(0x85);
+                        } else {
+                            // The exact result has the opposite sign to the wrapped one.
+                            self.local_get(RESULT_LONG);
+                            self.i64_const(-1);
+                            self.op(0x85);
+                        }
+                        self.i64_const(0);
+                        self.op(0x53);
+                        self.op(0x1B);
+                        self.local_set(RESULT_LONG);
+                        self.end();
+                    }
+                }
+                self.write_pair(third);
+            }
+            Opcode::DIVL | Opcode::MODL => {
+                self.read_pair(first);
+                self.local_set(FIRST_LONG);
+                self.read_pair(second);
+                self.local_tee(SECOND_LONG);
+                self.op(0x50);
+                self.fail_if(VmError::DivisionByZero { pc });
+                if instruction.opcode == Opcode::MODL {
+                    self.local_get(FIRST_LONG);
+                    self.local_get(SECOND_LONG);
+                    self.op(0x81);
+                    self.euclidean_remainder(SECOND_LONG, FIRST_LONG, true);
+                } else {
+                    self.local_get(FIRST_LONG);
+                    self.i64_const(i64::MIN);
+                    self.op(0x51);
+                    self.local_get(SECOND_LONG);
+                    self.i64_const(-1);
+                    self.op(0x51);
+                    self.op(0x71);
+                    self.local_tee(OVERFLOW);
+                    if mode == ArithmeticMode::Trap {
+                        self.fail_if(VmError::IntegerOverflow { pc });
+                        self.local_get(OVERFLOW);
+                    }
+                    self.if_result(I64);
+                    self.overflowed_division(mode);
+                    self.else_();
+                    self.local_get(FIRST_LONG);
+                    self.local_get(SECOND_LONG);
+                    self.op(0x7F);
+                    self.end();
+                }
+                self.local_set(RESULT_LONG);
+                self.write_pair(third);
+            }
+            Opcode::EQL | Opcode::NEQL | Opcode::GTL | Opcode::LTL | Opcode::GTQL | Opcode::LTQL => {
+                self.read_pair(first);
+                self.read_pair(second);
+                self.op(comparison(instruction.opcode, 0x51));
+                self.local_set(EQUAL);
+            }
+            Opcode::SEXT => {
+                self.local_get(register(first));
+                self.op(0xAC);
+                self.local_set(RESULT_LONG);
+                self.write_pair(second);
+            }
+            Opcode::NIL | Opcode::BOXI | Opcode::BOXF | Opcode::BOXB | Opcode::MOVV => {
+                let (tag, destination) = match instruction.opcode {
+                    Opcode::NIL => {
+                        self.i64_const(0);
+                        (NIL, first)
+                    }
+                    Opcode::BOXI => {
+                        self.local_get(register(first));
+                        self.op(0xAC);
+                        (INT, second)
+                    }
+                    Opcode::BOXF => {
+                        self.local_get(float_register(first));
+                        self.op(0xBD);
+                        (FLOAT, second)
+                    }
+                    Opcode::BOXB => {
+                        self.local_get(EQUAL);
+                        self.op(0xAD);
+                        (BOOL, first)
+                    }
+                    _ => {
+                        self.local_get(value_payload(first));
+                        self.local_get(value_tag(first));
+                        self.local_set(value_tag(second));
+                        self.local_set(value_payload(second));
+                        return Ok(());
+                    }
+                };
+                self.local_set(value_payload(destination));
+                self.i32_const(tag);
+                self.local_set(value_tag(destination));
+            }
+            Opcode::UNBOX => {
+                self.block_type(0x02);
+                self.local_get(value_tag(first));
+                self.i32_const(INT);
+                self.op(0x46);
+                self.block_type(0x04);
+                self.local_get(value_payload(first));
+                self.local_set(RESULT_LONG);
+                self.narrow(register(second), mode);
+                self.op(0x0C);
+                unsigned(&mut self.code, 1);
+                self.end();
+                self.local_get(value_tag(first));
+                self.i32_const(BOOL);
+                self.op(0x46);
+                self.block_type(0x04);
+                self.local_get(value_payload(first));
+                self.op(0xA7);
+                self.local_set(register(second));
+                self.op(0x0C);
+                unsigned(&mut self.code, 1);
+                self.end();
+                self.fail(VmError::TypeError { pc });
+                self.end();
+            }
+            Opcode::UNBOXF => {
+                self.block_type(0x02);
+                self.local_get(value_tag(first));
+                self.i32_const(FLOAT);
+                self.op(0x46);
+                self.block_type(0x04);
+                self.local_get(value_payload(first));
+                self.op(0xBF);
+                self.local_set(float_register(second));
+                self.op(0x0C);
+                unsigned(&mut self.code, 1);
+                self.end();
+                self.local_get(value_tag(first));
+                self.i32_const(INT);
+                self.op(0x46);
+                self.block_type(0x04);
+                self.local_get(value_payload(first));
+                self.op(0xB9);
+                self.local_set(float_register(second));
+                self.op(0x0C);
+                unsigned(&mut self.code, 1);
+                self.end();
+                self.fail(VmError::TypeError { pc });
+                self.end();
+            }
+            Opcode::LOADS => match strings.get(operand as usize).copied().flatten() {
+                Some(address) => {
+                    self.i64_const(address as i64);
+                    self.local_set(value_payload(first));
+                    self.i32_const(STRING);
+                    self.local_set(value_tag(first));
+                }
+                None => self.fail(VmError::BadConstant { pc, index: operand }),
+            },
+            Opcode::PRTS => {
+                self.string(first, FIRST);
+                self.local_get(FIRST);
+                self.i32_const(4);
+                self.op(0x6A);
+                self.local_get(FIRST);
+                self.memory(0x28, 2);
+                self.call(PRINT);
+            }
+            Opcode::SLEN => {
+                self.string(first, FIRST);
+                self.local_get(FIRST);
+                self.call(CHARACTER_COUNT);
+                self.local_set(register(second));
+            }
+            Opcode::ITOS | Opcode::CONCAT => {
+                let destination = if instruction.opcode == Opcode::ITOS {
+                    self.local_get(register(first));
+                    self.call(INTEGER_TO_STRING);
+                    second
+                } else {
+                    self.string(first, FIRST);
+                    self.string(second, SECOND);
+                    self.local_get(FIRST);
+                    self.local_get(SECOND);
+                    self.call(CONCATENATE);
+                    third
+                };
+                self.op(0xAD);
+                self.local_set(value_payload(destination));
+                self.i32_const(STRING);
+                self.local_set(value_tag(destination));
+            }
+            Opcode::CALL => {
+                self.local_get(CALL_DEPTH);
+                self.i32_const(MAX_CALL_DEPTH as i32);
+                self.op(0x4F);
+                self.fail_if(VmError::StackOverflow { pc });
+                self.local_get(CALL_DEPTH);
+                self.slot_address(layout.call_stack);
+                self.i32_const((pc + 1) as i32);
+                self.memory(0x36, 2);
+                self.local_get(CALL_DEPTH);
+                self.i32_const(1);
+                self.op(0x6A);
+                self.local_set(CALL_DEPTH);
+                self.local_get(register(first));
+                self.local_set(PC);
+                self.dispatch();
+            }
+            Opcode::RET => {
+                self.local_get(CALL_DEPTH);
+                self.op(0x45);
+                self.fail_if(VmError::StackUnderflow { pc });
+                self.local_get(CALL_DEPTH);
+                self.i32_const(1);
+                self.op(0x6B);
+                self.local_tee(CALL_DEPTH);
+                self.slot_address(layout.call_stack);
+                self.memory(0x28, 2);
+                self.local_set(PC);
+                self.dispatch();
+            }
+            Opcode::PUSH => {
+                self.local_get(STACK_LENGTH);
+                self.i32_const(STACK_LIMIT as i32);
+                self.op(0x4F);
+                self.fail_if(VmError::StackOverflow { pc });
+                self.local_get(STACK_LENGTH);
+                self.slot_address(layout.stack);
+                self.local_get(register(first));
+                self.memory(0x36, 2);
+                self.local_get(STACK_LENGTH);
+                self.i32_const(1);
+                self.op(0x6A);
+                self.local_set(STACK_LENGTH);
+            }
+            Opcode::POP => {
+                self.local_get(STACK_LENGTH);
+                self.op(0x45);
+                self.fail_if(VmError::StackUnderflow { pc });
+                self.local_get(STACK_LENGTH);
+                self.i32_const(1);
+                self.op(0x6B);
+                self.local_tee(STACK_LENGTH);
+                self.slot_address(layout.stack);
+                self.memory(0x28, 2);
+                self.local_set(register(first));
+            }
+            Opcode::ENTER => {
+                if operand < 0 {
+                    self.fail(VmError::NegativeSize { pc });
+                } else if operand as usize >= STACK_LIMIT {
+                    self.fail(VmError::StackOverflow { pc });
+                } else {
+                    self.local_get(STACK_LENGTH);
+                    self.i32_const(STACK_LIMIT as i32 - 1 - operand as i32);
+                    self.op(0x4B);
+                    self.fail_if(VmError::StackOverflow { pc });
+                    self.local_get(STACK_LENGTH);
+                    self.slot_address(layout.stack);
+                    self.local_get(FRAME_POINTER);
+                    self.memory(0x36, 2);
+                    self.local_get(STACK_LENGTH);
+                    self.i32_const(1);
+                    self.op(0x6A);
+                    self.local_tee(FRAME_POINTER);
+                    self.slot_address(layout.stack);
+                    self.i32_const(0);
+                    self.i32_const(operand as i32 * 4);
+                    self.bulk_memory(0x0B);
+                    self.local_get(FRAME_POINTER);
+                    self.i32_const(operand as i32);
+                    self.op(0x6A);
+                    self.local_set(STACK_LENGTH);
+                }
+            }
+            Opcode::LEAVE => {
+                self.local_get(FRAME_POINTER);
+                self.op(0x45);
+                self.local_get(FRAME_POINTER);
+                self.local_get(STACK_LENGTH);
+                self.op(0x4B);
+                self.op(0x72);
+                self.fail_if(VmError::StackUnderflow { pc });
+                // The caller's frame pointer is just below the frame.
+                self.local_get(FRAME_POINTER);
+                self.i32_const(1);
+                self.op(0x6B);
+                self.local_tee(STACK_LENGTH);
+                self.slot_address(layout.stack);
+                self.memory(0x28, 2);
+                self.local_set(FRAME_POINTER);
+            }
+            Opcode::LDF | Opcode::STF => match i32::try_from(operand) {
+                Ok(offset) => {
+                    // A slot below the bottom of the stack wraps around to a large unsigned index.
+                    self.local_get(FRAME_POINTER);
+                    self.i32_const(offset);
+                    self.op(0x6A);
+                    self.local_tee(FIRST);
+                    self.local_get(STACK_LENGTH);
+                    self.op(0x4F);
+                    self.fail_if(VmError::IndexOutOfBounds { pc });
+                    self.local_get(FIRST);
+                    self.slot_address(layout.stack);
+                    if instruction.opcode == Opcode::LDF {
+                        self.memory(0x28, 2);
+                        self.local_set(register(first));
+                    } else {
+                        self.local_get(register(first));
+                        self.memory(0x36, 2);
+                    }
+                }
+                Err(_) => self.fail(VmError::IndexOutOfBounds { pc }),
+            },
+            Opcode::YIELD => {}
+            Opcode::IGL => self.fail(VmError::IllegalOpcode { pc }),
+            Opcode::ALOC
+            | Opcode::TAG
+            | Opcode::EQV
+            | Opcode::NEWARR
+            | Opcode::NEWMAP
+            | Opcode::GETV
+            | Opcode::SETV
+            | Opcode::LENV
+            | Opcode::APPV
+            | Opcode::PUSHV
+            | Opcode::POPV
+            | Opcode::SUBSTR
+            | Opcode::SCMP
+            | Opcode::CHARAT
+            | Opcode::STOI
+            | Opcode::SPAWN
+            | Opcode::JOIN
+            | Opcode::SPAWNP
+            | Opcode::SEND
+            | Opcode::RECV
+            | Opcode::SELF => return Err(AotError::Unsupported { pc, opcode: instruction.opcode }),
+        }
+        return Ok(());
+    }
+}
+
+/// The wasm comparison for a comparison opcode, given the opcode of `eq` for the type. The signed
+/// comparisons follow in the same order for `i32` and `i64`.
+fn comparison(opcode: Opcode, equal: u8) -> u8 {
+    equal
+        + match opcode {
+            Opcode::EQ | Opcode::EQI | Opcode::EQL => 0,
+            Opcode::NEQ | Opcode::NEQI | Opcode::NEQL => 1,
+            Opcode::LT | Opcode::LTI | Opcode::LTL => 2,
+            Opcode::GT | Opcode::GTI | Opcode::GTL => 4,
+            Opcode::LTQ | Opcode::LTQI | Opcode::LTQL => 6,
+            _ => 8,
+        }
+}
+
+/// `allocate(size) -> address`: bumps the heap pointer by `size` rounded up to a multiple of 4,
+/// growing memory if needed.
+fn allocate() -> Vec<u8> {
+    let (size, address, end) = (0, 1, 2);
+    let mut function = Function::new(0);
+    function.op(0x23);
+    function.op(0x00);
+    function.local_tee(address);
+    function.local_get(size);
+    function.op(0x6A);
+    function.i32_const(3);
+    function.op(0x6A);
+    function.i32_const(-4);
+    function.op(0x71);
+    function.local_set(end);
+    function.block_type(0x02);
+    // Enough memory already.
+    function.local_get(end);
+    function.code.extend_from_slice(&[0x3F, 0x00]);
+    function.i32_const(16);
+    function.op(0x74);
+    function.op(0x4D);
+    function.op(0x0D);
+    unsigned(&mut function.code, 0);
+    // Otherwise grows by as many pages as are missing.
+    function.local_get(end);
+    function.code.extend_from_slice(&[0x3F, 0x00]);
+    function.i32_const(16);
+    function.op(0x74);
+    function.op(0x6B);
+    function.i32_const(PAGE_SIZE as i32 - 1);
+    function.op(0x6A);
+    function.i32_const(16);
+    function.op(0x76);
+    function.code.extend_from_slice(&[0x40, 0x00]);
+    function.i32_const(-1);
+    function.op(0x47);
+    function.op(0x0D);
+    unsigned(&mut function.code, 0);
+    function.op(0x00);
+    function.end();
+    function.local_get(end);
+    function.op(0x24);
+    function.op(0x00);
+    function.local_get(address);
+    return function.code;
+}
+
+/// `character_count(string) -> count`: counts the bytes of the string that do not continue a
+/// UTF-8 sequence.
+fn character_count() -> Vec<u8> {
+    let (string, position, end, count) = (0, 1, 2, 3);
+    let mut function = Function::new(0);
+    function.local_get(string);
+    function.i32_const(4);
+    function.op(0x6A);
+    function.local_tee(position);
+    function.local_get(string);
+    function.memory(0x28, 2);
+    function.op(0x6A);
+    function.local_set(end);
+    function.block_type(0x02);
+    function.block_type(0x03);
+    function.local_get(position);
+    function.local_get(end);
+    function.op(0x4F);
+    function.op(0x0D);
+    unsigned(&mut function.code, 1);
+    function.local_get(position);
+    function.memory(0x2D, 0);
+    function.i32_const(0xC0);
+    function.op(0x71);
+    function.i32_const(0x80);
+    function.op(0x47);
+    function.local_get(count);
+    function.op(0x6A);
+    function.local_set(count);
+    function.local_get(position);
+    function.i32_const(1);
+    function.op(0x6A);
+    function.local_set(position);
+    function.op(0x0C);
+    unsigned(&mut function.code, 0);
+    function.end();
+    function.end();
+    function.local_get(count);
+    return function.code;
+}
+
+/// `integer_to_string(number) -> string`: writes the digits backwards from the end of a new
+/// string, then moves them to its start.
+fn integer_to_string() -> Vec<u8> {
+    let (number, string, position, magnitude) = (0, 1, 2, 3);
+    // A length and at most 11 characters.
+    let size = 16;
+    let mut function = Function::new(0);
+    function.i32_const(size);
+    function.call(ALLOCATE);
+    function.local_tee(string);
+    function.i32_const(size);
+    function.op(0x6A);
+    function.local_set(position);
+    function.local_get(number);
+    function.op(0xAC);
+    function.local_tee(magnitude);
+    function.i64_const(0);
+    function.op(0x53);
+    function.block_type(0x04);
+    function.i64_const(0);
+    function.local_get(magnitude);
+    function.op(0x7D);
+    function.local_set(magnitude);
+    function.end();
+    function.block_type(0x03);
+    function.local_get(position);
+    function.i32_const(1);
+    function.op(0x6B);
+    function.local_tee(position);
+    function.local_get(magnitude);
+    function.i64_const(10);
+    function.op(0x82);
+    function.op(0xA7);
+    function.i32_const(b'0' as i32);
+    function.op(0x6A);
+    function.memory(0x3A, 0);
+    function.local_get(magnitude);
+    function.i64_const(10);
+    function.op(0x80);
+    function.local_tee(magnitude);
+    function.op(0x50);
+    function.op(0x45);
+    function.op(0x0D);
+    unsigned(&mut function.code, 0);
+    function.end();
+    function.local_get(number);
+    function.i32_const(0);
+    function.op(0x48);
+    function.block_type(0x04);
+    function.local_get(position);
+    function.i32_const(1);
+    function.op(0x6B);
+    function.local_tee(position);
+    function.i32_const(b'-' as i32);
+    function.memory(0x3A, 0);
+    function.end();
+    // The length is how far back the digits reached.
+    function.local_get(string);
+    function.local_get(string);
+    function.i32_const(size);
+    function.op(0x6A);
+    function.local_get(position);
+    function.op(0x6B);
+    function.local_tee(number);
+    function.memory(0x36, 2);
+    function.local_get(string);
+    function.i32_const(4);
+    function.op(0x6A);
+    function.local_get(position);
+    function.local_get(number);
+    function.bulk_memory(0x0A);
+    function.local_get(string);
+    return function.code;
+}
+
+/// `concatenate(first, second) -> string`.
+fn concatenate() -> Vec<u8> {
+    let (first, second, string, first_length, second_length) = (0, 1, 2, 3, 4);
+    let mut function = Function::new(0);
+    function.local_get(first);
+    function.memory(0x28, 2);
+    function.local_tee(first_length);
+    function.local_get(second);
+    function.memory(0x28, 2);
+    function.local_tee(second_length);
+    function.op(0x6A);
+    function.i32_const(4);
+    function.op(0x6A);
+    function.call(ALLOCATE);
+    function.local_tee(string);
+    function.local_get(first_length);
+    function.local_get(second_length);
+    function.op(0x6A);
+    function.memory(0x36, 2);
+    function.local_get(string);
+    function.i32_const(4);
+    function.op(0x6A);
+    function.local_get(first);
+    function.i32_const(4);
+    function.op(0x6A);
+    function.local_get(first_length);
+    function.bulk_memory(0x0A);
+    function.local_get(string);
+    function.i32_const(4);
+    function.op(0x6A);
+    function.local_get(first_length);
+    function.op(0x6A);
+    function.local_get(second);
+    function.i32_const(4);
+    function.op(0x6A);
+    function.local_get(second_length);
+    function.bulk_memory(0x0A);
+    function.local_get(string);
+    return function.code;
+}
+
+fn unsigned(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn signed(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn vector(bytes: &mut Vec<u8>, length: usize) {
+    unsigned(bytes, length as u64);
+}
+
+fn name(bytes: &mut Vec<u8>, name: &str) {
+    vector(bytes, name.len());
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: impl FnOnce(&mut Vec<u8>)) {
+    let mut bytes = vec![];
+    contents(&mut bytes);
+    module.push(id);
+    vector(module, bytes.len());
+    module.extend_from_slice(&bytes);
+}
+
+/// A function body, which ends with the `end` the emitted code leaves out.
+fn body(code: &mut Vec<u8>, locals: &[(usize, u8)], instructions: &[u8]) {
+    let mut bytes = vec![];
+    vector(&mut bytes, locals.len());
+    for (count, value_type) in locals {
+        unsigned(&mut bytes, *count as u64);
+        bytes.push(*value_type);
+    }
+    bytes.extend_from_slice(instructions);
+    bytes.push(0x0B);
+    vector(code, bytes.len());
+    code.extend_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use super::*;
+    use crate::{
+        lang,
+        testing::{self, assemble},
+    };
+
+    /// Validates and runs a module with `node`, printing one line per import call and then the
+    /// status `run` returned.
+    const HOST: &str = r#"
+const bytes = require("fs").readFileSync(process.argv[2]);
+if (!WebAssembly.validate(bytes)) {
+    console.log("invalid");
+    process.exit(0);
+}
+const lines = [];
+let memory;
+const imports = {
+    lang_vm: {
+        print: (address, length) => lines.push("print " + new TextDecoder().decode(new Uint8Array(memory.buffer, address, length))),
+        fail: (kind, pc, detail) => lines.push(`fail ${kind} ${pc} ${detail}`),
+    },
+};
+WebAssembly.instantiate(bytes, imports).then(({ instance }) => {
+    memory = instance.exports.memory;
+    lines.push("status " + instance.exports.run());
+    console.log(lines.join("\n"));
+});
+"#;
+
+    /// The LEB128 number at `position`, and the position after it.
+    fn read_unsigned(bytes: &[u8], mut position: usize) -> (u64, usize) {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[position];
+            position += 1;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return (value, position);
+            }
+            shift += 7;
+        }
+    }
+
+    /// Checks the module's framing without running it: the header, sections in order and
+    /// exactly as long as they say, and a body ending in `end` for every declared function.
+    fn check_structure(module: &[u8]) {
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+        let mut position = 8;
+        let mut last_id = 0;
+        let (mut functions, mut bodies) = (None, None);
+        while position < module.len() {
+            let id = module[position];
+            assert!(id > last_id, "section {} is out of order", id);
+            last_id = id;
+            let (length, start) = read_unsigned(module, position + 1);
+            position = start + length as usize;
+            assert!(position <= module.len(), "section {} runs past the end", id);
+            match id {
+                3 => functions = Some(read_unsigned(module, start).0),
+                10 => {
+                    let (count, mut body) = read_unsigned(module, start);
+                    for _ in 0..count {
+                        let (size, first) = read_unsigned(module, body);
+                        body = first + size as usize;
+                        assert_eq!(module[body - 1], 0x0B, "function body does not end in end");
+                    }
+                    assert_eq!(body, position, "code section has bytes after its bodies");
+                    bodies = Some(count);
+                }
+                _ => {}
+            }
+        }
+        assert!(functions.is_some() && functions == bodies);
+    }
+
+    /// What running the module printed. Panics without `node`, so the tests that call this are
+    /// ignored unless asked for with `cargo test -- --ignored`.
+    fn run_module(module: &[u8]) -> Vec<String> {
+        let module_path = testing::temp_path("wasm", ".wasm");
+        let host_path = module_path.with_extension("js");
+        fs::write(&module_path, module).unwrap();
+        fs::write(&host_path, HOST).unwrap();
+        let output = Command::new("node").arg(&host_path).arg(&module_path).output();
+        fs::remove_file(&module_path).unwrap();
+        fs::remove_file(&host_path).unwrap();
+        let output = output.expect("running modules needs node");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        return String::from_utf8(output.stdout).unwrap().lines().map(str::to_string).collect();
+    }
+
+    /// Runs the program with the VM, describing what it did the way the host does.
+    fn interpret(program: Vec<Instruction>, constants: Vec<Constant>, mode: ArithmeticMode) -> Vec<String> {
+        let interpreted = testing::interpret(program, constants, mode);
+        let mut lines: Vec<String> = interpreted.output.iter().map(|line| format!("print {}", line)).collect();
+        match interpreted.result {
+            Err(error) => {
+                let (kind, detail) = error_code(&error);
+                let pc = error.to_string().split(':').next().unwrap().to_string();
+                assert_eq!(super::error(kind, pc.parse().unwrap(), detail as i64), Some(error));
+                lines.push(format!("fail {} {} {}", kind, pc, detail));
+                lines.push("status 2".to_string());
+            }
+            Ok(()) if interpreted.halted => lines.push("status 1".to_string()),
+            Ok(()) => lines.push("status 0".to_string()),
+        }
+        return lines;
+    }
+
+    fn assert_same_as_interpreter(program: Vec<Instruction>, constants: Vec<Constant>, mode: ArithmeticMode) -> Vec<String> {
+        let module = to_wasm(&program, &constants, mode).unwrap();
+        check_structure(&module);
+        let lines = run_module(&module);
+        assert_eq!(lines, interpret(program, constants, mode));
+        return lines;
+    }
+
+    // Floats, register pairs, the stack, strings, values and relative jumps.
+    const ASSEMBLY_SOURCE: &str = ".data\nhalf: .float 0.5\ngreeting: .string \"snow \u{2603}: \"\n.code\n\
+        LOAD $0 #7\nITOF $0 $0\nLOADF $1 @half\nMULF $0 $1 $2\nFTOI $2 $3\n\
+        LOADL $4 #4294967296\nLOAD $6 #3\nSEXT $6 $6\nMULL $4 $6 $8\nSUBL $8 $6 $8\nDIVL $8 $6 $14\n\
+        PUSH $3\nPOP $10\nLOAD $11 #1\nJMPF $11\nLOAD $10 #99\nLOAD $10 #98\n\
+        ADD $10 $8 $12\nADD $12 $14 $12\nBOXI $12 $5\nMOVV $5 $6\nUNBOX $6 $12\n\
+        LOADS $0 @greeting\nITOS $12 $1\nCONCAT $0 $1 $2\nPRTS $2\nSLEN $2 $13\nITOS $13 $3\nPRTS $3\n\
+        LOAD $20 #-17\nDIV $20 $6 $21\nGETREM $22\nITOS $22 $3\nPRTS $3\nHLT";
+
+    // A loop back through a relative jump, one that lands before the start, and one that lands
+    // past the end. The last two add to their offsets so the verifier cannot see them.
+    const RELATIVE_JUMP_SOURCES: [&str; 3] = [
+        "LOAD $0 #3\nLOAD $1 @after-@loop\nloop: DEC $0\nNEQI $0 #0\nJEQB $1\nafter: ITOS $0 $0\nPRTS $0\nHLT",
+        "LOAD $0 #50\nLOAD $1 #1\nADD $0 $1 $0\nEQ $0 $0\nJEQB $0\nHLT",
+        "LOAD $0 #50\nLOAD $1 #1\nADD $0 $1 $0\nJMPF $0\nHLT",
+    ];
+
+    fn arithmetic_program(opcode: Opcode, number: i32, operand: i64) -> Vec<Instruction> {
+        return vec![
+            Instruction::new(Opcode::LOAD, [0, 0, 0], number as i64),
+            Instruction::new(opcode, [0, 0, 1], operand),
+            Instruction::new(Opcode::ITOS, [1, 0, 0], 0),
+            Instruction::new(Opcode::PRTS, [0, 0, 0], 0),
+            Instruction::new(Opcode::HLT, [0; 3], 0),
+        ];
+    }
+
+    const ARITHMETIC_CASES: [(i32, i64); 3] = [(i32::MAX, 1), (i32::MIN, -1), (-5, 3)];
+
+    #[test]
+    fn test_modules_are_well_formed() {
+        let mut programs: Vec<_> = testing::compiled_workloads().into_iter().map(|program| (program, vec![])).collect();
+        programs.push(assemble(ASSEMBLY_SOURCE));
+        programs.push(assemble(testing::REMAINDERS));
+        programs.extend(RELATIVE_JUMP_SOURCES.map(assemble));
+        for (program, constants) in programs {
+            check_structure(&to_wasm(&program, &constants, ArithmeticMode::Wrap).unwrap());
+        }
+        for (number, operand) in ARITHMETIC_CASES {
+            for mode in [ArithmeticMode::Trap, ArithmeticMode::Wrap, ArithmeticMode::Saturate] {
+                check_structure(&to_wasm(&arithmetic_program(Opcode::MULI, number, operand), &[], mode).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "runs modules with node"]
+    fn test_lang_programs_match_interpreter() {
+        for program in testing::compiled_workloads() {
+            let lines = assert_same_as_interpreter(program, vec![], ArithmeticMode::Wrap);
+            assert_eq!(lines, ["print 111144", "print 610", "status 1"]);
+        }
+        let program = lang::compile("print 0 - 2147483647 - 1;").unwrap();
+        assert_eq!(assert_same_as_interpreter(program, vec![], ArithmeticMode::Wrap), ["print -2147483648", "status 1"]);
+    }
+
+    #[test]
+    #[ignore = "runs modules with node"]
+    fn test_assembly_matches_interpreter() {
+        let (program, constants) = assemble(ASSEMBLY_SOURCE);
+        let lines = assert_same_as_interpreter(program, constants, ArithmeticMode::Wrap);
+        assert_eq!(lines, ["print snow \u{2603}: 94", "print 10", "print -2", "status 1"]);
+    }
+
+    #[test]
+    #[ignore = "runs modules with node"]
+    fn test_remainders_match_interpreter() {
+        let (program, constants) = assemble(testing::REMAINDERS);
+        let lines = assert_same_as_interpreter(program, constants, ArithmeticMode::Trap);
+        assert_eq!(lines[..5], ["print 2", "print 2", "print 0", "print 2147483647", "print 0"]);
+    }
+
+    #[test]
+    #[ignore = "runs modules with node"]
+    fn test_relative_jumps_match_interpreter() {
+        for source in RELATIVE_JUMP_SOURCES {
+            let (program, constants) = assemble(source);
+            assert_same_as_interpreter(program, constants, ArithmeticMode::Wrap);
+        }
+    }
+
+    #[test]
+    #[ignore = "runs modules with node"]
+    fn test_arithmetic_modes_match_interpreter() {
+        for opcode in [Opcode::ADDI, Opcode::MULI, Opcode::DIVI] {
+            for (number, operand) in ARITHMETIC_CASES {
+                for mode in [ArithmeticMode::Trap, ArithmeticMode::Wrap, ArithmeticMode::Saturate] {
+                    assert_same_as_interpreter(arithmetic_program(opcode, number, operand), vec![], mode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_unsupported_programs() {
+        let program = vec![Instruction::new(Opcode::SPAWN, [0, 1, 0], 0), Instruction::new(Opcode::HLT, [0; 3], 0)];
+        assert_eq!(to_wasm(&program, &[], ArithmeticMode::Wrap), Err(AotError::Unsupported { pc: 0, opcode: Opcode::SPAWN }));
+    }
+}
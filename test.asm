@@ -7,4 +7,5 @@ GT $1 $4
 ADD $2 $0 $0
 JEQ $3
 LOAD $3 #0
-LOAD $2 #0
\ No newline at end of file
+LOAD $2 #0
+HLT
\ No newline at end of file